@@ -0,0 +1,1980 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, CommandFactory, Parser, Subcommand};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use rbook_utils_core::{
+    AUTO_CLEANUP_PROFILE, AnchorMode, AnthologyFormat, AnthologyPlan, BookConversionResult,
+    BookOverride, ChapterFallbackMode, ChapterNav, ConversionPlan, ConversionSummary, ConvertError,
+    ConvertOptions, ConvertReport, CoverFormat, CoverNaming, CoverOptions, CoverReference,
+    ExportMode, FilenameScheme, FlashcardExport, ImageOutputFormat, MarkdownMode, NavCleanupMode,
+    Newline, NotesMode, OcrCleanupMode, OnConflict, OutputProfile, OutputTemplate, Progress,
+    ProgressHook, RubyMode, SearchHit, SearchOptions, SkipList, SlugStyle, SplitSection, StyleMode,
+    SvgMode, TextDirection, TextNormalization, TranslationExport, WarningCode, book_navigation,
+    book_resources, book_spine, build_anthology, collect_epub_paths, compare_editions,
+    compare_splits, convert_all, convert_all_to_tar, corpus_stats, extract_covers,
+    extract_resource, find_near_duplicates, search_library, validate_encoding,
+};
+use regex::Regex;
+
+#[derive(Parser, Debug)]
+#[command(name = "rbook-utils")]
+#[command(about = "EPUB to Markdown conversion powered by rbook")]
+#[command(
+    after_help = "Exit codes: 0 all books succeeded, 1 some books failed, \
+2 invalid options, 3 no EPUB files found, 4 fatal I/O error, 5 every book failed."
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[arg(long, default_value = "assets")]
+    input_dir: PathBuf,
+    #[arg(long, default_value = "rbook-utils/results")]
+    output_dir: PathBuf,
+    /// Send the converted files somewhere other than --output-dir: a tar
+    /// archive (tar://- for stdout, tar://PATH for a file) or, in builds with
+    /// the object-storage feature, a bucket (s3://BUCKET/PREFIX, gs://BUCKET/PREFIX).
+    #[arg(long, value_name = "URI", value_parser = parse_output_target)]
+    output: Option<OutputTarget>,
+    /// Content type of stored files with extension EXT, as EXT=TYPE (repeatable).
+    #[arg(long = "content-type", value_name = "EXT=TYPE", value_parser = parse_content_type)]
+    content_types: Vec<(String, String)>,
+    #[arg(long)]
+    media_all: bool,
+    #[arg(long, value_enum, default_value_t = MarkdownMode::Plain)]
+    markdown_mode: MarkdownMode,
+    #[arg(long, value_enum, default_value_t = StyleMode::Inline)]
+    style: StyleMode,
+    #[arg(long)]
+    split_chapters: bool,
+    #[arg(long, value_enum, default_value_t = ChapterFallbackMode::Auto)]
+    chapter_fallback: ChapterFallbackMode,
+    /// Regex for headings that start a section in heading fallback, besides
+    /// "Chapter 12", "Part IV", "Prologue" and the like (repeatable).
+    #[arg(long = "heading-pattern", value_name = "REGEX", value_parser = parse_heading_pattern)]
+    heading_patterns: Vec<Regex>,
+    /// Trade fidelity for speed when triaging large collections: plain markdown without CSS
+    /// collection or rich-mode complexity analysis, and no heading fallback scan.
+    #[arg(long, conflicts_with_all = ["markdown_mode", "chapter_fallback"])]
+    fast: bool,
+    /// Output preset: tts writes text ready for a speech engine, large-print writes
+    /// high-contrast HTML pages instead of markdown.
+    #[arg(long, value_enum, default_value_t = OutputProfile::Markdown,
+          conflicts_with_all = ["markdown_mode", "notes_mode"])]
+    profile: OutputProfile,
+    /// With --profile tts, write numbers, ordinals and years as words.
+    #[arg(long)]
+    spell_out_numbers: bool,
+    /// Base font size of --profile large-print pages, in CSS pixels.
+    #[arg(long, value_name = "PX", default_value_t = 24)]
+    font_size: u16,
+    /// Print each book's TOC and heading-fallback sections side by side instead of converting.
+    #[arg(long)]
+    compare_splits: bool,
+    #[arg(long, value_enum, default_value_t = NotesMode::Inline)]
+    notes_mode: NotesMode,
+    #[arg(long, value_enum, default_value_t = ExportMode::Off)]
+    export_manifest: ExportMode,
+    /// Write positions.v1.json mapping chapter/percentage positions to output offsets and CFIs
+    /// (markdown profile only).
+    #[arg(long, value_enum, default_value_t = ExportMode::Off)]
+    export_positions: ExportMode,
+    /// Write provenance.v1.json with the source XHTML byte/char range of every paragraph.
+    #[arg(long, value_enum, default_value_t = ExportMode::Off)]
+    export_provenance: ExportMode,
+    /// Write audio_split.v1.json and audio_split.sh to cut media overlay narration into chapters.
+    #[arg(long, value_enum, default_value_t = ExportMode::Off)]
+    export_audio_split: ExportMode,
+    /// Write a translation skeleton (source sentences, empty targets) as TMX or XLIFF.
+    #[arg(long, value_enum, default_value_t = TranslationExport::Off)]
+    export_translation: TranslationExport,
+    /// Target language of --export-translation, e.g. de; `und` in TMX when not given.
+    #[arg(long, value_name = "LANG")]
+    target_language: Option<String>,
+    /// Write glossary and vocabulary-list terms as a flashcard deck (CSV or Anki import text).
+    #[arg(long, value_enum, default_value_t = FlashcardExport::Off)]
+    export_flashcards: FlashcardExport,
+    #[arg(long, value_enum, default_value_t = ExportMode::Off)]
+    quality_report: ExportMode,
+    #[arg(long, value_enum, default_value_t = OcrCleanupMode::Off)]
+    ocr_cleanup: OcrCleanupMode,
+    #[arg(long, value_enum, default_value_t = NavCleanupMode::Auto)]
+    nav_cleanup: NavCleanupMode,
+    /// Remove a publisher's signature junk with this cleanup profile (gutenberg,
+    /// standard-ebooks, feedbooks, retail-stamps or one from --cleanup-profiles or
+    /// the config file); `auto` picks one by the book's dc:publisher.
+    #[arg(long, value_name = "NAME")]
+    cleanup_profile: Option<String>,
+    /// TOML file of extra cleanup profiles: [name] tables with publishers,
+    /// remove_patterns, skip_sections and strip_classes arrays.
+    #[arg(long, value_name = "FILE")]
+    cleanup_profiles: Option<PathBuf>,
+    /// Remove buyer names and emails that retailers stamp into books from the
+    /// metadata and text written, so outputs can be shared.
+    #[arg(long)]
+    strip_watermarks: bool,
+    #[arg(long, value_enum, default_value_t = FilenameScheme::Index)]
+    filename_scheme: FilenameScheme,
+    /// Lay books out by metadata, e.g. "{author_sort}/{title}/{index:03}_{section}.md".
+    /// Placeholders: title, author, author_sort, series, series_index, slug, and in the
+    /// file name index, section and section_id.
+    #[arg(long, value_name = "TEMPLATE")]
+    output_template: Option<OutputTemplate>,
+    /// What to do when a book's markdown already exists in the output directory.
+    #[arg(long, value_enum, default_value_t = OnConflict::Overwrite)]
+    on_conflict: OnConflict,
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=6))]
+    split_on_heading_level: Option<u8>,
+    /// Merge sections with fewer words than this into the following section.
+    #[arg(long, default_value_t = 0)]
+    min_section_words: usize,
+    /// Turn references to notes kept in other spine documents into footnotes.
+    #[arg(long)]
+    consolidate_endnotes: bool,
+    /// Add a List of Figures built from figure captions (also written as figures.v1.json).
+    #[arg(long)]
+    list_of_figures: bool,
+    /// Write a resized copy of each chapter's first image to thumbs/ and reference it in front matter.
+    #[arg(long)]
+    chapter_thumbnails: bool,
+    /// Longest edge in pixels for chapter thumbnails.
+    #[arg(long, default_value_t = 320)]
+    thumbnail_max_edge: u32,
+    /// How element ids that are link targets are kept in the markdown.
+    #[arg(long, value_enum, default_value_t = AnchorMode::Html)]
+    anchor_mode: AnchorMode,
+    /// Write compare/*.html pages showing source XHTML and rendered markdown side by side.
+    #[arg(long)]
+    compare_view: bool,
+    /// Do not take the output-directory and per-book locks (only safe when runs cannot overlap).
+    #[arg(long)]
+    no_lock: bool,
+    /// Treat an existing output lock older than this many seconds as stale.
+    #[arg(long, default_value_t = 6 * 60 * 60)]
+    lock_stale_after_secs: u64,
+    /// Base URL for image/media/style links, e.g. https://cdn.example.com/books/{slug}/
+    #[arg(long)]
+    asset_base_url: Option<String>,
+    /// What to do with inline <svg>: keep it, extract it to images/svg/, or rasterize it to PNG.
+    #[arg(long = "svg", value_enum, default_value_t = SvgMode::Inline)]
+    svg_mode: SvgMode,
+    /// How <ruby> annotations (furigana) are written: HTML ruby, 漢字（かんじ） brackets, or base text only.
+    #[arg(long, value_enum, default_value_t = RubyMode::Bracket)]
+    ruby_mode: RubyMode,
+    /// Base text direction; auto follows page-progression-direction, dir attributes and language.
+    #[arg(long, value_enum, default_value_t = TextDirection::Auto)]
+    text_direction: TextDirection,
+    /// Copy the book's OTF/TTF/WOFF fonts to fonts/ and point @font-face rules at them (rich mode).
+    #[arg(long)]
+    extract_fonts: bool,
+    /// Where the (always extracted) cover image is referenced: front matter or as the first image.
+    #[arg(long, value_enum, default_value_t = CoverReference::Frontmatter)]
+    cover_reference: CoverReference,
+    /// Only write metadata plus the first body-matter chapter of each book ({slug}.preview.md).
+    #[arg(long)]
+    preview: bool,
+    /// Word budget for --preview; the chapter is cut at the first block boundary past it.
+    #[arg(long, default_value_t = 1500)]
+    preview_max_words: usize,
+    /// Write each chapter as soon as it is rendered, for books too large to hold in memory.
+    /// Implies --split-chapters; skips heading fallback, section merging, cross-chapter
+    /// link rewriting, notes consolidation, chapter navigation links and book-level exports,
+    /// with a warning (W016) for each of those options that is set.
+    #[arg(long)]
+    stream: bool,
+    /// Re-encode extracted images (GIFs and SVGs are always kept as they are).
+    #[arg(long, value_enum, default_value_t = ImageOutputFormat::Original)]
+    image_format: ImageOutputFormat,
+    /// Also treat this media type as a content document (repeatable), e.g. text/plain.
+    #[arg(long = "readable-type")]
+    readable_types: Vec<String>,
+    /// Scale extracted images down to fit within WIDTHxHEIGHT, e.g. 1600x1600.
+    #[arg(long, value_parser = parse_image_size)]
+    max_image_size: Option<(u32, u32)>,
+    /// JPEG quality (1-100) for re-encoded images; WebP output is always lossless.
+    #[arg(long, default_value_t = 85, value_parser = clap::value_parser!(u8).range(1..=100))]
+    image_quality: u8,
+    /// Embed images up to SIZE (bytes, or with a K/M suffix, e.g. 16K) as data URIs.
+    #[arg(long, value_name = "SIZE", value_parser = parse_byte_size)]
+    inline_images_below: Option<u64>,
+    /// Drop warnings with this code or name, e.g. W004 or UnresolvedLinks (repeatable).
+    #[arg(long = "suppress", value_name = "CODE")]
+    suppress_warnings: Vec<WarningCode>,
+    /// Treat warnings with this code as errors, failing the book (repeatable).
+    #[arg(long = "error-on", value_name = "CODE")]
+    error_on_warnings: Vec<WarningCode>,
+    /// Fail books whose chapters had to be found by the heading fallback
+    /// (the same as --error-on W001).
+    #[arg(long)]
+    fail_on_fallback: bool,
+    /// Line written under captioned images; {caption} is replaced by the caption.
+    #[arg(long, default_value = "*{caption}*")]
+    figure_caption_template: String,
+    /// Naming rules for book folders, section files and heading anchors; ascii
+    /// transliterates other scripts, unicode keeps them, and github and mkdocs
+    /// link headings by the anchors those renderers generate.
+    #[arg(long, value_enum, default_value_t = SlugStyle::Ascii)]
+    slug_style: SlugStyle,
+    /// Remove EXIF, XMP and ICC metadata from extracted JPEG and PNG images.
+    #[arg(long)]
+    strip_image_metadata: bool,
+    /// Drop ornament and spacer images (role="presentation", empty alt, a few pixels in size).
+    #[arg(long)]
+    skip_decorative_images: bool,
+    /// Leave every image out of the text, e.g. for a scanned book; the cover is still extracted.
+    #[arg(long)]
+    skip_images: bool,
+    /// Previous/next/contents links between split chapter files, plus an index.md.
+    #[arg(long, value_enum, default_value_t = ChapterNav::Off)]
+    chapter_nav: ChapterNav,
+    /// Convert up to N books at once (0 = one per CPU core).
+    #[arg(long, default_value_t = 1, value_name = "N")]
+    jobs: usize,
+    /// Start no further books once one fails; the rest are reported as skipped.
+    #[arg(long)]
+    fail_fast: bool,
+    /// Read and decode each book's documents on N threads (0 = one per CPU
+    /// core); parsing and rendering stay on one thread.
+    #[arg(long, default_value_t = 1, value_name = "N")]
+    section_jobs: usize,
+    /// Keep at most N parsed documents per book, re-parsing the least recently used on demand.
+    #[arg(long, value_name = "N")]
+    cache_max_documents: Option<usize>,
+    /// Keep at most SIZE of parsed documents per book (by HTML size, e.g. 64M).
+    #[arg(long, value_name = "SIZE", value_parser = parse_byte_size)]
+    cache_max_bytes: Option<u64>,
+    /// Only convert books whose metadata marks them as (likely) public domain.
+    #[arg(long)]
+    only_public_domain: bool,
+    /// Books published before this year count as public domain [default: this year - 95].
+    #[arg(long, value_name = "YEAR")]
+    public_domain_before: Option<i32>,
+    /// Write markdown in this encoding, e.g. shift_jis or windows-1252 (needs the output-encoding feature).
+    #[arg(long, value_name = "LABEL", value_parser = parse_encoding)]
+    output_encoding: Option<String>,
+    /// Line endings of markdown, CSS and JSON outputs.
+    #[arg(long, value_enum, default_value_t = Newline::Lf)]
+    newline: Newline,
+    /// Start UTF-8 text outputs with a byte order mark.
+    #[arg(long)]
+    bom: bool,
+    /// Unicode normalization form of markdown outputs; nfkc also folds
+    /// ligatures, full-width forms and superscripts.
+    #[arg(long, value_enum, default_value_t = TextNormalization::Nfc)]
+    normalize_unicode: TextNormalization,
+    /// Write no-break, thin and other fixed-width spaces inside lines as ordinary spaces.
+    #[arg(long)]
+    collapse_spaces: bool,
+    /// Record the sections detected in each book as an editable TOML plan.
+    #[arg(long, value_name = "PATH")]
+    plan_export: Option<PathBuf>,
+    /// Convert only the books and sections of a plan, in its order and with its titles.
+    #[arg(long, value_name = "PATH")]
+    plan: Option<PathBuf>,
+    /// Add MinHash/simhash fingerprints of each book and section to the manifest.
+    #[arg(long)]
+    fingerprints: bool,
+    /// Add this run's totals, failures and duration to a local stats file
+    /// (TOML for .toml, JSON otherwise). Nothing leaves the machine.
+    #[arg(long, value_name = "PATH")]
+    usage_stats: Option<PathBuf>,
+    /// Skip the books listed in this file (by path, file name or identifier)
+    /// and add books that keep failing to it.
+    #[arg(long, value_name = "PATH")]
+    skip_list: Option<PathBuf>,
+    /// Failed runs in a row after which --skip-list adds a book; 0 never adds.
+    #[arg(long, value_name = "N", default_value_t = 3)]
+    skip_after: u32,
+    /// Only convert books that are new or changed since the last --incremental run into the
+    /// output directory, or were converted with different options.
+    #[arg(long)]
+    incremental: bool,
+    /// After converting, keep running and convert EPUBs as they are added to or modified in
+    /// the input directory (needs the watch feature).
+    #[arg(long, conflicts_with = "output")]
+    watch: bool,
+    /// Do not draw progress bars (they are only drawn on a terminal anyway).
+    #[arg(long)]
+    no_progress: bool,
+    /// Log conversion events to stderr: -v info, -vv per-section debug, -vvv trace.
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// Console output for per-book results and -v logs.
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+    /// Write a JSON report of the outcome and every book's status to PATH.
+    #[arg(long, global = true, value_name = "PATH")]
+    report_file: Option<PathBuf>,
+    /// TOML file of defaults for the top-level flags, keyed by flag name
+    /// (`split_chapters = true`, `heading_pattern = ["^Letter \\d+"]`), with
+    /// [cleanup_profiles.NAME] tables adding cleanup profiles and [books."KEY"]
+    /// tables of flags for the book with that file name or identifier. Read
+    /// from ./rbook-utils.toml when present; flags given on the command line win.
+    #[arg(long, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Cleanup profiles from the config file, and the file's path.
+    #[arg(skip)]
+    config_profiles: Option<(PathBuf, String)>,
+    /// The config's [books."KEY"] tables.
+    #[arg(skip)]
+    book_configs: Vec<BookConfig>,
+    /// Emit book text verbatim without escaping markdown syntax characters.
+    #[arg(long)]
+    no_escape: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print each EPUB's navigation (toc, landmarks, page-list) without converting.
+    Toc {
+        /// EPUB files or directories to scan.
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Summarize each EPUB's manifest: sizes and what the spine never reaches.
+    Inspect {
+        /// EPUB files or directories to scan.
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+        /// List every manifest item, largest first.
+        #[arg(long)]
+        resources: bool,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Print each EPUB's reading order: the spine documents a conversion walks, with word estimates.
+    Spine {
+        /// EPUB files or directories to scan.
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+        /// Also treat this media type as a content document (repeatable), as in conversion.
+        #[arg(long = "readable-type")]
+        readable_types: Vec<String>,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Read one resource out of an EPUB, resolving its href the way the converter does.
+    ExtractResource {
+        epub: PathBuf,
+        /// The href as written in the book, e.g. `../images/fig%201.png`.
+        href: String,
+        /// Href of the document the reference appears in, to resolve a relative href against.
+        #[arg(long, value_name = "HREF")]
+        base: Option<String>,
+        /// Where to write the resource, `-` for stdout; defaults to its file name.
+        #[arg(long, value_name = "PATH")]
+        out: Option<PathBuf>,
+    },
+    /// Search the text of every EPUB without writing converted output.
+    Find {
+        /// Phrase to look for (a regular expression with --regex).
+        pattern: String,
+        #[arg(long, default_value = "assets")]
+        input_dir: PathBuf,
+        #[arg(short, long)]
+        ignore_case: bool,
+        #[arg(long)]
+        regex: bool,
+        /// Characters of context shown on each side of a match.
+        #[arg(long, default_value_t = 60)]
+        context: usize,
+        /// Also search documents of this media type (repeatable).
+        #[arg(long = "readable-type")]
+        readable_types: Vec<String>,
+        /// json prints one object per hit (JSON lines) as they are found.
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Extract only the cover image of every EPUB, without converting.
+    Covers {
+        #[arg(long, default_value = "assets")]
+        input_dir: PathBuf,
+        #[arg(long, default_value = "rbook-utils/results/covers")]
+        output_dir: PathBuf,
+        /// Longest edge in pixels; larger covers are scaled down.
+        #[arg(long, default_value_t = 600)]
+        max_edge: u32,
+        #[arg(long, value_enum, default_value_t = CoverFormat::Jpeg)]
+        format: CoverFormat,
+        /// Name files after the title slug or the book's identifier (ISBN, UUID).
+        #[arg(long, value_enum, default_value_t = CoverNaming::Slug)]
+        naming: CoverNaming,
+    },
+    /// Align the chapters of two editions or translations of a work and diff each pair.
+    /// Conversion flags given before the subcommand apply to both books.
+    CompareEditions {
+        left: PathBuf,
+        right: PathBuf,
+        #[arg(long, default_value = "rbook-utils/results/editions")]
+        output_dir: PathBuf,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Combine chapters from several EPUBs into one reader, following a TOML plan.
+    /// Conversion flags given before the subcommand apply to every book.
+    Anthology {
+        /// Plan file listing the books and, in order, the sections to take from each.
+        plan: PathBuf,
+        #[arg(long, default_value = "rbook-utils/results/anthology")]
+        output_dir: PathBuf,
+        #[arg(long, value_enum, default_value_t = AnthologyFormat::Markdown)]
+        format: AnthologyFormat,
+    },
+    /// Summarize a converted library from its manifests: books, sections, words, languages,
+    /// images and quality-report totals. Books need --export-manifest v1 to be counted.
+    Stats {
+        #[arg(long, default_value = "rbook-utils/results")]
+        output_dir: PathBuf,
+        /// Also list every book.
+        #[arg(long)]
+        books: bool,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Convert every EPUB and report books and sections with near-identical text.
+    /// Conversion flags given before the subcommand apply to every book.
+    NearDuplicates {
+        #[arg(long, default_value = "assets")]
+        input_dir: PathBuf,
+        #[arg(long, default_value = "rbook-utils/results/near-duplicates")]
+        output_dir: PathBuf,
+        /// Minimum estimated similarity (0 to 1) of the word 5-grams to report.
+        #[arg(long, default_value_t = 0.8)]
+        threshold: f32,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Debug)]
+enum OutputTarget {
+    TarStdout,
+    TarFile(PathBuf),
+    /// `s3://` or `gs://` URL.
+    Bucket(String),
+}
+
+fn parse_output_target(value: &str) -> Result<OutputTarget, String> {
+    if let Some(target) = value.strip_prefix("tar://") {
+        return match target {
+            "-" => Ok(OutputTarget::TarStdout),
+            "" => Err(format!("expected tar://- or tar://PATH, got {value}")),
+            path => Ok(OutputTarget::TarFile(PathBuf::from(path))),
+        };
+    }
+    if value.starts_with("s3://") || value.starts_with("gs://") {
+        if cfg!(feature = "object-storage") {
+            return Ok(OutputTarget::Bucket(value.to_string()));
+        }
+        return Err(format!(
+            "{value} needs a build with the object-storage feature"
+        ));
+    }
+    Err(format!(
+        "expected tar://-, tar://PATH, s3://BUCKET/PREFIX or gs://BUCKET/PREFIX, got {value}"
+    ))
+}
+
+fn parse_heading_pattern(value: &str) -> Result<Regex, String> {
+    Regex::new(value).map_err(|err| format!("invalid heading pattern: {err}"))
+}
+
+fn parse_content_type(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((extension, content_type)) if !extension.is_empty() && !content_type.is_empty() => {
+            Ok((extension.to_string(), content_type.to_string()))
+        }
+        _ => Err(format!("expected EXT=TYPE, got {value}")),
+    }
+}
+
+/// Set while stdout carries an archive; lines normally printed there go to
+/// stderr instead.
+static STDOUT_IS_ARCHIVE: AtomicBool = AtomicBool::new(false);
+
+fn print_info(text: &str) {
+    if STDOUT_IS_ARCHIVE.load(Ordering::Relaxed) {
+        eprintln!("{text}");
+    } else {
+        println!("{text}");
+    }
+}
+
+#[cfg(feature = "object-storage")]
+fn convert_to_bucket(
+    url: &str,
+    content_types: &[(String, String)],
+    options: &ConvertOptions,
+) -> rbook_utils_core::Result<ConversionSummary> {
+    let storage = content_types.iter().fold(
+        rbook_utils_core::ObjectStorage::from_url(url)?,
+        |storage, (extension, content_type)| storage.with_content_type(extension, content_type),
+    );
+    rbook_utils_core::convert_all_to_storage(options, &storage)
+}
+
+#[cfg(not(feature = "object-storage"))]
+fn convert_to_bucket(
+    url: &str,
+    _content_types: &[(String, String)],
+    _options: &ConvertOptions,
+) -> rbook_utils_core::Result<ConversionSummary> {
+    unreachable!("{url} is rejected when parsing --output")
+}
+
+fn parse_image_size(value: &str) -> Result<(u32, u32), String> {
+    let (width, height) = value
+        .to_lowercase()
+        .split_once('x')
+        .map(|(width, height)| (width.trim().parse::<u32>(), height.trim().parse::<u32>()))
+        .ok_or_else(|| format!("expected WIDTHxHEIGHT, got {value}"))?;
+    match (width, height) {
+        (Ok(width), Ok(height)) if width > 0 && height > 0 => Ok((width, height)),
+        _ => Err(format!("expected positive WIDTHxHEIGHT, got {value}")),
+    }
+}
+
+/// A bar for books in the batch and one for sections of the book being
+/// converted, on stderr.
+struct ProgressBars {
+    books: ProgressBar,
+    sections: ProgressBar,
+}
+
+impl ProgressBars {
+    fn new() -> Self {
+        let bars = MultiProgress::new();
+        let books = bars.add(
+            ProgressBar::new(0).with_style(
+                ProgressStyle::with_template("{bar:30} {pos}/{len} books  ETA {eta}  {msg}")
+                    .expect("valid progress template"),
+            ),
+        );
+        let sections = bars.add(
+            ProgressBar::new(0).with_style(
+                ProgressStyle::with_template("{bar:30} {pos}/{len} sections  ETA {eta}")
+                    .expect("valid progress template"),
+            ),
+        );
+        Self { books, sections }
+    }
+
+    fn hook(&self) -> ProgressHook {
+        let books = self.books.clone();
+        let sections = self.sections.clone();
+        ProgressHook::new(move |progress| match progress {
+            Progress::BookStarted { path, total, .. } => {
+                books.set_length(*total as u64);
+                books.set_message(
+                    path.file_name()
+                        .map(|name| name.to_string_lossy().to_string())
+                        .unwrap_or_default(),
+                );
+                sections.reset();
+            }
+            Progress::SectionStarted { index, total, .. } => {
+                sections.set_length(*total as u64);
+                sections.set_position(*index as u64);
+            }
+            Progress::SectionRendered { index, .. } => {
+                sections.set_position(*index as u64 + 1);
+            }
+            Progress::ImageExtracted { .. } => {}
+            Progress::BookFinished { .. } => {
+                books.inc(1);
+                sections.set_position(sections.length().unwrap_or(0));
+            }
+        })
+    }
+
+    fn clear(&self) {
+        self.sections.finish_and_clear();
+        self.books.finish_and_clear();
+    }
+}
+
+fn parse_encoding(value: &str) -> Result<String, String> {
+    validate_encoding(value)?;
+    Ok(value.trim().to_string())
+}
+
+fn parse_byte_size(value: &str) -> Result<u64, String> {
+    let lower = value.trim().to_lowercase();
+    let lower = lower.trim_end_matches('b').trim_end_matches('i');
+    let (number, multiplier) = match lower.char_indices().last() {
+        Some((idx, 'k')) => (&lower[..idx], 1024),
+        Some((idx, 'm')) => (&lower[..idx], 1024 * 1024),
+        Some((idx, 'g')) => (&lower[..idx], 1024 * 1024 * 1024),
+        _ => (lower, 1),
+    };
+    number
+        .trim()
+        .parse::<u64>()
+        .map(|number| number * multiplier)
+        .map_err(|_| format!("expected a size such as 16384 or 16K, got {value}"))
+}
+
+/// Process exit codes. The values are stable so wrapper scripts can branch on
+/// them; 2 matches what clap exits with for a bad command line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Outcome {
+    Ok = 0,
+    BooksFailed = 1,
+    InvalidOptions = 2,
+    NoInput = 3,
+    FatalIo = 4,
+    /// Books were tried and none of them converted.
+    AllBooksFailed = 5,
+}
+
+impl Outcome {
+    fn name(self) -> &'static str {
+        match self {
+            Outcome::Ok => "ok",
+            Outcome::BooksFailed => "books_failed",
+            Outcome::InvalidOptions => "invalid_options",
+            Outcome::NoInput => "no_input",
+            Outcome::FatalIo => "fatal_io",
+            Outcome::AllBooksFailed => "all_books_failed",
+        }
+    }
+
+    /// Outcome of a batch where `failures` of the `attempted` books (those
+    /// not skipped) failed.
+    fn of_failures(failures: usize, attempted: usize) -> Self {
+        match failures {
+            0 => Outcome::Ok,
+            failures if failures >= attempted => Outcome::AllBooksFailed,
+            _ => Outcome::BooksFailed,
+        }
+    }
+
+    /// Category of an error that stopped the run before every book was tried.
+    fn of_error(err: &anyhow::Error) -> Self {
+        match err.downcast_ref::<ConvertError>() {
+            Some(ConvertError::NoInput { .. }) => Outcome::NoInput,
+            Some(
+                ConvertError::InvalidPattern(_)
+                | ConvertError::InvalidPlan { .. }
+                | ConvertError::InvalidCleanupProfiles { .. }
+                | ConvertError::InvalidStorageUrl { .. }
+                | ConvertError::UnsupportedEncoding(_)
+                | ConvertError::UnsupportedOption { .. },
+            ) => Outcome::InvalidOptions,
+            _ => Outcome::FatalIo,
+        }
+    }
+}
+
+fn epub_inputs(inputs: &[PathBuf]) -> anyhow::Result<Vec<PathBuf>> {
+    let paths: Vec<PathBuf> = inputs
+        .iter()
+        .flat_map(|input| collect_epub_paths(input))
+        .collect();
+    if paths.is_empty() {
+        let inputs: Vec<String> = inputs
+            .iter()
+            .map(|input| input.display().to_string())
+            .collect();
+        return Err(ConvertError::NoInput {
+            dir: PathBuf::from(inputs.join(", ")),
+        }
+        .into());
+    }
+    Ok(paths)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum LogFormat {
+    /// Info on stdout; warnings and errors on stderr, tagged with their code.
+    Text,
+    /// One JSON object per line on stderr.
+    Json,
+}
+
+impl LogFormat {
+    fn diagnostic(self, book: &BookConversionResult, diagnostic: &rbook_utils_core::Diagnostic) {
+        if self == LogFormat::Json {
+            let level = match diagnostic.level {
+                rbook_utils_core::DiagnosticLevel::Info => "info",
+                rbook_utils_core::DiagnosticLevel::Warning => "warning",
+                rbook_utils_core::DiagnosticLevel::Error => "error",
+            };
+            let missing = &book.missing_resources;
+            let details = match diagnostic.code {
+                Some(WarningCode::MissingImages) => serde_json::json!({ "images": missing.images }),
+                Some(WarningCode::MissingStylesheets) => {
+                    serde_json::json!({ "stylesheets": missing.stylesheets })
+                }
+                Some(WarningCode::UnresolvedLinks) => serde_json::json!({ "links": missing.links }),
+                _ => serde_json::Value::Null,
+            };
+            self.line(
+                book,
+                serde_json::json!({
+                    "event": "diagnostic",
+                    "level": level,
+                    "code": diagnostic.code.map(|code| code.code()),
+                    "name": diagnostic.code.map(|code| code.name()),
+                    "message": diagnostic.message,
+                    "details": details,
+                }),
+            );
+            return;
+        }
+        let code = diagnostic
+            .code
+            .map(|code| format!(" [{code} {}]", code.name()))
+            .unwrap_or_default();
+        match diagnostic.level {
+            rbook_utils_core::DiagnosticLevel::Info => print_info(&diagnostic.message),
+            rbook_utils_core::DiagnosticLevel::Warning => {
+                eprintln!("Warning{code}: {}", diagnostic.message)
+            }
+            rbook_utils_core::DiagnosticLevel::Error => {
+                eprintln!("Error{code}: {}", diagnostic.message)
+            }
+        }
+    }
+
+    /// `text` is the line printed in text mode.
+    fn written(self, book: &BookConversionResult, path: &Path, text: &str) {
+        match self {
+            LogFormat::Text => print_info(text),
+            LogFormat::Json => self.line(
+                book,
+                serde_json::json!({
+                    "event": "written",
+                    "level": "info",
+                    "output": path.display().to_string(),
+                }),
+            ),
+        }
+    }
+
+    fn skipped(self, book: &BookConversionResult, reason: &str) {
+        match self {
+            LogFormat::Text => print_info(&format!("Skipped {reason}")),
+            LogFormat::Json => self.line(
+                book,
+                serde_json::json!({
+                    "event": "skipped",
+                    "level": "info",
+                    "reason": reason,
+                }),
+            ),
+        }
+    }
+
+    fn line(self, book: &BookConversionResult, mut record: serde_json::Value) {
+        record["book"] = serde_json::json!(book.input_path.display().to_string());
+        record["title"] = serde_json::json!(book.title);
+        eprintln!("{record}");
+    }
+}
+
+fn write_report(
+    path: &Path,
+    outcome: Outcome,
+    error: Option<&str>,
+    summary: Option<&ConversionSummary>,
+) -> anyhow::Result<()> {
+    let books: Vec<serde_json::Value> = summary
+        .map(|summary| summary.books.as_slice())
+        .unwrap_or_default()
+        .iter()
+        .map(|book| {
+            let messages = |level: rbook_utils_core::DiagnosticLevel| -> Vec<serde_json::Value> {
+                book.diagnostics
+                    .iter()
+                    .filter(|diagnostic| diagnostic.level == level)
+                    .map(|diagnostic| {
+                        serde_json::json!({
+                            "code": diagnostic.code.map(|code| code.code()),
+                            "name": diagnostic.code.map(|code| code.name()),
+                            "message": diagnostic.message,
+                        })
+                    })
+                    .collect()
+            };
+            let errors = messages(rbook_utils_core::DiagnosticLevel::Error);
+            serde_json::json!({
+                "input": book.input_path.display().to_string(),
+                "title": book.title,
+                "output": book.output_path.as_ref().map(|path| path.display().to_string()),
+                "ok": (book.output_path.is_some() || book.skipped.is_some()) && errors.is_empty(),
+                "skipped": book.skipped,
+                "warnings": messages(rbook_utils_core::DiagnosticLevel::Warning),
+                "errors": errors,
+                "missing_resources": {
+                    "images": book.missing_resources.images,
+                    "stylesheets": book.missing_resources.stylesheets,
+                    "links": book.missing_resources.links,
+                },
+                "report": book_report_json(&book.report),
+            })
+        })
+        .collect();
+    let totals = summary.map(|summary| {
+        let batch = summary.report();
+        serde_json::json!({
+            "books": batch.books,
+            "converted": batch.converted,
+            "failed": batch.failed,
+            "skipped": batch.skipped,
+            "sections": batch.sections,
+            "words": batch.words,
+            "images_extracted": batch.images_extracted,
+            "media_extracted": batch.media_extracted,
+            "heading_fallback_books": batch.heading_fallback_books,
+            "degenerate_toc_books": batch.degenerate_toc_books,
+            "warnings_by_code": batch.warnings_by_code,
+            "errors": batch.errors,
+        })
+    });
+    let report = serde_json::json!({
+        "exit_code": outcome as u8,
+        "outcome": outcome.name(),
+        "error": error,
+        "totals": totals,
+        "books": books,
+    });
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, serde_json::to_string_pretty(&report)? + "\n")?;
+    Ok(())
+}
+
+fn book_report_json(report: &ConvertReport) -> serde_json::Value {
+    serde_json::json!({
+        "output_paths": report
+            .output_paths
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect::<Vec<_>>(),
+        "sections": report.section_count,
+        "words": report.word_count,
+        "section_words": report.section_words,
+        "images_extracted": report.images_extracted,
+        "media_extracted": report.media_extracted,
+        "heading_fallback_used": report.heading_fallback_used,
+        "toc": {
+            "entries": report.toc.entries,
+            "unique_hrefs": report.toc.unique_hrefs,
+            "coverage_ratio": report.toc.coverage_ratio,
+            "degenerate": report.toc.degenerate,
+        },
+    })
+}
+
+fn run_toc(inputs: &[PathBuf], format: OutputFormat) -> anyhow::Result<Outcome> {
+    let mut books = Vec::new();
+    for path in epub_inputs(inputs)? {
+        books.push(book_navigation(&path)?);
+    }
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&books)?),
+        OutputFormat::Text => {
+            for book in &books {
+                println!(
+                    "{} ({})",
+                    book["title"].as_str().unwrap_or(""),
+                    book["path"]
+                );
+                for (name, key) in [
+                    ("Contents", "toc"),
+                    ("Landmarks", "landmarks"),
+                    ("Page list", "page_list"),
+                ] {
+                    let Some(entries) = book[key].as_array().filter(|entries| !entries.is_empty())
+                    else {
+                        continue;
+                    };
+                    println!("  {name}:");
+                    print_toc_entries(entries, 2);
+                }
+            }
+        }
+    }
+    Ok(Outcome::Ok)
+}
+
+fn print_toc_entries(entries: &[serde_json::Value], indent: usize) {
+    for entry in entries {
+        println!(
+            "{}- {} -> {}",
+            "  ".repeat(indent),
+            entry["label"].as_str().unwrap_or(""),
+            entry["href"].as_str().unwrap_or("")
+        );
+        if let Some(children) = entry["children"].as_array() {
+            print_toc_entries(children, indent + 1);
+        }
+    }
+}
+
+fn run_extract_resource(
+    epub: &Path,
+    href: &str,
+    base: Option<&str>,
+    out: Option<&Path>,
+) -> anyhow::Result<Outcome> {
+    let resource = extract_resource(epub, href, base)?;
+    let default_out;
+    let out = match out {
+        Some(out) => out,
+        None => {
+            let name = resource
+                .output_path
+                .rsplit('/')
+                .next()
+                .unwrap_or("resource");
+            default_out = PathBuf::from(name);
+            &default_out
+        }
+    };
+    if out == Path::new("-") {
+        std::io::stdout().write_all(&resource.bytes)?;
+    } else {
+        fs::write(out, &resource.bytes)?;
+    }
+    // Details go to stderr so they never mix with the resource on stdout.
+    eprintln!("{href} -> {}", resource.resolved);
+    match (&resource.manifest_href, &resource.media_type) {
+        (Some(manifest_href), Some(media_type)) => {
+            eprintln!("  manifest item {manifest_href} ({media_type})")
+        }
+        _ => eprintln!("  not in the manifest (read from the container directly)"),
+    }
+    eprintln!(
+        "  {} bytes; converted books keep it as {}",
+        resource.bytes.len(),
+        resource.output_path
+    );
+    if out != Path::new("-") {
+        eprintln!("  written to {}", out.display());
+    }
+    Ok(Outcome::Ok)
+}
+
+fn run_compare_splits(options: &ConvertOptions) -> anyhow::Result<Outcome> {
+    let cell = |section: Option<&SplitSection>| {
+        section.map_or_else(String::new, |section| {
+            let mut label: String = section.label.chars().take(34).collect();
+            if label.len() < section.label.len() {
+                label.push('…');
+            }
+            format!("{label} ({}w)", section.words)
+        })
+    };
+    for (idx, path) in collect_epub_paths(&options.input_dir).iter().enumerate() {
+        let comparison = compare_splits(path, options)?;
+        if idx > 0 {
+            println!();
+        }
+        println!("{} ({})", comparison.title, path.display());
+        println!("  {:>5}  {:<44} {:<44}", "spine", "toc", "headings");
+        for (toc, headings) in comparison.boundaries() {
+            let Some(start) = toc.or(headings) else {
+                continue;
+            };
+            let marker = if toc.is_none() || headings.is_none() {
+                '*'
+            } else {
+                ' '
+            };
+            let position = match &start.start_fragment {
+                Some(fragment) => format!("{}#{fragment}", start.spine_start),
+                None => start.spine_start.to_string(),
+            };
+            println!(
+                "{marker} {position:>5}  {:<44} {:<44}",
+                cell(toc),
+                cell(headings)
+            );
+        }
+        println!(
+            "  {} toc sections, {} heading sections, {} boundaries differ (*); auto would use {}",
+            comparison.toc.len(),
+            comparison.headings.len(),
+            comparison.differing_boundaries(),
+            if comparison.auto_uses_headings() {
+                "headings"
+            } else {
+                "the toc"
+            }
+        );
+    }
+    Ok(Outcome::Ok)
+}
+
+fn run_spine(
+    inputs: &[PathBuf],
+    readable_types: &[String],
+    format: OutputFormat,
+) -> anyhow::Result<Outcome> {
+    let mut books = Vec::new();
+    for path in epub_inputs(inputs)? {
+        books.push(book_spine(&path, readable_types)?);
+    }
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&books)?),
+        OutputFormat::Text => {
+            for book in &books {
+                let totals = &book["totals"];
+                println!(
+                    "{} ({})",
+                    book["title"].as_str().unwrap_or(""),
+                    book["path"]
+                );
+                println!(
+                    "  {} of {} spine items read, ~{} words",
+                    totals["readable"], totals["itemrefs"], totals["words"]
+                );
+                for document in book["documents"].as_array().into_iter().flatten() {
+                    let href = document["href"]
+                        .as_str()
+                        .or_else(|| document["idref"].as_str())
+                        .unwrap_or("");
+                    let linear = if document["linear"].as_bool() == Some(false) {
+                        "  (non-linear)"
+                    } else {
+                        ""
+                    };
+                    let media_type = document["media_type"].as_str().unwrap_or("-");
+                    match document["index"].as_u64() {
+                        Some(index) => {
+                            let words = document["words"].as_u64().map_or_else(
+                                || "unreadable".to_string(),
+                                |words| words.to_string(),
+                            );
+                            println!("  {index:>4}  {words:>10}  {media_type:<24} {href}{linear}");
+                        }
+                        None => println!(
+                            "  {:>4}  {:>10}  {media_type:<24} {href}{linear}  [skipped: {}]",
+                            "-",
+                            "",
+                            document["skipped"].as_str().unwrap_or("")
+                        ),
+                    }
+                }
+            }
+        }
+    }
+    Ok(Outcome::Ok)
+}
+
+fn run_stats(output_dir: &Path, list_books: bool, format: OutputFormat) -> anyhow::Result<Outcome> {
+    let stats = corpus_stats(output_dir)?;
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&stats)?),
+        OutputFormat::Text => {
+            println!(
+                "{}: {} books, {} sections, {} words, {} images, {} media files",
+                stats.output_dir.display(),
+                stats.books,
+                stats.sections,
+                stats.words,
+                stats.images,
+                stats.media
+            );
+            if stats.books > 0 {
+                let (min, median, max) = stats.words_per_book;
+                println!("  words per book: min {min}, median {median}, max {max}");
+                let languages: Vec<String> = stats
+                    .languages
+                    .iter()
+                    .map(|(language, books)| format!("{language} {books}"))
+                    .collect();
+                println!("  languages: {}", languages.join(", "));
+            }
+            let quality = &stats.quality;
+            if quality.reports > 0 {
+                println!(
+                    "  quality ({} reports): {} heading fallback, {} degenerate TOC, \
+                     {} unresolved links, {} missing assets, {} warnings",
+                    quality.reports,
+                    quality.heading_fallback,
+                    quality.degenerate_toc,
+                    quality.unresolved_links,
+                    quality.missing_assets,
+                    quality.warnings
+                );
+            }
+            if list_books {
+                for book in &stats.per_book {
+                    println!(
+                        "  {:>8} words  {:>4} sections  {:>4} images  {:<6} {}",
+                        book.words,
+                        book.sections,
+                        book.images,
+                        book.language.as_deref().unwrap_or("-"),
+                        book.title
+                    );
+                }
+            }
+            for path in &stats.unreadable {
+                eprintln!("warning: could not read {}", path.display());
+            }
+        }
+    }
+    Ok(Outcome::Ok)
+}
+
+fn run_inspect(
+    inputs: &[PathBuf],
+    resources: bool,
+    format: OutputFormat,
+) -> anyhow::Result<Outcome> {
+    let mut books = Vec::new();
+    for path in epub_inputs(inputs)? {
+        let mut book = book_resources(&path)?;
+        if !resources && format == OutputFormat::Json {
+            if let Some(book) = book.as_object_mut() {
+                book.remove("resources");
+            }
+        }
+        books.push(book);
+    }
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&books)?),
+        OutputFormat::Text => {
+            for book in &books {
+                let totals = &book["totals"];
+                println!(
+                    "{} ({})",
+                    book["title"].as_str().unwrap_or(""),
+                    book["path"]
+                );
+                println!(
+                    "  {} items, {} stored / {} uncompressed; {} unreachable from the spine ({})",
+                    totals["items"],
+                    format_size(totals["compressed_size"].as_u64()),
+                    format_size(totals["size"].as_u64()),
+                    totals["unreachable_items"],
+                    format_size(totals["unreachable_size"].as_u64()),
+                );
+                if !resources {
+                    continue;
+                }
+                let mut items: Vec<&serde_json::Value> = book["resources"]
+                    .as_array()
+                    .map(|items| items.iter().collect())
+                    .unwrap_or_default();
+                items.sort_by_key(|item| std::cmp::Reverse(item["size"].as_u64().unwrap_or(0)));
+                for item in items {
+                    let reach = if item["in_spine"].as_bool() == Some(true) {
+                        "spine"
+                    } else if item["reachable"].as_bool() == Some(true) {
+                        "linked"
+                    } else {
+                        "unreachable"
+                    };
+                    println!(
+                        "  {:>10} {:>10}  {:<11} {:<28} {}",
+                        format_size(item["compressed_size"].as_u64()),
+                        format_size(item["size"].as_u64()),
+                        reach,
+                        item["media_type"].as_str().unwrap_or(""),
+                        item["href"].as_str().unwrap_or("")
+                    );
+                }
+            }
+        }
+    }
+    Ok(Outcome::Ok)
+}
+
+fn run_find(options: &SearchOptions, format: OutputFormat) -> anyhow::Result<Outcome> {
+    if collect_epub_paths(&options.input_dir).is_empty() {
+        return Err(ConvertError::NoInput {
+            dir: options.input_dir.clone(),
+        }
+        .into());
+    }
+    let mut print_hit = |hit: &SearchHit| match format {
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::json!({
+                "path": hit.input_path.display().to_string(),
+                "title": hit.title,
+                "section": hit.section,
+                "href": hit.href,
+                "context": hit.context,
+                "match": [hit.match_range.0, hit.match_range.1],
+            })
+        ),
+        OutputFormat::Text => println!(
+            "{} › {} ({}): {}",
+            hit.title, hit.section, hit.href, hit.context
+        ),
+    };
+    let summary = search_library(options, &mut print_hit)?;
+    for diagnostic in &summary.diagnostics {
+        eprintln!("Error: {}", diagnostic.message);
+    }
+    if format == OutputFormat::Text {
+        eprintln!("{} hit(s) in {} book(s)", summary.hits, summary.books);
+    }
+    if summary.diagnostics.is_empty() {
+        Ok(Outcome::Ok)
+    } else {
+        Ok(Outcome::BooksFailed)
+    }
+}
+
+fn run_covers(
+    options: &CoverOptions,
+    log_format: LogFormat,
+    summary_out: &mut Option<ConversionSummary>,
+) -> anyhow::Result<Outcome> {
+    if collect_epub_paths(&options.input_dir).is_empty() {
+        return Err(ConvertError::NoInput {
+            dir: options.input_dir.clone(),
+        }
+        .into());
+    }
+    let summary = summary_out.insert(extract_covers(options)?);
+    let mut failures = 0usize;
+    for book in &summary.books {
+        for diagnostic in &book.diagnostics {
+            log_format.diagnostic(book, diagnostic);
+            if diagnostic.level == rbook_utils_core::DiagnosticLevel::Error {
+                failures += 1;
+            }
+        }
+        if let Some(path) = &book.output_path {
+            log_format.written(book, path, &format!("Wrote {}", path.display()));
+        }
+    }
+    if failures > 0 {
+        eprintln!("Error: {failures} EPUB(s) failed to parse");
+        return Ok(Outcome::BooksFailed);
+    }
+    Ok(Outcome::Ok)
+}
+
+fn run_compare_editions(
+    left: &Path,
+    right: &Path,
+    options: &ConvertOptions,
+    format: OutputFormat,
+    log_format: LogFormat,
+) -> anyhow::Result<Outcome> {
+    let comparison = compare_editions(left, right, options)?;
+    for book in [&comparison.left, &comparison.right] {
+        for diagnostic in &book.diagnostics {
+            log_format.diagnostic(book, diagnostic);
+        }
+    }
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&comparison.to_json())?),
+        OutputFormat::Text => {
+            println!("{} / {}", comparison.left.title, comparison.right.title);
+            for (idx, pair) in comparison.chapters.iter().enumerate() {
+                let title = |chapter: &Option<rbook_utils_core::EditionChapter>| {
+                    chapter
+                        .as_ref()
+                        .map_or("-", |chapter| chapter.title.as_str())
+                        .to_string()
+                };
+                let scores = if pair.left.is_some() && pair.right.is_some() {
+                    format!(
+                        "title {:.0}%, text {:.0}%",
+                        pair.title_similarity * 100.0,
+                        pair.text_similarity * 100.0
+                    )
+                } else {
+                    "unaligned".to_string()
+                };
+                println!(
+                    "  {:>3}. {} <> {} ({scores})",
+                    idx + 1,
+                    title(&pair.left),
+                    title(&pair.right)
+                );
+            }
+            println!(
+                "Wrote comparison to {}",
+                comparison.output_dir.join("comparison.md").display()
+            );
+        }
+    }
+    Ok(Outcome::Ok)
+}
+
+fn run_anthology(
+    plan: &AnthologyPlan,
+    format: AnthologyFormat,
+    options: &ConvertOptions,
+    log_format: LogFormat,
+    summary_out: &mut Option<ConversionSummary>,
+) -> anyhow::Result<Outcome> {
+    let anthology = build_anthology(plan, format, options)?;
+    let summary = summary_out.insert(ConversionSummary {
+        books: anthology.books,
+    });
+    let mut failures = 0usize;
+    for book in &summary.books {
+        if let Some(reason) = &book.skipped {
+            log_format.skipped(book, reason);
+            continue;
+        }
+        let mut has_error = book.output_path.is_none();
+        for diagnostic in &book.diagnostics {
+            log_format.diagnostic(book, diagnostic);
+            if diagnostic.level == rbook_utils_core::DiagnosticLevel::Error {
+                has_error = true;
+            }
+        }
+        if has_error {
+            failures += 1;
+        }
+    }
+    println!(
+        "Wrote {} chapters to {}",
+        anthology.chapter_count,
+        anthology.output_path.display()
+    );
+    if failures > 0 {
+        eprintln!("Error: {failures} EPUB(s) failed or lacked planned sections");
+        return Ok(Outcome::BooksFailed);
+    }
+    Ok(Outcome::Ok)
+}
+
+fn run_near_duplicates(
+    options: &ConvertOptions,
+    threshold: f32,
+    format: OutputFormat,
+    log_format: LogFormat,
+    summary_out: &mut Option<ConversionSummary>,
+) -> anyhow::Result<Outcome> {
+    let summary = summary_out.insert(convert_all(options)?);
+    let mut failures = 0usize;
+    for book in &summary.books {
+        if let Some(reason) = &book.skipped {
+            log_format.skipped(book, reason);
+            continue;
+        }
+        let mut has_error = book.output_path.is_none();
+        for diagnostic in &book.diagnostics {
+            log_format.diagnostic(book, diagnostic);
+            if diagnostic.level == rbook_utils_core::DiagnosticLevel::Error {
+                has_error = true;
+            }
+        }
+        if has_error {
+            failures += 1;
+        }
+    }
+    let matches = find_near_duplicates(&summary.books, threshold);
+    match format {
+        OutputFormat::Json => {
+            let matches: Vec<_> = matches.iter().map(|found| found.to_json()).collect();
+            println!("{}", serde_json::to_string_pretty(&matches)?);
+        }
+        OutputFormat::Text => {
+            let side = |side: &rbook_utils_core::DuplicateSide| {
+                let file = side
+                    .input_path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                match &side.section_id {
+                    Some(id) => format!("{file} {id} \"{}\"", side.title),
+                    None => format!("{file} \"{}\"", side.title),
+                }
+            };
+            for found in &matches {
+                println!(
+                    "{:>3.0}%  {} <> {} (simhash distance {})",
+                    found.similarity * 100.0,
+                    side(&found.left),
+                    side(&found.right),
+                    found.simhash_distance
+                );
+            }
+            println!(
+                "{} near-duplicate pairs at {:.0}% or more",
+                matches.len(),
+                threshold * 100.0
+            );
+        }
+    }
+    if failures > 0 {
+        eprintln!("Error: {failures} EPUB(s) failed to parse");
+    }
+    let attempted = summary.books.len() - summary.skipped_count();
+    Ok(Outcome::of_failures(failures, attempted))
+}
+
+fn format_size(bytes: Option<u64>) -> String {
+    let Some(bytes) = bytes else {
+        return "-".to_string();
+    };
+    let mut value = bytes as f64;
+    for unit in ["B", "KiB", "MiB"] {
+        if value < 1024.0 {
+            return if unit == "B" {
+                format!("{bytes} B")
+            } else {
+                format!("{value:.1} {unit}")
+            };
+        }
+        value /= 1024.0;
+    }
+    format!("{value:.1} GiB")
+}
+
+/// A [books."KEY"] table of the config file: the flags for one book.
+#[derive(Debug)]
+struct BookConfig {
+    key: String,
+    /// The table's flags on one line.
+    summary: String,
+    /// The command line over the config's defaults and this table.
+    cli: Cli,
+}
+
+/// Read from the current directory when --config is not given.
+const CONFIG_FILE: &str = "rbook-utils.toml";
+
+/// The command line over the defaults of the config file, if there is one.
+fn parse_cli() -> anyhow::Result<Cli> {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let matches = Cli::command().get_matches_from(&args);
+    let path = match matches.get_one::<PathBuf>("config") {
+        Some(path) => path.clone(),
+        None if Path::new(CONFIG_FILE).is_file() => PathBuf::from(CONFIG_FILE),
+        None => return Ok(Cli::parse_from(args)),
+    };
+    let invalid = |reason: String| anyhow::anyhow!("invalid config {}: {reason}", path.display());
+    let text = fs::read_to_string(&path).map_err(|err| invalid(err.to_string()))?;
+    let mut table: toml::Table = text
+        .parse()
+        .map_err(|err: toml::de::Error| invalid(err.to_string()))?;
+    // A table of profiles rather than the --cleanup-profiles path.
+    let profiles = match table.get("cleanup_profiles") {
+        Some(toml::Value::Table(_)) => table.remove("cleanup_profiles"),
+        _ => None,
+    };
+    let config_profiles = match profiles {
+        Some(profiles) => {
+            let profiles = toml::to_string(&profiles).map_err(|err| invalid(err.to_string()))?;
+            Some((path.clone(), profiles))
+        }
+        None => None,
+    };
+    let books = match table.remove("books") {
+        Some(toml::Value::Table(books)) => books,
+        Some(_) => {
+            return Err(invalid(
+                "books must be a table of [books.\"KEY\"] tables".into(),
+            ));
+        }
+        None => toml::Table::new(),
+    };
+    let mut book_configs = Vec::new();
+    for (key, book) in books {
+        let invalid_book = |reason: String| invalid(format!("[books.\"{key}\"]: {reason}"));
+        let toml::Value::Table(book) = book else {
+            return Err(invalid_book("expected a table of flags".into()));
+        };
+        let mut book_table = table.clone();
+        book_table.extend(book.clone());
+        let mut book_args = vec![args[0].clone()];
+        book_args.extend(config_args(&book_table, &matches).map_err(invalid_book)?);
+        book_args.extend(args.iter().skip(1).cloned());
+        let mut book_cli = Cli::try_parse_from(book_args).map_err(|err| {
+            let message = err.to_string();
+            let first = message.lines().next().unwrap_or_default();
+            invalid_book(first.trim_start_matches("error: ").to_string())
+        })?;
+        book_cli.config_profiles = config_profiles.clone();
+        let summary = toml::to_string(&book)
+            .map_err(|err| invalid_book(err.to_string()))?
+            .trim()
+            .replace('\n', "; ");
+        book_configs.push(BookConfig {
+            key,
+            summary,
+            cli: book_cli,
+        });
+    }
+    let mut merged = vec![args[0].clone()];
+    merged.extend(config_args(&table, &matches).map_err(invalid)?);
+    merged.extend(args.into_iter().skip(1));
+    let mut cli = Cli::parse_from(merged);
+    cli.config_profiles = config_profiles;
+    cli.book_configs = book_configs;
+    Ok(cli)
+}
+
+/// Command-line arguments for the config's values of the top-level flags,
+/// leaving out flags given on the command line and those conflicting with one.
+fn config_args(table: &toml::Table, matches: &ArgMatches) -> Result<Vec<OsString>, String> {
+    let command = Cli::command();
+    let given = |arg: &clap::Arg| {
+        matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine)
+    };
+    let mut args: Vec<OsString> = Vec::new();
+    for (key, value) in table {
+        let flag = key.replace('_', "-");
+        let Some(arg) = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(flag.as_str()))
+        else {
+            return Err(format!("unknown option {key}"));
+        };
+        if flag == "config" {
+            return Err("config cannot name another config file".to_string());
+        }
+        let conflicts = command
+            .get_arguments()
+            .filter(|other| given(other))
+            .any(|other| {
+                command.get_arg_conflicts_with(other).contains(&arg)
+                    || command.get_arg_conflicts_with(arg).contains(&other)
+            });
+        if given(arg) || conflicts {
+            continue;
+        }
+        let long = format!("--{flag}");
+        let values = match value {
+            toml::Value::Array(values) => values.as_slice(),
+            value => std::slice::from_ref(value),
+        };
+        for value in values {
+            match (arg.get_action(), value) {
+                (ArgAction::SetTrue, toml::Value::Boolean(set)) => {
+                    if *set {
+                        args.push(long.clone().into());
+                    }
+                }
+                (ArgAction::Count, toml::Value::Integer(count)) => {
+                    args.extend((0..*count).map(|_| OsString::from(&long)));
+                }
+                (ArgAction::Set | ArgAction::Append, toml::Value::String(value)) => {
+                    args.push(format!("{long}={value}").into());
+                }
+                (
+                    ArgAction::Set | ArgAction::Append,
+                    toml::Value::Integer(_) | toml::Value::Float(_) | toml::Value::Boolean(_),
+                ) => args.push(format!("{long}={value}").into()),
+                _ => return Err(format!("unexpected value for {key}: {value}")),
+            }
+        }
+    }
+    Ok(args)
+}
+
+fn main() -> ExitCode {
+    let cli = match parse_cli() {
+        Ok(cli) => cli,
+        Err(err) => {
+            eprintln!("Error: {err:#}");
+            return ExitCode::from(Outcome::InvalidOptions as u8);
+        }
+    };
+    init_logging(cli.verbose, cli.log_format);
+    let report_file = cli.report_file.clone();
+    let mut summary = None;
+    let (outcome, error) = match run(cli, &mut summary) {
+        Ok(outcome) => (outcome, None),
+        Err(err) => {
+            eprintln!("Error: {err:?}");
+            (Outcome::of_error(&err), Some(format!("{err:#}")))
+        }
+    };
+    if let Some(path) = report_file {
+        if let Err(err) = write_report(&path, outcome, error.as_deref(), summary.as_ref()) {
+            eprintln!("Error: failed to write report {}: {err:#}", path.display());
+            return ExitCode::from(Outcome::FatalIo as u8);
+        }
+    }
+    ExitCode::from(outcome as u8)
+}
+
+fn init_logging(verbose: u8, format: LogFormat) {
+    let level = match verbose {
+        0 => return,
+        1 => tracing::Level::INFO,
+        2 => tracing::Level::DEBUG,
+        _ => tracing::Level::TRACE,
+    };
+    let logs = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(std::io::stderr);
+    match format {
+        LogFormat::Text => logs.init(),
+        LogFormat::Json => logs.json().init(),
+    }
+}
+
+/// Conversion settings from the top-level flags, shared by plain conversion
+/// and `anthology`.
+fn convert_options(cli: &Cli) -> anyhow::Result<ConvertOptions> {
+    let mut options = ConvertOptions::new(cli.input_dir.clone(), cli.output_dir.clone());
+    options.media_all = cli.media_all;
+    options.markdown_mode = cli.markdown_mode;
+    options.style = cli.style;
+    options.split_chapters = cli.split_chapters;
+    options.chapter_fallback = cli.chapter_fallback;
+    options.heading_patterns = cli.heading_patterns.clone();
+    options.notes_mode = cli.notes_mode;
+    options.export_manifest = cli.export_manifest;
+    options.export_positions = cli.export_positions;
+    options.export_provenance = cli.export_provenance;
+    options.export_audio_split = cli.export_audio_split;
+    options.translation_export = cli.export_translation;
+    options.translation_target_language = cli.target_language.clone();
+    options.flashcard_export = cli.export_flashcards;
+    options.quality_report = cli.quality_report;
+    options.ocr_cleanup = cli.ocr_cleanup;
+    options.nav_cleanup = cli.nav_cleanup;
+    if let Some((path, profiles)) = &cli.config_profiles {
+        options.cleanup_profiles.add_toml(profiles, path)?;
+    }
+    if let Some(path) = &cli.cleanup_profiles {
+        options.cleanup_profiles.add_file(path)?;
+    }
+    if let Some(name) = &cli.cleanup_profile {
+        if name != AUTO_CLEANUP_PROFILE && options.cleanup_profiles.get(name).is_none() {
+            let known: Vec<&str> = options.cleanup_profiles.names().collect();
+            anyhow::bail!(
+                "unknown cleanup profile {name}; known profiles: {}, or {AUTO_CLEANUP_PROFILE}",
+                known.join(", ")
+            );
+        }
+        options.cleanup_profile = Some(name.clone());
+    }
+    options.strip_watermarks = cli.strip_watermarks;
+    options.filename_scheme = cli.filename_scheme;
+    options.output_template = cli.output_template.clone();
+    options.on_conflict = cli.on_conflict;
+    options.split_on_heading_level = cli.split_on_heading_level;
+    if cli.fast {
+        options.fast_mode();
+    }
+    options.escape_markdown = !cli.no_escape;
+    options.min_section_words = cli.min_section_words;
+    options.consolidate_endnotes = cli.consolidate_endnotes;
+    options.list_of_figures = cli.list_of_figures;
+    options.chapter_thumbnails = cli.chapter_thumbnails;
+    options.thumbnail_max_edge = cli.thumbnail_max_edge;
+    options.anchor_mode = cli.anchor_mode;
+    options.compare_view = cli.compare_view;
+    options.lock_output = !cli.no_lock;
+    options.lock_stale_after_secs = cli.lock_stale_after_secs;
+    options.asset_base_url = cli.asset_base_url.clone();
+    options.svg_mode = cli.svg_mode;
+    options.ruby_mode = cli.ruby_mode;
+    options.text_direction = cli.text_direction;
+    options.extract_fonts = cli.extract_fonts;
+    options.cover_reference = cli.cover_reference;
+    options.preview = cli.preview;
+    options.preview_max_words = cli.preview_max_words;
+    options.stream = cli.stream;
+    options.image_format = cli.image_format;
+    options.extra_readable_types = cli.readable_types.clone();
+    options.max_image_size = cli.max_image_size;
+    options.image_quality = cli.image_quality;
+    options.inline_images_below = cli.inline_images_below;
+    options.suppress_warnings = cli.suppress_warnings.clone();
+    options.error_on_warnings = cli.error_on_warnings.clone();
+    if cli.fail_on_fallback
+        && !options
+            .error_on_warnings
+            .contains(&WarningCode::HeadingFallbackUsed)
+    {
+        options
+            .error_on_warnings
+            .push(WarningCode::HeadingFallbackUsed);
+    }
+    options.figure_caption_template = cli.figure_caption_template.clone();
+    options.slug_strategy = cli.slug_style.strategy();
+    options.strip_image_metadata = cli.strip_image_metadata;
+    options.skip_decorative_images = cli.skip_decorative_images;
+    options.skip_images = cli.skip_images;
+    options.chapter_nav = cli.chapter_nav;
+    options.jobs = cli.jobs;
+    options.fail_fast = cli.fail_fast;
+    options.section_jobs = cli.section_jobs;
+    options.cache_max_documents = cli.cache_max_documents;
+    options.cache_max_bytes = cli.cache_max_bytes;
+    options.only_public_domain = cli.only_public_domain;
+    if let Some(year) = cli.public_domain_before {
+        options.public_domain_before = year;
+    }
+    options.output_encoding = cli.output_encoding.clone();
+    options.newline = cli.newline;
+    options.bom = cli.bom;
+    options.text_normalization = cli.normalize_unicode;
+    options.collapse_spaces = cli.collapse_spaces;
+    options.plan_export = cli.plan_export.clone();
+    options.fingerprints = cli.fingerprints;
+    options.usage_stats = cli.usage_stats.clone();
+    if let Some(path) = &cli.plan {
+        options.plan = Some(ConversionPlan::from_file(path)?);
+    }
+    options.incremental = cli.incremental;
+    if let Some(path) = &cli.skip_list {
+        let mut skip_list = SkipList::open(path)?;
+        skip_list.max_failures = cli.skip_after;
+        options.skip_list = Some(skip_list);
+    }
+    options.spell_out_numbers = cli.spell_out_numbers;
+    options.large_print_font_size = cli.font_size;
+    options.set_profile(cli.profile);
+    // Positions are found through the anchors tts strips and the `.md` files
+    // large-print renames, so the export would come out empty.
+    if cli.export_positions != ExportMode::Off {
+        let profile = match cli.profile {
+            OutputProfile::Markdown => None,
+            OutputProfile::Tts => Some("--profile tts"),
+            OutputProfile::LargePrint => Some("--profile large-print"),
+        };
+        if let Some(operation) = profile {
+            return Err(ConvertError::UnsupportedOption {
+                option: "--export-positions",
+                operation,
+            }
+            .into());
+        }
+    }
+    for book in &cli.book_configs {
+        let book_options = convert_options(&book.cli)?;
+        options.book_overrides.push(BookOverride::new(
+            &book.key,
+            &book.summary,
+            move |options| apply_book_options(options, &book_options),
+        ));
+    }
+    Ok(options)
+}
+
+/// Converts the book with `book`'s settings, keeping the directories, output
+/// sink, hooks and skip list the batch was set up with.
+fn apply_book_options(options: &mut ConvertOptions, book: &ConvertOptions) {
+    let batch = std::mem::replace(options, book.clone());
+    options.input_dir = batch.input_dir;
+    options.output_dir = batch.output_dir;
+    options.output_sink = batch.output_sink;
+    options.on_progress = batch.on_progress;
+    options.on_warning = batch.on_warning;
+    options.cancel = batch.cancel;
+    options.skip_list = batch.skip_list;
+}
+
+fn run(cli: Cli, summary_out: &mut Option<ConversionSummary>) -> anyhow::Result<Outcome> {
+    if let Some(command) = &cli.command {
+        return match command {
+            Command::Toc { inputs, format } => run_toc(inputs, *format),
+            Command::Inspect {
+                inputs,
+                resources,
+                format,
+            } => run_inspect(inputs, *resources, *format),
+            Command::Spine {
+                inputs,
+                readable_types,
+                format,
+            } => run_spine(inputs, readable_types, *format),
+            Command::ExtractResource {
+                epub,
+                href,
+                base,
+                out,
+            } => run_extract_resource(epub, href, base.as_deref(), out.as_deref()),
+            Command::Find {
+                pattern,
+                input_dir,
+                ignore_case,
+                regex,
+                context,
+                readable_types,
+                format,
+            } => {
+                let mut options = SearchOptions::new(input_dir.clone(), pattern.clone());
+                options.ignore_case = *ignore_case;
+                options.regex = *regex;
+                options.context_chars = *context;
+                options.extra_readable_types = readable_types.clone();
+                run_find(&options, *format)
+            }
+            Command::Covers {
+                input_dir,
+                output_dir,
+                max_edge,
+                format,
+                naming,
+            } => {
+                let mut options = CoverOptions::new(input_dir.clone(), output_dir.clone());
+                options.max_edge = *max_edge;
+                options.format = *format;
+                options.naming = *naming;
+                run_covers(&options, cli.log_format, summary_out)
+            }
+            Command::CompareEditions {
+                left,
+                right,
+                output_dir,
+                format,
+            } => {
+                let mut options = convert_options(&cli)?;
+                options.output_dir = output_dir.clone();
+                run_compare_editions(left, right, &options, *format, cli.log_format)
+            }
+            Command::Anthology {
+                plan,
+                output_dir,
+                format,
+            } => {
+                let plan = AnthologyPlan::from_file(plan)?;
+                let mut options = convert_options(&cli)?;
+                options.output_dir = output_dir.clone();
+                run_anthology(&plan, *format, &options, cli.log_format, summary_out)
+            }
+            Command::Stats {
+                output_dir,
+                books,
+                format,
+            } => run_stats(output_dir, *books, *format),
+            Command::NearDuplicates {
+                input_dir,
+                output_dir,
+                threshold,
+                format,
+            } => {
+                let mut options = convert_options(&cli)?;
+                options.input_dir = input_dir.clone();
+                options.output_dir = output_dir.clone();
+                options.fingerprints = true;
+                run_near_duplicates(&options, *threshold, *format, cli.log_format, summary_out)
+            }
+        };
+    }
+    let mut options = convert_options(&cli)?;
+    if cli.watch && !cfg!(feature = "watch") {
+        anyhow::bail!("--watch needs a build with the watch feature");
+    }
+
+    let has_books = !collect_epub_paths(&options.input_dir).is_empty();
+    // A watched directory may start out empty.
+    if !has_books && (!cli.watch || cli.compare_splits) {
+        return Err(ConvertError::NoInput {
+            dir: options.input_dir.clone(),
+        }
+        .into());
+    }
+    if cli.compare_splits {
+        return run_compare_splits(&options);
+    }
+    if !has_books {
+        return run_watch(&cli, &options);
+    }
+    // Log lines and redrawn bars would garble each other.
+    let bars = (!cli.no_progress && cli.verbose == 0).then(|| {
+        let bars = ProgressBars::new();
+        options.on_progress = Some(bars.hook());
+        bars
+    });
+    let converted = match &cli.output {
+        None => convert_all(&options),
+        Some(OutputTarget::TarStdout) => {
+            STDOUT_IS_ARCHIVE.store(true, Ordering::Relaxed);
+            convert_all_to_tar(&options, BufWriter::new(io::stdout()))
+        }
+        Some(OutputTarget::TarFile(path)) => {
+            convert_all_to_tar(&options, BufWriter::new(fs::File::create(path)?))
+        }
+        Some(OutputTarget::Bucket(url)) => convert_to_bucket(url, &cli.content_types, &options),
+    };
+    if let Some(bars) = bars {
+        bars.clear();
+    }
+    let summary = summary_out.insert(converted?);
+    let failures = summary
+        .books
+        .iter()
+        .filter(|book| !report_book(&cli, &options, book))
+        .count();
+    if cli.watch {
+        options.on_progress = None;
+        if failures > 0 {
+            eprintln!("Error: {failures} EPUB(s) failed to parse");
+        }
+        return run_watch(&cli, &options);
+    }
+
+    if failures > 0 {
+        eprintln!("Error: {failures} EPUB(s) failed to parse");
+    }
+    let attempted = summary.books.len() - summary.skipped_count();
+    Ok(Outcome::of_failures(failures, attempted))
+}
+
+/// Logs one converted book; false when it failed.
+fn report_book(cli: &Cli, options: &ConvertOptions, book: &BookConversionResult) -> bool {
+    if let Some(reason) = &book.skipped {
+        cli.log_format.skipped(book, reason);
+        return true;
+    }
+    let mut has_error = false;
+    for diagnostic in &book.diagnostics {
+        cli.log_format.diagnostic(book, diagnostic);
+        if diagnostic.level == rbook_utils_core::DiagnosticLevel::Error {
+            has_error = true;
+        }
+    }
+
+    if let Some(path) = &book.output_path {
+        let text = if cli.output.is_some() {
+            format!("Stored {}", path.display())
+        } else if options.split_chapters && !options.preview {
+            format!("Wrote chapter files to {}", path.display())
+        } else {
+            format!("Wrote {}", path.display())
+        };
+        cli.log_format.written(book, path, &text);
+    } else {
+        has_error = true;
+    }
+    !has_error
+}
+
+/// Converts books as they appear until the process is stopped.
+#[cfg(feature = "watch")]
+fn run_watch(cli: &Cli, options: &ConvertOptions) -> anyhow::Result<Outcome> {
+    print_info(&format!(
+        "Watching {} for new or changed EPUBs (Ctrl-C to stop)",
+        options.input_dir.display()
+    ));
+    rbook_utils_core::watch_input_dir(options, |book| {
+        report_book(cli, options, book);
+    })?;
+    Ok(Outcome::Ok)
+}
+
+#[cfg(not(feature = "watch"))]
+fn run_watch(_cli: &Cli, _options: &ConvertOptions) -> anyhow::Result<Outcome> {
+    unreachable!("--watch is rejected without the watch feature")
+}
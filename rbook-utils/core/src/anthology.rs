@@ -0,0 +1,397 @@
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    BookConversionResult, ChapterNav, ConvertError, ConvertOptions, CoverReference, Diagnostic,
+    DiagnosticLevel, ExportMode, OutputLock, Result, convert_one, escape_link_text, relative_dir,
+    text_output,
+};
+
+/// A course reader assembled from several books, read from a TOML plan:
+///
+/// ```toml
+/// title = "Whaling Reader"
+/// authors = ["A. Teacher"]
+///
+/// [[books]]
+/// id = "moby"
+/// path = "assets/moby-dick.epub"
+///
+/// [[order]]
+/// book = "moby"
+/// sections = ["loomings", "The Carpet-Bag"]
+/// ```
+///
+/// `order` entries are emitted in sequence and may name the same book more
+/// than once; sections are matched by section id or title, and an entry
+/// without `sections` takes the whole book.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AnthologyPlan {
+    pub title: String,
+    #[serde(default)]
+    pub authors: Vec<String>,
+    pub books: Vec<AnthologyBook>,
+    pub order: Vec<AnthologyPart>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AnthologyBook {
+    pub id: String,
+    /// Relative paths are resolved against the plan file's directory.
+    pub path: PathBuf,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AnthologyPart {
+    pub book: String,
+    #[serde(default)]
+    pub sections: Vec<String>,
+}
+
+impl AnthologyPlan {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let invalid = |reason: String| ConvertError::InvalidPlan {
+            path: path.to_path_buf(),
+            reason,
+        };
+        let text = fs::read_to_string(path).map_err(|err| invalid(err.to_string()))?;
+        let mut plan: AnthologyPlan =
+            toml::from_str(&text).map_err(|err| invalid(err.to_string()))?;
+        let base = path.parent().unwrap_or(Path::new(""));
+        for book in &mut plan.books {
+            if book.path.is_relative() {
+                book.path = base.join(&book.path);
+            }
+        }
+        plan.validate().map_err(invalid)?;
+        Ok(plan)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let mut ids = HashSet::new();
+        for book in &self.books {
+            if !ids.insert(book.id.as_str()) {
+                return Err(format!("book id {} is listed twice", book.id));
+            }
+        }
+        if self.order.is_empty() {
+            return Err("no [[order]] entries".to_string());
+        }
+        for part in &self.order {
+            if !ids.contains(part.book.as_str()) {
+                return Err(format!("[[order]] names unknown book {}", part.book));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum AnthologyFormat {
+    /// One markdown file with every selected chapter.
+    Markdown,
+    /// An mdBook source tree: `book.toml`, `src/SUMMARY.md` and a file per chapter.
+    Mdbook,
+}
+
+#[derive(Clone, Debug)]
+pub struct AnthologyResult {
+    /// The combined markdown file, or the mdBook root.
+    pub output_path: PathBuf,
+    pub chapter_count: usize,
+    /// One per plan book; a plan section that matched nothing is an error
+    /// diagnostic on its book.
+    pub books: Vec<BookConversionResult>,
+}
+
+/// A selected chapter: its body with links relative to where it is written.
+struct Chapter {
+    book_id: String,
+    book_title: String,
+    section_id: String,
+    title: String,
+    body: String,
+}
+
+/// A converted section, as listed in the book's manifest.
+pub(crate) struct SourceSection {
+    pub(crate) section_id: String,
+    pub(crate) title: String,
+    /// Relative to the books directory.
+    pub(crate) output_path: String,
+}
+
+/// Converts every plan book as split chapters under `books/` in the output
+/// directory (`src/books/` for mdBook, which only publishes `src`), then
+/// assembles the selected chapters in plan order. Chapter bodies keep
+/// pointing at the books' extracted images and chapter files.
+pub fn build_anthology(
+    plan: &AnthologyPlan,
+    format: AnthologyFormat,
+    options: &ConvertOptions,
+) -> Result<AnthologyResult> {
+    let root = options.output_dir.clone();
+    let chapter_dir = match format {
+        AnthologyFormat::Markdown => root.clone(),
+        AnthologyFormat::Mdbook => root.join("src"),
+    };
+    let books_dir = chapter_dir.join("books");
+    let _lock = if options.lock_output {
+        Some(OutputLock::acquire(
+            &root,
+            std::time::Duration::from_secs(options.lock_stale_after_secs),
+        )?)
+    } else {
+        None
+    };
+
+    let mut book_options = options.clone();
+    book_options.output_dir = books_dir.clone();
+    book_options.split_chapters = true;
+    book_options.export_manifest = ExportMode::V1;
+    book_options.chapter_nav = ChapterNav::Off;
+    book_options.cover_reference = CoverReference::Off;
+    book_options.asset_base_url = None;
+    book_options.preview = false;
+
+    let mut books = Vec::new();
+    let mut sections_by_book = Vec::new();
+    for (idx, book) in plan.books.iter().enumerate() {
+        let mut result = convert_one(&book.path, idx, plan.books.len(), &book_options);
+        let sections = match &result.output_path {
+            Some(book_dir) => read_manifest_sections(book_dir).unwrap_or_else(|err| {
+                result.diagnostics.push(error(format!(
+                    "Cannot read the sections of {}: {}",
+                    result.title,
+                    err.full_message()
+                )));
+                Vec::new()
+            }),
+            None => Vec::new(),
+        };
+        books.push(result);
+        sections_by_book.push(sections);
+    }
+
+    let writer = text_output::TextWriter::new(options);
+    let mut chapters = Vec::new();
+    for part in &plan.order {
+        let book_idx = plan
+            .books
+            .iter()
+            .position(|book| book.id == part.book)
+            .expect("validated book id");
+        let result = &mut books[book_idx];
+        if result.output_path.is_none() {
+            continue;
+        }
+        let sections = &sections_by_book[book_idx];
+        let selected: Vec<&SourceSection> = if part.sections.is_empty() {
+            sections.iter().collect()
+        } else {
+            let mut selected = Vec::new();
+            for wanted in &part.sections {
+                match find_section(sections, wanted) {
+                    Some(section) => selected.push(section),
+                    None => result.diagnostics.push(error(format!(
+                        "Plan section {wanted} is not a section of {} ({})",
+                        result.title, part.book
+                    ))),
+                }
+            }
+            selected
+        };
+        for section in selected {
+            let source = books_dir.join(&section.output_path);
+            let Some(text) = text_output::read_text(
+                &*options.output_sink,
+                &source,
+                options.output_encoding.as_deref(),
+            ) else {
+                result.diagnostics.push(error(format!(
+                    "Cannot read converted chapter {}",
+                    source.display()
+                )));
+                continue;
+            };
+            let from = source.parent().unwrap_or(&books_dir);
+            chapters.push(Chapter {
+                book_id: part.book.clone(),
+                book_title: result.title.clone(),
+                section_id: section.section_id.clone(),
+                title: section.title.clone(),
+                body: relocate_links(
+                    &chapter_body(&text, &section.section_id),
+                    &relative_dir(&chapter_dir, from),
+                ),
+            });
+        }
+    }
+
+    let output_path = match format {
+        AnthologyFormat::Markdown => write_markdown(plan, &chapters, &root, options, &writer)?,
+        AnthologyFormat::Mdbook => write_mdbook(plan, &chapters, &root, &writer)?,
+    };
+    Ok(AnthologyResult {
+        output_path,
+        chapter_count: chapters.len(),
+        books,
+    })
+}
+
+fn error(message: String) -> Diagnostic {
+    Diagnostic {
+        level: DiagnosticLevel::Error,
+        code: None,
+        message,
+    }
+}
+
+pub(crate) fn read_manifest_sections(book_dir: &Path) -> Result<Vec<SourceSection>> {
+    let text = fs::read_to_string(book_dir.join("manifest.v1.json"))?;
+    let manifest: serde_json::Value = serde_json::from_str(text.trim_start_matches('\u{feff}'))?;
+    let field = |section: &serde_json::Value, key: &str| {
+        section[key].as_str().unwrap_or_default().to_string()
+    };
+    Ok(manifest["sections"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .map(|section| SourceSection {
+            section_id: field(section, "section_id"),
+            title: field(section, "title"),
+            output_path: field(section, "output_path"),
+        })
+        .collect())
+}
+
+/// By section id first, then by title ignoring case.
+fn find_section<'a>(sections: &'a [SourceSection], wanted: &str) -> Option<&'a SourceSection> {
+    let wanted = wanted.trim();
+    sections
+        .iter()
+        .find(|section| section.section_id == wanted)
+        .or_else(|| {
+            sections
+                .iter()
+                .find(|section| section.title.trim().to_lowercase() == wanted.to_lowercase())
+        })
+}
+
+/// The section text of a split chapter file: what follows its anchor and
+/// `##` heading, without the book header above them.
+pub(crate) fn chapter_body(text: &str, section_id: &str) -> String {
+    let text = text.replace("\r\n", "\n");
+    let anchor = format!("<a id=\"{section_id}\"></a>\n");
+    let rest = match text.find(&anchor) {
+        Some(at) => &text[at + anchor.len()..],
+        None => text.as_str(),
+    };
+    let rest = match rest.strip_prefix("## ") {
+        Some(heading) => heading.split_once('\n').map_or("", |(_, body)| body),
+        None => rest,
+    };
+    rest.trim().to_string()
+}
+
+/// Prefixes relative links (`./`, `../`) so they still resolve from a file
+/// `prefix` away from where they were written.
+fn relocate_links(text: &str, prefix: &str) -> String {
+    if prefix.is_empty() {
+        return text.to_string();
+    }
+    let mut out = text.to_string();
+    for opener in ["](", "src=\"", "href=\"", "url(\""] {
+        out = out
+            .replace(&format!("{opener}./"), &format!("{opener}{prefix}"))
+            .replace(&format!("{opener}../"), &format!("{opener}{prefix}../"));
+    }
+    out
+}
+
+fn chapter_anchor(chapter: &Chapter) -> String {
+    format!("{}-{}", chapter.book_id, chapter.section_id)
+}
+
+fn write_markdown(
+    plan: &AnthologyPlan,
+    chapters: &[Chapter],
+    root: &Path,
+    options: &ConvertOptions,
+    writer: &text_output::TextWriter,
+) -> Result<PathBuf> {
+    fs::create_dir_all(root).map_err(|err| ConvertError::write_failed(root, err))?;
+    let mut lines = vec![format!("# {}", plan.title)];
+    if !plan.authors.is_empty() {
+        lines.push(format!("**Author:** {}", plan.authors.join(", ")));
+    }
+    lines.push(String::new());
+    for chapter in chapters {
+        lines.push(format!(
+            "- [{}](#{})",
+            escape_link_text(&chapter.title),
+            chapter_anchor(chapter)
+        ));
+    }
+    lines.push(String::new());
+    for chapter in chapters {
+        lines.push(format!("<a id=\"{}\"></a>", chapter_anchor(chapter)));
+        lines.push(format!("## {}", chapter.title));
+        lines.push(String::new());
+        lines.push(format!("*From {}*", chapter.book_title));
+        lines.push(String::new());
+        lines.push(chapter.body.clone());
+        lines.push(String::new());
+    }
+    let output_path = root.join(format!("{}.md", options.slug_strategy.slug(&plan.title)));
+    writer.write(&output_path, &(lines.join("\n").trim().to_string() + "\n"))?;
+    Ok(output_path)
+}
+
+/// Chapters are grouped under a part heading per run of the same book.
+fn write_mdbook(
+    plan: &AnthologyPlan,
+    chapters: &[Chapter],
+    root: &Path,
+    writer: &text_output::TextWriter,
+) -> Result<PathBuf> {
+    let src = root.join("src");
+    fs::create_dir_all(&src).map_err(|err| ConvertError::write_failed(&src, err))?;
+    let mut book = toml::Table::new();
+    book.insert("title".into(), plan.title.clone().into());
+    book.insert("authors".into(), plan.authors.clone().into());
+    book.insert("src".into(), "src".into());
+    let mut config = toml::Table::new();
+    config.insert("book".into(), book.into());
+    writer.write_utf8(&root.join("book.toml"), &toml::to_string(&config)?)?;
+
+    let mut summary = vec!["# Summary".to_string()];
+    let mut current_book = None;
+    for (idx, chapter) in chapters.iter().enumerate() {
+        if current_book != Some(&chapter.book_id) {
+            current_book = Some(&chapter.book_id);
+            summary.push(String::new());
+            summary.push(format!("# {}", chapter.book_title));
+            summary.push(String::new());
+        }
+        let file = format!("{:03}-{}.md", idx + 1, chapter_anchor(chapter));
+        summary.push(format!(
+            "- [{}](./{file})",
+            escape_link_text(&chapter.title)
+        ));
+        writer.write(
+            &src.join(&file),
+            &format!("# {}\n\n{}\n", chapter.title, chapter.body),
+        )?;
+    }
+    writer.write(&src.join("SUMMARY.md"), &(summary.join("\n") + "\n"))?;
+    Ok(root.to_path_buf())
+}
@@ -0,0 +1,53 @@
+use std::io::Write;
+use std::sync::Mutex;
+
+use crate::{ConversionSummary, ConvertOptions, Result, Storage, convert_all_to_storage};
+
+/// A tar archive being written to `W`, one entry per stored file. Entries
+/// carry fixed timestamps and permissions, so the same books give the same
+/// archive.
+pub struct TarStorage<W: Write> {
+    builder: Mutex<tar::Builder<W>>,
+}
+
+impl<W: Write> TarStorage<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            builder: Mutex::new(tar::Builder::new(out)),
+        }
+    }
+
+    /// Writes the end-of-archive marker and returns the writer.
+    pub fn finish(self) -> Result<W> {
+        let builder = self.builder.into_inner().expect("archive lock");
+        let mut out = builder.into_inner()?;
+        out.flush()?;
+        Ok(out)
+    }
+}
+
+impl<W: Write + Send> Storage for TarStorage<W> {
+    fn put(&self, key: &str, bytes: Vec<u8>) -> Result<()> {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(bytes.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(0);
+        let mut builder = self.builder.lock().expect("archive lock");
+        builder.append_data(&mut header, key, bytes.as_slice())?;
+        builder.get_mut().flush()?;
+        Ok(())
+    }
+}
+
+/// Converts every book like [`convert_all`](crate::convert_all), streaming the
+/// outputs to `out` as a tar archive instead of leaving them in
+/// `options.output_dir`. See [`convert_all_to_storage`].
+pub fn convert_all_to_tar<W: Write + Send>(
+    options: &ConvertOptions,
+    out: W,
+) -> Result<ConversionSummary> {
+    let storage = TarStorage::new(out);
+    let summary = convert_all_to_storage(options, &storage)?;
+    storage.finish()?;
+    Ok(summary)
+}
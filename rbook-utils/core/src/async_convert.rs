@@ -0,0 +1,201 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+use crate::incremental::IncrementalState;
+use crate::output::{FsSink, MemorySink};
+use crate::{
+    BookConversionResult, ConversionPlan, ConversionSummary, ConvertError, ConvertOptions,
+    ConvertReport, INCREMENTAL_STATE_FILE_NAME, OutputLock, Result, collect_epub_paths,
+    convert_in_memory, convert_one_with, not_started_result, parallelism, skip_list,
+    up_to_date_result, usage,
+};
+
+/// Converts one book like [`convert_epub`](crate::convert_epub) without
+/// blocking the async runtime: the EPUB is read with `tokio::fs`, and parsing,
+/// rendering and writing the outputs run on the blocking thread pool.
+/// Must be called within a Tokio runtime.
+pub async fn convert_epub_async(
+    epub_path: &Path,
+    options: &ConvertOptions,
+) -> Result<ConvertReport> {
+    let bytes = tokio::fs::read(epub_path).await;
+    // Written to disk below, whatever `output_sink` says.
+    let sink = Arc::new(MemorySink::over(Arc::new(FsSink)));
+    let mut book_options = options.clone();
+    book_options.output_sink = sink.clone();
+    let path = epub_path.to_path_buf();
+    let result = blocking(move || convert_in_memory(&path, bytes, &book_options)).await?;
+    if let Some(reason) = result.skipped {
+        return Err(ConvertError::Skipped {
+            path: epub_path.to_path_buf(),
+            reason,
+        });
+    }
+    write_outputs(sink).await?;
+    Ok(result.report)
+}
+
+/// Converts every book like [`convert_all`](crate::convert_all) without
+/// blocking the async runtime. At most `options.jobs` books are converted at
+/// once; each is read with `tokio::fs` and parsed, rendered and written
+/// on the blocking thread pool. Must be called within a Tokio runtime.
+pub async fn convert_all_async(options: &ConvertOptions) -> Result<ConversionSummary> {
+    let started = Instant::now();
+    let input_dir = options.input_dir.clone();
+    let epub_paths = blocking(move || Ok(collect_epub_paths(&input_dir))).await?;
+    if epub_paths.is_empty() {
+        return Err(ConvertError::NoInput {
+            dir: options.input_dir.clone(),
+        });
+    }
+    let options = Arc::new(options.for_batch());
+
+    // Two runs sharing an output directory would delete each other's split files.
+    let _lock = if options.lock_output {
+        let lock_options = options.clone();
+        Some(
+            blocking(move || {
+                OutputLock::acquire(
+                    &lock_options.output_dir,
+                    Duration::from_secs(lock_options.lock_stale_after_secs),
+                )
+            })
+            .await?,
+        )
+    } else {
+        None
+    };
+
+    let state_options = options.clone();
+    let (mut incremental, up_to_date, pending) = blocking(move || {
+        let state = state_options
+            .incremental
+            .then(|| IncrementalState::load(&state_options));
+        let (up_to_date, pending): (Vec<_>, Vec<_>) =
+            epub_paths
+                .into_iter()
+                .enumerate()
+                .partition(|(_, epub_path)| {
+                    state
+                        .as_ref()
+                        .is_some_and(|state| state.is_current(epub_path))
+                });
+        Ok((state, up_to_date, pending))
+    })
+    .await?;
+
+    let permits = Arc::new(Semaphore::new(parallelism(options.jobs)));
+    let stopped = Arc::new(AtomicBool::new(false));
+    let total = pending.len();
+    let mut tasks = JoinSet::new();
+    for (pending_idx, (idx, epub_path)) in pending.into_iter().enumerate() {
+        let options = options.clone();
+        let permits = permits.clone();
+        let stopped = stopped.clone();
+        tasks.spawn(async move {
+            let _permit = permits
+                .acquire_owned()
+                .await
+                .expect("permits are never closed");
+            if options.is_cancelled() {
+                return Ok(None);
+            }
+            if stopped.load(Ordering::Relaxed) {
+                return Ok(Some((idx, not_started_result(&epub_path))));
+            }
+            let fail_fast = options.fail_fast;
+            let result = convert_book(epub_path, pending_idx, total, options).await?;
+            if fail_fast && result.failed() {
+                stopped.store(true, Ordering::Relaxed);
+            }
+            Ok::<_, ConvertError>(Some((idx, result)))
+        });
+    }
+    let mut results = Vec::with_capacity(total);
+    while let Some(joined) = tasks.join_next().await {
+        if let Some(done) = joined.map_err(join_error)?? {
+            results.push(done);
+        }
+    }
+    // Books cut short are failed results; the batch as a whole is not done.
+    options.check_cancelled()?;
+    results.extend(
+        up_to_date
+            .into_iter()
+            .map(|(idx, epub_path)| (idx, up_to_date_result(&epub_path))),
+    );
+    results.sort_by_key(|(idx, _)| *idx);
+    let summary = ConversionSummary {
+        books: results.into_iter().map(|(_, result)| result).collect(),
+    };
+
+    blocking(move || {
+        if let Some(Err(err)) = incremental
+            .as_mut()
+            .map(|state| state.record_run(&summary.books))
+        {
+            tracing::warn!(
+                "could not update {}: {}",
+                INCREMENTAL_STATE_FILE_NAME,
+                err.full_message()
+            );
+        }
+        if let Some(path) = &options.plan_export {
+            ConversionPlan::from_results(&summary.books).write(path, &options)?;
+        }
+        skip_list::record_run(&options, &summary.books);
+        usage::record_usage(&options, &summary, started);
+        Ok(summary)
+    })
+    .await
+}
+
+/// Converts one book of a batch; failures, including failing to write the
+/// outputs, become an error result for that book.
+async fn convert_book(
+    epub_path: PathBuf,
+    index: usize,
+    total: usize,
+    options: Arc<ConvertOptions>,
+) -> Result<BookConversionResult> {
+    let bytes = tokio::fs::read(&epub_path).await;
+    // Written to disk below, whatever `output_sink` says.
+    let sink = Arc::new(MemorySink::over(Arc::new(FsSink)));
+    let mut book_options = options.locking_book();
+    book_options.output_sink = sink.clone();
+    // The options come back holding the book's lock until its outputs are written.
+    let (mut result, book_options) = blocking(move || {
+        let result = convert_one_with(&epub_path, index, total, &book_options, || {
+            convert_in_memory(&epub_path, bytes, &book_options)
+        });
+        Ok((result, book_options))
+    })
+    .await?;
+    if let Err(err) = write_outputs(sink).await {
+        result.fail_output(&err);
+    }
+    drop(book_options);
+    Ok(result)
+}
+
+/// Replays what a conversion did to `sink` on disk, through the same
+/// [`FsSink`] a synchronous conversion writes with.
+async fn write_outputs(sink: Arc<MemorySink>) -> Result<()> {
+    blocking(move || sink.replay(&FsSink)).await
+}
+
+async fn blocking<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(join_error)?
+}
+
+fn join_error(err: tokio::task::JoinError) -> ConvertError {
+    ConvertError::Panicked(err.to_string())
+}
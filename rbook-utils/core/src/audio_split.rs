@@ -0,0 +1,335 @@
+use once_cell::sync::Lazy;
+use rbook::Epub;
+use regex::Regex;
+use serde_json::json;
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+
+use crate::positions::output_path_for;
+use crate::{
+    ConvertError, ConvertOptions, ExportMode, Result, SectionRecord, decode_path, resolve_href,
+    text_output,
+};
+
+static PAR_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?s)<par\b[^>]*>(.*?)</par>").expect("valid par regex"));
+static TEXT_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"<text\b(?:[^>"']|"[^"]*"|'[^']*')*>"#).expect("valid text regex"));
+static AUDIO_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"<audio\b(?:[^>"']|"[^"]*"|'[^']*')*>"#).expect("valid audio regex"));
+
+/// One `<par>` of a media overlay: the text fragment it narrates and where
+/// in which audio file.
+#[derive(Clone, Debug)]
+struct Clip {
+    fragment: Option<String>,
+    audio: String,
+    begin: f64,
+    end: f64,
+}
+
+/// A run of a section's clips in one audio file.
+struct Cut {
+    audio: String,
+    begin: f64,
+    end: f64,
+}
+
+/// Writes `audio_split.v1.json` and `audio_split.sh` when the book has media
+/// overlays: per section, the narration audio file and the start and end of
+/// the section in it, so the audio can be cut into chapter files named like
+/// the markdown. The narration files are extracted under `media/` and the
+/// script runs `ffmpeg` from the book directory, writing to `audio/`.
+///
+/// A section narrated across several audio files gets one cut per file.
+/// Sections without any narration are listed as uncovered; those with
+/// narration for only some of their documents are marked incomplete.
+pub(crate) fn write_audio_split_export(
+    enabled: ExportMode,
+    epub: &Epub,
+    book_dir: &Path,
+    book_slug: &str,
+    sections: &[SectionRecord],
+    spine_hrefs: &[String],
+    options: &ConvertOptions,
+) -> Result<()> {
+    if enabled != ExportMode::V1 {
+        return Ok(());
+    }
+    let mut overlays: HashMap<String, Vec<Clip>> = HashMap::new();
+    for entry in epub.manifest().entries() {
+        let Some(overlay) = entry.media_overlay() else {
+            continue;
+        };
+        let smil_href = overlay.href().as_str().to_string();
+        let Ok(smil) = epub.read_resource_str(&smil_href) else {
+            continue;
+        };
+        overlays.insert(
+            entry.href().as_str().to_string(),
+            smil_clips(&smil, &smil_href),
+        );
+    }
+    if overlays.is_empty() {
+        return Ok(());
+    }
+
+    let width = std::cmp::max(2, sections.len().to_string().len());
+    let mut planned = Vec::new();
+    let mut uncovered = Vec::new();
+    let mut audio_files = BTreeSet::new();
+    let mut script =
+        String::from("#!/bin/sh\n# Run from the book directory.\nset -e\nmkdir -p audio\n");
+    for (idx, section) in sections.iter().enumerate() {
+        let mut clips = Vec::new();
+        let mut complete = true;
+        for spine_idx in section.spine_start..=section.spine_end {
+            let Some(href) = spine_hrefs.get(spine_idx) else {
+                continue;
+            };
+            let Some(document_clips) = overlays.get(href) else {
+                complete = false;
+                continue;
+            };
+            let start = (spine_idx == section.spine_start)
+                .then_some(section.start_fragment.as_deref())
+                .flatten();
+            let end = (section.end_href.as_deref() == Some(href.as_str()))
+                .then_some(section.end_fragment.as_deref())
+                .flatten();
+            clips.extend(clips_between(document_clips, start, end));
+        }
+        if clips.is_empty() {
+            uncovered.push(json!({
+                "section_id": section.section_id,
+                "title": section.title,
+            }));
+            continue;
+        }
+
+        let cuts = cuts(&clips);
+        let stem = if options.split_chapters {
+            let name = section.output_path.rsplit('/').next().unwrap_or_default();
+            name.strip_suffix(".md").unwrap_or(name).to_string()
+        } else {
+            format!(
+                "{:0width$}_{}",
+                idx + 1,
+                options.slug_strategy.slug(&section.title),
+                width = width
+            )
+        };
+        let mut entries = Vec::new();
+        for (part, cut) in cuts.iter().enumerate() {
+            let input = format!("media/{}", decode_path(&cut.audio));
+            let extension = input
+                .rsplit_once('.')
+                .map(|(_, extension)| extension)
+                .filter(|extension| !extension.contains('/'))
+                .unwrap_or("mp3");
+            let output = if cuts.len() == 1 {
+                format!("audio/{stem}.{extension}")
+            } else {
+                format!("audio/{stem}_part{}.{extension}", part + 1)
+            };
+            let args = vec![
+                "-hide_banner".to_string(),
+                "-i".to_string(),
+                input.clone(),
+                "-ss".to_string(),
+                seconds(cut.begin),
+                "-to".to_string(),
+                seconds(cut.end),
+                "-c".to_string(),
+                "copy".to_string(),
+                output.clone(),
+            ];
+            script.push_str("ffmpeg");
+            for arg in &args {
+                script.push(' ');
+                script.push_str(&shell_quote(arg));
+            }
+            script.push('\n');
+            audio_files.insert(cut.audio.clone());
+            entries.push(json!({
+                "audio": input,
+                "start": seconds(cut.begin),
+                "end": seconds(cut.end),
+                "output": output,
+                "ffmpeg_args": args,
+            }));
+        }
+        planned.push(json!({
+            "section_id": section.section_id,
+            "title": section.title,
+            "markdown": output_path_for(section, book_slug, options),
+            "complete": complete,
+            "cuts": entries,
+        }));
+    }
+    if planned.is_empty() {
+        return Ok(());
+    }
+
+    let media_root = book_dir.join("media");
+    for audio in &audio_files {
+        let Ok(bytes) = epub.read_resource_bytes(audio) else {
+            continue;
+        };
+        let path = media_root.join(decode_path(audio));
+        if let Some(parent) = path.parent() {
+            options
+                .output_sink
+                .create_dir_all(parent)
+                .map_err(|err| ConvertError::write_failed(parent, err))?;
+        }
+        options
+            .output_sink
+            .write(&path, &bytes)
+            .map_err(|err| ConvertError::write_failed(&path, err))?;
+    }
+    let payload = json!({
+        "schema_version": "v1",
+        "time_units": "seconds",
+        "sections": planned,
+        "uncovered_sections": uncovered,
+    });
+    let writer = text_output::TextWriter::new(options);
+    writer.write_utf8(
+        &book_dir.join("audio_split.v1.json"),
+        &(serde_json::to_string_pretty(&payload)? + "\n"),
+    )?;
+    writer.write_utf8(&book_dir.join("audio_split.sh"), &script)?;
+    Ok(())
+}
+
+/// The `<par>` clips of a SMIL document in order, with text and audio
+/// sources resolved against `smil_href`. Pars without audio are skipped.
+fn smil_clips(smil: &str, smil_href: &str) -> Vec<Clip> {
+    let mut clips = Vec::new();
+    for par in PAR_RE.captures_iter(smil) {
+        let body = &par[1];
+        let Some(audio) = AUDIO_RE.find(body) else {
+            continue;
+        };
+        let Some(src) = attr(audio.as_str(), "src") else {
+            continue;
+        };
+        let fragment = TEXT_RE
+            .find(body)
+            .and_then(|text| attr(text.as_str(), "src"))
+            .and_then(|src| {
+                src.split_once('#')
+                    .map(|(_, fragment)| fragment.to_string())
+            });
+        let begin = attr(audio.as_str(), "clipBegin")
+            .and_then(|value| clock_value(&value))
+            .unwrap_or(0.0);
+        let Some(end) = attr(audio.as_str(), "clipEnd").and_then(|value| clock_value(&value))
+        else {
+            continue;
+        };
+        clips.push(Clip {
+            fragment,
+            audio: resolve_href(smil_href, src.split('#').next().unwrap_or_default()),
+            begin,
+            end,
+        });
+    }
+    clips
+}
+
+/// The clips from the one narrating `start` (or the first) up to, not
+/// including, the one narrating `end`. A fragment the overlay never narrates
+/// does not limit the range.
+fn clips_between(clips: &[Clip], start: Option<&str>, end: Option<&str>) -> Vec<Clip> {
+    let position = |fragment: Option<&str>| {
+        fragment.and_then(|fragment| {
+            clips
+                .iter()
+                .position(|clip| clip.fragment.as_deref() == Some(fragment))
+        })
+    };
+    let from = position(start).unwrap_or(0);
+    let to = position(end).unwrap_or(clips.len()).max(from);
+    clips[from..to].to_vec()
+}
+
+/// Consecutive clips in the same audio file become one cut from the earliest
+/// start to the latest end.
+fn cuts(clips: &[Clip]) -> Vec<Cut> {
+    let mut cuts: Vec<Cut> = Vec::new();
+    for clip in clips {
+        match cuts.last_mut() {
+            Some(cut) if cut.audio == clip.audio => {
+                cut.begin = cut.begin.min(clip.begin);
+                cut.end = cut.end.max(clip.end);
+            }
+            _ => cuts.push(Cut {
+                audio: clip.audio.clone(),
+                begin: clip.begin,
+                end: clip.end,
+            }),
+        }
+    }
+    cuts
+}
+
+/// SMIL clock values: `h:mm:ss.fff`, `mm:ss.fff`, or a number with an
+/// optional `h`, `min`, `s` or `ms` unit (seconds without one).
+fn clock_value(value: &str) -> Option<f64> {
+    let value = value.trim();
+    if value.contains(':') {
+        let mut total = 0.0;
+        for part in value.split(':') {
+            total = total * 60.0 + part.parse::<f64>().ok()?;
+        }
+        return Some(total);
+    }
+    let (number, scale) = if let Some(number) = value.strip_suffix("ms") {
+        (number, 0.001)
+    } else if let Some(number) = value.strip_suffix("min") {
+        (number, 60.0)
+    } else if let Some(number) = value.strip_suffix('h') {
+        (number, 3600.0)
+    } else if let Some(number) = value.strip_suffix('s') {
+        (number, 1.0)
+    } else {
+        (value, 1.0)
+    };
+    number
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .map(|number| number * scale)
+}
+
+/// Seconds with millisecond precision, as ffmpeg takes them.
+fn seconds(value: f64) -> String {
+    format!("{value:.3}")
+}
+
+fn attr(tag: &str, name: &str) -> Option<String> {
+    let pattern = format!(r#"\s{}\s*=\s*(?:"([^"]*)"|'([^']*)')"#, regex::escape(name));
+    let captures = Regex::new(&pattern).ok()?.captures(tag)?;
+    let value = captures.get(1).or_else(|| captures.get(2))?.as_str();
+    Some(
+        value
+            .replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&amp;", "&"),
+    )
+}
+
+fn shell_quote(value: &str) -> String {
+    if !value.is_empty()
+        && value
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || "-_./:".contains(ch))
+    {
+        return value.to_string();
+    }
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
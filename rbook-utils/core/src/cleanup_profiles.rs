@@ -0,0 +1,302 @@
+use kuchiki::NodeRef;
+use once_cell::sync::Lazy;
+use rbook::Epub;
+use rbook::prelude::{MetaEntry, Metadata};
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use crate::content_cache::ContentCache;
+use crate::{ConvertError, Result, SectionRecord, load_content};
+
+/// `--cleanup-profile` value that picks the profile by the book's publisher.
+pub const AUTO_CLEANUP_PROFILE: &str = "auto";
+
+static BLANK_RUN_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\n[ \t]*(?:\n[ \t]*){2,}").expect("valid blank run regex"));
+
+/// Profiles that come with rbook-utils. A user profile of the same name
+/// replaces the shipped one.
+const SHIPPED_PROFILES: &str = r#"
+[gutenberg]
+publishers = ["Project Gutenberg"]
+strip_classes = ["pg-boilerplate", "pgheader", "pgfooter"]
+skip_sections = [
+    '(?i)^\s*(the\s+)?full\s+project\s+gutenberg\s+licen[cs]e',
+    '(?i)^\s*project\s+gutenberg.*\b(licen[cs]e|header|footer)\b',
+]
+remove_patterns = [
+    '(?im)^[ \t]*\*{3}[ \t]*(start|end) of (the|this) project gutenberg e-?book\b.*$',
+]
+
+[standard-ebooks]
+publishers = ["Standard Ebooks"]
+skip_sections = ['(?i)^\s*(imprint|colophon|uncopyright)\s*$']
+
+[feedbooks]
+publishers = ["Feedbooks"]
+skip_sections = ['(?i)^\s*food for the mind\s*$']
+
+# Retailer stamps and promotional back matter; not tied to a publisher.
+[retail-stamps]
+skip_sections = [
+    '(?i)^\s*(also by|more (books )?(from|by)|about the publisher|newsletter|sign up for|praise for)\b',
+]
+remove_patterns = [
+    '(?im)^[ \t*_>]*(this (e-?book|copy) (was|is) )?(purchased by|licensed to|prepared for|for the exclusive use of)\b[^\n]{0,120}$',
+]
+"#;
+
+/// How one publisher's signature junk is cleaned out of the output.
+#[derive(Clone, Debug, Default)]
+pub struct CleanupProfile {
+    /// `dc:publisher` values the profile is picked for under
+    /// [`AUTO_CLEANUP_PROFILE`], matched case-insensitively as substrings.
+    pub publishers: Vec<String>,
+    /// Removed from the markdown of every section.
+    pub remove_patterns: Vec<Regex>,
+    /// Sections whose title matches one of these are left out.
+    pub skip_sections: Vec<Regex>,
+    /// Elements carrying one of these classes are dropped before rendering.
+    pub strip_classes: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileSpec {
+    #[serde(default)]
+    publishers: Vec<String>,
+    #[serde(default)]
+    remove_patterns: Vec<String>,
+    #[serde(default)]
+    skip_sections: Vec<String>,
+    #[serde(default)]
+    strip_classes: Vec<String>,
+}
+
+impl ProfileSpec {
+    fn compile(self, name: &str) -> std::result::Result<CleanupProfile, String> {
+        let compile = |patterns: Vec<String>| {
+            patterns
+                .iter()
+                .map(|pattern| Regex::new(pattern).map_err(|err| format!("profile {name}: {err}")))
+                .collect::<std::result::Result<Vec<_>, _>>()
+        };
+        Ok(CleanupProfile {
+            publishers: self.publishers,
+            remove_patterns: compile(self.remove_patterns)?,
+            skip_sections: compile(self.skip_sections)?,
+            strip_classes: self.strip_classes,
+        })
+    }
+}
+
+/// The named cleanup profiles to choose from: the shipped ones plus any read
+/// from a TOML file of `[name]` tables with `publishers`, `remove_patterns`,
+/// `skip_sections` and `strip_classes` arrays.
+#[derive(Clone, Debug)]
+pub struct CleanupProfiles {
+    /// User profiles first, so they win the publisher match.
+    profiles: Vec<(String, CleanupProfile)>,
+}
+
+impl Default for CleanupProfiles {
+    fn default() -> Self {
+        Self::shipped()
+    }
+}
+
+impl CleanupProfiles {
+    pub fn shipped() -> Self {
+        let profiles = parse_profiles(SHIPPED_PROFILES).expect("valid shipped cleanup profiles");
+        Self { profiles }
+    }
+
+    /// The shipped profiles with those in `path` added or replacing them.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let mut profiles = Self::shipped();
+        profiles.add_file(path)?;
+        Ok(profiles)
+    }
+
+    /// Adds the profiles in `path`, replacing any of the same name.
+    pub fn add_file(&mut self, path: &Path) -> Result<()> {
+        let text =
+            fs::read_to_string(path).map_err(|err| ConvertError::InvalidCleanupProfiles {
+                path: path.to_path_buf(),
+                reason: err.to_string(),
+            })?;
+        self.add_toml(&text, path)
+    }
+
+    /// Adds the profiles in `text`, read from `path`, replacing any of the
+    /// same name and ahead of the rest in the publisher match.
+    pub fn add_toml(&mut self, text: &str, path: &Path) -> Result<()> {
+        let user = parse_profiles(text).map_err(|reason| ConvertError::InvalidCleanupProfiles {
+            path: path.to_path_buf(),
+            reason,
+        })?;
+        self.profiles
+            .retain(|(name, _)| !user.iter().any(|(user_name, _)| user_name == name));
+        self.profiles.splice(0..0, user);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&CleanupProfile> {
+        self.profiles
+            .iter()
+            .find(|(profile, _)| profile == name)
+            .map(|(_, profile)| profile)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.profiles.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// The profile `choice` names, or with [`AUTO_CLEANUP_PROFILE`] the first
+    /// whose publishers match one of the book's.
+    pub(crate) fn select(&self, choice: &str, epub: &Epub) -> Option<(&str, &CleanupProfile)> {
+        if choice != AUTO_CLEANUP_PROFILE {
+            return self
+                .profiles
+                .iter()
+                .find(|(name, _)| name == choice)
+                .map(|(name, profile)| (name.as_str(), profile));
+        }
+        let publishers: Vec<String> = epub
+            .metadata()
+            .publishers()
+            .map(|publisher| publisher.value().trim().to_lowercase())
+            .filter(|publisher| !publisher.is_empty())
+            .collect();
+        self.profiles
+            .iter()
+            .find(|(_, profile)| {
+                profile.publishers.iter().any(|wanted| {
+                    let wanted = wanted.trim().to_lowercase();
+                    !wanted.is_empty() && publishers.iter().any(|found| found.contains(&wanted))
+                })
+            })
+            .map(|(name, profile)| (name.as_str(), profile))
+    }
+}
+
+fn parse_profiles(text: &str) -> std::result::Result<Vec<(String, CleanupProfile)>, String> {
+    let specs: BTreeMap<String, ProfileSpec> =
+        toml::from_str(text).map_err(|err| err.to_string())?;
+    specs
+        .into_iter()
+        .map(|(name, spec)| {
+            if name == AUTO_CLEANUP_PROFILE {
+                return Err(format!(
+                    "{AUTO_CLEANUP_PROFILE} is reserved and cannot name a profile"
+                ));
+            }
+            let profile = spec.compile(&name)?;
+            Ok((name, profile))
+        })
+        .collect()
+}
+
+/// What a cleanup profile removed from one book.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ProfileCleanup {
+    pub(crate) profile: String,
+    pub(crate) elements_stripped: usize,
+    pub(crate) sections_skipped: usize,
+    pub(crate) pattern_removals: usize,
+}
+
+/// Detaches the elements carrying one of the profile's classes from the
+/// spine documents. Returns how many were removed.
+pub(crate) fn strip_classes(
+    profile: &CleanupProfile,
+    epub: &Epub,
+    spine_hrefs: &[String],
+    cache: &mut ContentCache,
+) -> usize {
+    if profile.strip_classes.is_empty() {
+        return 0;
+    }
+    let mut removed = 0usize;
+    for href in spine_hrefs {
+        let Ok(content) = load_content(epub, href, cache) else {
+            continue;
+        };
+        let elements: Vec<NodeRef> = content
+            .document
+            .descendants()
+            .filter(|node| {
+                node.as_element().is_some_and(|element| {
+                    element
+                        .attributes
+                        .borrow()
+                        .get("class")
+                        .is_some_and(|class| {
+                            class.split_whitespace().any(|class| {
+                                profile.strip_classes.iter().any(|strip| strip == class)
+                            })
+                        })
+                })
+            })
+            .collect();
+        let removed_before = removed;
+        for element in elements {
+            // Already gone with a stripped ancestor.
+            if element
+                .ancestors()
+                .any(|ancestor| ancestor.as_document().is_some())
+            {
+                element.detach();
+                removed += 1;
+            }
+        }
+        if removed > removed_before {
+            cache.pin(href);
+        }
+    }
+    removed
+}
+
+/// Leaves out the sections whose title matches a skip rule and removes the
+/// profile's patterns from the rest, recording both in `cleanup`.
+pub(crate) fn clean_sections(
+    profile: &CleanupProfile,
+    sections: &mut Vec<SectionRecord>,
+    cleanup: &mut ProfileCleanup,
+) {
+    let before = sections.len();
+    sections.retain(|section| !skips_section(profile, &section.title));
+    cleanup.sections_skipped += before - sections.len();
+    for section in sections.iter_mut() {
+        let (text, removals) = remove_patterns(profile, &section.text);
+        section.text = text;
+        cleanup.pattern_removals += removals;
+    }
+}
+
+pub(crate) fn skips_section(profile: &CleanupProfile, title: &str) -> bool {
+    profile
+        .skip_sections
+        .iter()
+        .any(|pattern| pattern.is_match(title))
+}
+
+/// `text` without the profile's patterns, and how many matches went.
+pub(crate) fn remove_patterns(profile: &CleanupProfile, text: &str) -> (String, usize) {
+    let mut out = text.to_string();
+    let mut removals = 0usize;
+    for pattern in &profile.remove_patterns {
+        let found = pattern.find_iter(&out).count();
+        if found > 0 {
+            removals += found;
+            out = pattern.replace_all(&out, "").into_owned();
+        }
+    }
+    if removals > 0 {
+        out = BLANK_RUN_RE.replace_all(&out, "\n\n").trim().to_string();
+    }
+    (out, removals)
+}
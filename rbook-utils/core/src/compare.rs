@@ -0,0 +1,168 @@
+use pulldown_cmark::{Options, Parser, html};
+use rbook::Epub;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use crate::content_cache::ContentCache;
+use crate::output::OutputSink;
+use crate::{
+    ConvertError, Result, SectionRecord, load_content, partial_body_nodes, resolve_href,
+    serialize_node,
+};
+
+const PAGE_STYLE: &str = "body{margin:0;font-family:sans-serif}\
+header{padding:.5em 1em;background:#eee;display:flex;gap:1em;align-items:center}\
+header h1{font-size:1em;margin:0;flex:1}\
+main{display:flex;height:calc(100vh - 2.5em)}\
+main section{flex:1;display:flex;flex-direction:column;border-left:1px solid #ccc}\
+main h2{font-size:.8em;margin:0;padding:.25em 1em;background:#f6f6f6}\
+iframe{flex:1;border:0;width:100%}";
+
+const PANE_STYLE: &str = "body{max-width:42em;margin:1em auto;padding:0 1em;line-height:1.5}\
+img{max-width:100%}table{border-collapse:collapse}td,th{border:1px solid #ccc;padding:.2em .4em}";
+
+/// Writes `compare/{section_id}.html` pages that show each section's source
+/// XHTML next to its converted markdown (rendered), plus an index page.
+///
+/// Runs after every other output: image sources in the cached documents are
+/// pointed at the extracted copies so both panes show the same files.
+pub(crate) fn write_compare_view(
+    epub: &Epub,
+    book_dir: &Path,
+    title: &str,
+    sections: &[SectionRecord],
+    spine_hrefs: &[String],
+    cache: &mut ContentCache,
+    extracted_images: &HashMap<String, String>,
+    split_chapters: bool,
+    files: &dyn OutputSink,
+) -> Result<()> {
+    let compare_dir = book_dir.join("compare");
+    files
+        .create_dir_all(&compare_dir)
+        .map_err(|err| ConvertError::write_failed(&compare_dir, err))?;
+    // Markdown links are relative to the markdown file, which sits in book_dir
+    // (split) or its parent (single file).
+    let base = if split_chapters { "../" } else { "../../" };
+    let extracted_links: HashSet<&str> = extracted_images.values().map(String::as_str).collect();
+
+    let mut index_items = Vec::new();
+    for (idx, section) in sections.iter().enumerate() {
+        let mut source = String::new();
+        for spine_idx in section.spine_start..=section.spine_end {
+            let Some(href) = spine_hrefs.get(spine_idx) else {
+                continue;
+            };
+            let start = (spine_idx == section.spine_start)
+                .then_some(section.start_fragment.as_deref())
+                .flatten();
+            let end = (section.end_href.as_deref() == Some(href.as_str()))
+                .then_some(section.end_fragment.as_deref())
+                .flatten();
+            let Ok(content) = load_content(epub, href, cache) else {
+                continue;
+            };
+            let Some(nodes) = partial_body_nodes(content, start, end) else {
+                continue;
+            };
+            for node in &nodes {
+                point_images_at_extracted(node, href, extracted_images, &extracted_links);
+                source.push_str(&serialize_node(node));
+            }
+        }
+
+        let markdown = format!("## {}\n\n{}", section.title, section.text);
+        let mut rendered = String::new();
+        let options = Options::ENABLE_TABLES
+            | Options::ENABLE_FOOTNOTES
+            | Options::ENABLE_STRIKETHROUGH
+            | Options::ENABLE_HEADING_ATTRIBUTES;
+        html::push_html(&mut rendered, Parser::new_ext(&markdown, options));
+
+        let nav = |offset: isize, label: &str| -> String {
+            let target = idx as isize + offset;
+            match usize::try_from(target).ok().and_then(|t| sections.get(t)) {
+                Some(other) => format!("<a href=\"{}.html\">{label}</a>", other.section_id),
+                None => format!("<span>{label}</span>"),
+            }
+        };
+        let page = format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{title} — {section_title}</title>\
+<style>{PAGE_STYLE}</style></head><body>\
+<header><h1>{title} — {section_title}</h1>{prev}<a href=\"index.html\">index</a>{next}</header>\
+<main><section><h2>Source: {href}</h2><iframe sandbox srcdoc=\"{source_doc}\"></iframe></section>\
+<section><h2>Markdown: {output}</h2><iframe sandbox srcdoc=\"{markdown_doc}\"></iframe></section></main>\
+</body></html>\n",
+            title = escape_html(title),
+            section_title = escape_html(&section.title),
+            prev = nav(-1, "prev"),
+            next = nav(1, "next"),
+            href = escape_html(&section.start_href),
+            output = escape_html(&section.output_path),
+            source_doc = escape_html(&pane_document(base, &source)),
+            markdown_doc = escape_html(&pane_document(base, &rendered)),
+        );
+        let page_path = compare_dir.join(format!("{}.html", section.section_id));
+        files
+            .write(&page_path, page.as_bytes())
+            .map_err(|err| ConvertError::write_failed(&page_path, err))?;
+        index_items.push(format!(
+            "<li><a href=\"{}.html\">{}</a></li>",
+            section.section_id,
+            escape_html(&section.title)
+        ));
+    }
+
+    let index_path = compare_dir.join("index.html");
+    files
+        .write(
+            &index_path,
+            format!(
+                "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{title}</title></head>\
+<body><h1>{title}</h1><ol>{}</ol></body></html>\n",
+                index_items.join(""),
+                title = escape_html(title),
+            )
+            .as_bytes(),
+        )
+        .map_err(|err| ConvertError::write_failed(&index_path, err))?;
+    Ok(())
+}
+
+fn pane_document(base: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><base href=\"{base}\">\
+<style>{PANE_STYLE}</style></head><body>{body}</body></html>"
+    )
+}
+
+fn point_images_at_extracted(
+    node: &kuchiki::NodeRef,
+    href: &str,
+    extracted_images: &HashMap<String, String>,
+    extracted_links: &HashSet<&str>,
+) {
+    let Ok(images) = node.select("img") else {
+        return;
+    };
+    for img in images {
+        let mut attrs = img.attributes.borrow_mut();
+        let Some(src) = attrs.get("src").map(str::to_string) else {
+            continue;
+        };
+        if extracted_links.contains(src.as_str()) {
+            continue;
+        }
+        if let Some(link) = extracted_images.get(&resolve_href(href, &src)) {
+            attrs.insert("src", link.clone());
+        }
+    }
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+
+use crate::{ContentDoc, ConvertOptions};
+
+/// Parsed content documents by href, shared by the passes that revisit them
+/// (heading detection, notes, link targets, rendering, exports).
+///
+/// Unbounded by default. With a document or byte limit, the least recently
+/// used documents are dropped to make room and parsed again when next asked
+/// for. Bytes are counted as the size of each document's HTML, which
+/// undercounts the DOM but grows with it. Documents a pass has edited in
+/// place are pinned, since parsing them again would lose the edits.
+#[derive(Default)]
+pub(crate) struct ContentCache {
+    entries: HashMap<String, Entry>,
+    max_documents: Option<usize>,
+    max_bytes: Option<u64>,
+    bytes: u64,
+    clock: u64,
+}
+
+struct Entry {
+    content: ContentDoc,
+    bytes: u64,
+    last_used: u64,
+    pinned: bool,
+}
+
+impl ContentCache {
+    pub(crate) fn new(max_documents: Option<usize>, max_bytes: Option<u64>) -> Self {
+        Self {
+            max_documents,
+            max_bytes,
+            ..Self::default()
+        }
+    }
+
+    pub(crate) fn from_options(options: &ConvertOptions) -> Self {
+        Self::new(options.cache_max_documents, options.cache_max_bytes)
+    }
+
+    pub(crate) fn contains(&self, href: &str) -> bool {
+        self.entries.contains_key(href)
+    }
+
+    pub(crate) fn get(&mut self, href: &str) -> Option<&ContentDoc> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(href)?;
+        entry.last_used = self.clock;
+        Some(&entry.content)
+    }
+
+    /// Adds a document parsed from `bytes` of HTML, evicting others first if
+    /// the limits call for it. A document already cached is kept as is.
+    pub(crate) fn insert(&mut self, href: &str, content: ContentDoc, bytes: u64) -> &ContentDoc {
+        if !self.entries.contains_key(href) {
+            self.make_room(bytes);
+            self.bytes += bytes;
+            self.entries.insert(
+                href.to_string(),
+                Entry {
+                    content,
+                    bytes,
+                    last_used: 0,
+                    pinned: false,
+                },
+            );
+        }
+        self.get(href).expect("cache insert")
+    }
+
+    /// Whether a document of `bytes` fits without evicting anything.
+    pub(crate) fn has_room(&self, bytes: u64) -> bool {
+        self.max_documents
+            .is_none_or(|max| self.entries.len() < max)
+            && self.max_bytes.is_none_or(|max| self.bytes + bytes <= max)
+    }
+
+    /// Keeps `href` from being evicted to make room; call after editing its
+    /// DOM in place.
+    pub(crate) fn pin(&mut self, href: &str) {
+        if let Some(entry) = self.entries.get_mut(href) {
+            entry.pinned = true;
+        }
+    }
+
+    /// Drops the documents `keep` rejects, pinned or not. Their in-place edits
+    /// are lost, so only for documents the book will not read again.
+    pub(crate) fn retain_all(&mut self, mut keep: impl FnMut(&str) -> bool) {
+        let mut freed = 0;
+        self.entries.retain(|href, entry| {
+            let kept = keep(href);
+            if !kept {
+                freed += entry.bytes;
+            }
+            kept
+        });
+        self.bytes -= freed;
+    }
+
+    /// Evicts least recently used, unpinned documents until one of `bytes`
+    /// fits, or nothing evictable is left.
+    fn make_room(&mut self, bytes: u64) {
+        while !self.has_room(bytes) {
+            let Some(href) = self
+                .entries
+                .iter()
+                .filter(|(_, entry)| !entry.pinned)
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(href, _)| href.clone())
+            else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&href) {
+                self.bytes -= entry.bytes;
+            }
+        }
+    }
+}
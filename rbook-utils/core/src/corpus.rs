@@ -0,0 +1,174 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use crate::{ConvertError, Result, count_words, files_under};
+
+/// Totals across a converted library, read back from the `manifest.v1.json`
+/// (and, where present, `report.v1.json`) of every book under an output
+/// directory. Books converted without `--export-manifest v1` are not seen.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct CorpusStats {
+    pub output_dir: PathBuf,
+    pub books: usize,
+    pub sections: usize,
+    pub words: usize,
+    pub images: usize,
+    pub media: usize,
+    /// Books per language tag, `unknown` when the manifest has none.
+    pub languages: BTreeMap<String, usize>,
+    /// Words in the smallest, median and largest book.
+    pub words_per_book: (usize, usize, usize),
+    /// Totals over the books that have a quality report.
+    pub quality: QualityStats,
+    pub per_book: Vec<CorpusBook>,
+    /// Manifests that could not be read or parsed.
+    pub unreadable: Vec<PathBuf>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct CorpusBook {
+    /// The book's output directory.
+    pub path: PathBuf,
+    pub title: String,
+    pub author: Option<String>,
+    pub language: Option<String>,
+    pub sections: usize,
+    pub words: usize,
+    pub images: usize,
+    pub media: usize,
+    /// From `report.v1.json`, when the book was converted with `--quality-report v1`.
+    pub quality: Option<QualityStats>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct QualityStats {
+    pub reports: usize,
+    pub heading_fallback: usize,
+    pub degenerate_toc: usize,
+    pub unresolved_links: usize,
+    pub missing_assets: usize,
+    pub warnings: usize,
+}
+
+impl QualityStats {
+    fn add(&mut self, other: &QualityStats) {
+        self.reports += other.reports;
+        self.heading_fallback += other.heading_fallback;
+        self.degenerate_toc += other.degenerate_toc;
+        self.unresolved_links += other.unresolved_links;
+        self.missing_assets += other.missing_assets;
+        self.warnings += other.warnings;
+    }
+}
+
+/// Aggregates every book converted into `output_dir`, in path order.
+pub fn corpus_stats(output_dir: &Path) -> Result<CorpusStats> {
+    if !output_dir.is_dir() {
+        return Err(ConvertError::NoInput {
+            dir: output_dir.to_path_buf(),
+        });
+    }
+    let mut manifests: Vec<PathBuf> = files_under(output_dir)
+        .into_iter()
+        .filter(|path| {
+            path.file_name()
+                .is_some_and(|name| name == "manifest.v1.json")
+        })
+        .collect();
+    manifests.sort();
+
+    let mut stats = CorpusStats {
+        output_dir: output_dir.to_path_buf(),
+        ..CorpusStats::default()
+    };
+    for manifest_path in manifests {
+        let Some(book) = read_book(&manifest_path) else {
+            stats.unreadable.push(manifest_path);
+            continue;
+        };
+        stats.books += 1;
+        stats.sections += book.sections;
+        stats.words += book.words;
+        stats.images += book.images;
+        stats.media += book.media;
+        let language = book.language.as_deref().unwrap_or("unknown");
+        *stats.languages.entry(language.to_string()).or_default() += 1;
+        if let Some(quality) = &book.quality {
+            stats.quality.add(quality);
+        }
+        stats.per_book.push(book);
+    }
+
+    let mut words: Vec<usize> = stats.per_book.iter().map(|book| book.words).collect();
+    words.sort_unstable();
+    if let (Some(min), Some(max)) = (words.first(), words.last()) {
+        stats.words_per_book = (*min, words[words.len() / 2], *max);
+    }
+    Ok(stats)
+}
+
+fn read_book(manifest_path: &Path) -> Option<CorpusBook> {
+    let manifest: Value = serde_json::from_slice(&std::fs::read(manifest_path).ok()?).ok()?;
+    let book_dir = manifest_path.parent()?;
+    let sections = manifest["sections"].as_array()?;
+    let text = |value: &Value| {
+        value
+            .as_str()
+            .map(str::trim)
+            .filter(|text| !text.is_empty())
+            .map(str::to_string)
+    };
+    let count = |value: &Value| value.as_array().map_or(0, Vec::len);
+    let words = match sections
+        .iter()
+        .map(|section| section["words"].as_u64().map(|words| words as usize))
+        .sum::<Option<usize>>()
+    {
+        Some(words) => words,
+        // Manifests from before per-section counts: count the markdown itself.
+        None => markdown_words(book_dir, sections),
+    };
+    Some(CorpusBook {
+        path: book_dir.to_path_buf(),
+        title: text(&manifest["book"]["title"]).unwrap_or_default(),
+        author: text(&manifest["book"]["authors"]),
+        language: text(&manifest["book"]["language"]),
+        sections: sections.len(),
+        words,
+        images: count(&manifest["assets"]["images"]),
+        media: count(&manifest["assets"]["media"]),
+        quality: read_quality(&book_dir.join("report.v1.json")),
+    })
+}
+
+/// Words in the files the sections were written to. Their output paths are
+/// relative to the output root, the book directory's parent.
+fn markdown_words(book_dir: &Path, sections: &[Value]) -> usize {
+    let root = book_dir.parent().unwrap_or(book_dir);
+    let files: BTreeSet<&str> = sections
+        .iter()
+        .filter_map(|section| section["output_path"].as_str())
+        .collect();
+    files
+        .into_iter()
+        .filter_map(|path| std::fs::read_to_string(root.join(path)).ok())
+        .map(|markdown| count_words(&markdown))
+        .sum()
+}
+
+fn read_quality(report_path: &Path) -> Option<QualityStats> {
+    let report: Value = serde_json::from_slice(&std::fs::read(report_path).ok()?).ok()?;
+    let number = |value: &Value| value.as_u64().unwrap_or(0) as usize;
+    Some(QualityStats {
+        reports: 1,
+        heading_fallback: report["fallback_stats"]["used_heading_fallback"]
+            .as_bool()
+            .unwrap_or(false) as usize,
+        degenerate_toc: report["toc_stats"]["degenerate"].as_bool().unwrap_or(false) as usize,
+        unresolved_links: number(&report["link_stats"]["unresolved"]),
+        missing_assets: number(&report["asset_stats"]["missing_assets"]),
+        warnings: report["warnings"].as_array().map_or(0, Vec::len),
+    })
+}
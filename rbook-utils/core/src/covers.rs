@@ -0,0 +1,198 @@
+use image::ImageFormat;
+use image::imageops::FilterType;
+use rbook::ebook::manifest::Manifest;
+use rbook::prelude::{ManifestEntry, MetaEntry, Metadata};
+use rbook::{Ebook, Epub};
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use crate::{
+    BookConversionResult, ConversionSummary, ConvertError, ConvertReport, CoverFormat, CoverNaming,
+    CoverOptions, Diagnostic, DiagnosticLevel, MissingResources, Result, WarningCode, book_title,
+    collect_epub_paths, isolate_panics, open_epub, slugify,
+};
+
+/// Href of the cover image: the EPUB 3 `cover-image` manifest property, then
+/// the EPUB 2 `<meta name="cover">` pointing at a manifest id.
+pub(crate) fn cover_href(epub: &Epub) -> Option<String> {
+    if let Some(entry) = epub
+        .manifest()
+        .entries()
+        .find(|entry| entry.properties().has_property("cover-image"))
+    {
+        return Some(entry.href().as_str().to_string());
+    }
+    epub.metadata()
+        .entries()
+        .filter(|meta| meta.property().as_str() == "cover")
+        .find_map(|meta| epub.manifest().by_id(meta.value().trim()))
+        .filter(|entry| entry.media_type().starts_with("image/"))
+        .map(|entry| entry.href().as_str().to_string())
+}
+
+/// Writes only the cover of every EPUB under `input_dir` to `output_dir`,
+/// resized to fit `max_edge` and re-encoded, without converting the books.
+/// Each book's `output_path` is its cover file; books without one get none.
+pub fn extract_covers(options: &CoverOptions) -> Result<ConversionSummary> {
+    let epub_paths = collect_epub_paths(&options.input_dir);
+    if epub_paths.is_empty() {
+        return Err(ConvertError::NoInput {
+            dir: options.input_dir.clone(),
+        });
+    }
+    fs::create_dir_all(&options.output_dir)
+        .map_err(|err| ConvertError::write_failed(&options.output_dir, err))?;
+
+    let mut used_names: HashSet<String> = HashSet::new();
+    let mut summary = ConversionSummary::default();
+    for epub_path in epub_paths {
+        let result = isolate_panics(|| extract_cover(&epub_path, options, &mut used_names))
+            .unwrap_or_else(|err| BookConversionResult {
+                input_path: epub_path.clone(),
+                title: epub_path
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .unwrap_or("book")
+                    .to_string(),
+                output_path: None,
+                diagnostics: vec![Diagnostic {
+                    level: DiagnosticLevel::Error,
+                    code: None,
+                    message: format!(
+                        "Failed to extract cover from {}: {}",
+                        epub_path.display(),
+                        err.full_message()
+                    ),
+                }],
+                missing_resources: MissingResources::default(),
+                skipped: None,
+                error_kind: Some(err.kind()),
+                sections: Vec::new(),
+                report: ConvertReport::default(),
+            });
+        summary.books.push(result);
+    }
+    Ok(summary)
+}
+
+fn extract_cover(
+    epub_path: &Path,
+    options: &CoverOptions,
+    used_names: &mut HashSet<String>,
+) -> Result<BookConversionResult> {
+    let epub = open_epub(epub_path)?;
+    let title = book_title(&epub, epub_path);
+    let mut result = BookConversionResult {
+        input_path: epub_path.to_path_buf(),
+        title: title.clone(),
+        output_path: None,
+        diagnostics: Vec::new(),
+        missing_resources: MissingResources::default(),
+        skipped: None,
+        error_kind: None,
+        sections: Vec::new(),
+        report: ConvertReport::default(),
+    };
+    let Some(href) = cover_href(&epub) else {
+        result.diagnostics.push(Diagnostic {
+            level: DiagnosticLevel::Warning,
+            code: Some(WarningCode::CoverMissing),
+            message: format!("No cover image found for {title}"),
+        });
+        return Ok(result);
+    };
+    let bytes =
+        epub.read_resource_bytes(href.as_str())
+            .map_err(|err| ConvertError::ResourceRead {
+                href: href.clone(),
+                source: err.into(),
+            })?;
+
+    let base_name = match options.naming {
+        CoverNaming::Slug => slugify(&title),
+        CoverNaming::Identifier => epub
+            .metadata()
+            .identifier()
+            .map(|id| identifier_stem(id.value()))
+            .filter(|stem| !stem.is_empty())
+            .unwrap_or_else(|| slugify(&title)),
+    };
+    let mut name = base_name.clone();
+    let mut suffix = 2;
+    while !used_names.insert(name.clone()) {
+        name = format!("{base_name}_{suffix}");
+        suffix += 1;
+    }
+
+    let original_ext = href
+        .rsplit('/')
+        .next()
+        .and_then(|file| file.rsplit_once('.'))
+        .map(|(_, ext)| ext.to_lowercase())
+        .unwrap_or_else(|| "img".to_string());
+    let target = match options.format {
+        CoverFormat::Original => None,
+        CoverFormat::Jpeg => Some((ImageFormat::Jpeg, "jpg")),
+        CoverFormat::Png => Some((ImageFormat::Png, "png")),
+        CoverFormat::Webp => Some((ImageFormat::WebP, "webp")),
+    };
+    let decoded = target.and_then(|target| {
+        match image::load_from_memory(&bytes) {
+            Ok(image) => Some((image, target)),
+            Err(err) => {
+                // SVG or exotic covers are still useful copied as-is.
+                result.diagnostics.push(Diagnostic {
+                    level: DiagnosticLevel::Warning,
+                    code: Some(WarningCode::CoverNotReencoded),
+                    message: format!("Kept cover of {title} in its original format: {err}"),
+                });
+                None
+            }
+        }
+    });
+    let output_path = match decoded {
+        Some((image, (format, ext))) => {
+            let image = if image.width().max(image.height()) > options.max_edge {
+                image.resize(options.max_edge, options.max_edge, FilterType::Lanczos3)
+            } else {
+                image
+            };
+            // JPEG has no alpha channel.
+            let image = if format == ImageFormat::Jpeg {
+                image::DynamicImage::ImageRgb8(image.to_rgb8())
+            } else {
+                image
+            };
+            let output_path = options.output_dir.join(format!("{name}.{ext}"));
+            image
+                .save_with_format(&output_path, format)
+                .map_err(|err| ConvertError::WriteFailed {
+                    path: output_path.clone(),
+                    source: err.into(),
+                })?;
+            output_path
+        }
+        None => {
+            let output_path = options.output_dir.join(format!("{name}.{original_ext}"));
+            fs::write(&output_path, &bytes).map_err(|err| ConvertError::WriteFailed {
+                path: output_path.clone(),
+                source: err.into(),
+            })?;
+            output_path
+        }
+    };
+    result.output_path = Some(output_path);
+    Ok(result)
+}
+
+/// `urn:isbn:9780000000000` -> `9780000000000`; other identifiers are slugified whole.
+fn identifier_stem(identifier: &str) -> String {
+    let trimmed = identifier.trim();
+    let value = trimmed
+        .strip_prefix("urn:")
+        .and_then(|rest| rest.split_once(':'))
+        .map(|(_, value)| value)
+        .unwrap_or(trimmed);
+    slugify(value)
+}
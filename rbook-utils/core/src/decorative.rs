@@ -0,0 +1,131 @@
+use kuchiki::NodeRef;
+use rbook::Epub;
+use std::collections::HashMap;
+use std::io::Cursor;
+
+use crate::content_cache::ContentCache;
+use crate::{element_name, is_external, load_content, resolve_href};
+
+/// Images whose longest edge is at most this many pixels are spacers or ornaments.
+const MAX_DECORATIVE_EDGE: u32 = 16;
+/// Rules drawn as images are a pixel or two high (or wide).
+const MAX_RULE_THICKNESS: u32 = 2;
+
+/// Removes decorative `<img>` elements from the spine: those marked
+/// `role="presentation"`/`"none"` or `aria-hidden`, those with an empty
+/// `alt`, and spacer-sized images (by their attributes or the image file).
+/// Returns how many were removed.
+pub(crate) fn remove_decorative_images(
+    epub: &Epub,
+    spine_hrefs: &[String],
+    cache: &mut ContentCache,
+) -> usize {
+    let mut dimensions: HashMap<String, Option<(u32, u32)>> = HashMap::new();
+    let mut removed = 0usize;
+    for href in spine_hrefs {
+        let Ok(content) = load_content(epub, href, cache) else {
+            continue;
+        };
+        let Ok(matches) = content.document.select("img") else {
+            continue;
+        };
+        let images: Vec<NodeRef> = matches.map(|m| m.as_node().clone()).collect();
+        let removed_before = removed;
+        for image in images {
+            let decorative = is_marked_decorative(&image)
+                || attr_size(&image).is_some_and(is_tiny)
+                || attr(&image, "src")
+                    .filter(|src| !src.trim().is_empty() && !is_external(src))
+                    .and_then(|src| {
+                        let resolved = resolve_href(href, &src);
+                        *dimensions
+                            .entry(resolved.clone())
+                            .or_insert_with(|| file_size(epub, &resolved))
+                    })
+                    .is_some_and(is_tiny);
+            if decorative {
+                image.detach();
+                removed += 1;
+            }
+        }
+        if removed > removed_before {
+            cache.pin(href);
+        }
+    }
+    removed
+}
+
+/// Removes every image from the spine, for books whose pictures are not
+/// worth keeping (page scans under an OCR text layer, say): `<img>` elements
+/// and `<svg>` drawings that embed an `<image>`. Returns how many were removed.
+pub(crate) fn remove_all_images(
+    epub: &Epub,
+    spine_hrefs: &[String],
+    cache: &mut ContentCache,
+) -> usize {
+    let mut removed = 0usize;
+    for href in spine_hrefs {
+        let Ok(content) = load_content(epub, href, cache) else {
+            continue;
+        };
+        let Ok(matches) = content.document.select("img, svg") else {
+            continue;
+        };
+        let images: Vec<NodeRef> = matches
+            .map(|m| m.as_node().clone())
+            .filter(|node| !node.ancestors().any(|a| element_name(&a) == Some("svg")))
+            .filter(|node| {
+                element_name(node) == Some("img")
+                    || node
+                        .descendants()
+                        .any(|child| element_name(&child) == Some("image"))
+            })
+            .collect();
+        for image in &images {
+            image.detach();
+        }
+        if !images.is_empty() {
+            removed += images.len();
+            cache.pin(href);
+        }
+    }
+    removed
+}
+
+fn is_marked_decorative(image: &NodeRef) -> bool {
+    let role = attr(image, "role").unwrap_or_default();
+    matches!(role.trim(), "presentation" | "none")
+        || attr(image, "aria-hidden").is_some_and(|hidden| hidden.trim() == "true")
+        || attr(image, "alt").is_some_and(|alt| alt.trim().is_empty())
+}
+
+fn is_tiny((width, height): (u32, u32)) -> bool {
+    width.max(height) <= MAX_DECORATIVE_EDGE || width.min(height) <= MAX_RULE_THICKNESS
+}
+
+/// `width`/`height` attributes in pixels, when both are given.
+fn attr_size(image: &NodeRef) -> Option<(u32, u32)> {
+    let pixels = |name: &str| {
+        attr(image, name)?
+            .trim()
+            .trim_end_matches("px")
+            .parse::<u32>()
+            .ok()
+    };
+    Some((pixels("width")?, pixels("height")?))
+}
+
+/// Dimensions from the image header, without decoding the pixels.
+fn file_size(epub: &Epub, resolved: &str) -> Option<(u32, u32)> {
+    let bytes = epub.read_resource_bytes(resolved).ok()?;
+    image::ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+fn attr(node: &NodeRef, name: &str) -> Option<String> {
+    node.as_element()
+        .and_then(|el| el.attributes.borrow().get(name).map(str::to_string))
+}
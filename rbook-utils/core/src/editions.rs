@@ -0,0 +1,345 @@
+use serde_json::json;
+use similar::TextDiff;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::anthology::{chapter_body, read_manifest_sections};
+use crate::{
+    BookConversionResult, ChapterNav, ConvertError, ConvertOptions, CoverReference, ExportMode,
+    Result, convert_one, count_words, escape_link_text, normalize_space, text_output,
+};
+
+/// Pairs scoring below this are left unaligned rather than diffed.
+const MIN_PAIR_SCORE: f32 = 0.3;
+/// Word diffs of very long or very different chapters are cut off after
+/// this; the similarity is then an estimate.
+const DIFF_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Two editions (or translations) of a work with their chapters aligned.
+#[derive(Clone, Debug)]
+pub struct EditionComparison {
+    pub left: BookConversionResult,
+    pub right: BookConversionResult,
+    /// In reading order; unaligned chapters appear where they fall.
+    pub chapters: Vec<ChapterComparison>,
+    /// Holds `comparison.json`, `comparison.md` and `diffs/`.
+    pub output_dir: PathBuf,
+}
+
+#[derive(Clone, Debug)]
+pub struct ChapterComparison {
+    pub left: Option<EditionChapter>,
+    pub right: Option<EditionChapter>,
+    /// Character similarity of the titles, 0 to 1; 0 when unaligned.
+    pub title_similarity: f32,
+    /// Word similarity of the texts, 0 to 1; 0 when unaligned.
+    pub text_similarity: f32,
+    /// Paragraph-level unified diff, relative to the output directory; `None`
+    /// when unaligned or identical.
+    pub diff_path: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug)]
+pub struct EditionChapter {
+    /// 0-based position among the book's sections.
+    pub index: usize,
+    pub section_id: String,
+    pub title: String,
+    pub words: usize,
+}
+
+impl EditionComparison {
+    pub fn to_json(&self) -> serde_json::Value {
+        let chapter = |chapter: &Option<EditionChapter>| {
+            chapter.as_ref().map(|chapter| {
+                json!({
+                    "index": chapter.index,
+                    "section_id": chapter.section_id,
+                    "title": chapter.title,
+                    "words": chapter.words,
+                })
+            })
+        };
+        json!({
+            "left": {
+                "input": self.left.input_path.display().to_string(),
+                "title": self.left.title,
+            },
+            "right": {
+                "input": self.right.input_path.display().to_string(),
+                "title": self.right.title,
+            },
+            "chapters": self.chapters.iter().map(|pair| json!({
+                "left": chapter(&pair.left),
+                "right": chapter(&pair.right),
+                "title_similarity": pair.title_similarity,
+                "text_similarity": pair.text_similarity,
+                "diff": pair.diff_path.as_ref().map(|path| path.display().to_string()),
+            })).collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// A converted section with its text.
+struct Chapter {
+    info: EditionChapter,
+    text: String,
+    words: HashSet<String>,
+}
+
+/// Converts both books (as split chapters under `editions/left` and
+/// `editions/right` in the output directory), aligns their chapters in order
+/// by title and vocabulary overlap, and writes a word-similarity score and a
+/// paragraph diff for every aligned pair.
+pub fn compare_editions(
+    left: &Path,
+    right: &Path,
+    options: &ConvertOptions,
+) -> Result<EditionComparison> {
+    let root = options.output_dir.clone();
+    let (left, left_chapters) = convert_edition(left, 0, &root.join("editions/left"), options)?;
+    let (right, right_chapters) = convert_edition(right, 1, &root.join("editions/right"), options)?;
+
+    let diffs_dir = root.join("diffs");
+    if diffs_dir.exists() {
+        fs::remove_dir_all(&diffs_dir)
+            .map_err(|err| ConvertError::write_failed(&diffs_dir, err))?;
+    }
+    let writer = text_output::TextWriter::new(options);
+    let mut chapters = Vec::new();
+    for (left_idx, right_idx) in align(&left_chapters, &right_chapters) {
+        let (Some(a), Some(b)) = (
+            left_idx.map(|idx| &left_chapters[idx]),
+            right_idx.map(|idx| &right_chapters[idx]),
+        ) else {
+            chapters.push(ChapterComparison {
+                left: left_idx.map(|idx| left_chapters[idx].info.clone()),
+                right: right_idx.map(|idx| right_chapters[idx].info.clone()),
+                title_similarity: 0.0,
+                text_similarity: 0.0,
+                diff_path: None,
+            });
+            continue;
+        };
+        let text_similarity = TextDiff::configure()
+            .timeout(DIFF_TIMEOUT)
+            .diff_words(&a.text, &b.text)
+            .ratio();
+        let left_lines = paragraph_lines(&a.text);
+        let right_lines = paragraph_lines(&b.text);
+        let diff = TextDiff::configure()
+            .timeout(DIFF_TIMEOUT)
+            .diff_lines(&left_lines, &right_lines);
+        let diff_path = if left_lines == right_lines {
+            None
+        } else {
+            let relative = PathBuf::from("diffs").join(format!(
+                "{:03}-{}.diff",
+                chapters.len() + 1,
+                a.info.section_id
+            ));
+            let unified = diff
+                .unified_diff()
+                .context_radius(1)
+                .header(
+                    &format!("{} / {}", left.title, a.info.title),
+                    &format!("{} / {}", right.title, b.info.title),
+                )
+                .to_string();
+            fs::create_dir_all(&diffs_dir)
+                .map_err(|err| ConvertError::write_failed(&diffs_dir, err))?;
+            writer.write_utf8(&root.join(&relative), &unified)?;
+            Some(relative)
+        };
+        chapters.push(ChapterComparison {
+            left: Some(a.info.clone()),
+            right: Some(b.info.clone()),
+            title_similarity: title_similarity(&a.info.title, &b.info.title),
+            text_similarity,
+            diff_path,
+        });
+    }
+
+    let comparison = EditionComparison {
+        left,
+        right,
+        chapters,
+        output_dir: root.clone(),
+    };
+    writer.write_utf8(
+        &root.join("comparison.json"),
+        &(serde_json::to_string_pretty(&comparison.to_json())? + "\n"),
+    )?;
+    writer.write(&root.join("comparison.md"), &summary_markdown(&comparison))?;
+    Ok(comparison)
+}
+
+fn convert_edition(
+    epub_path: &Path,
+    index: usize,
+    output_dir: &Path,
+    options: &ConvertOptions,
+) -> Result<(BookConversionResult, Vec<Chapter>)> {
+    let mut book_options = options.clone();
+    book_options.output_dir = output_dir.to_path_buf();
+    book_options.split_chapters = true;
+    book_options.export_manifest = ExportMode::V1;
+    book_options.chapter_nav = ChapterNav::Off;
+    book_options.cover_reference = CoverReference::Off;
+    book_options.preview = false;
+    book_options.plan = None;
+    book_options.only_public_domain = false;
+
+    let result = convert_one(epub_path, index, 2, &book_options);
+    let Some(book_dir) = &result.output_path else {
+        let reason = result
+            .diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.message.as_str())
+            .collect::<Vec<_>>()
+            .join("; ");
+        return Err(ConvertError::BookFailed {
+            path: epub_path.to_path_buf(),
+            reason,
+        });
+    };
+    let sections = read_manifest_sections(book_dir)?;
+    let mut chapters = Vec::new();
+    for (idx, section) in sections.into_iter().enumerate() {
+        let source = output_dir.join(&section.output_path);
+        let text = text_output::read_text(
+            &*options.output_sink,
+            &source,
+            options.output_encoding.as_deref(),
+        )
+        .map(|text| chapter_body(&text, &section.section_id))
+        .unwrap_or_default();
+        chapters.push(Chapter {
+            info: EditionChapter {
+                index: idx,
+                section_id: section.section_id,
+                title: section.title,
+                words: count_words(&text),
+            },
+            words: vocabulary(&text),
+            text,
+        });
+    }
+    Ok((result, chapters))
+}
+
+/// Order-preserving alignment maximizing the summed pair scores; pairs below
+/// [`MIN_PAIR_SCORE`] are never matched. Returns `(left, right)` index pairs
+/// in reading order, with `None` on the side a chapter is missing from.
+fn align(left: &[Chapter], right: &[Chapter]) -> Vec<(Option<usize>, Option<usize>)> {
+    let (n, m) = (left.len(), right.len());
+    let score = |i: usize, j: usize| {
+        let position = 1.0 - ((i as f32 + 0.5) / n as f32 - (j as f32 + 0.5) / m as f32).abs();
+        0.4 * title_similarity(&left[i].info.title, &right[j].info.title)
+            + 0.4 * jaccard(&left[i].words, &right[j].words)
+            + 0.2 * position
+    };
+    // best[i][j]: best total over left[i..] and right[j..].
+    let mut best = vec![vec![0f32; m + 1]; n + 1];
+    let mut pair = vec![vec![0f32; m]; n];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            pair[i][j] = score(i, j);
+            let matched = if pair[i][j] >= MIN_PAIR_SCORE {
+                pair[i][j] + best[i + 1][j + 1]
+            } else {
+                0.0
+            };
+            best[i][j] = matched.max(best[i + 1][j]).max(best[i][j + 1]);
+        }
+    }
+    let mut aligned = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if pair[i][j] >= MIN_PAIR_SCORE && best[i][j] == pair[i][j] + best[i + 1][j + 1] {
+            aligned.push((Some(i), Some(j)));
+            i += 1;
+            j += 1;
+        } else if best[i][j] == best[i + 1][j] {
+            aligned.push((Some(i), None));
+            i += 1;
+        } else {
+            aligned.push((None, Some(j)));
+            j += 1;
+        }
+    }
+    aligned.extend((i..n).map(|i| (Some(i), None)));
+    aligned.extend((j..m).map(|j| (None, Some(j))));
+    aligned
+}
+
+fn title_similarity(a: &str, b: &str) -> f32 {
+    let a = normalize_space(a).to_lowercase();
+    let b = normalize_space(b).to_lowercase();
+    TextDiff::from_chars(a.as_str(), b.as_str()).ratio()
+}
+
+fn vocabulary(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+fn jaccard(a: &HashSet<String>, b: &HashSet<String>) -> f32 {
+    let union = a.union(b).count();
+    if union == 0 {
+        return 0.0;
+    }
+    a.intersection(b).count() as f32 / union as f32
+}
+
+/// One line per paragraph with whitespace collapsed, so rewrapped text does
+/// not show up as changed.
+fn paragraph_lines(text: &str) -> String {
+    text.split("\n\n")
+        .map(normalize_space)
+        .filter(|paragraph| !paragraph.is_empty())
+        .map(|paragraph| paragraph + "\n")
+        .collect()
+}
+
+fn summary_markdown(comparison: &EditionComparison) -> String {
+    let mut lines = vec![
+        format!("# {} / {}", comparison.left.title, comparison.right.title),
+        String::new(),
+        "| # | Left | Right | Title | Text | Diff |".to_string(),
+        "|---|---|---|---|---|---|".to_string(),
+    ];
+    let title = |chapter: &Option<EditionChapter>| {
+        chapter.as_ref().map_or("—".to_string(), |chapter| {
+            escape_link_text(&chapter.title).replace('|', "\\|")
+        })
+    };
+    for (idx, pair) in comparison.chapters.iter().enumerate() {
+        let aligned = pair.left.is_some() && pair.right.is_some();
+        let percent = |value: f32| {
+            if aligned {
+                format!("{:.0}%", value * 100.0)
+            } else {
+                "—".to_string()
+            }
+        };
+        let diff = match &pair.diff_path {
+            Some(path) => format!("[diff](./{})", path.display()),
+            None if aligned => "identical".to_string(),
+            None => String::new(),
+        };
+        lines.push(format!(
+            "| {} | {} | {} | {} | {} | {diff} |",
+            idx + 1,
+            title(&pair.left),
+            title(&pair.right),
+            percent(pair.title_similarity),
+            percent(pair.text_similarity),
+        ));
+    }
+    lines.join("\n") + "\n"
+}
@@ -0,0 +1,150 @@
+use std::error::Error as StdError;
+use std::path::{Path, PathBuf};
+
+/// Error of a lower layer (the EPUB reader, image codecs) kept as the cause.
+pub type BoxError = Box<dyn StdError + Send + Sync>;
+
+pub type Result<T, E = ConvertError> = std::result::Result<T, E>;
+
+/// Why a library operation failed. Per-book failures inside a batch are
+/// reported as diagnostics instead; these are what stops a single book or the
+/// whole run. New variants may be added, so matches need a wildcard arm.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum ConvertError {
+    #[error("No EPUB files found under {}", dir.display())]
+    NoInput { dir: PathBuf },
+    #[error("Failed to open epub {}", path.display())]
+    OpenFailed {
+        path: PathBuf,
+        #[source]
+        source: BoxError,
+    },
+    /// A resource of the book (content document, cover) could not be read.
+    #[error("Failed to read {href}")]
+    ResourceRead {
+        href: String,
+        #[source]
+        source: BoxError,
+    },
+    #[error("No readable sections found in {}", path.display())]
+    NoReadableSections { path: PathBuf },
+    #[error("The plan leaves no sections of {}", path.display())]
+    PlanLeavesNoSections { path: PathBuf },
+    #[error("No body-matter chapter found in {}", path.display())]
+    NoPreviewChapter { path: PathBuf },
+    /// A filter left the book out.
+    #[error("Skipped {}: {reason}", path.display())]
+    Skipped { path: PathBuf, reason: String },
+    /// A book another operation depends on could not be converted.
+    #[error("Cannot convert {}: {reason}", path.display())]
+    BookFailed { path: PathBuf, reason: String },
+    #[error("Invalid plan {}: {reason}", path.display())]
+    InvalidPlan { path: PathBuf, reason: String },
+    #[error("Invalid cleanup profiles {}: {reason}", path.display())]
+    InvalidCleanupProfiles { path: PathBuf, reason: String },
+    #[error("Invalid search pattern")]
+    InvalidPattern(#[from] regex::Error),
+    #[error("{0}")]
+    UnsupportedEncoding(String),
+    /// An option the called operation cannot honor.
+    #[error("{option} is not supported by {operation}")]
+    UnsupportedOption {
+        option: &'static str,
+        operation: &'static str,
+    },
+    #[error(
+        "{} is locked by {owner}; remove {} if that run is no longer active",
+        dir.display(),
+        lock.display()
+    )]
+    Locked {
+        dir: PathBuf,
+        owner: String,
+        lock: PathBuf,
+    },
+    /// A [`Storage`](crate::Storage) backend rejected a file.
+    #[error("Failed to store {key}")]
+    StoreFailed {
+        key: String,
+        #[source]
+        source: BoxError,
+    },
+    #[error("Invalid storage URL {url}: {reason}")]
+    InvalidStorageUrl { url: String, reason: String },
+    #[error("Failed to write {}", path.display())]
+    WriteFailed {
+        path: PathBuf,
+        #[source]
+        source: BoxError,
+    },
+    #[error("Failed to watch {}", dir.display())]
+    WatchFailed {
+        dir: PathBuf,
+        #[source]
+        source: BoxError,
+    },
+    /// The options' [`CancelToken`](crate::CancelToken) was triggered.
+    #[error("Conversion cancelled")]
+    Cancelled,
+    /// The HTML/markdown stack panicked on a malformed book.
+    #[error("panicked: {0}")]
+    Panicked(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Toml(#[from] toml::ser::Error),
+}
+
+impl ConvertError {
+    /// An I/O failure writing `path`, or the directory it goes in.
+    pub(crate) fn write_failed(path: &Path, err: std::io::Error) -> Self {
+        ConvertError::WriteFailed {
+            path: path.to_path_buf(),
+            source: err.into(),
+        }
+    }
+
+    /// A stable snake_case name for the variant, for grouping failures.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ConvertError::NoInput { .. } => "no_input",
+            ConvertError::OpenFailed { .. } => "open_failed",
+            ConvertError::ResourceRead { .. } => "resource_read",
+            ConvertError::NoReadableSections { .. } => "no_readable_sections",
+            ConvertError::PlanLeavesNoSections { .. } => "plan_leaves_no_sections",
+            ConvertError::NoPreviewChapter { .. } => "no_preview_chapter",
+            ConvertError::Skipped { .. } => "skipped",
+            ConvertError::BookFailed { .. } => "book_failed",
+            ConvertError::InvalidPlan { .. } => "invalid_plan",
+            ConvertError::InvalidCleanupProfiles { .. } => "invalid_cleanup_profiles",
+            ConvertError::InvalidPattern(_) => "invalid_pattern",
+            ConvertError::UnsupportedEncoding(_) => "unsupported_encoding",
+            ConvertError::UnsupportedOption { .. } => "unsupported_option",
+            ConvertError::Locked { .. } => "locked",
+            ConvertError::StoreFailed { .. } => "store_failed",
+            ConvertError::InvalidStorageUrl { .. } => "invalid_storage_url",
+            ConvertError::WriteFailed { .. } => "write_failed",
+            ConvertError::WatchFailed { .. } => "watch_failed",
+            ConvertError::Cancelled => "cancelled",
+            ConvertError::Panicked(_) => "panicked",
+            ConvertError::Io(_) => "io",
+            ConvertError::Json(_) => "json",
+            ConvertError::Toml(_) => "toml",
+        }
+    }
+
+    /// The message followed by its causes, for one-line diagnostics.
+    pub fn full_message(&self) -> String {
+        let mut message = self.to_string();
+        let mut source = self.source();
+        while let Some(cause) = source {
+            message.push_str(": ");
+            message.push_str(&cause.to_string());
+            source = cause.source();
+        }
+        message
+    }
+}
@@ -0,0 +1,103 @@
+use serde_json::{Value, json};
+use std::ffi::{CStr, CString, c_char};
+use std::path::{Path, PathBuf};
+
+use crate::json_api::{apply_json_options, diagnostic_json};
+use crate::{ConvertOptions, convert_epub_result};
+
+/// Converts the EPUB at `path` and returns a JSON report.
+///
+/// `options_json` is NULL or a JSON object with the output directory as
+/// `output_dir` (default: the current directory) and conversion options by
+/// their Rust field names, as for the browser bindings. Both strings are
+/// UTF-8 and stay owned by the caller.
+///
+/// The report is `{"ok": true, ...}` with the output paths, counts and
+/// diagnostics, or `{"ok": false, "error": "..."}`. It is never NULL and is
+/// owned by the caller, who must release it with [`rbook_utils_free_string`]
+/// (not `free`).
+///
+/// Build the shared library with `cargo rustc -p rbook-utils-core --lib
+/// --crate-type cdylib --release --features cdylib`.
+///
+/// # Safety
+///
+/// `path` must be a valid NUL-terminated string, and `options_json` NULL or
+/// one.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rbook_utils_convert(
+    path: *const c_char,
+    options_json: *const c_char,
+) -> *mut c_char {
+    // SAFETY: the caller guarantees both pointers as documented above.
+    let path = unsafe { c_str(path) };
+    let options_json = unsafe { c_str(options_json) };
+    let report = std::panic::catch_unwind(|| convert(path, options_json))
+        .unwrap_or_else(|_| Err("conversion panicked".to_string()))
+        .unwrap_or_else(|error| json!({ "ok": false, "error": error }));
+    // serde_json escapes control characters, so the report has no NUL.
+    CString::new(report.to_string())
+        .expect("JSON has no NUL bytes")
+        .into_raw()
+}
+
+/// Releases a string returned by [`rbook_utils_convert`]. NULL is ignored.
+///
+/// # Safety
+///
+/// `report` must be NULL or a pointer returned by this library and not yet
+/// released.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rbook_utils_free_string(report: *mut c_char) {
+    if !report.is_null() {
+        // SAFETY: the pointer came from `CString::into_raw` in this library.
+        drop(unsafe { CString::from_raw(report) });
+    }
+}
+
+/// # Safety
+///
+/// `ptr` must be NULL or a valid NUL-terminated string.
+unsafe fn c_str<'a>(ptr: *const c_char) -> Option<&'a [u8]> {
+    // SAFETY: guaranteed by the caller.
+    (!ptr.is_null()).then(|| unsafe { CStr::from_ptr(ptr) }.to_bytes())
+}
+
+fn convert(path: Option<&[u8]>, options_json: Option<&[u8]>) -> Result<Value, String> {
+    let path = path.ok_or("path is NULL")?;
+    let path = std::str::from_utf8(path).map_err(|_| "path is not UTF-8")?;
+    let mut options_value = match options_json {
+        Some(json) => serde_json::from_slice(json).map_err(|err| format!("options: {err}"))?,
+        None => json!({}),
+    };
+    let output_dir = match options_value
+        .as_object_mut()
+        .and_then(|fields| fields.remove("output_dir"))
+    {
+        Some(Value::String(dir)) => PathBuf::from(dir),
+        Some(other) => return Err(format!("invalid value {other} for output_dir")),
+        None => PathBuf::from("."),
+    };
+    let epub_path = Path::new(path);
+    let input_dir = epub_path.parent().unwrap_or(Path::new("")).to_path_buf();
+    let mut options = ConvertOptions::new(input_dir, output_dir);
+    apply_json_options(&mut options, &options_value)?;
+
+    let result = convert_epub_result(epub_path, &options).map_err(|err| err.full_message())?;
+    if let Some(reason) = result.skipped {
+        return Err(format!("skipped: {reason}"));
+    }
+    let report = &result.report;
+    Ok(json!({
+        "ok": true,
+        "title": result.title,
+        "output_path": result.output_path,
+        "output_paths": report.output_paths,
+        "section_count": report.section_count,
+        "word_count": report.word_count,
+        "images_extracted": report.images_extracted,
+        "media_extracted": report.media_extracted,
+        "heading_fallback_used": report.heading_fallback_used,
+        "diagnostics": result.diagnostics.iter().map(diagnostic_json).collect::<Vec<_>>(),
+    }))
+}
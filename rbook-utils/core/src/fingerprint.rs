@@ -0,0 +1,229 @@
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+use crate::BookConversionResult;
+
+/// Words per shingle.
+const SHINGLE_WORDS: usize = 5;
+/// MinHash signature length; the estimate's standard error is about
+/// `1 / sqrt(MINHASH_LEN)`.
+pub const MINHASH_LEN: usize = 64;
+/// Locality-sensitive hashing splits the signature into bands of this many
+/// values; two texts become candidates when any band matches exactly.
+const BAND_ROWS: usize = 4;
+/// Texts shorter than this (title pages, dedications) match each other too
+/// readily to be worth reporting.
+const MIN_SHINGLES: usize = 20;
+
+/// Content fingerprint of a section or book: a MinHash signature estimating
+/// the Jaccard similarity of word 5-gram sets, and a 64-bit simhash for
+/// Hamming-distance lookups. Stable across runs and platforms, so fingerprints
+/// from different batches can be compared.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub simhash: u64,
+    pub minhash: Vec<u32>,
+    /// Distinct shingles hashed; 0 for a text without words.
+    pub shingles: usize,
+}
+
+impl Fingerprint {
+    pub fn of_text(text: &str) -> Self {
+        Self::of_texts([text])
+    }
+
+    /// One fingerprint over several texts; shingles do not span texts.
+    pub fn of_texts<'a>(texts: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut hashes = HashSet::new();
+        for text in texts {
+            let words: Vec<String> = text
+                .split(|c: char| !c.is_alphanumeric())
+                .filter(|word| !word.is_empty())
+                .map(str::to_lowercase)
+                .collect();
+            if words.is_empty() {
+                continue;
+            }
+            for shingle in words.windows(SHINGLE_WORDS.min(words.len())) {
+                hashes.insert(fnv1a(&shingle.join(" ")));
+            }
+        }
+
+        let mut minhash = vec![u32::MAX; MINHASH_LEN];
+        let mut weights = [0i64; 64];
+        for &hash in &hashes {
+            for (seed, slot) in minhash.iter_mut().enumerate() {
+                let value = (mix(hash ^ mix(seed as u64 + 1)) >> 32) as u32;
+                *slot = (*slot).min(value);
+            }
+            let hash = mix(hash);
+            for (bit, weight) in weights.iter_mut().enumerate() {
+                *weight += if hash >> bit & 1 == 1 { 1 } else { -1 };
+            }
+        }
+        let simhash = weights
+            .iter()
+            .enumerate()
+            .filter(|(_, weight)| **weight > 0)
+            .fold(0u64, |acc, (bit, _)| acc | 1 << bit);
+        Self {
+            simhash,
+            minhash,
+            shingles: hashes.len(),
+        }
+    }
+
+    /// Estimated Jaccard similarity of the shingle sets, 0 to 1.
+    pub fn similarity(&self, other: &Self) -> f32 {
+        if self.shingles == 0 || other.shingles == 0 {
+            return 0.0;
+        }
+        let equal = self
+            .minhash
+            .iter()
+            .zip(&other.minhash)
+            .filter(|(a, b)| a == b)
+            .count();
+        equal as f32 / self.minhash.len().max(1) as f32
+    }
+
+    /// Differing simhash bits; near-duplicates differ in few.
+    pub fn simhash_distance(&self, other: &Self) -> u32 {
+        (self.simhash ^ other.simhash).count_ones()
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "simhash": format!("{:016x}", self.simhash),
+            "minhash": self.minhash,
+            "shingles": self.shingles,
+        })
+    }
+}
+
+/// A book or one of its sections.
+#[derive(Clone, Debug)]
+pub struct DuplicateSide {
+    pub input_path: PathBuf,
+    /// `None` for the whole book.
+    pub section_id: Option<String>,
+    /// Book title, or section title.
+    pub title: String,
+}
+
+/// Two books, or two sections, of a batch with similar content.
+#[derive(Clone, Debug)]
+pub struct NearDuplicate {
+    pub left: DuplicateSide,
+    pub right: DuplicateSide,
+    /// Estimated Jaccard similarity of the texts, 0 to 1.
+    pub similarity: f32,
+    pub simhash_distance: u32,
+}
+
+impl NearDuplicate {
+    pub fn to_json(&self) -> serde_json::Value {
+        let side = |side: &DuplicateSide| {
+            json!({
+                "input": side.input_path.display().to_string(),
+                "section_id": side.section_id,
+                "title": side.title,
+            })
+        };
+        json!({
+            "left": side(&self.left),
+            "right": side(&self.right),
+            "similarity": self.similarity,
+            "simhash_distance": self.simhash_distance,
+        })
+    }
+}
+
+/// Book pairs, then section pairs (also within one book), whose estimated
+/// similarity is at least `threshold`, most similar first. Needs books
+/// converted with `ConvertOptions::fingerprints`; candidates are found by
+/// banding the MinHash signatures, so the batch is never compared pairwise.
+pub fn find_near_duplicates(books: &[BookConversionResult], threshold: f32) -> Vec<NearDuplicate> {
+    let mut book_items = Vec::new();
+    let mut section_items = Vec::new();
+    for book in books {
+        if let Some(fingerprint) = &book.report.fingerprint {
+            book_items.push((
+                DuplicateSide {
+                    input_path: book.input_path.clone(),
+                    section_id: None,
+                    title: book.title.clone(),
+                },
+                fingerprint,
+            ));
+        }
+        for (section, fingerprint) in book.sections.iter().zip(&book.report.section_fingerprints) {
+            section_items.push((
+                DuplicateSide {
+                    input_path: book.input_path.clone(),
+                    section_id: Some(section.id.clone()),
+                    title: section.title.clone(),
+                },
+                fingerprint,
+            ));
+        }
+    }
+    let mut matches = Vec::new();
+    for items in [book_items, section_items] {
+        let mut found = candidate_pairs(&items)
+            .into_iter()
+            .filter_map(|(a, b)| {
+                let (left, left_fingerprint) = &items[a];
+                let (right, right_fingerprint) = &items[b];
+                let similarity = left_fingerprint.similarity(right_fingerprint);
+                (similarity >= threshold).then(|| NearDuplicate {
+                    left: left.clone(),
+                    right: right.clone(),
+                    similarity,
+                    simhash_distance: left_fingerprint.simhash_distance(right_fingerprint),
+                })
+            })
+            .collect::<Vec<_>>();
+        found.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+        matches.extend(found);
+    }
+    matches
+}
+
+/// Index pairs `(a, b)` with `a < b` sharing at least one signature band.
+fn candidate_pairs(items: &[(DuplicateSide, &Fingerprint)]) -> Vec<(usize, usize)> {
+    let mut buckets: HashMap<(usize, &[u32]), Vec<usize>> = HashMap::new();
+    for (idx, (_, fingerprint)) in items.iter().enumerate() {
+        if fingerprint.shingles < MIN_SHINGLES {
+            continue;
+        }
+        for (band, rows) in fingerprint.minhash.chunks(BAND_ROWS).enumerate() {
+            buckets.entry((band, rows)).or_default().push(idx);
+        }
+    }
+    let mut pairs = HashSet::new();
+    for members in buckets.values() {
+        for (pos, &a) in members.iter().enumerate() {
+            for &b in &members[pos + 1..] {
+                pairs.insert((a.min(b), a.max(b)));
+            }
+        }
+    }
+    let mut pairs: Vec<_> = pairs.into_iter().collect();
+    pairs.sort_unstable();
+    pairs
+}
+
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// splitmix64 finalizer.
+fn mix(mut value: u64) -> u64 {
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    value ^ (value >> 31)
+}
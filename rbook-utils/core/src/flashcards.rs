@@ -0,0 +1,262 @@
+use kuchiki::NodeRef;
+use rbook::Epub;
+use serde::Deserialize;
+use std::path::Path;
+
+use crate::content_cache::ContentCache;
+use crate::markdown::has_semantic;
+use crate::{
+    ConvertError, ConvertOptions, Result, SectionRecord, element_name, load_content,
+    normalize_space, partial_body_nodes, text_output,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum FlashcardExport {
+    Off,
+    /// `flashcards.csv` with term, definition and section columns.
+    Csv,
+    /// `flashcards.txt` in Anki's text import format, one Basic note per
+    /// term, tagged with its section and filed under a deck named after the
+    /// book.
+    Anki,
+}
+
+/// Section titles that mark a glossary or vocabulary list in books that do
+/// not say so with `epub:type`.
+const GLOSSARY_TITLES: &[&str] = &[
+    "glossary",
+    "vocabulary",
+    "word list",
+    "wordlist",
+    "key terms",
+    "lexicon",
+    "terminology",
+];
+
+/// What separates a bold term from its definition in `<p><b>term</b>: …`.
+const TERM_SEPARATORS: &[&str] = &[":", "—", "–", "-", "="];
+
+struct Card {
+    term: String,
+    definition: String,
+    section_id: String,
+}
+
+/// Writes the term/definition pairs of the book's glossaries and vocabulary
+/// lists as a flashcard deck.
+///
+/// Definition lists count in sections marked `glossary` (by `epub:type`,
+/// role or class) or titled like one, and anywhere else when the list itself
+/// is marked. In glossary sections, two-column table rows and paragraphs that
+/// open with a bold term and a separator count too. No file is written for a
+/// book without any pairs.
+pub(crate) fn write_flashcard_export(
+    epub: &Epub,
+    book_dir: &Path,
+    title: &str,
+    sections: &[SectionRecord],
+    spine_hrefs: &[String],
+    cache: &mut ContentCache,
+    options: &ConvertOptions,
+) -> Result<()> {
+    let format = options.flashcard_export;
+    if format == FlashcardExport::Off {
+        return Ok(());
+    }
+    let mut cards = Vec::new();
+    for section in sections {
+        let glossary_title = is_glossary_title(&section.title);
+        for spine_idx in section.spine_start..=section.spine_end {
+            let Some(href) = spine_hrefs.get(spine_idx) else {
+                continue;
+            };
+            let start = (spine_idx == section.spine_start)
+                .then_some(section.start_fragment.as_deref())
+                .flatten();
+            let end = (section.end_href.as_deref() == Some(href.as_str()))
+                .then_some(section.end_fragment.as_deref())
+                .flatten();
+            let Ok(content) = load_content(epub, href, cache) else {
+                continue;
+            };
+            let Some(nodes) = partial_body_nodes(content, start, end) else {
+                continue;
+            };
+            for node in nodes.iter().flat_map(|node| node.inclusive_descendants()) {
+                let pairs = match element_name(&node) {
+                    Some("dl") if glossary_title || in_glossary_element(&node) => {
+                        definition_list_pairs(&node)
+                    }
+                    Some("tr") if glossary_title || in_glossary_element(&node) => {
+                        table_row_pair(&node).into_iter().collect()
+                    }
+                    Some("p") if glossary_title || in_glossary_element(&node) => {
+                        bold_term_pair(&node).into_iter().collect()
+                    }
+                    _ => continue,
+                };
+                cards.extend(pairs.into_iter().map(|(term, definition)| Card {
+                    term,
+                    definition,
+                    section_id: section.section_id.clone(),
+                }));
+            }
+        }
+    }
+    if cards.is_empty() {
+        return Ok(());
+    }
+
+    let (file_name, document) = match format {
+        FlashcardExport::Anki => ("flashcards.txt", anki_text(title, &cards)),
+        _ => ("flashcards.csv", csv(&cards)),
+    };
+    options
+        .output_sink
+        .create_dir_all(book_dir)
+        .map_err(|err| ConvertError::write_failed(book_dir, err))?;
+    text_output::TextWriter::new(options).write_utf8(&book_dir.join(file_name), &document)?;
+    Ok(())
+}
+
+fn is_glossary_title(title: &str) -> bool {
+    let title = title.to_lowercase();
+    GLOSSARY_TITLES.iter().any(|word| title.contains(word))
+}
+
+/// Whether `node` or an ancestor within the body is marked as a glossary.
+fn in_glossary_element(node: &NodeRef) -> bool {
+    node.inclusive_ancestors()
+        .take_while(|ancestor| !matches!(element_name(ancestor), Some("body" | "html") | None))
+        .any(|ancestor| has_semantic(&ancestor, &["glossary"]))
+}
+
+/// One pair per term of a `<dl>`. Consecutive `<dt>`s share the definitions
+/// that follow them, and several `<dd>`s for one term are joined. `<div>`
+/// wrappers around groups, which HTML allows, are looked through.
+fn definition_list_pairs(dl: &NodeRef) -> Vec<(String, String)> {
+    let mut pairs = Vec::new();
+    let mut terms: Vec<String> = Vec::new();
+    let mut definitions: Vec<String> = Vec::new();
+    let mut flush = |terms: &mut Vec<String>, definitions: &mut Vec<String>| {
+        if !definitions.is_empty() {
+            let definition = definitions.join("; ");
+            pairs.extend(terms.drain(..).map(|term| (term, definition.clone())));
+        }
+        terms.clear();
+        definitions.clear();
+    };
+    let items = dl.children().flat_map(|child| match element_name(&child) {
+        Some("div") => child.children().collect::<Vec<_>>(),
+        _ => vec![child],
+    });
+    for item in items {
+        let text = normalize_space(&item.text_contents());
+        match element_name(&item) {
+            Some("dt") => {
+                if !definitions.is_empty() {
+                    flush(&mut terms, &mut definitions);
+                }
+                if !text.is_empty() {
+                    terms.push(text);
+                }
+            }
+            Some("dd") if !terms.is_empty() && !text.is_empty() => definitions.push(text),
+            _ => {}
+        }
+    }
+    flush(&mut terms, &mut definitions);
+    pairs
+}
+
+/// A table row of exactly two data cells; header rows are skipped.
+fn table_row_pair(tr: &NodeRef) -> Option<(String, String)> {
+    let cells: Vec<NodeRef> = tr
+        .children()
+        .filter(|cell| matches!(element_name(cell), Some("td" | "th")))
+        .collect();
+    if cells.len() != 2 || cells.iter().all(|cell| element_name(cell) == Some("th")) {
+        return None;
+    }
+    let term = normalize_space(&cells[0].text_contents());
+    let definition = normalize_space(&cells[1].text_contents());
+    (!term.is_empty() && !definition.is_empty()).then_some((term, definition))
+}
+
+/// `<p><b>term</b>: definition</p>`, also with `<strong>` or `<dfn>`, the
+/// colon inside the bold element, or a dash or equals sign for the colon.
+/// Paragraphs inside definition lists are left to the list.
+fn bold_term_pair(p: &NodeRef) -> Option<(String, String)> {
+    if p.ancestors()
+        .any(|ancestor| element_name(&ancestor) == Some("dl"))
+    {
+        return None;
+    }
+    let first = p
+        .children()
+        .find(|child| child.as_element().is_some() || !child.text_contents().trim().is_empty())?;
+    if !matches!(element_name(&first), Some("b" | "strong" | "dfn")) {
+        return None;
+    }
+    let bold = normalize_space(&first.text_contents());
+    let rest: String = first
+        .following_siblings()
+        .map(|sibling| sibling.text_contents())
+        .collect();
+    let rest = normalize_space(&rest);
+    let definition = if bold.ends_with(':') {
+        rest.as_str()
+    } else {
+        TERM_SEPARATORS
+            .iter()
+            .find_map(|separator| rest.strip_prefix(separator))?
+    };
+    let term = bold.trim_end_matches(':').trim_end();
+    let definition = definition.trim();
+    (!term.is_empty() && !definition.is_empty()).then(|| (term.to_string(), definition.to_string()))
+}
+
+fn csv(cards: &[Card]) -> String {
+    let mut out = String::from("term,definition,section\n");
+    for card in cards {
+        out.push_str(&format!(
+            "{},{},{}\n",
+            csv_field(&card.term),
+            csv_field(&card.definition),
+            csv_field(&card.section_id)
+        ));
+    }
+    out
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Anki's plain-text import: header lines set the separator, note type, deck
+/// and tag column, then one tab-separated note per line.
+fn anki_text(title: &str, cards: &[Card]) -> String {
+    let mut out = format!(
+        "#separator:tab\n#html:false\n#notetype:Basic\n#deck:{}\n#tags column:3\n",
+        anki_field(title)
+    );
+    for card in cards {
+        out.push_str(&format!(
+            "{}\t{}\t{}\n",
+            anki_field(&card.term),
+            anki_field(&card.definition),
+            anki_field(&card.section_id).replace(' ', "_")
+        ));
+    }
+    out
+}
+
+fn anki_field(value: &str) -> String {
+    value.replace(['\t', '\n', '\r'], " ")
+}
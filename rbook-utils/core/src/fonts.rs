@@ -0,0 +1,138 @@
+use once_cell::sync::Lazy;
+use rbook::Epub;
+use rbook::ebook::manifest::Manifest;
+use rbook::prelude::ManifestEntry;
+use regex::Regex;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::output::OutputSink;
+use crate::{decode_path, is_external, relative_dir, resolve_href};
+
+const FONT_EXTENSIONS: &[&str] = &["otf", "ttf", "woff", "woff2"];
+
+static CSS_URL_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"url\(\s*['"]?([^'")]+)['"]?\s*\)"#).expect("valid css url regex"));
+
+/// Fonts copied out of the book, as paths relative to the fonts directory.
+#[derive(Default)]
+pub(crate) struct FontFiles {
+    by_href: HashMap<String, String>,
+    /// File name to path; `None` when two fonts share a name.
+    by_name: HashMap<String, Option<String>>,
+}
+
+impl FontFiles {
+    pub(crate) fn len(&self) -> usize {
+        self.by_href.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.by_href.is_empty()
+    }
+
+    /// The copied font a CSS `url()` points at. Without a base (styles lifted
+    /// out of `<style>` elements) the reference is matched by file name.
+    fn lookup(&self, reference: &str, base_href: Option<&str>) -> Option<&str> {
+        let path = reference.split(['#', '?']).next().unwrap_or(reference);
+        if let Some(base_href) = base_href {
+            let resolved = resolve_href(base_href, path);
+            let trimmed = resolved.trim_start_matches('/');
+            for candidate in [trimmed.to_string(), format!("/{trimmed}")] {
+                if let Some(relative) = self.by_href.get(&candidate) {
+                    return Some(relative);
+                }
+            }
+        }
+        let name = path.rsplit('/').next().unwrap_or(path);
+        self.by_name
+            .get(&decode_path(name))
+            .and_then(|relative| relative.as_deref())
+    }
+}
+
+/// Copies every OTF/TTF/WOFF manifest item to `fonts_root`, keeping the book's
+/// directory layout. Items are recognised by media type or, since font media
+/// types are often mislabelled, by extension.
+pub(crate) fn extract_fonts(
+    epub: &Epub,
+    fonts_root: &Path,
+    files: &dyn OutputSink,
+    warn: &mut dyn FnMut(String),
+) -> FontFiles {
+    let mut fonts = FontFiles::default();
+    for entry in epub.manifest().entries() {
+        let href = entry.href().as_str().to_string();
+        let extension = href
+            .rsplit_once('.')
+            .map(|(_, ext)| ext.to_lowercase())
+            .unwrap_or_default();
+        if !entry.resource_kind().is_font() && !FONT_EXTENSIONS.contains(&extension.as_str()) {
+            continue;
+        }
+        let bytes = match epub.read_resource_bytes(href.as_str()) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                warn(format!("Failed to read font {href}: {err}"));
+                continue;
+            }
+        };
+        let relative = decode_path(&href);
+        let output_path = fonts_root.join(&relative);
+        if let Err(err) = files.write(&output_path, &bytes) {
+            warn(format!(
+                "Failed to write font {}: {err}",
+                output_path.display()
+            ));
+            continue;
+        }
+        let name = relative.rsplit('/').next().unwrap_or(&relative).to_string();
+        fonts
+            .by_name
+            .entry(name)
+            .and_modify(|existing| *existing = None)
+            .or_insert_with(|| Some(relative.clone()));
+        fonts.by_href.insert(href, relative);
+    }
+    fonts
+}
+
+/// Points `url()` references to copied fonts (in `@font-face` rules) at
+/// `link(relative_path)`; every other reference is left alone.
+pub(crate) fn rewrite_font_urls(
+    css: &str,
+    base_href: Option<&str>,
+    fonts: &FontFiles,
+    link: &dyn Fn(&str) -> String,
+) -> String {
+    if fonts.is_empty() {
+        return css.to_string();
+    }
+    CSS_URL_RE
+        .replace_all(css, |caps: &regex::Captures| {
+            let reference = caps[1].trim();
+            if is_external(reference) {
+                return caps[0].to_string();
+            }
+            match fonts.lookup(reference, base_href) {
+                Some(relative) => format!("url(\"{}\")", link(relative)),
+                None => caps[0].to_string(),
+            }
+        })
+        .into_owned()
+}
+
+/// Link to a copied font from a stylesheet written in `css_dir`: relative to
+/// the stylesheet for local output, under the asset base URL otherwise.
+pub(crate) fn font_link(
+    css_dir: &Path,
+    fonts_root: &Path,
+    font_link_prefix: &str,
+    relative: &str,
+) -> String {
+    if font_link_prefix.starts_with("./") {
+        format!("{}{relative}", relative_dir(css_dir, fonts_root))
+    } else {
+        format!("{font_link_prefix}/{relative}")
+    }
+}
@@ -0,0 +1,456 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat};
+use rbook::Epub;
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+use crate::output::OutputSink;
+use crate::{ConvertOptions, ImageOutputFormat, Progress, ProgressHook, decode_path};
+
+/// How extracted images are written, derived from [`ConvertOptions`], plus
+/// running totals of what processing did to them. The totals are atomic so
+/// sections rendering on several threads can share one set.
+#[derive(Debug)]
+pub(crate) struct ImageOptions {
+    pub(crate) format: ImageOutputFormat,
+    pub(crate) max_size: Option<(u32, u32)>,
+    pub(crate) jpeg_quality: u8,
+    /// Images of at most this many bytes (after processing) become `data:` URIs.
+    pub(crate) inline_below: Option<u64>,
+    pub(crate) inlined: AtomicUsize,
+    pub(crate) strip_metadata: bool,
+    pub(crate) stripped: AtomicUsize,
+    pub(crate) resized: AtomicUsize,
+    pub(crate) bytes_in: AtomicU64,
+    pub(crate) bytes_out: AtomicU64,
+    pub(crate) duplicates: AtomicUsize,
+    /// Resolved hrefs of referenced images the book does not contain.
+    pub(crate) missing: Mutex<BTreeSet<String>>,
+    progress: Option<ProgressHook>,
+    book: PathBuf,
+    pub(crate) files: Arc<dyn OutputSink>,
+}
+
+impl ImageOptions {
+    pub(crate) fn from_convert_options(options: &ConvertOptions, epub_path: &Path) -> Self {
+        Self {
+            format: options.image_format,
+            max_size: options.max_image_size,
+            jpeg_quality: options.image_quality,
+            inline_below: options.inline_images_below,
+            inlined: AtomicUsize::new(0),
+            strip_metadata: options.strip_image_metadata,
+            stripped: AtomicUsize::new(0),
+            resized: AtomicUsize::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            duplicates: AtomicUsize::new(0),
+            missing: Mutex::new(BTreeSet::new()),
+            progress: options.on_progress.clone(),
+            book: epub_path.to_path_buf(),
+            files: options.output_sink.clone(),
+        }
+    }
+
+    pub(crate) fn report_extracted(&self, href: &str, count: usize) {
+        if let Some(hook) = &self.progress {
+            hook.emit(Progress::ImageExtracted {
+                path: &self.book,
+                href,
+                count,
+            });
+        }
+    }
+
+    /// Bytes saved across all processed images (zero when nothing was processed
+    /// or the output grew).
+    pub(crate) fn bytes_saved(&self) -> u64 {
+        self.bytes_in
+            .load(Ordering::Relaxed)
+            .saturating_sub(self.bytes_out.load(Ordering::Relaxed))
+    }
+
+    /// The resolved hrefs recorded as missing so far.
+    pub(crate) fn take_missing(&self) -> BTreeSet<String> {
+        std::mem::take(&mut *self.missing.lock().expect("image options lock"))
+    }
+
+    fn count_processed(&self, before: usize, after: usize) {
+        self.bytes_in.fetch_add(before as u64, Ordering::Relaxed);
+        self.bytes_out.fetch_add(after as u64, Ordering::Relaxed);
+    }
+}
+
+/// Extracts one book's images into `root`, linked as `link_prefix/...`.
+/// Shared by reference between the threads rendering sections: each
+/// resolved href is read and written once even when several threads reach
+/// it together, bytes already written under another href are linked rather
+/// than written again, and no two images are written to the same path.
+#[derive(Debug)]
+pub(crate) struct ImageExtractor {
+    root: PathBuf,
+    link_prefix: String,
+    pub(crate) options: ImageOptions,
+    state: Mutex<ExtractorState>,
+    /// Signalled whenever an href or hash claimed by a thread is settled.
+    settled: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct ExtractorState {
+    /// Resolved href to its link; `None` while a thread is extracting it.
+    by_href: HashMap<String, Option<String>>,
+    /// SHA-1 of the source bytes to the link written for them; `None` while
+    /// a thread is writing them.
+    by_hash: HashMap<String, Option<String>>,
+    /// Paths under `root` already written or being written.
+    targets: HashSet<String>,
+    written: usize,
+}
+
+impl ImageExtractor {
+    pub(crate) fn new(root: PathBuf, link_prefix: String, options: ImageOptions) -> Self {
+        Self {
+            root,
+            link_prefix,
+            options,
+            state: Mutex::new(ExtractorState::default()),
+            settled: Condvar::new(),
+        }
+    }
+
+    /// The link for the image at `resolved`, extracting it on first use.
+    /// `None` when the book does not contain it or it cannot be written.
+    pub(crate) fn extract(&self, epub: &Epub, resolved: &str) -> Option<String> {
+        {
+            let mut state = self.lock();
+            loop {
+                match state.by_href.get(resolved) {
+                    Some(Some(link)) => return Some(link.clone()),
+                    Some(None) => state = self.wait(state),
+                    None => break,
+                }
+            }
+            state.by_href.insert(resolved.to_string(), None);
+        }
+        let link = self.extract_new(epub, resolved);
+        let mut state = self.lock();
+        match &link {
+            Some(link) => state
+                .by_href
+                .insert(resolved.to_string(), Some(link.clone())),
+            // Left for a later reference to try again.
+            None => state.by_href.remove(resolved),
+        };
+        drop(state);
+        self.settled.notify_all();
+        link
+    }
+
+    /// Resolved href to link for every image extracted so far.
+    pub(crate) fn links(&self) -> HashMap<String, String> {
+        self.lock()
+            .by_href
+            .iter()
+            .filter_map(|(href, link)| Some((href.clone(), link.clone()?)))
+            .collect()
+    }
+
+    /// Image files written so far; duplicates and `data:` URIs are not
+    /// counted.
+    pub(crate) fn written(&self) -> usize {
+        self.lock().written
+    }
+
+    fn extract_new(&self, epub: &Epub, resolved: &str) -> Option<String> {
+        let Ok(bytes) = epub.read_resource_bytes(resolved) else {
+            self.options
+                .missing
+                .lock()
+                .expect("image options lock")
+                .insert(resolved.to_string());
+            return None;
+        };
+        // Ornaments are often shipped several times under different names.
+        let mut hasher = Sha1::new();
+        hasher.update(&bytes);
+        let hash = format!("{:x}", hasher.finalize());
+        {
+            let mut state = self.lock();
+            loop {
+                match state.by_hash.get(&hash) {
+                    Some(Some(link)) => {
+                        self.options.duplicates.fetch_add(1, Ordering::Relaxed);
+                        return Some(link.clone());
+                    }
+                    Some(None) => state = self.wait(state),
+                    None => break,
+                }
+            }
+            state.by_hash.insert(hash.clone(), None);
+        }
+
+        let original = decode_path(resolved);
+        let (bytes, relative) = prepare_image(bytes, &original, &self.options);
+        let inline = self
+            .options
+            .inline_below
+            .is_some_and(|limit| bytes.len() as u64 <= limit);
+        let written = if inline {
+            self.options.inlined.fetch_add(1, Ordering::Relaxed);
+            Some((data_uri(&bytes, &relative), None))
+        } else {
+            let relative = self.claim_target(relative, &original);
+            match self.options.files.write(&self.root.join(&relative), &bytes) {
+                Ok(()) => Some((format!("{}/{relative}", self.link_prefix), Some(relative))),
+                Err(_) => {
+                    self.lock().targets.remove(&relative);
+                    None
+                }
+            }
+        };
+
+        let mut state = self.lock();
+        let link = match written {
+            Some((link, file)) => {
+                if file.is_some() {
+                    state.written += 1;
+                    self.options.report_extracted(resolved, state.written);
+                }
+                state.by_hash.insert(hash, Some(link.clone()));
+                Some(link)
+            }
+            None => {
+                state.by_hash.remove(&hash);
+                None
+            }
+        };
+        drop(state);
+        self.settled.notify_all();
+        link
+    }
+
+    /// Reserves a path under `root` for an image, renaming it when another
+    /// image already has that path.
+    fn claim_target(&self, relative: String, original: &str) -> String {
+        let mut state = self.lock();
+        let mut claimed = relative.clone();
+        if relative != original && state.targets.contains(&claimed) {
+            // a.png and a.jpg both transcoded: keep the source extension in the name.
+            let (stem, ext) = relative.rsplit_once('.').unwrap_or((&relative, ""));
+            let source_ext = original.rsplit_once('.').map(|(_, ext)| ext).unwrap_or("");
+            claimed = format!("{stem}_{source_ext}.{ext}");
+        }
+        let (stem, ext) = match claimed.rsplit_once('.') {
+            Some((stem, ext)) => (stem.to_string(), format!(".{ext}")),
+            None => (claimed.clone(), String::new()),
+        };
+        let mut n = 2;
+        while state.targets.contains(&claimed) {
+            claimed = format!("{stem}-{n}{ext}");
+            n += 1;
+        }
+        state.targets.insert(claimed.clone());
+        claimed
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ExtractorState> {
+        self.state.lock().expect("image extractor lock")
+    }
+
+    fn wait<'a>(&self, state: MutexGuard<'a, ExtractorState>) -> MutexGuard<'a, ExtractorState> {
+        self.settled.wait(state).expect("image extractor lock")
+    }
+}
+
+/// Shrinks `bytes` to fit `max_size` and re-encodes it into the configured
+/// format, swapping the extension of `relative` to match. Images that need
+/// neither, cannot be decoded (SVG) or may be animated (GIF) are returned
+/// unchanged.
+pub(crate) fn prepare_image(
+    bytes: Vec<u8>,
+    relative: &str,
+    options: &ImageOptions,
+) -> (Vec<u8>, String) {
+    let bytes = if options.strip_metadata {
+        match strip_metadata(&bytes) {
+            Some(stripped) => {
+                options.stripped.fetch_add(1, Ordering::Relaxed);
+                options.count_processed(bytes.len(), stripped.len());
+                stripped
+            }
+            None => bytes,
+        }
+    } else {
+        bytes
+    };
+    if options.format == ImageOutputFormat::Original && options.max_size.is_none() {
+        return (bytes, relative.to_string());
+    }
+    let Ok(source) = image::guess_format(&bytes) else {
+        return (bytes, relative.to_string());
+    };
+    if source == ImageFormat::Gif {
+        return (bytes, relative.to_string());
+    }
+    let (target, extension) = match options.format {
+        ImageOutputFormat::Original => (source, None),
+        ImageOutputFormat::Webp => (ImageFormat::WebP, Some("webp")),
+        ImageOutputFormat::Png => (ImageFormat::Png, Some("png")),
+        ImageOutputFormat::Jpeg => (ImageFormat::Jpeg, Some("jpg")),
+    };
+    let Ok(decoded) = image::load_from_memory(&bytes) else {
+        return (bytes, relative.to_string());
+    };
+    let oversized = options.max_size.is_some_and(|(max_width, max_height)| {
+        decoded.width() > max_width || decoded.height() > max_height
+    });
+    if target == source && !oversized {
+        return (bytes, relative.to_string());
+    }
+    let decoded = match options.max_size {
+        Some((max_width, max_height)) if oversized => {
+            options.resized.fetch_add(1, Ordering::Relaxed);
+            decoded.resize(max_width, max_height, FilterType::Lanczos3)
+        }
+        _ => decoded,
+    };
+    let Some(encoded) = encode(&decoded, target, options.jpeg_quality) else {
+        return (bytes, relative.to_string());
+    };
+    options.count_processed(bytes.len(), encoded.len());
+    let relative = match extension {
+        Some(extension) if target != source => with_extension(relative, extension),
+        _ => relative.to_string(),
+    };
+    (encoded, relative)
+}
+
+/// JPEG APPn markers carrying EXIF/XMP (APP1), ICC profiles (APP2) and
+/// IPTC (APP13). JFIF (APP0) and Adobe (APP14) affect decoding and are kept.
+const JPEG_METADATA_MARKERS: &[u8] = &[0xE1, 0xE2, 0xED];
+
+/// PNG ancillary chunks with EXIF, text/XMP, ICC profiles and timestamps.
+const PNG_METADATA_CHUNKS: &[&[u8]] = &[b"eXIf", b"tEXt", b"zTXt", b"iTXt", b"iCCP", b"tIME"];
+
+/// `bytes` without EXIF, XMP and ICC metadata, or `None` when it is not a
+/// well-formed JPEG/PNG or carries none. Pixel data is copied untouched, so
+/// nothing is recompressed; an EXIF orientation is lost with the rest.
+pub(crate) fn strip_metadata(bytes: &[u8]) -> Option<Vec<u8>> {
+    if bytes.starts_with(&[0xFF, 0xD8]) {
+        strip_jpeg_metadata(bytes)
+    } else if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        strip_png_metadata(bytes)
+    } else {
+        None
+    }
+}
+
+fn strip_jpeg_metadata(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(bytes.len());
+    out.extend_from_slice(&bytes[..2]);
+    let mut pos = 2;
+    let mut removed = false;
+    loop {
+        if pos + 4 > bytes.len() || bytes[pos] != 0xFF {
+            return None;
+        }
+        let marker = bytes[pos + 1];
+        // Fill bytes before a marker.
+        if marker == 0xFF {
+            pos += 1;
+            continue;
+        }
+        // Start of scan: the entropy-coded data and everything after it is kept.
+        if marker == 0xDA {
+            out.extend_from_slice(&bytes[pos..]);
+            break;
+        }
+        let length = u16::from_be_bytes([bytes[pos + 2], bytes[pos + 3]]) as usize;
+        let end = pos + 2 + length;
+        if length < 2 || end > bytes.len() {
+            return None;
+        }
+        if JPEG_METADATA_MARKERS.contains(&marker) || marker == 0xFE {
+            removed = true;
+        } else {
+            out.extend_from_slice(&bytes[pos..end]);
+        }
+        pos = end;
+    }
+    removed.then_some(out)
+}
+
+fn strip_png_metadata(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(bytes.len());
+    out.extend_from_slice(&bytes[..8]);
+    let mut pos = 8;
+    let mut removed = false;
+    while pos < bytes.len() {
+        if pos + 12 > bytes.len() {
+            return None;
+        }
+        let length =
+            u32::from_be_bytes([bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]])
+                as usize;
+        let end = pos
+            .checked_add(12 + length)
+            .filter(|end| *end <= bytes.len())?;
+        let kind = &bytes[pos + 4..pos + 8];
+        if PNG_METADATA_CHUNKS.contains(&kind) {
+            removed = true;
+        } else {
+            out.extend_from_slice(&bytes[pos..end]);
+        }
+        pos = end;
+        if kind == b"IEND" {
+            break;
+        }
+    }
+    removed.then_some(out)
+}
+
+/// JPEG honours `jpeg_quality`; the `image` crate only writes lossless WebP.
+fn encode(image: &DynamicImage, format: ImageFormat, jpeg_quality: u8) -> Option<Vec<u8>> {
+    let mut encoded = Cursor::new(Vec::new());
+    if format == ImageFormat::Jpeg {
+        // JPEG has no alpha channel.
+        let rgb = image.to_rgb8();
+        JpegEncoder::new_with_quality(&mut encoded, jpeg_quality.clamp(1, 100))
+            .encode_image(&rgb)
+            .ok()?;
+    } else {
+        image.write_to(&mut encoded, format).ok()?;
+    }
+    Some(encoded.into_inner())
+}
+
+/// `data:` URI for `bytes`, typed by sniffing the content or, for SVG, by the
+/// extension of `relative`.
+pub(crate) fn data_uri(bytes: &[u8], relative: &str) -> String {
+    let mime = match image::guess_format(bytes) {
+        Ok(format) => format.to_mime_type(),
+        Err(_) if relative.to_lowercase().ends_with(".svg") => "image/svg+xml",
+        Err(_) => "application/octet-stream",
+    };
+    format!("data:{mime};base64,{}", STANDARD.encode(bytes))
+}
+
+fn with_extension(relative: &str, extension: &str) -> String {
+    let (dir, name) = match relative.rsplit_once('/') {
+        Some((dir, name)) => (Some(dir), name),
+        None => (None, relative),
+    };
+    let stem = name.rsplit_once('.').map(|(stem, _)| stem).unwrap_or(name);
+    match dir {
+        Some(dir) => format!("{dir}/{stem}.{extension}"),
+        None => format!("{stem}.{extension}"),
+    }
+}
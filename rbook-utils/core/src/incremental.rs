@@ -0,0 +1,149 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::{BookConversionResult, ConvertError, ConvertOptions, Result, build_settings_json};
+
+/// Kept in the output directory; delete it to convert everything again.
+pub const INCREMENTAL_STATE_FILE_NAME: &str = ".rbook-incremental.json";
+
+/// What `--incremental` knows about earlier runs into one output directory:
+/// each converted book's size, modification time and SHA-1, and a hash of
+/// the options it was converted with.
+///
+/// A book is up to date when its output still exists and either its size and
+/// mtime are unchanged or, failing that, its contents hash the same. Any
+/// change to the output-shaping options makes every book stale.
+pub(crate) struct IncrementalState {
+    path: PathBuf,
+    options_hash: String,
+    books: BTreeMap<String, BookState>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct StateFile {
+    options_hash: String,
+    books: BTreeMap<String, BookState>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct BookState {
+    size: u64,
+    modified_ms: u64,
+    sha1: String,
+    output_path: PathBuf,
+}
+
+impl IncrementalState {
+    /// Reads the state file in `options.output_dir`; a missing or unreadable
+    /// file, or one written with other options, knows no books.
+    pub(crate) fn load(options: &ConvertOptions) -> Self {
+        let path = options.output_dir.join(INCREMENTAL_STATE_FILE_NAME);
+        let options_hash = options_hash(options);
+        let books = fs::read_to_string(&path)
+            .ok()
+            .and_then(|text| serde_json::from_str::<StateFile>(&text).ok())
+            .filter(|state| state.options_hash == options_hash)
+            .map(|state| state.books)
+            .unwrap_or_default();
+        Self {
+            path,
+            options_hash,
+            books,
+        }
+    }
+
+    pub(crate) fn is_current(&self, epub_path: &Path) -> bool {
+        let Some(known) = self.books.get(&key(epub_path)) else {
+            return false;
+        };
+        if !known.output_path.exists() {
+            return false;
+        }
+        let Ok((size, modified_ms)) = file_stamp(epub_path) else {
+            return false;
+        };
+        if size == known.size && modified_ms == known.modified_ms {
+            return true;
+        }
+        // Copied or touched, but possibly the same bytes.
+        size == known.size && file_sha1(epub_path).is_ok_and(|sha1| sha1 == known.sha1)
+    }
+
+    /// Records the books of a finished batch that converted cleanly and writes
+    /// the state file. A failed book is converted again next run, even if it
+    /// left some output behind.
+    pub(crate) fn record_run(&mut self, books: &[BookConversionResult]) -> Result<()> {
+        for book in books {
+            if book.failed() {
+                self.books.remove(&key(&book.input_path));
+                continue;
+            }
+            let Some(output_path) = &book.output_path else {
+                continue;
+            };
+            let (Ok((size, modified_ms)), Ok(sha1)) =
+                (file_stamp(&book.input_path), file_sha1(&book.input_path))
+            else {
+                continue;
+            };
+            self.books.insert(
+                key(&book.input_path),
+                BookState {
+                    size,
+                    modified_ms,
+                    sha1,
+                    output_path: output_path.clone(),
+                },
+            );
+        }
+        let state = StateFile {
+            options_hash: self.options_hash.clone(),
+            books: self.books.clone(),
+        };
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).map_err(|err| ConvertError::write_failed(parent, err))?;
+        }
+        fs::write(&self.path, serde_json::to_string_pretty(&state)? + "\n")
+            .map_err(|err| ConvertError::write_failed(&self.path, err))?;
+        Ok(())
+    }
+}
+
+fn key(epub_path: &Path) -> String {
+    epub_path.to_string_lossy().into_owned()
+}
+
+fn options_hash(options: &ConvertOptions) -> String {
+    let settings = json!({
+        "build": build_settings_json(options),
+        "export_manifest": format!("{:?}", options.export_manifest),
+        "quality_report": format!("{:?}", options.quality_report),
+        "preview": options.preview,
+        "preview_max_words": options.preview.then_some(options.preview_max_words),
+        "stream": options.stream,
+    });
+    let mut hasher = Sha1::new();
+    hasher.update(settings.to_string().as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+fn file_stamp(path: &Path) -> std::io::Result<(u64, u64)> {
+    let metadata = fs::metadata(path)?;
+    let modified_ms = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64);
+    Ok((metadata.len(), modified_ms))
+}
+
+fn file_sha1(path: &Path) -> std::io::Result<String> {
+    let mut hasher = Sha1::new();
+    std::io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
@@ -0,0 +1,94 @@
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+
+use crate::{ConvertOptions, Diagnostic, DiagnosticLevel};
+
+/// Sets the options named in `json`, an object keyed by [`ConvertOptions`]
+/// field names; enum values are kebab-case, as on the command line.
+pub(crate) fn apply_json_options(options: &mut ConvertOptions, json: &Value) -> Result<(), String> {
+    let Some(fields) = json.as_object() else {
+        return Err("options must be a JSON object".to_string());
+    };
+    for (key, value) in fields {
+        match key.as_str() {
+            "markdown_mode" => options.markdown_mode = value_enum(key, value)?,
+            "style" => options.style = value_enum(key, value)?,
+            "chapter_fallback" => options.chapter_fallback = value_enum(key, value)?,
+            "notes_mode" => options.notes_mode = value_enum(key, value)?,
+            "ocr_cleanup" => options.ocr_cleanup = value_enum(key, value)?,
+            "nav_cleanup" => options.nav_cleanup = value_enum(key, value)?,
+            "anchor_mode" => options.anchor_mode = value_enum(key, value)?,
+            "svg_mode" => options.svg_mode = value_enum(key, value)?,
+            "ruby_mode" => options.ruby_mode = value_enum(key, value)?,
+            "text_direction" => options.text_direction = value_enum(key, value)?,
+            "image_format" => options.image_format = value_enum(key, value)?,
+            "split_on_heading_level" => {
+                options.split_on_heading_level = match value {
+                    Value::Null => None,
+                    value => Some(number(key, value)?),
+                }
+            }
+            "min_section_words" => options.min_section_words = number(key, value)?,
+            "image_quality" => options.image_quality = number(key, value)?,
+            "inline_images_below" => {
+                options.inline_images_below = match value {
+                    Value::Null => None,
+                    value => Some(number(key, value)?),
+                }
+            }
+            "cache_max_documents" => {
+                options.cache_max_documents = match value {
+                    Value::Null => None,
+                    value => Some(number(key, value)?),
+                }
+            }
+            "cache_max_bytes" => {
+                options.cache_max_bytes = match value {
+                    Value::Null => None,
+                    value => Some(number(key, value)?),
+                }
+            }
+            "split_chapters" => options.split_chapters = flag(key, value)?,
+            "escape_markdown" => options.escape_markdown = flag(key, value)?,
+            "consolidate_endnotes" => options.consolidate_endnotes = flag(key, value)?,
+            "media_all" => options.media_all = flag(key, value)?,
+            "skip_decorative_images" => options.skip_decorative_images = flag(key, value)?,
+            "skip_images" => options.skip_images = flag(key, value)?,
+            "strip_image_metadata" => options.strip_image_metadata = flag(key, value)?,
+            _ => return Err(format!("unknown option {key}")),
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn diagnostic_json(diagnostic: &Diagnostic) -> Value {
+    json!({
+        "level": match diagnostic.level {
+            DiagnosticLevel::Info => "info",
+            DiagnosticLevel::Warning => "warning",
+            DiagnosticLevel::Error => "error",
+        },
+        "code": diagnostic.code.map(|code| code.code()),
+        "message": diagnostic.message,
+    })
+}
+
+fn value_enum<T: DeserializeOwned>(key: &str, value: &Value) -> Result<T, String> {
+    value
+        .as_str()
+        .and_then(|name| serde_json::from_value(Value::from(name.to_lowercase())).ok())
+        .ok_or_else(|| format!("invalid value {value} for {key}"))
+}
+
+fn number<T: TryFrom<u64>>(key: &str, value: &Value) -> Result<T, String> {
+    value
+        .as_u64()
+        .and_then(|number| T::try_from(number).ok())
+        .ok_or_else(|| format!("invalid value {value} for {key}"))
+}
+
+fn flag(key: &str, value: &Value) -> Result<bool, String> {
+    value
+        .as_bool()
+        .ok_or_else(|| format!("invalid value {value} for {key}"))
+}
@@ -0,0 +1,105 @@
+use pulldown_cmark::{CowStr, Event, HeadingLevel, Options, Parser, Tag, html};
+
+/// Single column, black on white (white on black in dark mode), generous
+/// spacing, and nothing from the publisher. `{size}` is the base font size.
+const PAGE_STYLE: &str = "html{font-size:{size}px}\
+body{margin:0;background:#fff;color:#000;font-family:Verdana,Tahoma,sans-serif;line-height:1.6}\
+main{max-width:36em;margin:0 auto;padding:1em}\
+h1,h2,h3,h4,h5,h6{line-height:1.25;margin:1.5em 0 .5em}\
+p,li{margin:0 0 1em}\
+a{color:#00e;text-decoration:underline;text-underline-offset:.15em}\
+a:visited{color:#551a8b}\
+a:focus{outline:.2em solid #000;outline-offset:.1em}\
+img{display:block;max-width:100%;height:auto;margin:1em auto}\
+blockquote{margin:1em 0;padding-left:1em;border-left:.3em solid #000}\
+table{border-collapse:collapse;margin:1em 0}td,th{border:2px solid #000;padding:.3em .5em;text-align:left}\
+pre,code{font-size:1em;white-space:pre-wrap}\
+hr{border:0;border-top:2px solid #000;margin:2em 0}\
+@media (prefers-color-scheme:dark){body{background:#000;color:#fff}\
+a{color:#8cf}a:visited{color:#d9f}a:focus{outline-color:#fff}\
+blockquote{border-left-color:#fff}td,th{border-color:#fff}hr{border-top-color:#fff}}";
+
+/// Renders one markdown output as a standalone large-print HTML page: the
+/// book's headings kept as the document outline, each `##` section in its
+/// own `<section>` inside `<main>`, links to other markdown outputs pointed
+/// at their `.html` counterparts, and front matter dropped.
+pub(crate) fn large_print_page(markdown: &str, font_px: u16) -> String {
+    let markdown = strip_front_matter(markdown);
+    let title = markdown
+        .lines()
+        .find_map(|line| line.strip_prefix("# "))
+        .unwrap_or("")
+        .trim();
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_HEADING_ATTRIBUTES;
+    let mut events = Vec::new();
+    let mut in_section = false;
+    for event in Parser::new_ext(markdown, options) {
+        let event = match event {
+            Event::Start(Tag::Heading(HeadingLevel::H2, ..)) => {
+                if in_section {
+                    events.push(Event::Html("</section>\n".into()));
+                }
+                events.push(Event::Html("<section>\n".into()));
+                in_section = true;
+                event
+            }
+            Event::Start(Tag::Link(kind, dest, link_title)) => {
+                Event::Start(Tag::Link(kind, html_link(dest), link_title))
+            }
+            Event::End(Tag::Link(kind, dest, link_title)) => {
+                Event::End(Tag::Link(kind, html_link(dest), link_title))
+            }
+            event => event,
+        };
+        events.push(event);
+    }
+    if in_section {
+        events.push(Event::Html("</section>\n".into()));
+    }
+    let mut body = String::new();
+    html::push_html(&mut body, events.into_iter());
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+<title>{}</title>\n<style>{}</style>\n</head>\n<body>\n<main>\n{body}</main>\n</body>\n</html>\n",
+        escape_html(title),
+        PAGE_STYLE.replace("{size}", &font_px.to_string())
+    )
+}
+
+/// Relative links to markdown outputs, with or without a fragment, point at
+/// the HTML written in their place.
+fn html_link(dest: CowStr<'_>) -> CowStr<'_> {
+    if dest.contains("://") || dest.starts_with('#') {
+        return dest;
+    }
+    let (path, fragment) = match dest.split_once('#') {
+        Some((path, fragment)) => (path, Some(fragment)),
+        None => (&*dest, None),
+    };
+    let Some(stem) = path.strip_suffix(".md") else {
+        return dest;
+    };
+    match fragment {
+        Some(fragment) => format!("{stem}.html#{fragment}").into(),
+        None => format!("{stem}.html").into(),
+    }
+}
+
+fn strip_front_matter(markdown: &str) -> &str {
+    markdown
+        .strip_prefix("---\n")
+        .and_then(|rest| rest.split_once("\n---\n"))
+        .map_or(markdown, |(_, body)| body)
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,5209 @@
+// serde_json's json! recurses once per key; the manifest build record is long.
+#![recursion_limit = "256"]
+
+use once_cell::sync::Lazy;
+use rbook::ebook::manifest::Manifest;
+use rbook::ebook::spine::Spine;
+use rbook::ebook::toc::{Toc, TocChildren, TocEntry};
+use rbook::prelude::{ManifestEntry, MetaEntry, Metadata, SpineEntry};
+use rbook::{Ebook, Epub};
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::json;
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use kuchiki::traits::*;
+use kuchiki::{NodeRef, parse_html};
+
+mod anthology;
+mod archive;
+#[cfg(feature = "async")]
+mod async_convert;
+mod audio_split;
+mod cleanup_profiles;
+mod compare;
+mod content_cache;
+mod corpus;
+mod covers;
+mod decorative;
+mod editions;
+mod error;
+#[cfg(feature = "cdylib")]
+mod ffi;
+mod fingerprint;
+mod flashcards;
+mod fonts;
+mod images;
+mod incremental;
+#[cfg(any(feature = "wasm", feature = "cdylib"))]
+mod json_api;
+mod large_print;
+mod lock;
+mod markdown;
+mod media;
+mod memory;
+mod model;
+mod navigation;
+#[cfg(feature = "object-storage")]
+mod object_storage;
+mod output;
+mod overrides;
+mod pipeline;
+mod plan;
+mod positions;
+mod preview;
+mod progress;
+mod provenance;
+mod report;
+mod resources;
+mod rights;
+mod search;
+mod skip_list;
+mod slugs;
+mod speech;
+mod splits;
+mod storage;
+mod streaming;
+mod svg;
+mod templates;
+mod text_output;
+mod thumbnails;
+mod translation;
+mod usage;
+mod warnings;
+#[cfg(feature = "wasm")]
+mod wasm;
+#[cfg(feature = "watch")]
+mod watch;
+mod watermarks;
+
+use cleanup_profiles::ProfileCleanup;
+use content_cache::ContentCache;
+use markdown::{BookNotes, RenderOptions};
+use templates::BookFields;
+use watermarks::WatermarkReport;
+
+pub use anthology::{
+    AnthologyBook, AnthologyFormat, AnthologyPart, AnthologyPlan, AnthologyResult, build_anthology,
+};
+pub use archive::{TarStorage, convert_all_to_tar};
+#[cfg(feature = "async")]
+pub use async_convert::{convert_all_async, convert_epub_async};
+pub use cleanup_profiles::{AUTO_CLEANUP_PROFILE, CleanupProfile, CleanupProfiles};
+pub use corpus::{CorpusBook, CorpusStats, QualityStats, corpus_stats};
+pub use covers::extract_covers;
+pub use editions::{ChapterComparison, EditionChapter, EditionComparison, compare_editions};
+pub use error::{BoxError, ConvertError, Result};
+#[cfg(feature = "cdylib")]
+pub use ffi::{rbook_utils_convert, rbook_utils_free_string};
+pub use fingerprint::{DuplicateSide, Fingerprint, NearDuplicate, find_near_duplicates};
+pub use flashcards::FlashcardExport;
+pub use incremental::INCREMENTAL_STATE_FILE_NAME;
+pub use lock::{LOCK_FILE_NAME, OutputLock};
+pub use memory::{BookOutput, convert_epub_to_sections};
+pub use model::{Book, Chapter, Resource, TocNode};
+pub use navigation::{book_navigation, book_spine};
+#[cfg(feature = "object-storage")]
+pub use object_storage::ObjectStorage;
+pub use output::{CHAPTER_LIST_FILE_NAME, FsSink, OutputSink, is_chapter_file};
+pub use overrides::BookOverride;
+pub use plan::{ConversionPlan, PlannedBook, SectionInfo};
+pub use progress::{CancelToken, Progress, ProgressHook};
+pub use report::{BatchReport, ConvertReport, TocStats};
+pub use resources::{ExtractedResource, book_resources, extract_resource};
+pub use rights::{RightsInfo, RightsStatus};
+pub use search::{SearchHit, SearchOptions, SearchSummary, search_library};
+pub use skip_list::{SkipEntry, SkipList};
+pub use slugs::{AsciiSlugs, GithubSlugs, MkdocsSlugs, SlugStrategy, UnicodeSlugs};
+pub use splits::{SplitComparison, SplitSection, compare_splits};
+pub use storage::{LocalStorage, MemoryStorage, Storage, content_type, convert_all_to_storage};
+pub use templates::OutputTemplate;
+pub use text_output::validate_encoding;
+pub use translation::TranslationExport;
+pub use usage::{RunStats, UsageStats};
+pub use warnings::{WarningCode, WarningHook};
+#[cfg(feature = "wasm")]
+pub use wasm::convert_epub_bytes;
+#[cfg(feature = "watch")]
+pub use watch::watch_input_dir;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum MarkdownMode {
+    Plain,
+    Rich,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum StyleMode {
+    Inline,
+    External,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum ChapterFallbackMode {
+    Off,
+    Auto,
+    Force,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum NotesMode {
+    Inline,
+    ChapterEnd,
+    Global,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum ExportMode {
+    Off,
+    V1,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum Newline {
+    Lf,
+    Crlf,
+}
+
+/// Unicode normalization form of markdown outputs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum TextNormalization {
+    /// Written as the book spells it.
+    None,
+    /// Composed: a letter and combining accent become one character.
+    Nfc,
+    /// Compatibility composed: also folds ligatures, full-width forms,
+    /// superscripts and the like to their plain equivalents.
+    Nfkc,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum OcrCleanupMode {
+    Off,
+    Basic,
+    Aggressive,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum NavCleanupMode {
+    Off,
+    Auto,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum AnchorMode {
+    Off,
+    Html,
+    Attributes,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum RubyMode {
+    Html,
+    Bracket,
+    BaseOnly,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum TextDirection {
+    Auto,
+    Ltr,
+    Rtl,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum SvgMode {
+    Inline,
+    Extract,
+    Rasterize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum ImageOutputFormat {
+    Original,
+    Webp,
+    Png,
+    Jpeg,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum CoverReference {
+    Off,
+    Frontmatter,
+    Image,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum CoverFormat {
+    Original,
+    Jpeg,
+    Png,
+    Webp,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum CoverNaming {
+    Slug,
+    Identifier,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum FilenameScheme {
+    Index,
+    Hash,
+}
+
+/// Output presets, picked with [`ConvertOptions::set_profile`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputProfile {
+    /// Markdown as the other options describe it.
+    Markdown,
+    /// Text to feed a speech engine, in the same files: no markup, images,
+    /// tables or anchors, notes read as asides and abbreviations written out.
+    Tts,
+    /// Single-column, high-contrast HTML for low-vision readers, written as
+    /// `.html` in place of each `.md`, in a configurable base font size.
+    LargePrint,
+}
+
+/// What to do about a book whose markdown already exists in `output_dir`:
+/// `{slug}.md`, or markdown in the `{slug}` directory when splitting, or an
+/// earlier book of the same batch under that slug. Checked through the
+/// [`OutputSink`], so in archives and buckets only books of the same batch
+/// conflict.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum OnConflict {
+    /// Write over the existing files. A split also removes the chapter files
+    /// and `index.md` of an earlier split; other markdown is left alone.
+    Overwrite,
+    /// Leave the book out, reported as skipped.
+    Skip,
+    /// Write under the first free `{slug}-2`, `{slug}-3`, ...
+    Rename,
+}
+
+/// Where split chapter files get previous/next/index links.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum ChapterNav {
+    Off,
+    /// A link line at the bottom of each chapter.
+    Footer,
+    /// `prev`, `next` and `index` keys in the front matter.
+    Frontmatter,
+}
+
+/// Built-in [`SlugStrategy`] implementations, for picking one by name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum SlugStyle {
+    Ascii,
+    Unicode,
+    Github,
+    Mkdocs,
+}
+
+impl SlugStyle {
+    pub fn strategy(self) -> Arc<dyn SlugStrategy> {
+        match self {
+            SlugStyle::Ascii => Arc::new(AsciiSlugs),
+            SlugStyle::Unicode => Arc::new(UnicodeSlugs),
+            SlugStyle::Github => Arc::new(GithubSlugs),
+            SlugStyle::Mkdocs => Arc::new(MkdocsSlugs),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ConvertOptions {
+    pub input_dir: PathBuf,
+    pub output_dir: PathBuf,
+    pub media_all: bool,
+    pub markdown_mode: MarkdownMode,
+    pub style: StyleMode,
+    pub split_chapters: bool,
+    pub chapter_fallback: ChapterFallbackMode,
+    /// Headings that start a section in heading fallback besides "Chapter
+    /// 12", "Part IV", "Prologue" and the like, e.g. `^Letter \d+`.
+    pub heading_patterns: Vec<Regex>,
+    pub notes_mode: NotesMode,
+    pub export_manifest: ExportMode,
+    /// Only meaningful with [`OutputProfile::Markdown`]: the tts and
+    /// large-print profiles leave no positions to find.
+    pub export_positions: ExportMode,
+    pub export_provenance: ExportMode,
+    /// Writes `audio_split.v1.json` and an ffmpeg script cutting media
+    /// overlay narration into chapter files.
+    pub export_audio_split: ExportMode,
+    pub quality_report: ExportMode,
+    pub ocr_cleanup: OcrCleanupMode,
+    pub nav_cleanup: NavCleanupMode,
+    /// Cleanup profile to apply by name, or [`AUTO_CLEANUP_PROFILE`] to pick
+    /// one by the book's `dc:publisher`; `None` applies none.
+    pub cleanup_profile: Option<String>,
+    /// The profiles `cleanup_profile` chooses from; the shipped ones unless
+    /// loaded with [`CleanupProfiles::from_file`].
+    pub cleanup_profiles: CleanupProfiles,
+    /// Removes buyer names and emails that retailers stamp into books, from
+    /// the metadata written and the text; `report.v1.json` counts what went.
+    pub strip_watermarks: bool,
+    pub filename_scheme: FilenameScheme,
+    /// Lays books out under `output_dir` by metadata instead of as
+    /// `{slug}.md` / `{slug}/`; see [`OutputTemplate`].
+    pub output_template: Option<OutputTemplate>,
+    pub on_conflict: OnConflict,
+    pub split_on_heading_level: Option<u8>,
+    pub escape_markdown: bool,
+    pub min_section_words: usize,
+    pub consolidate_endnotes: bool,
+    pub list_of_figures: bool,
+    pub chapter_thumbnails: bool,
+    pub thumbnail_max_edge: u32,
+    pub anchor_mode: AnchorMode,
+    pub compare_view: bool,
+    pub lock_output: bool,
+    pub lock_stale_after_secs: u64,
+    pub asset_base_url: Option<String>,
+    pub svg_mode: SvgMode,
+    pub ruby_mode: RubyMode,
+    pub text_direction: TextDirection,
+    pub extract_fonts: bool,
+    pub cover_reference: CoverReference,
+    pub preview: bool,
+    pub preview_max_words: usize,
+    /// Write each chapter as soon as it is rendered, keeping memory bounded
+    /// on very large books at the cost of the whole-book passes (see
+    /// `--stream`). Implies split chapters.
+    pub stream: bool,
+    /// Parsed documents kept for revisiting at once; the least recently used
+    /// are dropped and re-parsed past this. Unbounded when `None`.
+    pub cache_max_documents: Option<usize>,
+    /// Like `cache_max_documents`, by the total size of the documents' HTML.
+    pub cache_max_bytes: Option<u64>,
+    pub image_format: ImageOutputFormat,
+    /// Media types read as content documents besides XHTML/HTML, e.g. `text/plain`.
+    pub extra_readable_types: Vec<String>,
+    /// Extracted images larger than (width, height) are scaled down to fit.
+    pub max_image_size: Option<(u32, u32)>,
+    /// JPEG quality used when images are re-encoded.
+    pub image_quality: u8,
+    /// Images of at most this many bytes are embedded as `data:` URIs instead
+    /// of being written next to the markdown.
+    pub inline_images_below: Option<u64>,
+    /// Warnings with these codes are dropped.
+    pub suppress_warnings: Vec<WarningCode>,
+    /// Warnings with these codes are reported as errors, failing the book.
+    pub error_on_warnings: Vec<WarningCode>,
+    /// Line written under an image's `<figcaption>`; `{caption}` is replaced
+    /// by the caption text.
+    pub figure_caption_template: String,
+    /// Names book directories and section files, and decides how section
+    /// links address headings.
+    pub slug_strategy: Arc<dyn SlugStrategy>,
+    /// Drop EXIF, XMP and ICC metadata from extracted JPEG and PNG files.
+    pub strip_image_metadata: bool,
+    /// Drop ornaments and spacers: images marked presentational, with an
+    /// empty `alt`, or only a few pixels in size.
+    pub skip_decorative_images: bool,
+    /// Leave every image out of the text, as for a scanned book whose page
+    /// images only duplicate its text. The cover is still handled as
+    /// `cover_reference` says.
+    pub skip_images: bool,
+    /// Previous/next/index links between split chapter files; also writes an
+    /// `index.md` listing the chapters.
+    pub chapter_nav: ChapterNav,
+    /// Books converted at once by [`convert_all`]; `0` uses every available core.
+    pub jobs: usize,
+    /// Threads reading and decoding one book's documents ahead of the
+    /// converting thread, up to the cache limits; `0` uses every available
+    /// core. Only the archive reads run in parallel: parsing, the DOM passes
+    /// and rendering stay in spine order on the converting thread.
+    pub section_jobs: usize,
+    /// Skip books not classified as (likely) public domain.
+    pub only_public_domain: bool,
+    /// Books published before this year count as public domain.
+    pub public_domain_before: i32,
+    /// Encoding of markdown outputs (a WHATWG label such as `shift_jis` or
+    /// `windows-1252`); `None` writes UTF-8. Other encodings need the
+    /// `output-encoding` feature.
+    pub output_encoding: Option<String>,
+    /// Called as books and sections are processed.
+    pub on_progress: Option<ProgressHook>,
+    /// Called with each book's warnings and errors as the book finishes.
+    pub on_warning: Option<WarningHook>,
+    /// Checked between books and sections; see [`CancelToken`].
+    pub cancel: Option<CancelToken>,
+    /// Start no further books once one fails (see
+    /// [`BookConversionResult::failed`]). Books already being converted
+    /// finish; the rest are reported as skipped.
+    pub fail_fast: bool,
+    /// Line endings of markdown, CSS and JSON outputs.
+    pub newline: Newline,
+    /// Start UTF-8 text outputs with a byte order mark.
+    pub bom: bool,
+    /// Normalization form of markdown outputs.
+    pub text_normalization: TextNormalization,
+    /// Write no-break, thin and other fixed-width spaces inside lines of
+    /// markdown as ordinary spaces.
+    pub collapse_spaces: bool,
+    /// Convert only the books and sections listed here, in its order and with
+    /// its titles.
+    pub plan: Option<ConversionPlan>,
+    /// Write the sections [`convert_all`] produced as a plan to this file.
+    pub plan_export: Option<PathBuf>,
+    /// Receives the converted files; [`FsSink`] writes them under
+    /// `output_dir`.
+    pub output_sink: Arc<dyn OutputSink>,
+    /// Fingerprint each book and section (see [`Fingerprint`]) into the
+    /// report and, when exported, the manifest.
+    pub fingerprints: bool,
+    /// Add each [`convert_all`] run to the [`UsageStats`] in this local file
+    /// (TOML when it ends in `.toml`, JSON otherwise).
+    pub usage_stats: Option<PathBuf>,
+    /// Batches skip the books listed here and add books that keep failing;
+    /// see [`SkipList`].
+    pub skip_list: Option<SkipList>,
+    /// Settings [`convert_all`] changes for particular books; see
+    /// [`BookOverride`].
+    pub book_overrides: Vec<BookOverride>,
+    /// [`convert_all`] and `convert_all_async` skip books whose output is
+    /// up to date with the input file and these options, as recorded in
+    /// [`INCREMENTAL_STATE_FILE_NAME`] in `output_dir` by earlier incremental
+    /// runs. [`convert_all_to_storage`] cannot tell and rejects it.
+    pub incremental: bool,
+    /// Write a TMX or XLIFF skeleton of each book's text for translators.
+    pub translation_export: TranslationExport,
+    /// Target language of the translation skeleton, e.g. `de`.
+    pub translation_target_language: Option<String>,
+    /// Write the term/definition pairs of glossaries and vocabulary lists as
+    /// a CSV or Anki flashcard deck.
+    pub flashcard_export: FlashcardExport,
+    /// Set with [`ConvertOptions::set_profile`].
+    pub profile: OutputProfile,
+    /// Under [`OutputProfile::Tts`], also write numbers, ordinals and years
+    /// as words.
+    pub spell_out_numbers: bool,
+    /// Base font size, in CSS pixels, of [`OutputProfile::LargePrint`] pages.
+    pub large_print_font_size: u16,
+    /// Book slugs taken so far by the running batch, so `on_conflict` also
+    /// sees books whose outputs are not written yet. Set by the batch
+    /// functions through [`ConvertOptions::for_batch`].
+    pub(crate) output_slugs: Option<Arc<Mutex<HashSet<String>>>>,
+    /// Holds the lock on the book being converted until these options (and
+    /// every clone of them) are dropped, so it outlives deferred writes. Books
+    /// are locked only when this is set; see [`ConvertOptions::locking_book`].
+    pub(crate) book_lock: Option<Arc<Mutex<Option<OutputLock>>>>,
+}
+
+impl ConvertOptions {
+    pub fn new(input_dir: PathBuf, output_dir: PathBuf) -> Self {
+        Self {
+            input_dir,
+            output_dir,
+            media_all: false,
+            markdown_mode: MarkdownMode::Plain,
+            style: StyleMode::Inline,
+            split_chapters: false,
+            chapter_fallback: ChapterFallbackMode::Auto,
+            heading_patterns: Vec::new(),
+            notes_mode: NotesMode::Inline,
+            export_manifest: ExportMode::Off,
+            export_positions: ExportMode::Off,
+            export_provenance: ExportMode::Off,
+            export_audio_split: ExportMode::Off,
+            quality_report: ExportMode::Off,
+            ocr_cleanup: OcrCleanupMode::Off,
+            nav_cleanup: NavCleanupMode::Auto,
+            cleanup_profile: None,
+            cleanup_profiles: CleanupProfiles::shipped(),
+            strip_watermarks: false,
+            filename_scheme: FilenameScheme::Index,
+            output_template: None,
+            on_conflict: OnConflict::Overwrite,
+            split_on_heading_level: None,
+            escape_markdown: true,
+            min_section_words: 0,
+            consolidate_endnotes: false,
+            list_of_figures: false,
+            chapter_thumbnails: false,
+            thumbnail_max_edge: 320,
+            anchor_mode: AnchorMode::Html,
+            compare_view: false,
+            lock_output: true,
+            lock_stale_after_secs: 6 * 60 * 60,
+            asset_base_url: None,
+            svg_mode: SvgMode::Inline,
+            ruby_mode: RubyMode::Bracket,
+            text_direction: TextDirection::Auto,
+            extract_fonts: false,
+            cover_reference: CoverReference::Frontmatter,
+            preview: false,
+            preview_max_words: 1500,
+            stream: false,
+            cache_max_documents: None,
+            cache_max_bytes: None,
+            image_format: ImageOutputFormat::Original,
+            extra_readable_types: Vec::new(),
+            max_image_size: None,
+            image_quality: 85,
+            inline_images_below: None,
+            suppress_warnings: Vec::new(),
+            error_on_warnings: Vec::new(),
+            figure_caption_template: "*{caption}*".to_string(),
+            slug_strategy: Arc::new(AsciiSlugs),
+            strip_image_metadata: false,
+            skip_decorative_images: false,
+            skip_images: false,
+            chapter_nav: ChapterNav::Off,
+            jobs: 1,
+            section_jobs: 1,
+            only_public_domain: false,
+            public_domain_before: rights::default_public_domain_before(),
+            output_encoding: None,
+            on_progress: None,
+            on_warning: None,
+            cancel: None,
+            fail_fast: false,
+            newline: Newline::Lf,
+            bom: false,
+            text_normalization: TextNormalization::Nfc,
+            collapse_spaces: false,
+            plan: None,
+            plan_export: None,
+            output_sink: Arc::new(FsSink),
+            fingerprints: false,
+            usage_stats: None,
+            skip_list: None,
+            book_overrides: Vec::new(),
+            incremental: false,
+            translation_export: TranslationExport::Off,
+            translation_target_language: None,
+            flashcard_export: FlashcardExport::Off,
+            profile: OutputProfile::Markdown,
+            spell_out_numbers: false,
+            large_print_font_size: 24,
+            output_slugs: None,
+            book_lock: None,
+        }
+    }
+
+    /// These options with an empty set of taken book slugs, for one batch.
+    pub(crate) fn for_batch(&self) -> ConvertOptions {
+        let mut options = self.clone();
+        options.output_slugs = Some(Arc::default());
+        options
+    }
+
+    /// These options for converting one book under its own output lock, when
+    /// `lock_output` is on; the lock is released once they are dropped.
+    pub(crate) fn locking_book(&self) -> ConvertOptions {
+        let mut options = self.clone();
+        options.book_lock = self.lock_output.then(Arc::default);
+        options
+    }
+
+    /// Trades fidelity for speed, for first-pass triage of large collections:
+    /// plain markdown, which skips CSS collection and the rich-mode complexity
+    /// analysis, and no heading fallback scan of books with degenerate TOCs.
+    pub fn fast_mode(&mut self) {
+        self.markdown_mode = MarkdownMode::Plain;
+        self.chapter_fallback = ChapterFallbackMode::Off;
+    }
+
+    /// Switches to `profile` and sets the options it depends on. For
+    /// [`OutputProfile::Tts`] that is plain markdown with notes inline, bare
+    /// figure captions, and no cover link, chapter navigation or list of
+    /// figures, so little is left for the speech pass to strip. For
+    /// [`OutputProfile::LargePrint`] it is plain markdown, which leaves out
+    /// the publisher's CSS, and no embedded fonts.
+    pub fn set_profile(&mut self, profile: OutputProfile) {
+        self.profile = profile;
+        match profile {
+            OutputProfile::Markdown => {}
+            OutputProfile::Tts => {
+                self.markdown_mode = MarkdownMode::Plain;
+                self.notes_mode = NotesMode::Inline;
+                self.figure_caption_template = "{caption}".to_string();
+                self.cover_reference = CoverReference::Off;
+                self.chapter_nav = ChapterNav::Off;
+                self.list_of_figures = false;
+            }
+            OutputProfile::LargePrint => {
+                self.markdown_mode = MarkdownMode::Plain;
+                self.extract_fonts = false;
+            }
+        }
+    }
+
+    fn report(&self, progress: Progress<'_>) {
+        if let Some(hook) = &self.on_progress {
+            hook.emit(progress);
+        }
+    }
+
+    /// Reports [`Progress::SectionStarted`]; the guard reports
+    /// [`Progress::SectionRendered`] when dropped.
+    fn section_progress<'a>(
+        &'a self,
+        path: &'a Path,
+        index: usize,
+        total: usize,
+    ) -> progress::SectionProgress<'a> {
+        self.report(Progress::SectionStarted { path, index, total });
+        progress::SectionProgress {
+            hook: self.on_progress.as_ref(),
+            path,
+            index,
+            total,
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(CancelToken::is_cancelled)
+    }
+
+    fn check_cancelled(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(ConvertError::Cancelled);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CoverOptions {
+    pub input_dir: PathBuf,
+    pub output_dir: PathBuf,
+    pub max_edge: u32,
+    pub format: CoverFormat,
+    pub naming: CoverNaming,
+}
+
+impl CoverOptions {
+    pub fn new(input_dir: PathBuf, output_dir: PathBuf) -> Self {
+        Self {
+            input_dir,
+            output_dir,
+            max_edge: 600,
+            format: CoverFormat::Jpeg,
+            naming: CoverNaming::Slug,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiagnosticLevel {
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub level: DiagnosticLevel,
+    /// Set for warnings (and warnings promoted to errors by `error_on_warnings`).
+    pub code: Option<WarningCode>,
+    pub message: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct BookConversionResult {
+    pub input_path: PathBuf,
+    pub title: String,
+    pub output_path: Option<PathBuf>,
+    pub diagnostics: Vec<Diagnostic>,
+    pub missing_resources: MissingResources,
+    /// Why a filter left the book out; skipped books are neither converted
+    /// nor failed.
+    pub skipped: Option<String>,
+    /// For books that failed with an error, its [`ConvertError::kind`].
+    pub error_kind: Option<&'static str>,
+    /// Sections written, in output order; empty unless converted.
+    pub sections: Vec<SectionInfo>,
+    pub report: ConvertReport,
+}
+
+impl BookConversionResult {
+    /// Not skipped, and either not converted or converted with errors.
+    pub fn failed(&self) -> bool {
+        self.skipped.is_none()
+            && (self.output_path.is_none()
+                || self
+                    .diagnostics
+                    .iter()
+                    .any(|diagnostic| diagnostic.level == DiagnosticLevel::Error))
+    }
+
+    /// Turns a converted book into a failed one because its outputs could
+    /// not be written after conversion.
+    pub(crate) fn fail_output(&mut self, err: &ConvertError) {
+        tracing::error!(path = %self.input_path.display(), "failed: {}", err.full_message());
+        self.diagnostics.push(Diagnostic {
+            level: DiagnosticLevel::Error,
+            code: None,
+            message: format!(
+                "Failed to write outputs for {}: {}",
+                self.title,
+                err.full_message()
+            ),
+        });
+        self.output_path = None;
+        self.error_kind = Some(err.kind());
+        self.report = ConvertReport::default();
+    }
+}
+
+/// What a book refers to but does not contain (or could not be read), by
+/// resolved href. Links include their `#fragment`.
+#[derive(Clone, Debug, Default)]
+pub struct MissingResources {
+    pub images: BTreeSet<String>,
+    pub stylesheets: BTreeSet<String>,
+    pub links: BTreeSet<String>,
+}
+
+impl MissingResources {
+    pub fn is_empty(&self) -> bool {
+        self.images.is_empty() && self.stylesheets.is_empty() && self.links.is_empty()
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ConversionSummary {
+    pub books: Vec<BookConversionResult>,
+}
+
+impl ConversionSummary {
+    pub fn failure_count(&self) -> usize {
+        self.books
+            .iter()
+            .filter(|book| book.output_path.is_none() && book.skipped.is_none())
+            .count()
+    }
+
+    pub fn skipped_count(&self) -> usize {
+        self.books
+            .iter()
+            .filter(|book| book.skipped.is_some())
+            .count()
+    }
+
+    pub fn success_count(&self) -> usize {
+        self.books
+            .len()
+            .saturating_sub(self.failure_count() + self.skipped_count())
+    }
+}
+
+#[derive(Clone, Debug)]
+struct TocEntryInfo {
+    label: String,
+    href_path: String,
+    fragment: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+struct ContentDoc {
+    href_path: String,
+    document: NodeRef,
+}
+
+impl ContentDoc {
+    /// Footnote references and bodies of the document, for rendering any of its nodes.
+    fn note_index(&self, render_options: &RenderOptions) -> markdown::NoteIndex {
+        markdown::NoteIndex::build(&self.document, &self.href_path, &render_options.book_notes)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct HeadingCandidate {
+    spine_idx: usize,
+    score: f32,
+    label: String,
+}
+
+#[derive(Clone, Debug)]
+struct HeadingSplit {
+    label: Option<String>,
+    text: String,
+    anchors: Vec<String>,
+    start_fragment: Option<String>,
+    end_fragment: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct SectionRecord {
+    title: String,
+    text: String,
+    start_href: String,
+    start_fragment: Option<String>,
+    end_href: Option<String>,
+    end_fragment: Option<String>,
+    spine_start: usize,
+    spine_end: usize,
+    anchors: Vec<String>,
+    section_id: String,
+    output_path: String,
+    /// Anchor the renderer gives the section heading, when the slug strategy
+    /// names one; links use `section_id` otherwise.
+    heading_anchor: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+struct FigureRecord {
+    caption: String,
+    href: String,
+    fragment: Option<String>,
+    /// Whether the renderer writes an anchor for `fragment`, so links can use it.
+    anchored: bool,
+    image: Option<String>,
+    section_idx: usize,
+}
+
+#[derive(Clone, Debug, Default)]
+struct PostprocessStats {
+    link_rewritten: usize,
+    link_unresolved: usize,
+    unresolved_targets: BTreeSet<String>,
+    /// Plan section ids that matched no detected section.
+    plan_missing: Vec<String>,
+    cleanup_changes: usize,
+    /// What the book's cleanup profile removed, when one applied.
+    profile_cleanup: Option<ProfileCleanup>,
+    /// What `strip_watermarks` removed, when enabled.
+    watermarks: Option<WatermarkReport>,
+    notes_written: usize,
+    sections_merged: usize,
+    global_note_lines: Vec<String>,
+}
+
+const COMPLEX_HTML_TAGS: &[&str] = &[
+    "table",
+    "thead",
+    "tbody",
+    "tr",
+    "td",
+    "th",
+    "figure",
+    "figcaption",
+    "svg",
+    "math",
+];
+
+const READABLE_MIME: &[&str] = &["application/xhtml+xml", "text/html", "image/svg+xml"];
+static MAJOR_HEADING_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"(?i)\b(?:chapter|book|part)\s+(?:[ivxlcdm]+|\d+)\b|\b(?:preface|prologue|epilogue|introduction|foreword|afterword)\b",
+    )
+    .expect("valid heading regex")
+});
+static MAJOR_HEADING_LABEL_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"(?i)\b(?:chapter|book|part)\s+(?:[ivxlcdm]+|\d+)(?:\s*[:.-]?\s*[a-z0-9][a-z0-9' -]{0,70})?|\b(?:preface|prologue|epilogue|introduction|foreword|afterword)\b",
+    )
+    .expect("valid heading label regex")
+});
+static OCR_NOISE_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)estimated\s+to\s+be\s+only\s+\d+(?:\.\d+)?%\s+accurate")
+        .expect("valid ocr regex")
+});
+pub(crate) static MARKDOWN_LINK_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(!?)\[((?:[^\]\\]|\\.)+)\]\(([^)]+)\)").expect("valid markdown link regex")
+});
+/// An anchor line as the renderer writes it above a heading that is a link target.
+static HTML_ANCHOR_LINE_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"^<a id="([^"]*)"></a>$"#).expect("valid anchor line regex"));
+static HTML_HREF_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"(?i)(<a\b[^>]*?\bhref=")([^"]+)(")"#).expect("valid html href regex")
+});
+static FOOTNOTE_DEF_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^\[\^([^\]]+)\]:\s*(.*)$").expect("valid footnote regex"));
+
+/// EPUB files under `input` (recursively), or `input` itself when it is a file.
+pub fn collect_epub_paths(input: &Path) -> Vec<PathBuf> {
+    if input.is_file() {
+        return vec![input.to_path_buf()];
+    }
+    files_under(input)
+        .into_iter()
+        .filter(|path| is_epub(path))
+        .collect()
+}
+
+/// Whether `path` is named like an EPUB, in any case (`.epub`, `.EPUB`).
+pub(crate) fn is_epub(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("epub"))
+}
+
+/// Files under `dir`, recursively and in directory order. Symbolic links
+/// inside `dir` are not followed; unreadable directories are left out.
+pub(crate) fn files_under(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return files;
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_dir() {
+            files.extend(files_under(&entry.path()));
+        } else if file_type.is_file() {
+            files.push(entry.path());
+        }
+    }
+    files
+}
+
+pub fn convert_all(options: &ConvertOptions) -> Result<ConversionSummary> {
+    let options = &options.for_batch();
+    let epub_paths = collect_epub_paths(&options.input_dir);
+    if epub_paths.is_empty() {
+        return Err(ConvertError::NoInput {
+            dir: options.input_dir.clone(),
+        });
+    }
+
+    // Two runs sharing an output directory would delete each other's split files.
+    let _lock = if options.lock_output {
+        Some(OutputLock::acquire(
+            &options.output_dir,
+            std::time::Duration::from_secs(options.lock_stale_after_secs),
+        )?)
+    } else {
+        None
+    };
+
+    let started = std::time::Instant::now();
+    let mut incremental = options
+        .incremental
+        .then(|| incremental::IncrementalState::load(options));
+    let (up_to_date, pending): (Vec<_>, Vec<_>) =
+        epub_paths
+            .into_iter()
+            .enumerate()
+            .partition(|(_, epub_path)| {
+                incremental
+                    .as_ref()
+                    .is_some_and(|state| state.is_current(epub_path))
+            });
+    let (pending_order, pending_paths): (Vec<usize>, Vec<PathBuf>) = pending.into_iter().unzip();
+    let total = pending_paths.len();
+    let jobs = parallelism(options.jobs).min(total);
+    // Streamed books are written as they render, not held for a writer stage.
+    let converted = if jobs > 1 && !options.stream {
+        pipeline::convert_staged(&pending_paths, options, jobs)?
+    } else {
+        convert_batch(&pending_paths, options, |idx, epub_path| {
+            convert_one(epub_path, idx, total, &options.locking_book())
+        })?
+    };
+    let mut books: Vec<(usize, BookConversionResult)> =
+        pending_order.into_iter().zip(converted).collect();
+    books.extend(
+        up_to_date
+            .into_iter()
+            .map(|(idx, epub_path)| (idx, up_to_date_result(&epub_path))),
+    );
+    books.sort_by_key(|(idx, _)| *idx);
+    let books: Vec<BookConversionResult> = books.into_iter().map(|(_, book)| book).collect();
+    if let Some(Err(err)) = incremental.as_mut().map(|state| state.record_run(&books)) {
+        tracing::warn!(
+            "could not update {}: {}",
+            INCREMENTAL_STATE_FILE_NAME,
+            err.full_message()
+        );
+    }
+    if let Some(path) = &options.plan_export {
+        ConversionPlan::from_results(&books).write(path, options)?;
+    }
+    skip_list::record_run(options, &books);
+    let summary = ConversionSummary { books };
+    usage::record_usage(options, &summary, started);
+    Ok(summary)
+}
+
+/// The result of a book an incremental batch leaves alone.
+pub(crate) fn up_to_date_result(epub_path: &Path) -> BookConversionResult {
+    let title = epub_path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("book")
+        .to_string();
+    let reason = format!("{title} is up to date");
+    skipped_result(epub_path, title, reason)
+}
+
+/// Runs `convert` on every book, `options.jobs` at a time, and returns the
+/// results in input order.
+fn convert_batch(
+    epub_paths: &[PathBuf],
+    options: &ConvertOptions,
+    convert: impl Fn(usize, &Path) -> BookConversionResult + Sync,
+) -> Result<Vec<BookConversionResult>> {
+    let stopped = AtomicBool::new(false);
+    let convert = |idx: usize, epub_path: &Path| {
+        if stopped.load(Ordering::Relaxed) {
+            return not_started_result(epub_path);
+        }
+        let result = convert(idx, epub_path);
+        if options.fail_fast && result.failed() {
+            stopped.store(true, Ordering::Relaxed);
+        }
+        result
+    };
+    let jobs = parallelism(options.jobs).min(epub_paths.len());
+    let mut results: Vec<(usize, BookConversionResult)> = if jobs <= 1 {
+        epub_paths
+            .iter()
+            .enumerate()
+            .take_while(|_| !options.is_cancelled())
+            .map(|(idx, epub_path)| (idx, convert(idx, epub_path)))
+            .collect()
+    } else {
+        // Workers take the next unclaimed book; results are put back in input order.
+        let next = AtomicUsize::new(0);
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..jobs)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut done = Vec::new();
+                        loop {
+                            if options.is_cancelled() {
+                                break;
+                            }
+                            let idx = next.fetch_add(1, Ordering::Relaxed);
+                            let Some(epub_path) = epub_paths.get(idx) else {
+                                break;
+                            };
+                            done.push((idx, convert(idx, epub_path)));
+                        }
+                        done
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().expect("conversion worker panicked"))
+                .collect()
+        })
+    };
+    // Books cut short are failed results; the batch as a whole is not done.
+    options.check_cancelled()?;
+    results.sort_by_key(|(idx, _)| *idx);
+    Ok(results.into_iter().map(|(_, result)| result).collect())
+}
+
+/// Threads to use for a `jobs` setting, where `0` means every available core.
+/// Always one on `wasm32`, which cannot spawn threads.
+pub(crate) fn parallelism(jobs: usize) -> usize {
+    if cfg!(target_arch = "wasm32") {
+        return 1;
+    }
+    match jobs {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        jobs => jobs,
+    }
+}
+
+/// Converts the book read into `bytes`, leaving its outputs in
+/// `options.output_sink`.
+pub(crate) fn convert_in_memory(
+    epub_path: &Path,
+    bytes: std::io::Result<Vec<u8>>,
+    options: &ConvertOptions,
+) -> Result<BookConversionResult> {
+    let open_failed = |source: BoxError| ConvertError::OpenFailed {
+        path: epub_path.to_path_buf(),
+        source,
+    };
+    let bytes = bytes.map_err(|err| open_failed(err.into()))?;
+    let epub = Epub::read(std::io::Cursor::new(bytes)).map_err(|err| open_failed(err.into()))?;
+    let _book = tracing::info_span!("book", path = %epub_path.display()).entered();
+    convert_book_epub(&epub, epub_path, options)
+}
+
+/// Converts one book of a batch; failures become an error result for that book.
+fn convert_one(
+    epub_path: &Path,
+    index: usize,
+    total: usize,
+    options: &ConvertOptions,
+) -> BookConversionResult {
+    convert_one_with(epub_path, index, total, options, || {
+        convert_epub_result(epub_path, options)
+    })
+}
+
+/// [`convert_one`], with the book's conversion done by `convert`.
+fn convert_one_with(
+    epub_path: &Path,
+    index: usize,
+    total: usize,
+    options: &ConvertOptions,
+    convert: impl FnOnce() -> Result<BookConversionResult>,
+) -> BookConversionResult {
+    options.report(Progress::BookStarted {
+        path: epub_path,
+        index,
+        total,
+    });
+    let listed = options
+        .skip_list
+        .as_ref()
+        .and_then(|list| list.by_path(epub_path));
+    if let Some(entry) = listed {
+        tracing::info!(path = %epub_path.display(), "skipped: on the skip list");
+        let title = epub_path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("book")
+            .to_string();
+        let reason = skip_list_reason(&title, entry);
+        let result = skipped_result(epub_path, title, reason);
+        options.report(Progress::BookFinished {
+            path: epub_path,
+            index,
+            total,
+        });
+        return result;
+    }
+    let result = isolate_panics(convert).unwrap_or_else(|err| {
+        tracing::error!(path = %epub_path.display(), "failed: {}", err.full_message());
+        BookConversionResult {
+            input_path: epub_path.to_path_buf(),
+            title: epub_path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or("book")
+                .to_string(),
+            output_path: None,
+            diagnostics: vec![Diagnostic {
+                level: DiagnosticLevel::Error,
+                code: None,
+                message: format!(
+                    "Failed to parse {}: {}",
+                    epub_path.display(),
+                    err.full_message()
+                ),
+            }],
+            missing_resources: MissingResources::default(),
+            skipped: None,
+            error_kind: Some(err.kind()),
+            sections: Vec::new(),
+            report: ConvertReport::default(),
+        }
+    });
+    if let Some(hook) = options
+        .on_warning
+        .as_ref()
+        .filter(|_| !options.is_cancelled())
+    {
+        for diagnostic in &result.diagnostics {
+            if diagnostic.level != DiagnosticLevel::Info {
+                hook.emit(epub_path, diagnostic);
+            }
+        }
+    }
+    options.report(Progress::BookFinished {
+        path: epub_path,
+        index,
+        total,
+    });
+    result
+}
+
+/// Mirrors a diagnostic as a `tracing` event, inside the current book span.
+fn trace_diagnostic(diagnostic: &Diagnostic) {
+    let code = diagnostic.code.map(WarningCode::code);
+    match diagnostic.level {
+        DiagnosticLevel::Info => tracing::info!(code, "{}", diagnostic.message),
+        DiagnosticLevel::Warning => tracing::warn!(code, "{}", diagnostic.message),
+        DiagnosticLevel::Error => tracing::error!(code, "{}", diagnostic.message),
+    }
+}
+
+/// Runs one book's work, turning a panic inside the HTML/markdown stack into an
+/// error so a single malformed book cannot abort a whole batch.
+fn isolate_panics<T>(work: impl FnOnce() -> Result<T>) -> Result<T> {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(work)) {
+        Ok(result) => result,
+        Err(payload) => {
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown cause".to_string());
+            Err(ConvertError::Panicked(message))
+        }
+    }
+}
+
+/// Converts one book, failing when it is skipped by a filter.
+pub fn convert_epub(epub_path: &Path, options: &ConvertOptions) -> Result<ConvertReport> {
+    let result = convert_epub_result(epub_path, options)?;
+    if let Some(reason) = result.skipped {
+        return Err(ConvertError::Skipped {
+            path: epub_path.to_path_buf(),
+            reason,
+        });
+    }
+    Ok(result.report)
+}
+
+pub(crate) fn open_epub(epub_path: &Path) -> Result<Epub> {
+    Epub::open(epub_path).map_err(|err| ConvertError::OpenFailed {
+        path: epub_path.to_path_buf(),
+        source: err.into(),
+    })
+}
+
+fn skipped_result(epub_path: &Path, title: String, reason: String) -> BookConversionResult {
+    BookConversionResult {
+        input_path: epub_path.to_path_buf(),
+        title,
+        output_path: None,
+        diagnostics: Vec::new(),
+        missing_resources: MissingResources::default(),
+        skipped: Some(reason),
+        error_kind: None,
+        sections: Vec::new(),
+        report: ConvertReport::default(),
+    }
+}
+
+/// Stands in for a book `fail_fast` kept from being started.
+pub(crate) fn not_started_result(epub_path: &Path) -> BookConversionResult {
+    let title = epub_path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("book")
+        .to_string();
+    let reason = format!("{title} was not started after an earlier book failed");
+    skipped_result(epub_path, title, reason)
+}
+
+fn skip_list_reason(title: &str, entry: &SkipEntry) -> String {
+    match &entry.reason {
+        Some(reason) => format!("{title} is on the skip list: {reason}"),
+        None => format!("{title} is on the skip list"),
+    }
+}
+
+pub fn convert_epub_result(
+    epub_path: &Path,
+    options: &ConvertOptions,
+) -> Result<BookConversionResult> {
+    let _book = tracing::info_span!("book", path = %epub_path.display()).entered();
+    let epub = open_epub(epub_path)?;
+    convert_book_epub(&epub, epub_path, options)
+}
+
+/// Converts an opened book with its overrides applied, as a preview, a
+/// stream or a full conversion as its options say.
+fn convert_book_epub(
+    epub: &Epub,
+    epub_path: &Path,
+    options: &ConvertOptions,
+) -> Result<BookConversionResult> {
+    let options = &*overrides::book_options(epub_path, epub, options);
+    if options.preview {
+        return preview::convert_preview(epub, epub_path, options)
+            .inspect(|result| result.diagnostics.iter().for_each(trace_diagnostic));
+    }
+    if options.stream {
+        return streaming::convert_streaming(epub, epub_path, options)
+            .inspect(|result| result.diagnostics.iter().for_each(trace_diagnostic));
+    }
+    convert_opened_epub(epub, epub_path, options).map(|(result, _)| result)
+}
+
+/// Converts an opened book, also returning its rendered sections.
+/// `epub_path` names the book in outputs and diagnostics.
+pub(crate) fn convert_opened_epub(
+    epub: &Epub,
+    epub_path: &Path,
+    options: &ConvertOptions,
+) -> Result<(BookConversionResult, Book)> {
+    let watermarks = options
+        .strip_watermarks
+        .then(|| watermarks::Watermarks::detect(epub));
+    let mut title = book_title(epub, epub_path);
+    let mut rights = rights::classify_rights(epub, options.public_domain_before);
+    if let Some(watermarks) = &watermarks {
+        title = watermarks.scrub_metadata("title", &title);
+        rights.rights = rights
+            .rights
+            .map(|statement| watermarks.scrub_metadata("rights", &statement));
+    }
+    if options.only_public_domain && !rights.status.is_shareable() {
+        tracing::info!(status = rights.status.name(), "skipped: {}", rights.reason);
+        let reason = format!(
+            "{title} is {}: {}",
+            rights.status.name().replace('_', " "),
+            rights.reason
+        );
+        return Ok((skipped_result(epub_path, title, reason), Book::default()));
+    }
+    let planned = match &options.plan {
+        Some(plan) => match plan.book(epub_path) {
+            Some(planned) => Some(planned),
+            None => {
+                tracing::info!("skipped: not in the plan");
+                let reason = format!("{title} is not in the plan");
+                return Ok((skipped_result(epub_path, title, reason), Book::default()));
+            }
+        },
+        None => None,
+    };
+
+    let listed = options.skip_list.as_ref().and_then(|list| {
+        let identifier = epub.metadata().identifier()?;
+        list.by_identifier(identifier.value())
+    });
+    if let Some(entry) = listed {
+        tracing::info!("skipped: on the skip list");
+        let reason = skip_list_reason(&title, entry);
+        return Ok((skipped_result(epub_path, title, reason), Book::default()));
+    }
+
+    let author = epub
+        .metadata()
+        .creators()
+        .next()
+        .map(|c| c.value().to_string())
+        .map(|author| match &watermarks {
+            Some(watermarks) => watermarks.scrub_metadata("author", &author),
+            None => author,
+        });
+    let language = epub
+        .metadata()
+        .language()
+        .map(|language| language.value().trim().to_string())
+        .filter(|language| !language.is_empty());
+
+    let book_fields = BookFields::from_epub(epub, &title, options.slug_strategy.as_ref());
+    let Some(book_slug) = resolve_output_conflict(
+        book_fields.book_path(options.output_template.as_ref()),
+        options.split_chapters,
+        options,
+    ) else {
+        tracing::info!("skipped: output exists");
+        let reason = format!("{title} already exists in the output directory");
+        return Ok((skipped_result(epub_path, title, reason), Book::default()));
+    };
+    lock_book(&book_slug, options)?;
+    let book_dir = options.output_dir.join(&book_slug);
+    let image_root = book_dir.join("images");
+    let media_root = book_dir.join("media");
+    let style_root = book_dir.join("styles");
+    let fonts_root = book_dir.join("fonts");
+    let thumbs_root = book_dir.join("thumbs");
+    let image_link_prefix = asset_link_prefix(options, &book_slug, "images");
+    let media_link_prefix = asset_link_prefix(options, &book_slug, "media");
+    let style_link_prefix = asset_link_prefix(options, &book_slug, "styles");
+    let font_link_prefix = asset_link_prefix(options, &book_slug, "fonts");
+    let thumb_link_prefix = asset_link_prefix(options, &book_slug, "thumbs");
+
+    let image_extractor = images::ImageExtractor::new(
+        image_root.clone(),
+        image_link_prefix.clone(),
+        images::ImageOptions::from_convert_options(options, epub_path),
+    );
+    let mut extracted_media: HashMap<String, String> = HashMap::new();
+    let mut extracted_media_count = 0usize;
+
+    let mut css_hrefs: HashSet<String> = HashSet::new();
+    let mut inline_styles: Vec<String> = Vec::new();
+    let mut warnings: Vec<(WarningCode, String)> = Vec::new();
+
+    let mut warn = |code: WarningCode, message: String| {
+        if !options.suppress_warnings.contains(&code) {
+            warnings.push((code, message));
+        }
+    };
+
+    if options.media_all {
+        for image in epub.manifest().images() {
+            let href = image.href().as_str().to_string();
+            let _ = image_extractor.extract(epub, &href);
+        }
+        for entry in epub.manifest().entries() {
+            let kind = entry.resource_kind();
+            if !(kind.is_audio() || kind.is_video()) {
+                continue;
+            }
+            let href = entry.href().as_str().to_string();
+            let _ = extract_media_file(
+                epub,
+                &href,
+                &media_root,
+                &media_link_prefix,
+                &mut extracted_media,
+                &mut extracted_media_count,
+                &*options.output_sink,
+            );
+        }
+    }
+
+    // Cover pages rarely reference the cover image in a way the renderer keeps,
+    // so it is extracted on its own, with or without --media-all.
+    let cover_link = covers::cover_href(epub).and_then(|href| {
+        let link = image_extractor.extract(epub, &href);
+        if link.is_none() {
+            warn(
+                WarningCode::CoverUnreadable,
+                format!("Failed to extract cover image {href}"),
+            );
+        }
+        link
+    });
+
+    let mut content_cache = ContentCache::from_options(options);
+
+    let toc_entries_raw = build_toc_entries(epub, &options.extra_readable_types)?;
+    let (toc_entries, nav_removed) = cleanup_toc_entries(toc_entries_raw, options.nav_cleanup);
+    let spine_hrefs = reading_order(epub, &options.extra_readable_types);
+    let spine_index_by_href: HashMap<String, usize> = spine_hrefs
+        .iter()
+        .enumerate()
+        .map(|(idx, href)| (href.clone(), idx))
+        .collect();
+    let section_jobs = parallelism(options.section_jobs);
+    if section_jobs > 1 {
+        tracing::debug!(
+            documents = spine_hrefs.len(),
+            threads = section_jobs,
+            "prefetching"
+        );
+        prefetch_documents(epub_path, &spine_hrefs, section_jobs, &mut content_cache);
+    }
+    let mut render_options = RenderOptions::from_convert_options(options);
+    render_options.rtl = book_is_rtl(epub, &spine_hrefs, &mut content_cache, options);
+    let mut endnotes_consolidated = 0usize;
+    if options.consolidate_endnotes {
+        let book_notes = build_book_notes(epub, &spine_hrefs, &mut content_cache);
+        endnotes_consolidated = book_notes.bodies.len();
+        render_options.book_notes = Rc::new(book_notes);
+    }
+    if options.anchor_mode != AnchorMode::Off {
+        let mut link_targets =
+            collect_link_targets(epub, &spine_hrefs, &toc_entries, &mut content_cache);
+        // The list of figures links to each figure's id.
+        if options.list_of_figures {
+            link_targets.extend(figure_link_targets(epub, &spine_hrefs, &mut content_cache));
+        }
+        render_options.link_targets = Rc::new(link_targets);
+    }
+    let images_removed = if options.skip_images {
+        decorative::remove_all_images(epub, &spine_hrefs, &mut content_cache)
+    } else {
+        0
+    };
+    let svgs_written = svg::replace_inline_svgs(
+        epub,
+        &spine_hrefs,
+        &mut content_cache,
+        options.svg_mode,
+        &svg::SvgTargets {
+            image_root: &image_root,
+            image_link_prefix: &image_link_prefix,
+            images: &image_extractor,
+        },
+        &mut |message| warn(WarningCode::SvgExportFailed, message),
+    );
+
+    let media_elements_replaced = media::replace_media_elements(
+        epub,
+        &spine_hrefs,
+        &mut content_cache,
+        &media_root,
+        &media_link_prefix,
+        &mut extracted_media,
+        &mut extracted_media_count,
+        &*options.output_sink,
+    );
+    let decorative_images_removed = if options.skip_decorative_images {
+        decorative::remove_decorative_images(epub, &spine_hrefs, &mut content_cache)
+    } else {
+        0
+    };
+    let cleanup_profile = options
+        .cleanup_profile
+        .as_deref()
+        .and_then(|choice| options.cleanup_profiles.select(choice, epub));
+    let mut profile_cleanup = cleanup_profile.map(|(name, profile)| {
+        tracing::debug!(profile = name, "applying cleanup profile");
+        ProfileCleanup {
+            profile: name.to_string(),
+            elements_stripped: cleanup_profiles::strip_classes(
+                profile,
+                epub,
+                &spine_hrefs,
+                &mut content_cache,
+            ),
+            ..ProfileCleanup::default()
+        }
+    });
+
+    let mut image_resolver = |src: &str, base_href: &str| -> Option<String> {
+        resolve_and_extract_image(epub, src, base_href, &image_extractor)
+    };
+
+    let (toc_is_degenerate, toc_entry_count, toc_unique_count, toc_coverage_ratio) =
+        toc_degeneracy_stats(&toc_entries, spine_hrefs.len());
+    let mut sections: Vec<SectionRecord> = Vec::new();
+
+    let mut use_heading_fallback = false;
+    let attempt_heading_fallback = match options.chapter_fallback {
+        ChapterFallbackMode::Off => false,
+        ChapterFallbackMode::Auto => {
+            if toc_is_degenerate {
+                true
+            } else {
+                warn(
+                    WarningCode::HeadingFallbackNotNeeded,
+                    format!(
+                        "heading fallback skipped for {}: TOC not degenerate (entries={}, unique_hrefs={}, coverage={:.2}).",
+                        title, toc_entry_count, toc_unique_count, toc_coverage_ratio
+                    ),
+                );
+                false
+            }
+        }
+        ChapterFallbackMode::Force => true,
+    };
+
+    if attempt_heading_fallback {
+        if let Some(starts) = heading_fallback_starts(
+            epub,
+            &toc_entries,
+            &spine_hrefs,
+            &mut content_cache,
+            &options.heading_patterns,
+        ) {
+            warn(
+                WarningCode::HeadingFallbackUsed,
+                format!(
+                    "using heading fallback for {} (mode={:?}, toc_entries={}, spine_docs={}, detected_starts={}).",
+                    title,
+                    options.chapter_fallback,
+                    toc_entry_count,
+                    spine_hrefs.len(),
+                    starts.len() - 1
+                ),
+            );
+            use_heading_fallback = true;
+
+            for (start_pos, (start_idx, section_label)) in starts.iter().enumerate() {
+                let _progress = options.section_progress(epub_path, start_pos, starts.len());
+                options.check_cancelled()?;
+                let _section =
+                    tracing::debug_span!("section", index = start_pos, title = %section_label)
+                        .entered();
+                let next_start = starts
+                    .get(start_pos + 1)
+                    .map(|(idx, _)| *idx)
+                    .unwrap_or(spine_hrefs.len());
+                if next_start == 0 || next_start <= *start_idx {
+                    continue;
+                }
+                let Some(start_href) = spine_hrefs.get(*start_idx) else {
+                    continue;
+                };
+                let end_idx = next_start - 1;
+                let mut chunks: Vec<String> = Vec::new();
+                let mut anchors: HashSet<String> = HashSet::new();
+                for spine_idx in *start_idx..=end_idx {
+                    let Some(href) = spine_hrefs.get(spine_idx) else {
+                        continue;
+                    };
+                    let content = match load_content(epub, href, &mut content_cache) {
+                        Ok(content) => content,
+                        Err(err) => {
+                            warn(
+                                WarningCode::UnreadableSpineItem,
+                                format!(
+                                    "{title}: left out a spine document: {}",
+                                    err.full_message()
+                                ),
+                            );
+                            continue;
+                        }
+                    };
+                    if options.markdown_mode == MarkdownMode::Rich {
+                        collect_css(content, href, &mut css_hrefs, &mut inline_styles);
+                    }
+                    let (part, part_anchors) = render_partial_with_anchors(
+                        content,
+                        &render_options,
+                        None,
+                        None,
+                        &mut image_resolver,
+                    );
+                    for anchor in part_anchors {
+                        anchors.insert(anchor);
+                    }
+                    if let Some(part) = part {
+                        if !part.trim().is_empty() {
+                            chunks.push(part);
+                        }
+                    }
+                }
+                let text = chunks.join("\n\n").trim().to_string();
+                if !text.is_empty() {
+                    sections.push(SectionRecord {
+                        title: section_label.clone(),
+                        text,
+                        start_href: start_href.clone(),
+                        start_fragment: None,
+                        end_href: spine_hrefs.get(end_idx).cloned(),
+                        end_fragment: None,
+                        spine_start: *start_idx,
+                        spine_end: end_idx,
+                        anchors: {
+                            let mut values: Vec<String> = anchors.into_iter().collect();
+                            values.sort();
+                            values
+                        },
+                        section_id: String::new(),
+                        output_path: String::new(),
+                        heading_anchor: None,
+                    });
+                }
+            }
+        } else {
+            warn(
+                WarningCode::HeadingFallbackLowConfidence,
+                format!(
+                    "heading fallback skipped for {}: insufficient heading confidence.",
+                    title
+                ),
+            );
+        }
+    }
+
+    if !use_heading_fallback && !toc_entries.is_empty() {
+        for (idx, entry) in toc_entries.iter().enumerate() {
+            let _progress = options.section_progress(epub_path, idx, toc_entries.len());
+            options.check_cancelled()?;
+            let _section =
+                tracing::debug_span!("section", index = idx, title = %entry.label).entered();
+            let Some((start_idx, end_idx)) =
+                toc_section_span(&toc_entries, idx, &spine_index_by_href, spine_hrefs.len())
+            else {
+                continue;
+            };
+            let next_entry = toc_entries.get(idx + 1);
+
+            let parts = toc_section_parts(entry, next_entry, start_idx, end_idx);
+
+            if let (Some(level), [(spine_idx, None, None)]) =
+                (options.split_on_heading_level, parts.as_slice())
+            {
+                let Some(href) = spine_hrefs.get(*spine_idx) else {
+                    continue;
+                };
+                let content = match load_content(epub, href, &mut content_cache) {
+                    Ok(content) => content,
+                    Err(err) => {
+                        warn(
+                            WarningCode::UnreadableSpineItem,
+                            format!("{title}: left out a spine document: {}", err.full_message()),
+                        );
+                        continue;
+                    }
+                };
+                if options.markdown_mode == MarkdownMode::Rich {
+                    collect_css(content, href, &mut css_hrefs, &mut inline_styles);
+                }
+                if let Some(splits) =
+                    split_content_at_headings(content, &render_options, level, &mut image_resolver)
+                {
+                    push_heading_splits(&mut sections, splits, &entry.label, href, *spine_idx);
+                    continue;
+                }
+            }
+
+            let mut chunks: Vec<String> = Vec::new();
+            let mut section_anchors: HashSet<String> = HashSet::new();
+            for (spine_idx, start_fragment, end_fragment) in parts {
+                let Some(href) = spine_hrefs.get(spine_idx) else {
+                    continue;
+                };
+                let content = match load_content(epub, href, &mut content_cache) {
+                    Ok(content) => content,
+                    Err(err) => {
+                        warn(
+                            WarningCode::UnreadableSpineItem,
+                            format!("{title}: left out a spine document: {}", err.full_message()),
+                        );
+                        continue;
+                    }
+                };
+                if options.markdown_mode == MarkdownMode::Rich {
+                    collect_css(content, href, &mut css_hrefs, &mut inline_styles);
+                }
+
+                let (part, part_anchors) = render_partial_with_anchors(
+                    content,
+                    &render_options,
+                    start_fragment,
+                    end_fragment,
+                    &mut image_resolver,
+                );
+                for anchor in part_anchors {
+                    section_anchors.insert(anchor);
+                }
+                if let Some(part) = part {
+                    if !part.trim().is_empty() {
+                        chunks.push(part);
+                    }
+                }
+            }
+
+            let text = chunks.join("\n\n").trim().to_string();
+            if !text.is_empty() {
+                sections.push(SectionRecord {
+                    title: entry.label.clone(),
+                    text,
+                    start_href: entry.href_path.clone(),
+                    start_fragment: entry.fragment.clone(),
+                    end_href: next_entry.map(|n| n.href_path.clone()),
+                    end_fragment: next_entry.and_then(|n| n.fragment.clone()),
+                    spine_start: start_idx,
+                    spine_end: end_idx,
+                    anchors: {
+                        let mut values: Vec<String> = section_anchors.into_iter().collect();
+                        values.sort();
+                        values
+                    },
+                    section_id: String::new(),
+                    output_path: String::new(),
+                    heading_anchor: None,
+                });
+            }
+        }
+    } else if !use_heading_fallback {
+        for spine_entry in epub.spine().entries() {
+            if let Some(manifest_entry) = spine_entry.manifest_entry() {
+                if !is_readable(manifest_entry.media_type(), &options.extra_readable_types) {
+                    continue;
+                }
+                let href_path = manifest_entry.href().as_str().to_string();
+                let label = manifest_entry.href().name().decode().to_string();
+                let index = spine_index_by_href.get(&href_path).copied().unwrap_or(0);
+                let _progress = options.section_progress(epub_path, index, spine_hrefs.len());
+                options.check_cancelled()?;
+                let _section = tracing::debug_span!("section", index, title = %label).entered();
+                let content = match load_content(epub, &href_path, &mut content_cache) {
+                    Ok(content) => content,
+                    Err(err) => {
+                        warn(
+                            WarningCode::UnreadableSpineItem,
+                            format!("{title}: left out a spine document: {}", err.full_message()),
+                        );
+                        continue;
+                    }
+                };
+                if options.markdown_mode == MarkdownMode::Rich {
+                    collect_css(content, &href_path, &mut css_hrefs, &mut inline_styles);
+                }
+                let spine_idx = spine_index_by_href
+                    .get(&content.href_path)
+                    .copied()
+                    .unwrap_or(0);
+                if let Some(level) = options.split_on_heading_level {
+                    if let Some(splits) = split_content_at_headings(
+                        content,
+                        &render_options,
+                        level,
+                        &mut image_resolver,
+                    ) {
+                        push_heading_splits(&mut sections, splits, &label, &href_path, spine_idx);
+                        continue;
+                    }
+                }
+                let (text_opt, anchors) = render_partial_with_anchors(
+                    content,
+                    &render_options,
+                    None,
+                    None,
+                    &mut image_resolver,
+                );
+                if let Some(text) = text_opt {
+                    if !text.trim().is_empty() {
+                        sections.push(SectionRecord {
+                            title: label,
+                            text,
+                            start_href: href_path,
+                            start_fragment: None,
+                            end_href: None,
+                            end_fragment: None,
+                            spine_start: spine_idx,
+                            spine_end: spine_idx,
+                            anchors,
+                            section_id: String::new(),
+                            output_path: String::new(),
+                            heading_anchor: None,
+                        });
+                    }
+                }
+            }
+        }
+    }
+    if let (Some((_, profile)), Some(cleanup)) = (cleanup_profile, profile_cleanup.as_mut()) {
+        cleanup_profiles::clean_sections(profile, &mut sections, cleanup);
+    }
+    if let Some(watermarks) = &watermarks {
+        watermarks.scrub_sections(&mut sections);
+    }
+    if sections.is_empty() {
+        return Err(ConvertError::NoReadableSections {
+            path: epub_path.to_path_buf(),
+        });
+    }
+
+    let mut stats = postprocess_sections(
+        &mut sections,
+        options.split_chapters,
+        SectionNaming::new(options, &book_fields),
+        &book_slug,
+        options.ocr_cleanup,
+        options.notes_mode,
+        options.min_section_words,
+        &extracted_media,
+        &title,
+        options.slug_strategy.as_ref(),
+        planned,
+    );
+    stats.profile_cleanup = profile_cleanup;
+    stats.watermarks = watermarks
+        .as_ref()
+        .map(|watermarks| watermarks.report.borrow().clone());
+    if !stats.plan_missing.is_empty() {
+        warn(
+            WarningCode::PlanSectionsMissing,
+            format!(
+                "{title}: {} planned sections were not detected ({}); check that the plan was \
+exported with the same section settings.",
+                stats.plan_missing.len(),
+                stats.plan_missing.join(", ")
+            ),
+        );
+    }
+    if sections.is_empty() {
+        return Err(ConvertError::PlanLeavesNoSections {
+            path: epub_path.to_path_buf(),
+        });
+    }
+    tracing::debug!(
+        sections = sections.len(),
+        links_rewritten = stats.link_rewritten,
+        "sections rendered"
+    );
+    if stats.link_unresolved > 0 {
+        warn(
+            WarningCode::UnresolvedLinks,
+            format!(
+                "{}: unresolved internal links detected ({}).",
+                title, stats.link_unresolved
+            ),
+        );
+    }
+
+    // Fonts only matter where the book's CSS is carried over.
+    let font_files = if options.extract_fonts && options.markdown_mode == MarkdownMode::Rich {
+        fonts::extract_fonts(epub, &fonts_root, &*options.output_sink, &mut |message| {
+            warn(WarningCode::FontCopyFailed, message)
+        })
+    } else {
+        fonts::FontFiles::default()
+    };
+
+    let writer = text_output::TextWriter::new(options);
+    let mut missing_stylesheets = BTreeSet::new();
+    let style_header_lines = if options.markdown_mode == MarkdownMode::Rich {
+        build_style_header(
+            epub,
+            &css_hrefs,
+            &inline_styles,
+            &style_root,
+            &style_link_prefix,
+            options.style,
+            &font_files,
+            &fonts_root,
+            &font_link_prefix,
+            &mut missing_stylesheets,
+            &writer,
+        )?
+    } else {
+        Vec::new()
+    };
+    let missing_resources = MissingResources {
+        images: image_extractor.options.take_missing(),
+        stylesheets: missing_stylesheets,
+        links: stats.unresolved_targets.clone(),
+    };
+    if !missing_resources.images.is_empty() {
+        warn(
+            WarningCode::MissingImages,
+            format!(
+                "{title}: {} referenced images not found: {}",
+                missing_resources.images.len(),
+                missing_resources
+                    .images
+                    .iter()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        );
+    }
+    if !missing_resources.stylesheets.is_empty() {
+        warn(
+            WarningCode::MissingStylesheets,
+            format!(
+                "{title}: {} referenced stylesheets not found: {}",
+                missing_resources.stylesheets.len(),
+                missing_resources
+                    .stylesheets
+                    .iter()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        );
+    }
+
+    let extracted_images = image_extractor.links();
+    let extracted_count = image_extractor.written();
+    let image_options = &image_extractor.options;
+    let chapter_thumbnails = if options.chapter_thumbnails {
+        thumbnails::generate_chapter_thumbnails(
+            epub,
+            &sections,
+            &extracted_images,
+            &thumbs_root,
+            &thumb_link_prefix,
+            options.thumbnail_max_edge,
+            &*options.output_sink,
+            &mut |message| warn(WarningCode::ThumbnailFailed, message),
+        )
+    } else {
+        HashMap::new()
+    };
+
+    let figures = if options.list_of_figures {
+        collect_figures(
+            epub,
+            &spine_hrefs,
+            &mut content_cache,
+            &sections,
+            &extracted_images,
+            &render_options.link_targets,
+        )
+    } else {
+        Vec::new()
+    };
+    let figure_lines = build_list_of_figures(&figures, &sections, options.split_chapters);
+
+    // Before the RTL wrapper is added, which is markup rather than text.
+    let section_fingerprints: Vec<Fingerprint> = if options.fingerprints {
+        sections
+            .iter()
+            .map(|section| Fingerprint::of_text(&section.text))
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let book_fingerprint = options
+        .fingerprints
+        .then(|| Fingerprint::of_texts(sections.iter().map(|section| section.text.as_str())));
+
+    if render_options.rtl && options.markdown_mode == MarkdownMode::Rich {
+        for section in &mut sections {
+            section.text = format!("<div dir=\"rtl\">\n\n{}\n\n</div>", section.text);
+        }
+    }
+
+    let return_path = write_markdown_outputs(
+        &sections,
+        options,
+        &options.output_dir,
+        &book_dir,
+        &book_slug,
+        &title,
+        author.as_ref(),
+        &style_header_lines,
+        &stats.global_note_lines,
+        &figure_lines,
+        &chapter_thumbnails,
+        cover_link.as_deref(),
+        &writer,
+    )?;
+    if writer.unmappable.get() > 0 {
+        warn(
+            WarningCode::UnmappableCharacters,
+            format!(
+                "{title}: {} characters cannot be written in {} and were replaced with ?",
+                writer.unmappable.get(),
+                writer.encoding_name()
+            ),
+        );
+    }
+    positions::write_positions_export(
+        options.export_positions,
+        epub,
+        &book_dir,
+        &return_path,
+        &book_slug,
+        &sections,
+        &mut content_cache,
+        options,
+    )?;
+    provenance::write_provenance_export(
+        options.export_provenance,
+        epub,
+        &book_dir,
+        &book_slug,
+        &sections,
+        &spine_hrefs,
+        &mut content_cache,
+        options,
+    )?;
+    audio_split::write_audio_split_export(
+        options.export_audio_split,
+        epub,
+        &book_dir,
+        &book_slug,
+        &sections,
+        &spine_hrefs,
+        options,
+    )?;
+    if options.list_of_figures {
+        write_figures_export(&book_dir, &book_slug, &figures, &sections, options)?;
+    }
+    translation::write_translation_export(
+        epub,
+        &book_dir,
+        &book_slug,
+        &sections,
+        &spine_hrefs,
+        &mut content_cache,
+        options,
+    )?;
+    flashcards::write_flashcard_export(
+        epub,
+        &book_dir,
+        &title,
+        &sections,
+        &spine_hrefs,
+        &mut content_cache,
+        options,
+    )?;
+
+    let mut extra = model::extra_metadata(epub, |property, value| match property {
+        "title" => title == value,
+        "creator" => author.as_deref() == Some(value),
+        "language" => language.as_deref() == Some(value),
+        "rights" => rights.rights.as_deref() == Some(value),
+        _ => false,
+    });
+    if let Some(watermarks) = &watermarks {
+        watermarks.scrub_extra(&mut extra);
+    }
+    write_manifest_export(
+        options.export_manifest,
+        &book_dir,
+        &title,
+        author.as_ref(),
+        language.as_deref(),
+        &book_slug,
+        &spine_hrefs,
+        &toc_entries,
+        &sections,
+        &extracted_images,
+        &extracted_media,
+        &chapter_thumbnails,
+        &rights,
+        &extra,
+        book_fingerprint.as_ref(),
+        &section_fingerprints,
+        options,
+    )?;
+    write_quality_report(
+        options.quality_report,
+        &book_dir,
+        toc_entry_count,
+        toc_unique_count,
+        toc_coverage_ratio,
+        toc_is_degenerate,
+        use_heading_fallback,
+        options,
+        &stats,
+        extracted_count,
+        extracted_media_count,
+        nav_removed,
+        &missing_resources,
+        &warnings,
+    )?;
+
+    if options.compare_view {
+        compare::write_compare_view(
+            epub,
+            &book_dir,
+            &title,
+            &sections,
+            &spine_hrefs,
+            &mut content_cache,
+            &extracted_images,
+            options.split_chapters,
+            &*options.output_sink,
+        )?;
+    }
+
+    let mut diagnostics = Vec::new();
+    if extracted_count > 0 {
+        diagnostics.push(Diagnostic {
+            level: DiagnosticLevel::Info,
+            code: None,
+            message: format!("Extracted {extracted_count} images for {title}"),
+        });
+    }
+    if extracted_media_count > 0 {
+        diagnostics.push(Diagnostic {
+            level: DiagnosticLevel::Info,
+            code: None,
+            message: format!("Extracted {extracted_media_count} media files for {title}"),
+        });
+    }
+    if !font_files.is_empty() {
+        diagnostics.push(Diagnostic {
+            level: DiagnosticLevel::Info,
+            code: None,
+            message: format!("Copied {} fonts for {title}", font_files.len()),
+        });
+    }
+    if media_elements_replaced > 0 {
+        diagnostics.push(Diagnostic {
+            level: DiagnosticLevel::Info,
+            code: None,
+            message: format!("Linked {media_elements_replaced} audio/video elements for {title}"),
+        });
+    }
+    if images_removed > 0 {
+        diagnostics.push(Diagnostic {
+            level: DiagnosticLevel::Info,
+            code: None,
+            message: format!("Left out {images_removed} images for {title}"),
+        });
+    }
+    if decorative_images_removed > 0 {
+        diagnostics.push(Diagnostic {
+            level: DiagnosticLevel::Info,
+            code: None,
+            message: format!("Skipped {decorative_images_removed} decorative images for {title}"),
+        });
+    }
+    if image_options.duplicates.load(Ordering::Relaxed) > 0 {
+        diagnostics.push(Diagnostic {
+            level: DiagnosticLevel::Info,
+            code: None,
+            message: format!(
+                "Linked {} duplicate images to existing files for {title}",
+                image_options.duplicates.load(Ordering::Relaxed)
+            ),
+        });
+    }
+    if image_options.inlined.load(Ordering::Relaxed) > 0 {
+        diagnostics.push(Diagnostic {
+            level: DiagnosticLevel::Info,
+            code: None,
+            message: format!(
+                "Embedded {} small images as data URIs for {title}",
+                image_options.inlined.load(Ordering::Relaxed)
+            ),
+        });
+    }
+    if image_options.stripped.load(Ordering::Relaxed) > 0 {
+        diagnostics.push(Diagnostic {
+            level: DiagnosticLevel::Info,
+            code: None,
+            message: format!(
+                "Stripped metadata from {} images for {title}",
+                image_options.stripped.load(Ordering::Relaxed)
+            ),
+        });
+    }
+    if image_options.resized.load(Ordering::Relaxed) > 0 || image_options.bytes_saved() > 0 {
+        diagnostics.push(Diagnostic {
+            level: DiagnosticLevel::Info,
+            code: None,
+            message: format!(
+                "Processed images for {title}: {} downscaled, {} KiB saved",
+                image_options.resized.load(Ordering::Relaxed),
+                image_options.bytes_saved() / 1024
+            ),
+        });
+    }
+    if svgs_written > 0 {
+        diagnostics.push(Diagnostic {
+            level: DiagnosticLevel::Info,
+            code: None,
+            message: format!("Wrote {svgs_written} inline SVGs as images for {title}"),
+        });
+    }
+    if endnotes_consolidated > 0 {
+        diagnostics.push(Diagnostic {
+            level: DiagnosticLevel::Info,
+            code: None,
+            message: format!("Consolidated {endnotes_consolidated} endnotes for {title}"),
+        });
+    }
+    if let Some(report) = stats
+        .watermarks
+        .as_ref()
+        .filter(|report| report.removed() > 0)
+    {
+        diagnostics.push(Diagnostic {
+            level: DiagnosticLevel::Info,
+            code: None,
+            message: format!(
+                "Removed {} watermark stamps and {} mentions of the buyer for {title}",
+                report.stamp_lines + report.metadata_fields.len(),
+                report.redactions
+            ),
+        });
+    }
+    diagnostics.extend(warnings.into_iter().map(|(code, message)| Diagnostic {
+        level: if options.error_on_warnings.contains(&code) {
+            DiagnosticLevel::Error
+        } else {
+            DiagnosticLevel::Warning
+        },
+        code: Some(code),
+        message,
+    }));
+
+    diagnostics.iter().for_each(trace_diagnostic);
+    let section_words: Vec<usize> = sections
+        .iter()
+        .map(|section| count_words(&section.text))
+        .collect();
+    let report = ConvertReport {
+        output_paths: writer.written.take(),
+        section_count: sections.len(),
+        word_count: section_words.iter().sum(),
+        section_words,
+        images_extracted: extracted_count,
+        media_extracted: extracted_media_count,
+        heading_fallback_used: use_heading_fallback,
+        toc: TocStats {
+            entries: toc_entry_count,
+            unique_hrefs: toc_unique_count,
+            coverage_ratio: toc_coverage_ratio,
+            degenerate: toc_is_degenerate,
+        },
+        warnings: diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.code.is_some())
+            .cloned()
+            .collect(),
+        fingerprint: book_fingerprint,
+        section_fingerprints,
+    };
+    let result = BookConversionResult {
+        input_path: epub_path.to_path_buf(),
+        title,
+        output_path: Some(return_path),
+        diagnostics,
+        missing_resources,
+        skipped: None,
+        error_kind: None,
+        sections: sections.iter().map(SectionInfo::of).collect(),
+        report,
+    };
+    let mut book = Book::describe(epub, result.title.clone());
+    if let Some(watermarks) = &watermarks {
+        for author in &mut book.authors {
+            *author = watermarks.scrub_metadata("author", author);
+        }
+        book.publisher = book
+            .publisher
+            .map(|publisher| watermarks.scrub_metadata("publisher", &publisher));
+        watermarks.scrub_extra(&mut book.extra);
+    }
+    book.populate(
+        epub,
+        sections,
+        extracted_images.into_iter().chain(extracted_media),
+    );
+    Ok((result, book))
+}
+
+/// Right-to-left when forced, or (in auto mode) when the spine progresses
+/// right-to-left, the content documents declare `dir="rtl"`, or the book
+/// language is written right-to-left.
+fn book_is_rtl(
+    epub: &Epub,
+    spine_hrefs: &[String],
+    cache: &mut ContentCache,
+    options: &ConvertOptions,
+) -> bool {
+    match options.text_direction {
+        TextDirection::Ltr => return false,
+        TextDirection::Rtl => return true,
+        TextDirection::Auto => {}
+    }
+    if epub.spine().page_direction() == rbook::ebook::spine::PageDirection::RightToLeft {
+        return true;
+    }
+    for href in spine_hrefs.iter().take(3) {
+        let Ok(content) = load_content(epub, href, cache) else {
+            continue;
+        };
+        for selector in ["html", "body"] {
+            if let Ok(node) = content.document.select_first(selector) {
+                if let Some(rtl) = markdown::element_rtl(node.as_node()) {
+                    return rtl;
+                }
+            }
+        }
+    }
+    epub.metadata()
+        .language()
+        .map(|lang| {
+            let primary = lang.value().trim().to_ascii_lowercase();
+            let primary = primary.split(['-', '_']).next().unwrap_or("").to_string();
+            matches!(
+                primary.as_str(),
+                "ar" | "arc" | "dv" | "fa" | "he" | "iw" | "ps" | "sd" | "ug" | "ur" | "yi"
+            )
+        })
+        .unwrap_or(false)
+}
+
+fn book_title(epub: &Epub, epub_path: &Path) -> String {
+    epub.metadata()
+        .title()
+        .map(|t| t.value().to_string())
+        .unwrap_or_else(|| {
+            epub_path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or("book")
+                .to_string()
+        })
+}
+
+/// Link prefix for one asset directory: relative to the markdown output by
+/// default, or under `asset_base_url` (with `{slug}` expanded) when the assets
+/// are hosted elsewhere.
+fn asset_link_prefix(options: &ConvertOptions, book_slug: &str, kind: &str) -> String {
+    if let Some(base) = &options.asset_base_url {
+        let base = base.replace("{slug}", book_slug);
+        return format!("{}/{kind}", base.trim_end_matches('/'));
+    }
+    if options.split_chapters {
+        format!("./{kind}")
+    } else {
+        // A templated book path can hold spaces and several components.
+        let book_path: Vec<_> = book_slug.split('/').map(urlencoding::encode).collect();
+        format!("./{}/{kind}", book_path.join("/"))
+    }
+}
+
+/// Asset links are rendered relative to `layout_root` (the book directory for
+/// split output, the output directory otherwise). A file written anywhere else
+/// gets its `./images`-style prefixes rewritten relative to its own location.
+fn rebase_asset_links(
+    text: &str,
+    asset_prefixes: &[String],
+    layout_root: &Path,
+    output_file: &Path,
+) -> String {
+    let Some(file_dir) = output_file.parent() else {
+        return text.to_string();
+    };
+    let up = relative_dir(file_dir, layout_root);
+    if up.is_empty() {
+        return text.to_string();
+    }
+    let mut out = text.to_string();
+    for prefix in asset_prefixes {
+        let Some(rest) = prefix.strip_prefix("./") else {
+            continue;
+        };
+        // `: "` covers front matter values such as `cover:` and `thumbnail:`.
+        for opener in ["](", "src=\"", "href=\"", "url(\"", ": \""] {
+            out = out.replace(
+                &format!("{opener}{prefix}/"),
+                &format!("{opener}{up}{rest}/"),
+            );
+        }
+    }
+    out
+}
+
+/// `../` steps plus path needed to reach `to` from `from` (both under the same
+/// root); empty when they are the same directory.
+fn relative_dir(from: &Path, to: &Path) -> String {
+    let from: Vec<_> = from.components().collect();
+    let to: Vec<_> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let mut out = "../".repeat(from.len() - common);
+    for component in &to[common..] {
+        out.push_str(&component.as_os_str().to_string_lossy());
+        out.push('/');
+    }
+    out
+}
+
+fn build_toc_entries(epub: &Epub, extra_types: &[String]) -> Result<Vec<TocEntryInfo>> {
+    let mut entries = Vec::new();
+    if let Some(root) = epub.toc().contents() {
+        for entry in root.children().flatten() {
+            let href = match entry.href() {
+                Some(href) => href,
+                None => continue,
+            };
+            if let Some(manifest_entry) = entry.manifest_entry() {
+                if !is_readable(manifest_entry.media_type(), extra_types) {
+                    continue;
+                }
+            }
+            let label = entry.label().to_string();
+            let href_path = href.path().as_str().to_string();
+            let fragment = href.fragment().map(|frag| frag.to_string());
+            entries.push(TocEntryInfo {
+                label,
+                href_path,
+                fragment,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// Indexes note bodies that are referenced from a different spine document, so
+/// the renderer can emit them as footnotes next to their references.
+/// Every `href#id` that something in the book links to, so the renderer can
+/// keep an anchor for it.
+fn collect_link_targets(
+    epub: &Epub,
+    spine_hrefs: &[String],
+    toc_entries: &[TocEntryInfo],
+    cache: &mut ContentCache,
+) -> HashSet<String> {
+    let mut targets: HashSet<String> = toc_entries
+        .iter()
+        .filter_map(|entry| {
+            let fragment = entry.fragment.as_ref()?;
+            Some(format!("{}#{}", entry.href_path, fragment))
+        })
+        .collect();
+    for href in spine_hrefs {
+        let Ok(content) = load_content(epub, href, cache) else {
+            continue;
+        };
+        let Ok(anchors) = content.document.select("a[href]") else {
+            continue;
+        };
+        for anchor in anchors {
+            let attrs = anchor.attributes.borrow();
+            let Some(target) = attrs.get("href") else {
+                continue;
+            };
+            if let Some((target_href, Some(fragment))) = resolve_internal_target(target, href) {
+                targets.insert(format!("{target_href}#{fragment}"));
+            }
+        }
+    }
+    targets
+}
+
+fn build_book_notes(epub: &Epub, spine_hrefs: &[String], cache: &mut ContentCache) -> BookNotes {
+    let spine_set: HashSet<&str> = spine_hrefs.iter().map(String::as_str).collect();
+    let mut pending: Vec<(NodeRef, Option<String>, String, String)> = Vec::new();
+    for href in spine_hrefs {
+        let Ok(content) = load_content(epub, href, cache) else {
+            continue;
+        };
+        let Ok(anchors) = content.document.select("a[href]") else {
+            continue;
+        };
+        for anchor in anchors {
+            let attrs = anchor.attributes.borrow();
+            let Some(target) = attrs.get("href") else {
+                continue;
+            };
+            let Some((target_href, Some(fragment))) = resolve_internal_target(target, href) else {
+                continue;
+            };
+            if target_href == *href || !spine_set.contains(target_href.as_str()) {
+                continue;
+            }
+            pending.push((
+                anchor.as_node().clone(),
+                attrs.get("id").map(|id| format!("{href}#{id}")),
+                target_href,
+                fragment,
+            ));
+        }
+    }
+
+    let mut notes = BookNotes::default();
+    for (anchor, ref_key, target_href, fragment) in pending {
+        let Ok(content) = load_content(epub, &target_href, cache) else {
+            continue;
+        };
+        let Some(target) = find_anchor(&content.document, &fragment) else {
+            continue;
+        };
+        if !markdown::is_noteref(&anchor, &target) {
+            continue;
+        }
+        notes.bodies.insert(
+            format!("{target_href}#{fragment}"),
+            markdown::note_body(&target),
+        );
+        if let Some(key) = ref_key {
+            notes.refs.insert(key);
+        }
+    }
+    notes
+}
+
+fn toc_degeneracy_stats(
+    toc_entries: &[TocEntryInfo],
+    spine_doc_count: usize,
+) -> (bool, usize, usize, f32) {
+    let toc_entry_count = toc_entries.len();
+    let unique_toc_hrefs: HashSet<&str> = toc_entries
+        .iter()
+        .map(|entry| entry.href_path.as_str())
+        .collect();
+    let unique_count = unique_toc_hrefs.len();
+    let coverage_ratio = if spine_doc_count > 0 {
+        unique_count as f32 / spine_doc_count as f32
+    } else {
+        0.0
+    };
+    let is_degenerate = toc_entry_count <= 1 || unique_count < 3 || coverage_ratio < 0.15;
+    (is_degenerate, toc_entry_count, unique_count, coverage_ratio)
+}
+
+/// The spine documents TOC entry `idx` spans, from its own document to the
+/// one the next entry starts in; `None` when its target is not in the
+/// reading order or the next entry points back before it.
+fn toc_section_span(
+    toc_entries: &[TocEntryInfo],
+    idx: usize,
+    spine_index_by_href: &HashMap<String, usize>,
+    spine_len: usize,
+) -> Option<(usize, usize)> {
+    let start_idx = spine_index_by_href
+        .get(&toc_entries.get(idx)?.href_path)
+        .copied()?;
+    let end_idx = toc_entries
+        .get(idx + 1)
+        .and_then(|next| spine_index_by_href.get(&next.href_path).copied())
+        .unwrap_or(spine_len.saturating_sub(1));
+    (end_idx >= start_idx).then_some((start_idx, end_idx))
+}
+
+/// The spine documents of a TOC section with the fragments it starts and
+/// ends at in each; a next entry at the top of a document ends the section
+/// before that document.
+fn toc_section_parts<'a>(
+    entry: &'a TocEntryInfo,
+    next_entry: Option<&'a TocEntryInfo>,
+    start_idx: usize,
+    end_idx: usize,
+) -> Vec<(usize, Option<&'a str>, Option<&'a str>)> {
+    let mut parts = Vec::new();
+    for spine_idx in start_idx..=end_idx {
+        if let Some(next) = next_entry {
+            if spine_idx == end_idx && next.fragment.is_none() {
+                // Next section starts at the beginning of this file.
+                continue;
+            }
+        }
+
+        let start_fragment = if spine_idx == start_idx {
+            entry.fragment.as_deref()
+        } else {
+            None
+        };
+        let end_fragment = if let Some(next) = next_entry {
+            if spine_idx == end_idx {
+                next.fragment.as_deref()
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+        parts.push((spine_idx, start_fragment, end_fragment));
+    }
+    parts
+}
+
+/// Where heading fallback starts its sections: the first spine document,
+/// labelled from the TOC or its file name, then every confident heading
+/// candidate after it. `None` when there are no such candidates.
+/// `heading_patterns` are matched besides the built-in major headings.
+fn heading_fallback_starts(
+    epub: &Epub,
+    toc_entries: &[TocEntryInfo],
+    spine_hrefs: &[String],
+    cache: &mut ContentCache,
+    heading_patterns: &[Regex],
+) -> Option<Vec<(usize, String)>> {
+    let confident_candidates: Vec<HeadingCandidate> =
+        detect_heading_candidates(spine_hrefs, cache, epub, heading_patterns)
+            .into_iter()
+            .filter(|candidate| candidate.spine_idx > 0)
+            .collect();
+    if confident_candidates.is_empty() {
+        return None;
+    }
+    let first_label = toc_entries
+        .first()
+        .map(|entry| entry.label.clone())
+        .filter(|label| !label.trim().is_empty())
+        .unwrap_or_else(|| {
+            spine_hrefs
+                .first()
+                .map(|href| prettify_section_name(href))
+                .unwrap_or_else(|| "Section 1".to_string())
+        });
+    let mut starts: Vec<(usize, String)> = vec![(0, first_label)];
+    for candidate in &confident_candidates {
+        let label = if candidate.label.trim().is_empty() {
+            format!("Section {}", starts.len() + 1)
+        } else {
+            candidate.label.clone()
+        };
+        starts.push((candidate.spine_idx, label));
+    }
+    Some(starts)
+}
+
+fn detect_heading_candidates(
+    spine_hrefs: &[String],
+    cache: &mut ContentCache,
+    epub: &Epub,
+    heading_patterns: &[Regex],
+) -> Vec<HeadingCandidate> {
+    let mut accepted: Vec<HeadingCandidate> = Vec::new();
+    let min_gap_docs = 2usize;
+
+    for (idx, href) in spine_hrefs.iter().enumerate() {
+        let content = match load_content(epub, href, cache) {
+            Ok(content) => content,
+            Err(_) => continue,
+        };
+        let (score, label, true_heading) = score_heading_candidate(content, heading_patterns);
+        if score < 1.0 {
+            continue;
+        }
+        if idx == 0 && !true_heading {
+            continue;
+        }
+
+        let candidate = HeadingCandidate {
+            spine_idx: idx,
+            score,
+            label: clean_heading_label(&label),
+        };
+
+        if let Some(prev) = accepted.last_mut() {
+            if idx.saturating_sub(prev.spine_idx) < min_gap_docs {
+                if candidate.score > prev.score {
+                    *prev = candidate;
+                }
+                continue;
+            }
+        }
+        accepted.push(candidate);
+    }
+
+    accepted
+}
+
+fn score_heading_candidate(
+    content: &ContentDoc,
+    heading_patterns: &[Regex],
+) -> (f32, String, bool) {
+    let (top_window_text, first_nonempty_line, heading_texts) = extract_heading_features(content);
+    let is_major = |text: &str| find_major_heading(text, heading_patterns).is_some();
+
+    let mut score = 0.0f32;
+    let mut label = String::new();
+    let mut heading_match = false;
+
+    for heading_text in &heading_texts {
+        if is_major(heading_text) {
+            score += 0.9;
+            heading_match = true;
+            label = extract_major_heading_label(heading_text)
+                .unwrap_or_else(|| clean_heading_label(heading_text));
+            break;
+        }
+    }
+
+    let top_match = find_major_heading(&top_window_text, heading_patterns);
+    if top_match.is_some() {
+        score += 0.8;
+        if label.is_empty() {
+            if !first_nonempty_line.is_empty() && is_major(&first_nonempty_line) {
+                label = extract_major_heading_label(&first_nonempty_line)
+                    .unwrap_or_else(|| clean_heading_label(&first_nonempty_line));
+            } else if let Some(found) = top_match {
+                label = extract_major_heading_label(&top_window_text)
+                    .unwrap_or_else(|| clean_heading_label(found.as_str()));
+            }
+        }
+    }
+
+    let first_line_major_match = !first_nonempty_line.is_empty() && is_major(&first_nonempty_line);
+    if !first_nonempty_line.is_empty()
+        && (is_heading_like_line(&first_nonempty_line) || first_line_major_match)
+    {
+        score += 0.4;
+        if label.is_empty() && first_line_major_match {
+            label = extract_major_heading_label(&first_nonempty_line)
+                .unwrap_or_else(|| clean_heading_label(&first_nonempty_line));
+        }
+    }
+
+    if OCR_NOISE_RE.is_match(&top_window_text) {
+        score -= 0.5;
+    }
+
+    score = score.clamp(0.0, 2.0);
+    let true_heading = heading_match || top_match.is_some();
+    (score, label, true_heading)
+}
+
+/// The earliest match of the built-in major heading pattern or one of
+/// `heading_patterns` in `text`.
+fn find_major_heading<'t>(text: &'t str, heading_patterns: &[Regex]) -> Option<regex::Match<'t>> {
+    std::iter::once(&*MAJOR_HEADING_RE)
+        .chain(heading_patterns)
+        .filter_map(|pattern| pattern.find(text))
+        .min_by_key(|found| found.start())
+}
+
+fn extract_heading_features(content: &ContentDoc) -> (String, String, Vec<String>) {
+    let Ok(body) = content.document.select_first("body") else {
+        return (String::new(), String::new(), Vec::new());
+    };
+    let body_node = body.as_node();
+    let body_text = body_node.text_contents();
+    let top_window_raw: String = body_text.chars().take(1500).collect();
+    let top_window_text = normalize_space(&top_window_raw);
+
+    let mut first_nonempty_line = String::new();
+    for line in top_window_raw.lines() {
+        let stripped = normalize_space(line);
+        if !stripped.is_empty() {
+            first_nonempty_line = stripped;
+            break;
+        }
+    }
+    if first_nonempty_line.is_empty() && !top_window_text.is_empty() {
+        first_nonempty_line = top_window_text.chars().take(80).collect::<String>();
+    }
+
+    let mut heading_texts: Vec<String> = Vec::new();
+    if let Ok(headings) = body_node.select("h1, h2, h3") {
+        for heading in headings {
+            let text = normalize_space(&heading.text_contents());
+            if !text.is_empty() {
+                heading_texts.push(text);
+            }
+        }
+    }
+
+    (top_window_text, first_nonempty_line, heading_texts)
+}
+
+fn is_heading_like_line(line: &str) -> bool {
+    let normalized = normalize_space(line);
+    if normalized.is_empty() || normalized.chars().count() > 80 {
+        return false;
+    }
+    let words: Vec<&str> = normalized
+        .split_whitespace()
+        .filter(|word| word.chars().any(|c| c.is_alphabetic()))
+        .collect();
+    if words.is_empty() {
+        return false;
+    }
+    let letters: Vec<char> = normalized.chars().filter(|c| c.is_alphabetic()).collect();
+    if letters.is_empty() {
+        return false;
+    }
+    let all_caps = letters.iter().all(|c| !c.is_lowercase());
+    let title_like = words
+        .iter()
+        .filter(|word| {
+            word.chars()
+                .next()
+                .map(|c| c.is_uppercase())
+                .unwrap_or(false)
+        })
+        .count()
+        >= std::cmp::max(1, (words.len() * 8) / 10);
+    all_caps || title_like
+}
+
+fn normalize_space(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn clean_heading_label(text: &str) -> String {
+    let normalized = normalize_space(text);
+    normalized
+        .trim_matches(|c: char| !c.is_alphanumeric() && c != '_' && c != '-')
+        .to_string()
+}
+
+fn extract_major_heading_label(text: &str) -> Option<String> {
+    MAJOR_HEADING_LABEL_RE
+        .find(text)
+        .map(|m| clean_heading_label(m.as_str()))
+        .filter(|label| !label.is_empty())
+}
+
+fn prettify_section_name(value: &str) -> String {
+    let file_name = value
+        .rsplit('/')
+        .next()
+        .unwrap_or(value)
+        .rsplit_once('.')
+        .map(|(stem, _)| stem)
+        .unwrap_or(value);
+    let cleaned = file_name.replace(['_', '-'], " ");
+    let cleaned = normalize_space(&cleaned);
+    if cleaned.is_empty() {
+        value.to_string()
+    } else {
+        cleaned
+    }
+}
+
+fn load_content<'a>(
+    epub: &Epub,
+    href_path: &str,
+    cache: &'a mut ContentCache,
+) -> Result<&'a ContentDoc> {
+    if !cache.contains(href_path) {
+        let html = document_html(epub, href_path)?;
+        let bytes = html.len() as u64;
+        return Ok(cache.insert(href_path, parse_content(href_path, html), bytes));
+    }
+    Ok(cache.get(href_path).expect("cached document"))
+}
+
+/// A content document as HTML, converting plain-text and SVG documents.
+fn document_html(epub: &Epub, href_path: &str) -> Result<String> {
+    let html = epub
+        .read_resource_str(href_path)
+        .map_err(|err| ConvertError::ResourceRead {
+            href: href_path.to_string(),
+            source: err.into(),
+        })?;
+    let media_type = epub
+        .manifest()
+        .entries()
+        .find(|entry| entry.href().as_str() == href_path)
+        .map(|entry| entry.media_type().to_lowercase())
+        .unwrap_or_default();
+    Ok(match media_type.as_str() {
+        "text/plain" => plain_text_to_html(&html),
+        "image/svg+xml" => svg::svg_document_to_html(&html),
+        _ => html,
+    })
+}
+
+fn parse_content(href_path: &str, html: String) -> ContentDoc {
+    ContentDoc {
+        href_path: href_path.to_string(),
+        document: parse_html().one(html),
+    }
+}
+
+/// Reads and decodes `hrefs` on `jobs` threads, each with its own handle on
+/// the archive, and parses them into `cache` in spine order. The DOM is not
+/// thread-safe, so parsing and everything after it stays on this thread;
+/// documents a worker could not read are left to [`load_content`]. Reading
+/// stops once the cache is full, since parsing ahead would only evict
+/// earlier documents.
+fn prefetch_documents(epub_path: &Path, hrefs: &[String], jobs: usize, cache: &mut ContentCache) {
+    let next = AtomicUsize::new(0);
+    let full = AtomicBool::new(false);
+    let (fetched_tx, fetched_rx) = std::sync::mpsc::channel::<(usize, Option<String>)>();
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.min(hrefs.len()) {
+            let fetched_tx = fetched_tx.clone();
+            let (next, full) = (&next, &full);
+            scope.spawn(move || {
+                let Ok(epub) = Epub::open(epub_path) else {
+                    return;
+                };
+                while !full.load(Ordering::Relaxed) {
+                    let idx = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(href) = hrefs.get(idx) else {
+                        break;
+                    };
+                    let html = document_html(&epub, href).ok();
+                    if fetched_tx.send((idx, html)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(fetched_tx);
+
+        // Documents that arrive ahead of their turn wait here.
+        let mut waiting: BTreeMap<usize, Option<String>> = BTreeMap::new();
+        let mut turn = 0usize;
+        for (idx, html) in fetched_rx {
+            waiting.insert(idx, html);
+            while let Some(html) = waiting.remove(&turn) {
+                let href = &hrefs[turn];
+                turn += 1;
+                let Some(html) = html else {
+                    continue;
+                };
+                let bytes = html.len() as u64;
+                if !cache.has_room(bytes) {
+                    full.store(true, Ordering::Relaxed);
+                    return;
+                }
+                cache.insert(href, parse_content(href, html), bytes);
+            }
+        }
+    });
+}
+
+/// Blank-line separated paragraphs, so `text/plain` spine items (allowed via
+/// `extra_readable_types`) render like any other document.
+fn plain_text_to_html(text: &str) -> String {
+    let paragraphs: Vec<String> = text
+        .replace("\r\n", "\n")
+        .split("\n\n")
+        .map(str::trim)
+        .filter(|paragraph| !paragraph.is_empty())
+        .map(|paragraph| {
+            let escaped = paragraph
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;");
+            format!("<p>{escaped}</p>")
+        })
+        .collect();
+    format!("<html><body>{}</body></html>", paragraphs.join("\n"))
+}
+
+/// The spine documents a conversion walks, in order: spine items with a
+/// manifest entry of a readable media type. Indices into this list are the
+/// `spine_start`/`spine_end` of each section.
+fn reading_order(epub: &Epub, extra_types: &[String]) -> Vec<String> {
+    epub.spine()
+        .entries()
+        .filter_map(|entry| entry.manifest_entry())
+        .filter(|entry| is_readable(entry.media_type(), extra_types))
+        .map(|entry| entry.href().as_str().to_string())
+        .collect()
+}
+
+fn is_readable(media_type: &str, extra_types: &[String]) -> bool {
+    READABLE_MIME
+        .iter()
+        .copied()
+        .chain(extra_types.iter().map(String::as_str))
+        .any(|mime| mime.eq_ignore_ascii_case(media_type))
+}
+
+fn collect_css(
+    content: &ContentDoc,
+    base_href: &str,
+    css_hrefs: &mut HashSet<String>,
+    inline_styles: &mut Vec<String>,
+) {
+    if let Ok(head) = content.document.select_first("head") {
+        let node = head.as_node();
+        if let Ok(links) = node.select("link[rel~='stylesheet']") {
+            for link in links {
+                let attrs = link.attributes.borrow();
+                if let Some(href) = attrs.get("href") {
+                    if is_external(href) {
+                        continue;
+                    }
+                    let resolved = resolve_href(base_href, href);
+                    css_hrefs.insert(resolved);
+                }
+            }
+        }
+        if let Ok(styles) = node.select("style") {
+            for style_node in styles {
+                let text = style_node.text_contents();
+                if !text.trim().is_empty() {
+                    inline_styles.push(text);
+                }
+            }
+        }
+    }
+}
+
+fn build_style_header(
+    epub: &Epub,
+    css_hrefs: &HashSet<String>,
+    inline_styles: &[String],
+    styles_root: &Path,
+    style_link_prefix: &str,
+    style_mode: StyleMode,
+    font_files: &fonts::FontFiles,
+    fonts_root: &Path,
+    font_link_prefix: &str,
+    missing: &mut BTreeSet<String>,
+    writer: &text_output::TextWriter,
+) -> Result<Vec<String>> {
+    let mut lines = Vec::new();
+    if css_hrefs.is_empty() && inline_styles.is_empty() {
+        return Ok(lines);
+    }
+
+    match style_mode {
+        StyleMode::External => {
+            for href in css_hrefs.iter().collect::<Vec<_>>() {
+                let Ok(bytes) = epub.read_resource_bytes(href.as_str()) else {
+                    missing.insert(href.clone());
+                    continue;
+                };
+                let relative = decode_path(href);
+                let output_path = styles_root.join(&relative);
+                if font_files.is_empty() {
+                    // Stylesheets in other encodings are copied untouched.
+                    match std::str::from_utf8(&bytes) {
+                        Ok(css) => writer.write_utf8(&output_path, css)?,
+                        Err(_) => writer
+                            .files
+                            .write(&output_path, &bytes)
+                            .map_err(|err| ConvertError::write_failed(&output_path, err))?,
+                    }
+                } else {
+                    let css_dir = output_path.parent().unwrap_or(styles_root);
+                    let link = |relative: &str| {
+                        fonts::font_link(css_dir, fonts_root, font_link_prefix, relative)
+                    };
+                    let css = String::from_utf8_lossy(&bytes);
+                    writer.write_utf8(
+                        &output_path,
+                        &fonts::rewrite_font_urls(&css, Some(href), font_files, &link),
+                    )?;
+                }
+                lines.push(format!(
+                    "<link rel=\"stylesheet\" href=\"{style_link_prefix}/{relative}\">"
+                ));
+            }
+
+            if !inline_styles.is_empty() {
+                let inline_path = styles_root.join("inline_styles.css");
+                let link = |relative: &str| {
+                    fonts::font_link(styles_root, fonts_root, font_link_prefix, relative)
+                };
+                let css =
+                    fonts::rewrite_font_urls(&inline_styles.join("\n\n"), None, font_files, &link);
+                writer.write_utf8(&inline_path, &css)?;
+                lines.push(format!(
+                    "<link rel=\"stylesheet\" href=\"{style_link_prefix}/inline_styles.css\">"
+                ));
+            }
+        }
+        StyleMode::Inline => {
+            let link = |relative: &str| format!("{font_link_prefix}/{relative}");
+            let mut css_chunks = Vec::new();
+            for href in css_hrefs.iter().collect::<Vec<_>>() {
+                let Ok(bytes) = epub.read_resource_bytes(href.as_str()) else {
+                    missing.insert(href.clone());
+                    continue;
+                };
+                let css = String::from_utf8_lossy(&bytes).to_string();
+                css_chunks.push(fonts::rewrite_font_urls(
+                    &css,
+                    Some(href),
+                    font_files,
+                    &link,
+                ));
+            }
+            css_chunks.extend(
+                inline_styles
+                    .iter()
+                    .map(|css| fonts::rewrite_font_urls(css, None, font_files, &link)),
+            );
+            if !css_chunks.is_empty() {
+                lines.push("<style>".to_string());
+                lines.push(css_chunks.join("\n\n"));
+                lines.push("</style>".to_string());
+            }
+        }
+    }
+
+    Ok(lines)
+}
+
+fn render_full_content(
+    content: &ContentDoc,
+    notes: &markdown::NoteIndex,
+    render_options: &RenderOptions,
+    image_resolver: &mut impl FnMut(&str, &str) -> Option<String>,
+) -> Option<String> {
+    if let Ok(body) = content.document.select_first("body") {
+        let body = body.as_node().clone();
+        match render_options.mode {
+            MarkdownMode::Plain => {
+                render_plain(&body, content, notes, render_options, image_resolver)
+            }
+            MarkdownMode::Rich => Some(render_rich(
+                &body,
+                content,
+                notes,
+                render_options,
+                image_resolver,
+            )),
+        }
+    } else {
+        None
+    }
+}
+
+fn split_content_at_headings(
+    content: &ContentDoc,
+    render_options: &RenderOptions,
+    max_level: u8,
+    image_resolver: &mut impl FnMut(&str, &str) -> Option<String>,
+) -> Option<Vec<HeadingSplit>> {
+    let body = content
+        .document
+        .select_first("body")
+        .ok()?
+        .as_node()
+        .clone();
+    let container = heading_split_container(&body);
+    let children: Vec<NodeRef> = container.children().collect();
+    let mut starts: Vec<(usize, Option<NodeRef>)> = Vec::new();
+    for (idx, child) in children.iter().enumerate() {
+        if let Some(heading) = leading_heading(child, max_level) {
+            starts.push((idx, Some(heading)));
+        }
+    }
+    if starts.is_empty() {
+        return None;
+    }
+    if starts[0].0 > 0 && has_meaningful_content(&children[..starts[0].0]) {
+        starts.insert(0, (0, None));
+    } else {
+        starts[0].0 = 0;
+    }
+    if starts.len() < 2 {
+        return None;
+    }
+
+    let fragments: Vec<Option<String>> = starts
+        .iter()
+        .map(|(idx, heading)| {
+            heading
+                .as_ref()
+                .and_then(node_anchor_id)
+                .or_else(|| node_anchor_id(&children[*idx]))
+        })
+        .collect();
+    let notes = content.note_index(render_options);
+    let mut splits = Vec::new();
+    for (pos, (start_idx, heading)) in starts.iter().enumerate() {
+        let end_idx = starts
+            .get(pos + 1)
+            .map(|(idx, _)| *idx)
+            .unwrap_or(children.len());
+        let nodes = &children[*start_idx..end_idx];
+        let Some(text) =
+            render_nodes_for_mode(nodes, content, &notes, render_options, image_resolver)
+        else {
+            continue;
+        };
+        let label = heading
+            .as_ref()
+            .map(|node| normalize_space(&node.text_contents()))
+            .filter(|label| !label.is_empty());
+        splits.push(HeadingSplit {
+            label,
+            text,
+            anchors: collect_anchors_from_nodes(nodes),
+            start_fragment: fragments[pos].clone(),
+            end_fragment: fragments.get(pos + 1).cloned().flatten(),
+        });
+    }
+    if splits.len() < 2 { None } else { Some(splits) }
+}
+
+fn push_heading_splits(
+    sections: &mut Vec<SectionRecord>,
+    splits: Vec<HeadingSplit>,
+    fallback_label: &str,
+    href: &str,
+    spine_idx: usize,
+) {
+    for (idx, split) in splits.into_iter().enumerate() {
+        let title = split.label.unwrap_or_else(|| {
+            if idx == 0 {
+                fallback_label.to_string()
+            } else {
+                format!("{} ({})", fallback_label, idx + 1)
+            }
+        });
+        sections.push(SectionRecord {
+            title,
+            text: split.text,
+            start_href: href.to_string(),
+            start_fragment: split.start_fragment,
+            end_href: Some(href.to_string()),
+            end_fragment: split.end_fragment,
+            spine_start: spine_idx,
+            spine_end: spine_idx,
+            anchors: split.anchors,
+            section_id: String::new(),
+            output_path: String::new(),
+            heading_anchor: None,
+        });
+    }
+}
+
+/// Descends through single wrapper elements (`<div class="book">`) so heading
+/// boundaries can be found among siblings rather than inside one opaque child.
+fn heading_split_container(body: &NodeRef) -> NodeRef {
+    let mut container = body.clone();
+    loop {
+        let mut element_children = container.children().filter(|child| {
+            child.as_element().is_some()
+                || child
+                    .as_text()
+                    .map(|text| !text.borrow().trim().is_empty())
+                    .unwrap_or(false)
+        });
+        let (Some(only), None) = (element_children.next(), element_children.next()) else {
+            return container;
+        };
+        match element_name(&only) {
+            Some("div" | "section" | "article" | "main") => container = only,
+            _ => return container,
+        }
+    }
+}
+
+fn leading_heading(node: &NodeRef, max_level: u8) -> Option<NodeRef> {
+    let tag = element_name(node)?;
+    if let Some(level) = heading_level(tag) {
+        return (level <= max_level).then(|| node.clone());
+    }
+    if !matches!(tag, "div" | "section" | "article" | "header" | "hgroup") {
+        return None;
+    }
+    let first = node.children().find(|child| {
+        child.as_element().is_some()
+            || child
+                .as_text()
+                .map(|text| !text.borrow().trim().is_empty())
+                .unwrap_or(false)
+    })?;
+    leading_heading(&first, max_level)
+}
+
+fn heading_level(tag: &str) -> Option<u8> {
+    match tag {
+        "h1" => Some(1),
+        "h2" => Some(2),
+        "h3" => Some(3),
+        "h4" => Some(4),
+        "h5" => Some(5),
+        "h6" => Some(6),
+        _ => None,
+    }
+}
+
+fn node_anchor_id(node: &NodeRef) -> Option<String> {
+    let el = node.as_element()?;
+    let attrs = el.attributes.borrow();
+    attrs
+        .get("id")
+        .map(|id| id.trim().to_string())
+        .filter(|id| !id.is_empty())
+}
+
+fn has_meaningful_content(nodes: &[NodeRef]) -> bool {
+    nodes.iter().any(|node| {
+        !node.text_contents().trim().is_empty()
+            || node
+                .select("img, svg, image")
+                .map(|mut m| m.next().is_some())
+                .unwrap_or(false)
+    })
+}
+
+fn render_partial_with_anchors(
+    content: &ContentDoc,
+    render_options: &RenderOptions,
+    start_fragment: Option<&str>,
+    end_fragment: Option<&str>,
+    image_resolver: &mut impl FnMut(&str, &str) -> Option<String>,
+) -> (Option<String>, Vec<String>) {
+    let notes = content.note_index(render_options);
+    if start_fragment.is_none() && end_fragment.is_none() {
+        return (
+            render_full_content(content, &notes, render_options, image_resolver),
+            collect_anchors_from_content(content),
+        );
+    }
+    let Some(nodes) = partial_body_nodes(content, start_fragment, end_fragment) else {
+        return (None, Vec::new());
+    };
+    (
+        render_nodes_for_mode(&nodes, content, &notes, render_options, image_resolver),
+        collect_anchors_from_nodes(&nodes),
+    )
+}
+
+/// Top-level body children from the one holding `start_fragment` up to (not
+/// including) the one holding `end_fragment`.
+fn partial_body_nodes(
+    content: &ContentDoc,
+    start_fragment: Option<&str>,
+    end_fragment: Option<&str>,
+) -> Option<Vec<NodeRef>> {
+    let body = content
+        .document
+        .select_first("body")
+        .ok()?
+        .as_node()
+        .clone();
+    let children: Vec<NodeRef> = body.children().collect();
+    if children.is_empty() {
+        return None;
+    }
+    let mut start_idx = 0usize;
+    if let Some(fragment) = start_fragment {
+        let anchor = find_anchor(&content.document, fragment)?;
+        let top = top_level_body_child(&body, &anchor)?;
+        start_idx = child_index(&children, &top)?;
+    }
+    let mut end_idx = children.len();
+    if let Some(fragment) = end_fragment {
+        if let Some(anchor) = find_anchor(&content.document, fragment) {
+            if let Some(top) = top_level_body_child(&body, &anchor) {
+                if let Some(idx) = child_index(&children, &top) {
+                    if idx > start_idx {
+                        end_idx = idx;
+                    }
+                }
+            }
+        }
+    }
+    if start_idx >= end_idx {
+        return None;
+    }
+    Some(children[start_idx..end_idx].to_vec())
+}
+
+fn collect_anchors_from_nodes(nodes: &[NodeRef]) -> Vec<String> {
+    let mut anchors: HashSet<String> = HashSet::new();
+    for node in nodes {
+        if let Ok(matches) = node.select("[id]") {
+            for n in matches {
+                let attrs = n.attributes.borrow();
+                if let Some(id) = attrs.get("id") {
+                    if !id.trim().is_empty() {
+                        anchors.insert(id.trim().to_string());
+                    }
+                }
+            }
+        }
+        if let Ok(matches) = node.select("a[name]") {
+            for n in matches {
+                let attrs = n.attributes.borrow();
+                if let Some(name) = attrs.get("name") {
+                    if !name.trim().is_empty() {
+                        anchors.insert(name.trim().to_string());
+                    }
+                }
+            }
+        }
+    }
+    let mut values: Vec<String> = anchors.into_iter().collect();
+    values.sort();
+    values
+}
+
+fn collect_anchors_from_content(content: &ContentDoc) -> Vec<String> {
+    let Ok(body) = content.document.select_first("body") else {
+        return Vec::new();
+    };
+    let nodes: Vec<NodeRef> = body.as_node().children().collect();
+    collect_anchors_from_nodes(&nodes)
+}
+
+fn render_nodes_for_mode(
+    nodes: &[NodeRef],
+    content: &ContentDoc,
+    notes: &markdown::NoteIndex,
+    render_options: &RenderOptions,
+    image_resolver: &mut impl FnMut(&str, &str) -> Option<String>,
+) -> Option<String> {
+    match render_options.mode {
+        MarkdownMode::Plain => {
+            render_nodes_plain(nodes, content, notes, render_options, image_resolver)
+        }
+        MarkdownMode::Rich => {
+            let rich = render_nodes_rich(nodes, content, notes, render_options, image_resolver);
+            if rich.trim().is_empty() {
+                None
+            } else {
+                Some(rich.trim().to_string())
+            }
+        }
+    }
+}
+
+fn render_nodes_plain(
+    nodes: &[NodeRef],
+    content: &ContentDoc,
+    notes: &markdown::NoteIndex,
+    render_options: &RenderOptions,
+    image_resolver: &mut impl FnMut(&str, &str) -> Option<String>,
+) -> Option<String> {
+    for node in nodes {
+        rewrite_images(node, content, image_resolver);
+    }
+    let trimmed = markdown::render_nodes(nodes, notes, render_options);
+    if trimmed.is_empty() {
+        None
+    } else {
+        Some(trimmed)
+    }
+}
+
+fn render_nodes_rich(
+    nodes: &[NodeRef],
+    content: &ContentDoc,
+    notes: &markdown::NoteIndex,
+    render_options: &RenderOptions,
+    image_resolver: &mut impl FnMut(&str, &str) -> Option<String>,
+) -> String {
+    let mut chunks = Vec::new();
+    for node in nodes {
+        if let Some(text) = node.as_text() {
+            let t = text.borrow();
+            if !t.trim().is_empty() {
+                chunks.push(t.trim().to_string());
+            }
+            continue;
+        }
+        if is_complex(node) {
+            rewrite_images(node, content, image_resolver);
+            chunks.push(serialize_node(node));
+        } else {
+            rewrite_images(node, content, image_resolver);
+            let md = markdown::render_nodes(std::slice::from_ref(node), notes, render_options);
+            if !md.is_empty() {
+                chunks.push(md);
+            }
+        }
+    }
+    chunks.join("\n\n")
+}
+
+fn top_level_body_child(body: &NodeRef, node: &NodeRef) -> Option<NodeRef> {
+    let mut current = node.clone();
+    loop {
+        let parent = current.parent()?;
+        if parent == *body {
+            return Some(current);
+        }
+        current = parent;
+    }
+}
+
+fn child_index(children: &[NodeRef], target: &NodeRef) -> Option<usize> {
+    children.iter().position(|child| child == target)
+}
+
+fn render_plain(
+    node: &NodeRef,
+    content: &ContentDoc,
+    notes: &markdown::NoteIndex,
+    render_options: &RenderOptions,
+    image_resolver: &mut impl FnMut(&str, &str) -> Option<String>,
+) -> Option<String> {
+    rewrite_images(node, content, image_resolver);
+    let children: Vec<NodeRef> = node.children().collect();
+    let trimmed = markdown::render_nodes(&children, notes, render_options);
+    if trimmed.is_empty() {
+        None
+    } else {
+        Some(trimmed)
+    }
+}
+
+fn render_rich(
+    node: &NodeRef,
+    content: &ContentDoc,
+    notes: &markdown::NoteIndex,
+    render_options: &RenderOptions,
+    image_resolver: &mut impl FnMut(&str, &str) -> Option<String>,
+) -> String {
+    let mut chunks = Vec::new();
+    for child in node.children() {
+        if let Some(text) = child.as_text() {
+            let t = text.borrow();
+            if !t.trim().is_empty() {
+                chunks.push(t.trim().to_string());
+            }
+            continue;
+        }
+        if is_complex(&child) {
+            rewrite_images(&child, content, image_resolver);
+            chunks.push(serialize_node(&child));
+        } else {
+            rewrite_images(&child, content, image_resolver);
+            let md = markdown::render_nodes(std::slice::from_ref(&child), notes, render_options);
+            if !md.is_empty() {
+                chunks.push(md);
+            }
+        }
+    }
+    chunks.join("\n\n")
+}
+
+fn rewrite_images(
+    node: &NodeRef,
+    content: &ContentDoc,
+    image_resolver: &mut impl FnMut(&str, &str) -> Option<String>,
+) {
+    if let Ok(images) = node.select("img") {
+        for img in images {
+            let mut attrs = img.attributes.borrow_mut();
+            if let Some(src) = attrs.get("src").map(str::to_string) {
+                // The original name is lost once the image is renamed or inlined.
+                if attrs.get("alt").is_none() {
+                    if let Some(alt) = markdown::alt_from_filename(&src) {
+                        attrs.insert("alt", alt);
+                    }
+                }
+                if let Some(resolved) = image_resolver(&src, &content.href_path) {
+                    attrs.insert("src", resolved);
+                }
+            }
+        }
+    }
+}
+
+fn find_anchor(document: &NodeRef, fragment: &str) -> Option<NodeRef> {
+    if let Ok(nodes) = document.select("[id]") {
+        for node in nodes {
+            let attrs = node.attributes.borrow();
+            if let Some(id) = attrs.get("id") {
+                if id == fragment {
+                    return Some(node.as_node().clone());
+                }
+            }
+        }
+    }
+    if let Ok(nodes) = document.select("a[name]") {
+        for node in nodes {
+            let attrs = node.attributes.borrow();
+            if let Some(name) = attrs.get("name") {
+                if name == fragment {
+                    return Some(node.as_node().clone());
+                }
+            }
+        }
+    }
+    None
+}
+
+fn element_name(node: &NodeRef) -> Option<&str> {
+    node.as_element().map(|el| el.name.local.as_ref())
+}
+
+fn is_complex(node: &NodeRef) -> bool {
+    if let Some(tag) = element_name(node) {
+        // Table styling is presentational; only structure decides whether a
+        // pipe table can represent it.
+        if tag == "table" {
+            return !markdown::is_simple_table(node);
+        }
+        if tag == "figure" && markdown::is_image_figure(node) {
+            return false;
+        }
+        if COMPLEX_HTML_TAGS.contains(&tag) {
+            return true;
+        }
+    }
+    if let Some(el) = node.as_element() {
+        let attrs = el.attributes.borrow();
+        if attrs.get("class").is_some() || attrs.get("style").is_some() {
+            return true;
+        }
+    }
+    for descendant in node.descendants() {
+        if let Some(el) = descendant.as_element() {
+            let attrs = el.attributes.borrow();
+            if attrs.get("class").is_some() || attrs.get("style").is_some() {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+fn serialize_node(node: &NodeRef) -> String {
+    let mut bytes = Vec::new();
+    node.serialize(&mut bytes).ok();
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+fn resolve_and_extract_image(
+    epub: &Epub,
+    src: &str,
+    base_href: &str,
+    images: &images::ImageExtractor,
+) -> Option<String> {
+    if src.trim().is_empty() || is_external(src) {
+        return Some(src.to_string());
+    }
+    let resolved = resolve_href(base_href, src);
+    Some(
+        images
+            .extract(epub, &resolved)
+            .unwrap_or_else(|| src.to_string()),
+    )
+}
+
+fn extract_media_file(
+    epub: &Epub,
+    resolved: &str,
+    media_root: &Path,
+    media_link_prefix: &str,
+    extracted: &mut HashMap<String, String>,
+    extracted_count: &mut usize,
+    files: &dyn OutputSink,
+) -> Option<String> {
+    if let Some(existing) = extracted.get(resolved) {
+        return Some(existing.clone());
+    }
+    let bytes = epub.read_resource_bytes(resolved).ok()?;
+    let relative = decode_path(resolved);
+    files.write(&media_root.join(&relative), &bytes).ok()?;
+    *extracted_count += 1;
+    let rel_path = format!("{media_link_prefix}/{relative}");
+    extracted.insert(resolved.to_string(), rel_path.clone());
+    Some(rel_path)
+}
+
+fn resolve_href(base_href: &str, rel: &str) -> String {
+    if rel.starts_with('/') {
+        normalize_path(rel)
+    } else {
+        let base_dir = base_href.rsplit_once('/').map(|(dir, _)| dir).unwrap_or("");
+        let combined = format!("{base_dir}/{rel}");
+        normalize_path(&combined)
+    }
+}
+
+fn normalize_path(path: &str) -> String {
+    let mut parts = Vec::new();
+    let absolute = path.starts_with('/');
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            _ => parts.push(part),
+        }
+    }
+    let joined = parts.join("/");
+    if absolute {
+        format!("/{joined}")
+    } else {
+        joined
+    }
+}
+
+fn decode_path(path: &str) -> String {
+    let trimmed = path.trim_start_matches('/');
+    urlencoding::decode(trimmed)
+        .map(|s| s.into_owned())
+        .unwrap_or_else(|_| trimmed.to_string())
+}
+
+fn is_external(value: &str) -> bool {
+    let lower = value.to_lowercase();
+    lower.starts_with("http://") || lower.starts_with("https://") || lower.starts_with("data:")
+}
+
+/// Transliterates to ASCII first, so "Война и мир" becomes `Voina_i_mir`
+/// rather than nothing.
+fn slugify(value: &str) -> String {
+    let mut out = String::new();
+    let mut prev_underscore = false;
+    for ch in deunicode::deunicode(value).chars() {
+        if ch.is_ascii_alphanumeric() || ch == '.' || ch == '-' {
+            out.push(ch);
+            prev_underscore = false;
+        } else if !prev_underscore {
+            out.push('_');
+            prev_underscore = true;
+        }
+    }
+    let trimmed = out.trim_matches(&['_', '.', '-'][..]).to_string();
+    if trimmed.is_empty() {
+        "book".to_string()
+    } else {
+        trimmed
+    }
+}
+
+fn build_section_id(
+    start_href: &str,
+    start_fragment: Option<&str>,
+    end_href: Option<&str>,
+    end_fragment: Option<&str>,
+) -> String {
+    let canonical = format!(
+        "{}#{}|{}#{}",
+        start_href,
+        start_fragment.unwrap_or(""),
+        end_href.unwrap_or(""),
+        end_fragment.unwrap_or("")
+    );
+    let mut hasher = Sha1::new();
+    hasher.update(canonical.as_bytes());
+    let digest = hasher.finalize();
+    format!("{:x}", digest)[..12].to_string()
+}
+
+fn cleanup_toc_entries(
+    entries: Vec<TocEntryInfo>,
+    mode: NavCleanupMode,
+) -> (Vec<TocEntryInfo>, usize) {
+    if mode == NavCleanupMode::Off {
+        return (entries, 0);
+    }
+    let mut cleaned: Vec<TocEntryInfo> = Vec::new();
+    let mut removed = 0usize;
+    let mut seen: HashSet<(String, String, String)> = HashSet::new();
+    for entry in entries {
+        let label = normalize_space(&entry.label).to_lowercase();
+        if label.is_empty() || OCR_NOISE_RE.is_match(&label) {
+            removed += 1;
+            continue;
+        }
+        let key = (
+            entry.href_path.clone(),
+            entry.fragment.clone().unwrap_or_default(),
+            label,
+        );
+        if seen.contains(&key) {
+            removed += 1;
+            continue;
+        }
+        seen.insert(key);
+        if let Some(prev) = cleaned.last() {
+            if prev.href_path == entry.href_path && prev.fragment == entry.fragment {
+                removed += 1;
+                continue;
+            }
+        }
+        cleaned.push(entry);
+    }
+    (cleaned, removed)
+}
+
+fn apply_ocr_cleanup(text: &str, mode: OcrCleanupMode) -> (String, usize) {
+    if mode == OcrCleanupMode::Off {
+        return (text.to_string(), 0);
+    }
+    let mut cleaned = text.to_string();
+    let mut changes = 0usize;
+    let hyphen_fixed = Regex::new(r"([A-Za-z])-\n([a-z])")
+        .expect("regex")
+        .replace_all(&cleaned, "$1$2")
+        .to_string();
+    if hyphen_fixed != cleaned {
+        changes += 1;
+        cleaned = hyphen_fixed;
+    }
+    let mut out = Vec::new();
+    let mut prev = String::new();
+    for line in cleaned.lines() {
+        let stripped = line.trim();
+        if OCR_NOISE_RE.is_match(stripped) {
+            changes += 1;
+            continue;
+        }
+        if mode == OcrCleanupMode::Aggressive && stripped.len() > 12 {
+            let noise = stripped
+                .chars()
+                .filter(|c| {
+                    !(c.is_ascii_alphanumeric()
+                        || c.is_ascii_whitespace()
+                        || ".,;:!?'-_()[]\"/".contains(*c))
+                })
+                .count();
+            if (noise as f32) / (stripped.len() as f32) > 0.35 {
+                changes += 1;
+                continue;
+            }
+        }
+        let compact = normalize_space(line);
+        if !compact.is_empty() && compact == prev {
+            changes += 1;
+            continue;
+        }
+        out.push(line.to_string());
+        if !compact.is_empty() {
+            prev = compact;
+        }
+    }
+    (out.join("\n").trim().to_string(), changes)
+}
+
+fn resolve_internal_target(target: &str, base_href: &str) -> Option<(String, Option<String>)> {
+    let trimmed = target.trim();
+    if trimmed.is_empty() {
+        return None;
+    }
+    let lower = trimmed.to_lowercase();
+    if lower.starts_with("http://")
+        || lower.starts_with("https://")
+        || lower.starts_with("mailto:")
+        || lower.starts_with("javascript:")
+        || lower.starts_with("data:")
+    {
+        return None;
+    }
+    let (raw_path, fragment) = match trimmed.split_once('#') {
+        Some((path, frag)) => (path, Some(frag.to_string())),
+        None => (trimmed, None),
+    };
+    let href = if raw_path.is_empty() {
+        normalize_path(base_href)
+    } else {
+        resolve_href(base_href, raw_path)
+    };
+    Some((href, fragment))
+}
+
+fn replace_markdown_links(
+    input: &str,
+    mut f: impl FnMut(&str) -> (String, bool),
+) -> (String, usize, usize) {
+    let mut rewritten = 0usize;
+    let mut unresolved = 0usize;
+    let output = MARKDOWN_LINK_RE
+        .replace_all(input, |caps: &regex::Captures| {
+            let bang = caps.get(1).map(|m| m.as_str()).unwrap_or("");
+            let label = caps.get(2).map(|m| m.as_str()).unwrap_or("");
+            let href = caps.get(3).map(|m| m.as_str()).unwrap_or("");
+            if bang == "!" {
+                return format!("![{}]({})", label, href);
+            }
+            let (new_href, resolved) = f(href);
+            if new_href != href {
+                rewritten += 1;
+            }
+            if !resolved {
+                unresolved += 1;
+            }
+            format!("[{}]({})", label, new_href)
+        })
+        .to_string();
+    (output, rewritten, unresolved)
+}
+
+fn replace_html_links(
+    input: &str,
+    mut f: impl FnMut(&str) -> (String, bool),
+) -> (String, usize, usize) {
+    let mut rewritten = 0usize;
+    let mut unresolved = 0usize;
+    let output = HTML_HREF_RE
+        .replace_all(input, |caps: &regex::Captures| {
+            let prefix = caps.get(1).map(|m| m.as_str()).unwrap_or("");
+            let href = caps.get(2).map(|m| m.as_str()).unwrap_or("");
+            let suffix = caps.get(3).map(|m| m.as_str()).unwrap_or("");
+            let (new_href, resolved) = f(href);
+            if new_href != href {
+                rewritten += 1;
+            }
+            if !resolved {
+                unresolved += 1;
+            }
+            format!("{prefix}{new_href}{suffix}")
+        })
+        .to_string();
+    (output, rewritten, unresolved)
+}
+
+fn extract_markdown_footnotes(text: &str) -> (String, Vec<(String, String)>) {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut kept = Vec::new();
+    let mut notes: Vec<(String, String)> = Vec::new();
+    let mut i = 0usize;
+    while i < lines.len() {
+        let line = lines[i];
+        let Some(caps) = FOOTNOTE_DEF_RE.captures(line) else {
+            kept.push(line.to_string());
+            i += 1;
+            continue;
+        };
+        let id = caps.get(1).map(|m| m.as_str()).unwrap_or("").to_string();
+        let mut payload = vec![
+            caps.get(2)
+                .map(|m| m.as_str())
+                .unwrap_or("")
+                .trim_end()
+                .to_string(),
+        ];
+        i += 1;
+        while i < lines.len() {
+            let cont = lines[i];
+            if cont.starts_with("    ") || cont.starts_with('\t') {
+                payload.push(cont.trim_start().to_string());
+                i += 1;
+            } else {
+                break;
+            }
+        }
+        let value = payload.join("\n").trim().to_string();
+        if !id.is_empty() && !value.is_empty() {
+            notes.push((id, value));
+        }
+    }
+    (kept.join("\n").trim().to_string(), notes)
+}
+
+fn rewrite_note_refs(text: &str, id_map: &HashMap<String, String>) -> String {
+    if id_map.is_empty() {
+        return text.to_string();
+    }
+    Regex::new(r"\[\^([^\]]+)\]")
+        .expect("regex")
+        .replace_all(text, |caps: &regex::Captures| {
+            let key = caps.get(1).map(|m| m.as_str()).unwrap_or("");
+            let mapped = id_map.get(key).cloned().unwrap_or_else(|| key.to_string());
+            format!("[^{}]", mapped)
+        })
+        .to_string()
+}
+
+/// Applies `options.on_conflict` to a book about to be written as
+/// `book_slug`: the slug to write under, or `None` to skip the book.
+pub(crate) fn resolve_output_conflict(
+    book_slug: String,
+    split_chapters: bool,
+    options: &ConvertOptions,
+) -> Option<String> {
+    let output_dir = &options.output_dir;
+    let files = &*options.output_sink;
+    let extension = text_output::output_extension(options.profile);
+    // Held until the slug is taken, so two books of a batch cannot both pick it.
+    let mut taken = options
+        .output_slugs
+        .as_ref()
+        .map(|slugs| slugs.lock().expect("output slugs lock"));
+    let chosen = {
+        let is_taken = |slug: &str| taken.as_ref().is_some_and(|taken| taken.contains(slug));
+        let has_markdown = |slug: &str| {
+            if split_chapters {
+                files
+                    .list_dir(&output_dir.join(slug))
+                    .iter()
+                    .any(|path| path.extension().and_then(|ext| ext.to_str()) == Some(extension))
+            } else {
+                files.exists(&output_dir.join(format!("{slug}.{extension}")))
+            }
+        };
+        match options.on_conflict {
+            OnConflict::Overwrite => Some(book_slug),
+            _ if !is_taken(&book_slug) && !has_markdown(&book_slug) => Some(book_slug),
+            OnConflict::Skip => None,
+            OnConflict::Rename => (2..).map(|n| format!("{book_slug}-{n}")).find(|slug| {
+                !is_taken(slug)
+                    && !files.exists(&output_dir.join(slug))
+                    && !files.exists(&output_dir.join(format!("{slug}.{extension}")))
+            }),
+        }
+    };
+    if let (Some(taken), Some(slug)) = (taken.as_mut(), &chosen) {
+        taken.insert(slug.clone());
+    }
+    chosen
+}
+
+/// Takes the lock on `book_slug` for the options' book lock, if they have one.
+pub(crate) fn lock_book(book_slug: &str, options: &ConvertOptions) -> Result<()> {
+    if let Some(slot) = &options.book_lock {
+        let lock = OutputLock::acquire_book(
+            &options.output_dir,
+            book_slug,
+            std::time::Duration::from_secs(options.lock_stale_after_secs),
+        )?;
+        *slot.lock().expect("book lock") = Some(lock);
+    }
+    Ok(())
+}
+
+/// How split chapter files are named: by the filename scheme, or by the file
+/// name part of `options.output_template` filled in for the book.
+#[derive(Clone, Copy)]
+pub(crate) enum SectionNaming<'a> {
+    Scheme(FilenameScheme),
+    Template(&'a OutputTemplate, &'a BookFields),
+}
+
+impl<'a> SectionNaming<'a> {
+    pub(crate) fn new(options: &'a ConvertOptions, fields: &'a BookFields) -> Self {
+        match &options.output_template {
+            Some(template) if template.names_sections() => Self::Template(template, fields),
+            _ => Self::Scheme(options.filename_scheme),
+        }
+    }
+}
+
+fn assign_section_output_paths(
+    sections: &mut [SectionRecord],
+    split_chapters: bool,
+    naming: SectionNaming<'_>,
+    book_slug: &str,
+    slugs: &dyn SlugStrategy,
+) {
+    if !split_chapters {
+        for section in sections {
+            section.output_path = format!("{book_slug}.md");
+        }
+        return;
+    }
+    let width = std::cmp::max(2, sections.len().to_string().len());
+    for (idx, section) in sections.iter_mut().enumerate() {
+        section.output_path = section_file_name(section, idx, width, naming, slugs);
+    }
+}
+
+/// The chapter file name of the `idx`th section, with indices padded to
+/// `width` digits.
+fn section_file_name(
+    section: &SectionRecord,
+    idx: usize,
+    width: usize,
+    naming: SectionNaming<'_>,
+    slugs: &dyn SlugStrategy,
+) -> String {
+    let mut section_slug = if section.title.trim().is_empty() {
+        format!("section_{:0width$}", idx + 1, width = width)
+    } else {
+        slugs.slug(&section.title)
+    };
+    section_slug = section_slug
+        .chars()
+        .take(80)
+        .collect::<String>()
+        .trim_matches(&['_', '.', '-'][..])
+        .to_string();
+    if section_slug.is_empty() {
+        section_slug = format!("section_{:0width$}", idx + 1, width = width);
+    }
+    match naming {
+        SectionNaming::Scheme(FilenameScheme::Index) => {
+            format!("{:0width$}_{}.md", idx + 1, section_slug, width = width)
+        }
+        SectionNaming::Scheme(FilenameScheme::Hash) => {
+            format!("{}_{}.md", section.section_id, section_slug)
+        }
+        SectionNaming::Template(template, fields) => {
+            template.section_file_name(fields, idx + 1, &section_slug, &section.section_id)
+        }
+    }
+}
+
+/// Replays the headings of each output file (book title, then each section's
+/// heading and the headings in its text) so repeated titles get the same
+/// numbered anchors the renderer will give them. Returns, per section, the
+/// heading anchor of each element id rendered as `<a id>` right above a heading.
+fn assign_heading_anchors(
+    sections: &mut [SectionRecord],
+    split_chapters: bool,
+    book_title: &str,
+    slugs: &dyn SlugStrategy,
+) -> Vec<HashMap<String, String>> {
+    let mut counter = slugs::AnchorCounter::new(slugs);
+    let mut fragment_anchors = Vec::with_capacity(sections.len());
+    for (idx, section) in sections.iter_mut().enumerate() {
+        if idx == 0 || split_chapters {
+            counter = slugs::AnchorCounter::new(slugs);
+            counter.next(book_title);
+        }
+        section.heading_anchor = counter.next(&section.title);
+        let mut ids = HashMap::new();
+        let mut in_fence = false;
+        let mut pending_id: Option<String> = None;
+        for line in section.text.lines() {
+            let trimmed = line.trim_start();
+            if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+                in_fence = !in_fence;
+            }
+            if in_fence || trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+                pending_id = None;
+                continue;
+            }
+            let hashes = trimmed.chars().take_while(|ch| *ch == '#').count();
+            if (1..=6).contains(&hashes) && trimmed[hashes..].starts_with(' ') {
+                let anchor = counter.next(trimmed[hashes..].trim());
+                if let (Some(id), Some(anchor)) = (pending_id.take(), anchor) {
+                    ids.insert(id, anchor);
+                }
+                continue;
+            }
+            pending_id = HTML_ANCHOR_LINE_RE
+                .captures(trimmed.trim_end())
+                .map(|caps| caps[1].replace("&quot;", "\"").replace("&amp;", "&"));
+        }
+        fragment_anchors.push(ids);
+    }
+    fragment_anchors
+}
+
+fn section_anchor(section: &SectionRecord) -> &str {
+    section
+        .heading_anchor
+        .as_deref()
+        .unwrap_or(&section.section_id)
+}
+
+fn rewrite_section_links(
+    sections: &mut [SectionRecord],
+    split_chapters: bool,
+    extracted_media: &HashMap<String, String>,
+    fragment_anchors: &[HashMap<String, String>],
+) -> (usize, usize, BTreeSet<String>) {
+    let mut href_to_section: HashMap<String, usize> = HashMap::new();
+    let mut anchor_to_section: HashMap<(String, String), usize> = HashMap::new();
+    for (idx, section) in sections.iter().enumerate() {
+        href_to_section
+            .entry(section.start_href.clone())
+            .or_insert(idx);
+        if let Some(fragment) = &section.start_fragment {
+            anchor_to_section.insert((section.start_href.clone(), fragment.clone()), idx);
+        }
+        for anchor in &section.anchors {
+            anchor_to_section.insert((section.start_href.clone(), anchor.clone()), idx);
+        }
+    }
+
+    let mut link_rewritten = 0usize;
+    let mut link_unresolved = 0usize;
+    let mut unresolved_targets = BTreeSet::new();
+    for idx in 0..sections.len() {
+        let base_href = sections[idx].start_href.clone();
+        let mut replacer = |target: &str| -> (String, bool) {
+            let Some((target_href, fragment)) = resolve_internal_target(target, &base_href) else {
+                return (target.to_string(), true);
+            };
+            if let Some(link) = extracted_media
+                .get(&target_href)
+                .or_else(|| extracted_media.get(target_href.trim_start_matches('/')))
+            {
+                return (link.clone(), true);
+            }
+            let mut target_idx = None;
+            if let Some(frag) = &fragment {
+                target_idx = anchor_to_section
+                    .get(&(target_href.clone(), frag.clone()))
+                    .copied();
+            }
+            if target_idx.is_none() {
+                target_idx = href_to_section.get(&target_href).copied();
+            }
+            let Some(target_idx) = target_idx else {
+                unresolved_targets.insert(match &fragment {
+                    Some(frag) => format!("{target_href}#{frag}"),
+                    None => target_href,
+                });
+                return (target.to_string(), false);
+            };
+            // Headings are addressed the way the renderer names them.
+            let fragment = fragment.map(|frag| {
+                fragment_anchors[target_idx]
+                    .get(&frag)
+                    .cloned()
+                    .unwrap_or(frag)
+            });
+            if split_chapters {
+                if target_idx == idx {
+                    if let Some(frag) = fragment {
+                        return (format!("#{frag}"), true);
+                    }
+                    return (format!("./{}", sections[target_idx].output_path), true);
+                }
+                let mut out = format!("./{}", sections[target_idx].output_path);
+                if let Some(frag) = fragment {
+                    out.push('#');
+                    out.push_str(&frag);
+                }
+                return (out, true);
+            }
+            if let Some(frag) = fragment {
+                return (format!("#{frag}"), true);
+            }
+            (format!("#{}", section_anchor(&sections[target_idx])), true)
+        };
+        let (rewritten_md, md_rw, md_unresolved) =
+            replace_markdown_links(&sections[idx].text, &mut replacer);
+        let (rewritten_html, html_rw, html_unresolved) =
+            replace_html_links(&rewritten_md, &mut replacer);
+        sections[idx].text = rewritten_html;
+        link_rewritten += md_rw + html_rw;
+        link_unresolved += md_unresolved + html_unresolved;
+    }
+    (link_rewritten, link_unresolved, unresolved_targets)
+}
+
+/// Moves footnote definitions emitted per rendered chunk to the end of their section.
+fn gather_section_footnotes(sections: &mut [SectionRecord]) {
+    for section in sections {
+        let (stripped, notes) = extract_markdown_footnotes(&section.text);
+        if notes.is_empty() {
+            continue;
+        }
+        let mut seen: HashSet<&str> = HashSet::new();
+        let defs: Vec<String> = notes
+            .iter()
+            .filter(|(note_id, _)| seen.insert(note_id.as_str()))
+            .map(|(note_id, text)| format!("[^{}]: {}", note_id, text.replace('\n', "\n    ")))
+            .collect();
+        section.text = format!("{}\n\n{}", stripped, defs.join("\n"));
+    }
+}
+
+fn apply_notes_mode_to_sections(
+    sections: &mut [SectionRecord],
+    notes_mode: NotesMode,
+) -> (usize, Vec<String>) {
+    if notes_mode == NotesMode::Inline {
+        gather_section_footnotes(sections);
+        return (0, Vec::new());
+    }
+    let mut notes_written = 0usize;
+    let mut global_note_lines: Vec<String> = Vec::new();
+    for section in sections {
+        let (stripped, notes) = extract_markdown_footnotes(&section.text);
+        if notes.is_empty() {
+            continue;
+        }
+        let mut id_map: HashMap<String, String> = HashMap::new();
+        for (idx, (note_id, _)) in notes.iter().enumerate() {
+            id_map.insert(
+                note_id.clone(),
+                format!("note-{}-{:03}", section.section_id, idx + 1),
+            );
+        }
+        section.text = rewrite_note_refs(&stripped, &id_map);
+        let rendered_defs: Vec<String> = notes
+            .iter()
+            .map(|(note_id, text)| {
+                format!("[^{}]: {}", id_map.get(note_id).unwrap_or(note_id), text)
+            })
+            .collect();
+        notes_written += rendered_defs.len();
+        match notes_mode {
+            NotesMode::Inline => {}
+            NotesMode::ChapterEnd => {
+                section.text = format!(
+                    "{}\n\n### Notes\n\n{}",
+                    section.text.trim(),
+                    rendered_defs.join("\n")
+                );
+            }
+            NotesMode::Global => {
+                global_note_lines.push(format!("## {} ({})", section.title, section.section_id));
+                global_note_lines.push(String::new());
+                global_note_lines.extend(rendered_defs);
+                global_note_lines.push(String::new());
+            }
+        }
+    }
+    (notes_written, global_note_lines)
+}
+
+fn count_words(text: &str) -> usize {
+    text.split_whitespace()
+        .filter(|word| word.chars().any(|c| c.is_alphanumeric()))
+        .count()
+}
+
+/// Folds sections under `min_words` (half-titles, separators) into the section
+/// that follows them; a trailing tiny section is folded into its predecessor.
+fn merge_tiny_sections(sections: &mut Vec<SectionRecord>, min_words: usize) -> usize {
+    if min_words == 0 || sections.len() < 2 {
+        return 0;
+    }
+    let mut merged = 0usize;
+    let mut idx = 0usize;
+    while idx < sections.len() && sections.len() > 1 {
+        if count_words(&sections[idx].text) >= min_words {
+            idx += 1;
+            continue;
+        }
+        let tiny = sections.remove(idx);
+        if idx < sections.len() {
+            let next = &mut sections[idx];
+            next.text = format!("{}\n\n{}", tiny.text.trim(), next.text.trim());
+            next.start_href = tiny.start_href;
+            next.start_fragment = tiny.start_fragment;
+            next.spine_start = tiny.spine_start;
+            next.anchors.extend(tiny.anchors);
+            next.anchors.sort();
+            next.anchors.dedup();
+        } else {
+            let prev = &mut sections[idx - 1];
+            prev.text = format!("{}\n\n{}", prev.text.trim(), tiny.text.trim());
+            prev.end_href = tiny.end_href;
+            prev.end_fragment = tiny.end_fragment;
+            prev.spine_end = tiny.spine_end;
+            prev.anchors.extend(tiny.anchors);
+            prev.anchors.sort();
+            prev.anchors.dedup();
+        }
+        merged += 1;
+    }
+    merged
+}
+
+/// The section's id, unique among `seen_ids`, which it is added to.
+fn unique_section_id(section: &SectionRecord, seen_ids: &mut HashSet<String>) -> String {
+    let mut section_id = build_section_id(
+        &section.start_href,
+        section.start_fragment.as_deref(),
+        section.end_href.as_deref(),
+        section.end_fragment.as_deref(),
+    );
+    // Heading splits without element ids share boundaries; disambiguate by order.
+    let mut ordinal = 1usize;
+    while seen_ids.contains(&section_id) {
+        ordinal += 1;
+        section_id = build_section_id(
+            &format!("{}~{}", section.start_href, ordinal),
+            section.start_fragment.as_deref(),
+            section.end_href.as_deref(),
+            section.end_fragment.as_deref(),
+        );
+    }
+    seen_ids.insert(section_id.clone());
+    section_id
+}
+
+fn postprocess_sections(
+    sections: &mut Vec<SectionRecord>,
+    split_chapters: bool,
+    naming: SectionNaming<'_>,
+    book_slug: &str,
+    ocr_cleanup: OcrCleanupMode,
+    notes_mode: NotesMode,
+    min_section_words: usize,
+    extracted_media: &HashMap<String, String>,
+    book_title: &str,
+    slugs: &dyn SlugStrategy,
+    planned: Option<&PlannedBook>,
+) -> PostprocessStats {
+    let mut stats = PostprocessStats::default();
+    stats.sections_merged = merge_tiny_sections(sections, min_section_words);
+    let mut seen_ids: HashSet<String> = HashSet::new();
+    for section in sections.iter_mut() {
+        section.section_id = unique_section_id(section, &mut seen_ids);
+        let (cleaned, changes) = apply_ocr_cleanup(&section.text, ocr_cleanup);
+        section.text = cleaned;
+        stats.cleanup_changes += changes;
+    }
+    // After ids are known and before anything depends on the section list.
+    if let Some(planned) = planned {
+        stats.plan_missing = plan::apply_plan(sections, planned);
+    }
+    assign_section_output_paths(sections, split_chapters, naming, book_slug, slugs);
+    let fragment_anchors = assign_heading_anchors(sections, split_chapters, book_title, slugs);
+    let (rewritten, unresolved, unresolved_targets) =
+        rewrite_section_links(sections, split_chapters, extracted_media, &fragment_anchors);
+    stats.link_rewritten = rewritten;
+    stats.link_unresolved = unresolved;
+    stats.unresolved_targets = unresolved_targets;
+    let (notes_written, global_note_lines) = apply_notes_mode_to_sections(sections, notes_mode);
+    stats.notes_written = notes_written;
+    stats.global_note_lines = global_note_lines;
+    stats
+}
+
+fn write_markdown_outputs(
+    sections: &[SectionRecord],
+    options: &ConvertOptions,
+    output_dir: &Path,
+    book_dir: &Path,
+    book_slug: &str,
+    title: &str,
+    author: Option<&String>,
+    style_header_lines: &[String],
+    global_note_lines: &[String],
+    figure_lines: &[String],
+    chapter_thumbnails: &HashMap<String, String>,
+    cover_link: Option<&str>,
+    writer: &text_output::TextWriter,
+) -> Result<PathBuf> {
+    let output_root = if options.split_chapters {
+        book_dir.to_path_buf()
+    } else {
+        output_dir.to_path_buf()
+    };
+    writer
+        .files
+        .create_dir_all(&output_root)
+        .map_err(|err| ConvertError::write_failed(&output_root, err))?;
+
+    let mut base_lines = Vec::new();
+    base_lines.push(format!("# {title}"));
+    if let Some(author) = author {
+        base_lines.push(format!("**Author:** {author}"));
+    }
+    if !style_header_lines.is_empty() {
+        base_lines.push(String::new());
+        base_lines.extend(style_header_lines.to_vec());
+    }
+    base_lines.push(String::new());
+
+    let mut cover_front_matter = None;
+    let mut cover_lines = Vec::new();
+    if let Some(cover_link) = cover_link {
+        match options.cover_reference {
+            CoverReference::Off => {}
+            CoverReference::Frontmatter => {
+                cover_front_matter = Some(format!("cover: {}", serde_json::to_string(cover_link)?));
+            }
+            CoverReference::Image => {
+                cover_lines.push(format!("![Cover]({cover_link})"));
+                cover_lines.push(String::new());
+            }
+        }
+    }
+
+    let asset_prefixes: Vec<String> = ["images", "media", "styles", "fonts", "thumbs"]
+        .iter()
+        .map(|kind| asset_link_prefix(options, book_slug, kind))
+        .collect();
+
+    let mut return_path = output_root.clone();
+    if options.split_chapters {
+        output::remove_chapter_files(&*writer.files, &output_root)
+            .map_err(|err| ConvertError::write_failed(&output_root, err))?;
+        let chapter_link = |idx: usize| format!("./{}", sections[idx].output_path);
+        let previous = |idx: usize| idx.checked_sub(1);
+        let next = |idx: usize| Some(idx + 1).filter(|next| *next < sections.len());
+        for (idx, section) in sections.iter().enumerate() {
+            let mut front_matter: Vec<String> = cover_front_matter.iter().cloned().collect();
+            if let Some(thumbnail) = chapter_thumbnails.get(&section.section_id) {
+                front_matter.push(format!("thumbnail: {}", serde_json::to_string(thumbnail)?));
+            }
+            if options.chapter_nav == ChapterNav::Frontmatter {
+                for (key, target) in [("prev", previous(idx)), ("next", next(idx))] {
+                    if let Some(target) = target {
+                        front_matter.push(format!(
+                            "{key}: {}",
+                            serde_json::to_string(&chapter_link(target))?
+                        ));
+                    }
+                }
+                front_matter.push("index: \"./index.md\"".to_string());
+            }
+            let mut lines = Vec::new();
+            if !front_matter.is_empty() {
+                lines.push("---".to_string());
+                lines.extend(front_matter);
+                lines.push("---".to_string());
+            }
+            lines.extend(base_lines.iter().cloned());
+            if idx == 0 {
+                lines.extend(cover_lines.iter().cloned());
+            }
+            lines.push(format!("<a id=\"{}\"></a>", section.section_id));
+            lines.push(format!("## {}", section.title));
+            lines.push(String::new());
+            lines.push(section.text.clone());
+            lines.push(String::new());
+            if options.chapter_nav == ChapterNav::Footer {
+                let mut links = Vec::new();
+                if let Some(target) = previous(idx) {
+                    links.push(format!(
+                        "[← {}]({})",
+                        escape_link_text(&sections[target].title),
+                        chapter_link(target)
+                    ));
+                }
+                links.push("[Contents](./index.md)".to_string());
+                if let Some(target) = next(idx) {
+                    links.push(format!(
+                        "[{} →]({})",
+                        escape_link_text(&sections[target].title),
+                        chapter_link(target)
+                    ));
+                }
+                lines.push("---".to_string());
+                lines.push(String::new());
+                lines.push(links.join(" · "));
+                lines.push(String::new());
+            }
+            let output_path = output_root.join(&section.output_path);
+            if let Some(parent) = output_path.parent() {
+                writer
+                    .files
+                    .create_dir_all(parent)
+                    .map_err(|err| ConvertError::write_failed(parent, err))?;
+            }
+            let text = rebase_asset_links(
+                &lines.join("\n"),
+                &asset_prefixes,
+                &output_root,
+                &output_path,
+            );
+            writer.write(&output_path, &(text.trim().to_string() + "\n"))?;
+        }
+        if options.chapter_nav != ChapterNav::Off {
+            let mut lines = vec![format!("# {title}"), String::new()];
+            for (idx, section) in sections.iter().enumerate() {
+                lines.push(format!(
+                    "{}. [{}]({})",
+                    idx + 1,
+                    escape_link_text(&section.title),
+                    chapter_link(idx)
+                ));
+            }
+            writer.write(&output_root.join("index.md"), &(lines.join("\n") + "\n"))?;
+        }
+    } else {
+        let output_path = output_root.join(format!("{book_slug}.md"));
+        if let Some(parent) = output_path.parent() {
+            writer
+                .files
+                .create_dir_all(parent)
+                .map_err(|err| ConvertError::write_failed(parent, err))?;
+        }
+        let mut lines = Vec::new();
+        if let Some(cover_front_matter) = cover_front_matter {
+            lines.push("---".to_string());
+            lines.push(cover_front_matter);
+            lines.push("---".to_string());
+        }
+        lines.extend(base_lines);
+        lines.extend(cover_lines);
+        for section in sections {
+            lines.push(format!("<a id=\"{}\"></a>", section.section_id));
+            lines.push(format!("## {}", section.title));
+            lines.push(String::new());
+            lines.push(section.text.clone());
+            lines.push(String::new());
+        }
+        if !figure_lines.is_empty() {
+            lines.push("## List of Figures".to_string());
+            lines.push(String::new());
+            lines.extend(figure_lines.to_vec());
+            lines.push(String::new());
+        }
+        if options.notes_mode == NotesMode::Global && !global_note_lines.is_empty() {
+            lines.push("## Notes".to_string());
+            lines.push(String::new());
+            lines.extend(global_note_lines.to_vec());
+        }
+        let text = rebase_asset_links(
+            &lines.join("\n"),
+            &asset_prefixes,
+            &output_root,
+            &output_path,
+        );
+        writer.write(&output_path, &(text.trim().to_string() + "\n"))?;
+        return_path = writer.output_path(&output_path);
+    }
+
+    if options.split_chapters && !figure_lines.is_empty() {
+        writer.write(
+            &output_root.join("list_of_figures.md"),
+            &format!("# List of Figures\n\n{}\n", figure_lines.join("\n").trim()),
+        )?;
+    }
+
+    if options.notes_mode == NotesMode::Global && !global_note_lines.is_empty() {
+        writer
+            .files
+            .create_dir_all(book_dir)
+            .map_err(|err| ConvertError::write_failed(book_dir, err))?;
+        writer.write(
+            &book_dir.join("notes.md"),
+            &format!("# Notes\n\n{}\n", global_note_lines.join("\n").trim()),
+        )?;
+    }
+    if options.split_chapters {
+        output::record_chapter_files(&*writer.files, &output_root, &writer.written.borrow())?;
+    }
+    Ok(return_path)
+}
+
+/// The figure elements of a document, outermost only: a figure inside a
+/// `div.figure` is one figure.
+fn figure_nodes(document: &NodeRef) -> Vec<NodeRef> {
+    let Ok(nodes) = document.select("figure, div[class*='figure'], div[class*='illustration']")
+    else {
+        return Vec::new();
+    };
+    nodes
+        .map(|node| node.as_node().clone())
+        .filter(|node| {
+            !node.ancestors().any(|ancestor| {
+                matches!(element_name(&ancestor), Some("figure"))
+                    || ancestor
+                        .as_element()
+                        .and_then(|el| el.attributes.borrow().get("class").map(str::to_string))
+                        .map(|class| class.contains("figure") || class.contains("illustration"))
+                        .unwrap_or(false)
+            })
+        })
+        .collect()
+}
+
+/// The id a figure is linked by: its own, or the first one inside it.
+fn figure_fragment(node: &NodeRef) -> Option<String> {
+    std::iter::once(node.clone())
+        .chain(node.descendants())
+        .find_map(|candidate| {
+            candidate.as_element().and_then(|el| {
+                el.attributes
+                    .borrow()
+                    .get("id")
+                    .map(|id| id.trim().to_string())
+            })
+        })
+        .filter(|id| !id.is_empty())
+}
+
+/// `href#id` of every figure the list of figures links to, so the renderer
+/// keeps their anchors.
+fn figure_link_targets(
+    epub: &Epub,
+    spine_hrefs: &[String],
+    cache: &mut ContentCache,
+) -> HashSet<String> {
+    let mut targets = HashSet::new();
+    for href in spine_hrefs {
+        let Ok(content) = load_content(epub, href, cache) else {
+            continue;
+        };
+        for node in figure_nodes(&content.document) {
+            if figure_caption(&node).is_some() {
+                if let Some(fragment) = figure_fragment(&node) {
+                    targets.insert(format!("{href}#{fragment}"));
+                }
+            }
+        }
+    }
+    targets
+}
+
+/// Walks the spine for captioned figures and attributes each one to the
+/// section that rendered it (by anchor when the figure has an id, otherwise by
+/// spine position).
+fn collect_figures(
+    epub: &Epub,
+    spine_hrefs: &[String],
+    cache: &mut ContentCache,
+    sections: &[SectionRecord],
+    extracted_images: &HashMap<String, String>,
+    link_targets: &HashSet<String>,
+) -> Vec<FigureRecord> {
+    let mut figures = Vec::new();
+    for (spine_idx, href) in spine_hrefs.iter().enumerate() {
+        let Ok(content) = load_content(epub, href, cache) else {
+            continue;
+        };
+        for node in figure_nodes(&content.document) {
+            let node = &node;
+            let image_src = node.select_first("img, image").ok().and_then(|img| {
+                let attrs = img.attributes.borrow();
+                attrs
+                    .get("src")
+                    .or_else(|| attrs.get("xlink:href"))
+                    .or_else(|| attrs.get("href"))
+                    .map(str::to_string)
+            });
+            let Some(caption) = figure_caption(node) else {
+                continue;
+            };
+            let fragment = figure_fragment(node);
+            let section_idx = fragment
+                .as_ref()
+                .and_then(|id| {
+                    sections.iter().position(|section| {
+                        spine_idx >= section.spine_start
+                            && spine_idx <= section.spine_end
+                            && section.anchors.contains(id)
+                    })
+                })
+                .or_else(|| {
+                    sections.iter().position(|section| {
+                        spine_idx >= section.spine_start && spine_idx <= section.spine_end
+                    })
+                });
+            let Some(section_idx) = section_idx else {
+                continue;
+            };
+            let image = image_src.map(|src| {
+                let resolved = resolve_href(href, &src);
+                extracted_images.get(&resolved).cloned().unwrap_or(src)
+            });
+            let anchored = fragment
+                .as_ref()
+                .is_some_and(|id| link_targets.contains(&format!("{href}#{id}")));
+            figures.push(FigureRecord {
+                caption,
+                href: href.clone(),
+                fragment,
+                anchored,
+                image,
+                section_idx,
+            });
+        }
+    }
+    figures
+}
+
+fn figure_caption(node: &NodeRef) -> Option<String> {
+    let caption = node
+        .select_first("figcaption, [class*='caption']")
+        .ok()
+        .map(|caption| normalize_space(&caption.as_node().text_contents()))
+        .filter(|caption| !caption.is_empty());
+    caption.or_else(|| {
+        let img = node.select_first("img").ok()?;
+        let attrs = img.attributes.borrow();
+        attrs
+            .get("title")
+            .or_else(|| attrs.get("alt"))
+            .map(normalize_space)
+            .filter(|caption| !caption.is_empty())
+    })
+}
+
+fn escape_link_text(text: &str) -> String {
+    text.replace('[', "\\[").replace(']', "\\]")
+}
+
+fn figure_link(figure: &FigureRecord, sections: &[SectionRecord], split_chapters: bool) -> String {
+    let section = &sections[figure.section_idx];
+    // Without an anchor in the output, the figure's section is the closest target.
+    let fragment = figure.fragment.as_ref().filter(|_| figure.anchored);
+    match (fragment, split_chapters) {
+        (Some(fragment), true) => format!("./{}#{}", section.output_path, fragment),
+        (None, true) => format!("./{}", section.output_path),
+        (Some(fragment), false) => format!("#{fragment}"),
+        (None, false) => format!("#{}", section_anchor(section)),
+    }
+}
+
+fn build_list_of_figures(
+    figures: &[FigureRecord],
+    sections: &[SectionRecord],
+    split_chapters: bool,
+) -> Vec<String> {
+    figures
+        .iter()
+        .enumerate()
+        .map(|(idx, figure)| {
+            format!(
+                "{}. [{}]({}) — {}",
+                idx + 1,
+                escape_link_text(&figure.caption),
+                figure_link(figure, sections, split_chapters),
+                sections[figure.section_idx].title
+            )
+        })
+        .collect()
+}
+
+fn write_figures_export(
+    book_dir: &Path,
+    book_slug: &str,
+    figures: &[FigureRecord],
+    sections: &[SectionRecord],
+    options: &ConvertOptions,
+) -> Result<()> {
+    options
+        .output_sink
+        .create_dir_all(book_dir)
+        .map_err(|err| ConvertError::write_failed(book_dir, err))?;
+    let figures_json: Vec<serde_json::Value> = figures
+        .iter()
+        .enumerate()
+        .map(|(idx, figure)| {
+            let section = &sections[figure.section_idx];
+            json!({
+                "order": idx + 1,
+                "caption": figure.caption,
+                "image": figure.image,
+                "section_id": section.section_id,
+                "section_title": section.title,
+                "output_path": if options.split_chapters {
+                    format!("{}/{}", book_slug, section.output_path)
+                } else {
+                    section.output_path.clone()
+                },
+                "link": figure_link(figure, sections, options.split_chapters),
+                "source": {
+                    "href": figure.href,
+                    "fragment": figure.fragment,
+                },
+            })
+        })
+        .collect();
+    let payload = json!({
+        "schema_version": "v1",
+        "figures": figures_json,
+    });
+    text_output::TextWriter::new(options).write_utf8(
+        &book_dir.join("figures.v1.json"),
+        &(serde_json::to_string_pretty(&payload)? + "\n"),
+    )?;
+    Ok(())
+}
+
+fn write_manifest_export(
+    enabled: ExportMode,
+    book_dir: &Path,
+    title: &str,
+    author: Option<&String>,
+    language: Option<&str>,
+    book_slug: &str,
+    spine_hrefs: &[String],
+    toc_entries: &[TocEntryInfo],
+    sections: &[SectionRecord],
+    extracted_images: &HashMap<String, String>,
+    extracted_media: &HashMap<String, String>,
+    chapter_thumbnails: &HashMap<String, String>,
+    rights: &RightsInfo,
+    extra: &BTreeMap<String, Vec<String>>,
+    book_fingerprint: Option<&Fingerprint>,
+    section_fingerprints: &[Fingerprint],
+    options: &ConvertOptions,
+) -> Result<()> {
+    if enabled != ExportMode::V1 {
+        return Ok(());
+    }
+    options
+        .output_sink
+        .create_dir_all(book_dir)
+        .map_err(|err| ConvertError::write_failed(book_dir, err))?;
+    let sections_json: Vec<serde_json::Value> = sections
+        .iter()
+        .enumerate()
+        .map(|(idx, section)| {
+            json!({
+                "section_id": section.section_id,
+                "order": idx + 1,
+                "title": section.title,
+                "words": count_words(&section.text),
+                "output_path": if options.split_chapters {
+                    format!("{}/{}", book_slug, section.output_path)
+                } else {
+                    section.output_path.clone()
+                },
+                "source_start": {
+                    "href": section.start_href,
+                    "fragment": section.start_fragment,
+                    "spine_index": section.spine_start,
+                },
+                "source_end": {
+                    "href": section.end_href,
+                    "fragment": section.end_fragment,
+                    "spine_index": section.spine_end,
+                },
+                "anchors": section.anchors,
+                "thumbnail": chapter_thumbnails.get(&section.section_id),
+                "fingerprint": section_fingerprints.get(idx).map(Fingerprint::to_json),
+            })
+        })
+        .collect();
+    let toc_json: Vec<serde_json::Value> = toc_entries
+        .iter()
+        .enumerate()
+        .map(|(idx, entry)| {
+            json!({
+                "order": idx,
+                "label": entry.label,
+                "href": entry.href_path,
+                "fragment": entry.fragment
+            })
+        })
+        .collect();
+    // Kept out of the payload literal below, which would otherwise exceed
+    // the json! recursion limit.
+    let build = build_settings_json(options);
+    let manifest_payload = json!({
+        "schema_version": "v1",
+        "book": {
+            "title": title,
+            "authors": author.cloned().unwrap_or_default(),
+            "language": language,
+            "slug": book_slug,
+            "rights": rights.to_json(),
+            "extra": extra,
+            "fingerprint": book_fingerprint.map(Fingerprint::to_json),
+        },
+        "spine": spine_hrefs.iter().enumerate().map(|(idx, href)| {
+            json!({"index": idx, "href": href})
+        }).collect::<Vec<_>>(),
+        "toc_tree": toc_json,
+        "sections": sections_json,
+        "landmarks": [],
+        "page_list": [],
+        "assets": {
+            "images": extracted_images.keys().collect::<Vec<_>>(),
+            "media": extracted_media.keys().collect::<Vec<_>>(),
+        },
+        "build": build,
+    });
+    text_output::TextWriter::new(options).write_utf8(
+        &book_dir.join("manifest.v1.json"),
+        &(serde_json::to_string_pretty(&manifest_payload)? + "\n"),
+    )?;
+    Ok(())
+}
+
+/// The options that shape a book's output, as recorded in its manifest.
+/// Settings that only affect how the run goes (jobs, caching) are left out.
+fn build_settings_json(options: &ConvertOptions) -> serde_json::Value {
+    json!({
+        "markdown_mode": format!("{:?}", options.markdown_mode),
+        "style": format!("{:?}", options.style),
+        "split_chapters": options.split_chapters,
+        "chapter_fallback": format!("{:?}", options.chapter_fallback),
+        "heading_patterns": options
+            .heading_patterns
+            .iter()
+            .map(Regex::as_str)
+            .collect::<Vec<_>>(),
+        "notes_mode": format!("{:?}", options.notes_mode),
+        "ocr_cleanup": format!("{:?}", options.ocr_cleanup),
+        "nav_cleanup": format!("{:?}", options.nav_cleanup),
+        "cleanup_profile": options.cleanup_profile,
+        "cleanup_profiles": options
+            .cleanup_profile
+            .as_ref()
+            .map(|_| format!("{:?}", options.cleanup_profiles)),
+        "strip_watermarks": options.strip_watermarks,
+        "filename_scheme": format!("{:?}", options.filename_scheme),
+        "output_template": options.output_template.as_ref().map(ToString::to_string),
+        "split_on_heading_level": options.split_on_heading_level,
+        "escape_markdown": options.escape_markdown,
+        "min_section_words": options.min_section_words,
+        "consolidate_endnotes": options.consolidate_endnotes,
+        "list_of_figures": options.list_of_figures,
+        "anchor_mode": format!("{:?}", options.anchor_mode),
+        "export_positions": format!("{:?}", options.export_positions),
+        "export_provenance": format!("{:?}", options.export_provenance),
+        "export_audio_split": format!("{:?}", options.export_audio_split),
+        "compare_view": options.compare_view,
+        "asset_base_url": options.asset_base_url,
+        "svg_mode": format!("{:?}", options.svg_mode),
+        "ruby_mode": format!("{:?}", options.ruby_mode),
+        "text_direction": format!("{:?}", options.text_direction),
+        "extract_fonts": options.extract_fonts,
+        "media_all": options.media_all,
+        "cover_reference": format!("{:?}", options.cover_reference),
+        "image_format": format!("{:?}", options.image_format),
+        "extra_readable_types": options.extra_readable_types,
+        "max_image_size": options.max_image_size.map(|(width, height)| format!("{width}x{height}")),
+        "image_quality": options.image_quality,
+        "inline_images_below": options.inline_images_below,
+        "suppress_warnings": options.suppress_warnings.iter().map(|code| code.code()).collect::<Vec<_>>(),
+        "error_on_warnings": options.error_on_warnings.iter().map(|code| code.code()).collect::<Vec<_>>(),
+        "figure_caption_template": options.figure_caption_template,
+        "slug_strategy": format!("{:?}", options.slug_strategy),
+        "strip_image_metadata": options.strip_image_metadata,
+        "skip_decorative_images": options.skip_decorative_images,
+        "skip_images": options.skip_images,
+        "chapter_nav": format!("{:?}", options.chapter_nav),
+        "only_public_domain": options.only_public_domain,
+        "public_domain_before": options.public_domain_before,
+        "output_encoding": options.output_encoding,
+        "newline": format!("{:?}", options.newline),
+        "bom": options.bom,
+        "text_normalization": format!("{:?}", options.text_normalization),
+        "collapse_spaces": options.collapse_spaces,
+        "plan": options.plan.is_some(),
+        "chapter_thumbnails": options.chapter_thumbnails,
+        "thumbnail_max_edge": options.thumbnail_max_edge,
+        "fingerprints": options.fingerprints,
+        "translation_export": format!("{:?}", options.translation_export),
+        "translation_target_language": options.translation_target_language,
+        "flashcard_export": format!("{:?}", options.flashcard_export),
+        "profile": format!("{:?}", options.profile),
+        "spell_out_numbers": options.spell_out_numbers,
+        "large_print_font_size": options.large_print_font_size,
+        "book_overrides": options
+            .book_overrides
+            .iter()
+            .map(|book| json!({"key": book.key, "summary": book.summary}))
+            .collect::<Vec<_>>(),
+    })
+}
+
+fn write_quality_report(
+    enabled: ExportMode,
+    book_dir: &Path,
+    toc_entry_count: usize,
+    toc_unique_count: usize,
+    toc_coverage_ratio: f32,
+    toc_is_degenerate: bool,
+    use_heading_fallback: bool,
+    options: &ConvertOptions,
+    stats: &PostprocessStats,
+    extracted_count: usize,
+    extracted_media_count: usize,
+    nav_removed: usize,
+    missing_resources: &MissingResources,
+    warnings: &[(WarningCode, String)],
+) -> Result<()> {
+    if enabled != ExportMode::V1 {
+        return Ok(());
+    }
+    options
+        .output_sink
+        .create_dir_all(book_dir)
+        .map_err(|err| ConvertError::write_failed(book_dir, err))?;
+    let report = json!({
+        "toc_stats": {
+            "entries": toc_entry_count,
+            "unique_hrefs": toc_unique_count,
+            "coverage_ratio": toc_coverage_ratio,
+            "degenerate": toc_is_degenerate,
+        },
+        "fallback_stats": {
+            "mode": format!("{:?}", options.chapter_fallback),
+            "used_heading_fallback": use_heading_fallback,
+        },
+        "link_stats": {
+            "rewritten": stats.link_rewritten,
+            "unresolved": stats.link_unresolved,
+        },
+        "asset_stats": {
+            "images_extracted": extracted_count,
+            "media_extracted": extracted_media_count,
+            "missing_assets": missing_resources.images.len() + missing_resources.stylesheets.len(),
+        },
+        "missing_resources": {
+            "images": missing_resources.images,
+            "stylesheets": missing_resources.stylesheets,
+            "links": missing_resources.links,
+        },
+        "ocr_stats": {
+            "mode": format!("{:?}", options.ocr_cleanup),
+            "cleanup_changes": stats.cleanup_changes,
+        },
+        "cleanup_stats": {
+            "nav_cleanup_mode": format!("{:?}", options.nav_cleanup),
+            "toc_entries_removed": nav_removed,
+            "profile": stats.profile_cleanup.as_ref().map(|cleanup| json!({
+                "name": cleanup.profile,
+                "elements_stripped": cleanup.elements_stripped,
+                "sections_skipped": cleanup.sections_skipped,
+                "pattern_removals": cleanup.pattern_removals,
+            })),
+            "watermarks": stats.watermarks.as_ref().map(WatermarkReport::to_json),
+        },
+        "notes_stats": {
+            "mode": format!("{:?}", options.notes_mode),
+            "notes_written": stats.notes_written,
+        },
+        "section_stats": {
+            "min_section_words": options.min_section_words,
+            "sections_merged": stats.sections_merged,
+        },
+        "warnings": warnings.iter().map(|(_, msg)| msg).collect::<Vec<_>>(),
+        "warning_codes": warnings.iter().map(|(code, _)| code.code()).collect::<Vec<_>>(),
+        "errors": warnings
+            .iter()
+            .filter(|(code, _)| options.error_on_warnings.contains(code))
+            .map(|(_, msg)| msg)
+            .collect::<Vec<_>>(),
+    });
+    text_output::TextWriter::new(options).write_utf8(
+        &book_dir.join("report.v1.json"),
+        &(serde_json::to_string_pretty(&report)? + "\n"),
+    )?;
+    Ok(())
+}
@@ -0,0 +1,302 @@
+use serde_json::json;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{ConvertError, Result};
+
+pub const LOCK_FILE_NAME: &str = ".rbook-utils.lock";
+
+/// Exclusive claim on an output directory, released when dropped.
+///
+/// The lock file records the owner's pid, host, start time and a nonce
+/// unique to the lock. A lock left behind by a process that no longer exists
+/// on this host is taken over instead of blocking forever; so is a lock of
+/// another host (or of a platform where liveness cannot be checked) once it
+/// is older than the stale threshold. A live owner on this host keeps its
+/// lock however long it runs.
+#[derive(Debug)]
+pub struct OutputLock {
+    path: PathBuf,
+    nonce: String,
+}
+
+impl OutputLock {
+    pub fn acquire(dir: &Path, stale_after: Duration) -> Result<Self> {
+        Self::acquire_file(dir, dir.join(LOCK_FILE_NAME), stale_after)
+    }
+
+    /// Claims only the book written as `book_slug` under `dir`, so runs sharing
+    /// an output directory (a watcher and a scheduled batch) get in each
+    /// other's way only when they convert the same book at once.
+    pub fn acquire_book(dir: &Path, book_slug: &str, stale_after: Duration) -> Result<Self> {
+        let book_path = dir.join(book_slug);
+        let file_name = match book_path.file_name() {
+            Some(name) => format!(".{}{LOCK_FILE_NAME}", name.to_string_lossy()),
+            None => LOCK_FILE_NAME.to_string(),
+        };
+        let lock_dir = book_path.parent().unwrap_or(dir).to_path_buf();
+        let path = lock_dir.join(file_name);
+        Self::acquire_file(&book_path, path, stale_after)
+    }
+
+    /// Takes the lock file at `path`; `dir` is what it claims, for errors.
+    fn acquire_file(dir: &Path, path: PathBuf, stale_after: Duration) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|err| ConvertError::write_failed(parent, err))?;
+        }
+        let nonce = new_nonce();
+        let payload = serde_json::to_string(&json!({
+            "pid": std::process::id(),
+            "host": host_name(),
+            "started_at": unix_now(),
+            "nonce": nonce,
+        }))?;
+        let mut owner = None;
+        // A takeover can lose to another run taking the same stale lock, and
+        // a lock can vanish between failing to create it and reading it.
+        for _ in 0..3 {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    if let Err(err) = file.write_all(payload.as_bytes()) {
+                        let _ = fs::remove_file(&path);
+                        return Err(ConvertError::write_failed(&path, err));
+                    }
+                    return Ok(Self { path, nonce });
+                }
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                    let Ok(seen) = fs::read(&path) else {
+                        continue;
+                    };
+                    let current = LockOwner::parse(&seen, &path);
+                    if !current.is_stale(stale_after) {
+                        owner = Some(current);
+                        break;
+                    }
+                    match remove_matching(&path, &nonce, |bytes| bytes == seen) {
+                        Ok(true) => {}
+                        Err(err) if err.kind() == ErrorKind::NotFound => {}
+                        // Replaced by a live run since it was read.
+                        _ => {
+                            owner = fs::read(&path)
+                                .ok()
+                                .map(|bytes| LockOwner::parse(&bytes, &path));
+                            break;
+                        }
+                    }
+                }
+                Err(err) => return Err(ConvertError::write_failed(&path, err)),
+            }
+        }
+        Err(ConvertError::Locked {
+            dir: dir.to_path_buf(),
+            owner: owner.map_or_else(|| "another run".to_string(), |owner| owner.describe()),
+            lock: path,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for OutputLock {
+    fn drop(&mut self) {
+        // A lock taken over from this run belongs to its new owner.
+        let _ = remove_matching(&self.path, &self.nonce, |bytes| {
+            serde_json::from_slice::<serde_json::Value>(bytes)
+                .is_ok_and(|value| value.get("nonce").and_then(|v| v.as_str()) == Some(&self.nonce))
+        });
+    }
+}
+
+/// Deletes the lock file at `path` if `expected` holds for its contents, and
+/// reports whether it did. The file is first renamed to a name only the
+/// caller (by `nonce`) uses, so another run cannot replace it between the
+/// check and the delete; a file that fails the check is put back unless a new
+/// lock has taken its place meanwhile.
+fn remove_matching(
+    path: &Path,
+    nonce: &str,
+    expected: impl Fn(&[u8]) -> bool,
+) -> std::io::Result<bool> {
+    let mut aside = path.as_os_str().to_owned();
+    aside.push(format!(".{nonce}"));
+    let aside = PathBuf::from(aside);
+    fs::rename(path, &aside)?;
+    let matched = fs::read(&aside).is_ok_and(|bytes| expected(&bytes));
+    if !matched {
+        let _ = fs::hard_link(&aside, path);
+    }
+    let _ = fs::remove_file(&aside);
+    Ok(matched)
+}
+
+/// Tells this lock apart from any other, including earlier ones of this process.
+fn new_nonce() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+    format!(
+        "{:x}-{nanos:x}-{:x}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+struct LockOwner {
+    pid: Option<u32>,
+    host: Option<String>,
+    started_at: Option<u64>,
+}
+
+impl LockOwner {
+    /// The owner recorded in `bytes`, read from the lock file at `path`.
+    fn parse(bytes: &[u8], path: &Path) -> Self {
+        let value: Option<serde_json::Value> = serde_json::from_slice(bytes).ok();
+        let started_at = value
+            .as_ref()
+            .and_then(|v| v.get("started_at"))
+            .and_then(|v| v.as_u64())
+            .or_else(|| {
+                // Unreadable or half-written lock: fall back to the file's age.
+                fs::metadata(path)
+                    .and_then(|meta| meta.modified())
+                    .ok()
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .map(|age| age.as_secs())
+            });
+        Self {
+            pid: value
+                .as_ref()
+                .and_then(|v| v.get("pid"))
+                .and_then(|v| v.as_u64())
+                .and_then(|pid| u32::try_from(pid).ok()),
+            host: value
+                .as_ref()
+                .and_then(|v| v.get("host"))
+                .and_then(|v| v.as_str())
+                .map(str::to_string),
+            started_at,
+        }
+    }
+
+    fn is_stale(&self, stale_after: Duration) -> bool {
+        // On this host the pid answers for certain; age is only a guess.
+        let on_this_host = self.host.as_deref() == Some(host_name().as_str());
+        if let (true, Some(alive)) = (on_this_host, self.pid.and_then(process_alive)) {
+            return !alive;
+        }
+        self.started_at
+            .is_some_and(|started_at| unix_now().saturating_sub(started_at) > stale_after.as_secs())
+    }
+
+    fn describe(&self) -> String {
+        let pid = self
+            .pid
+            .map(|pid| pid.to_string())
+            .unwrap_or_else(|| "?".to_string());
+        let host = self.host.as_deref().unwrap_or("unknown host");
+        match self.started_at {
+            Some(started_at) => format!(
+                "pid {pid} on {host} ({}s ago)",
+                unix_now().saturating_sub(started_at)
+            ),
+            None => format!("pid {pid} on {host}"),
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn host_name() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "localhost".to_string())
+}
+
+/// Only Linux exposes liveness cheaply without extra dependencies; elsewhere
+/// this is `None` and a lock is considered live until it ages past the stale
+/// threshold.
+fn process_alive(pid: u32) -> Option<bool> {
+    cfg!(target_os = "linux").then(|| Path::new(&format!("/proc/{pid}")).exists())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rbook-lock-{name}-{}", new_nonce()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_owner(dir: &Path, pid: u32, host: &str, started_at: u64) {
+        let owner = json!({ "pid": pid, "host": host, "started_at": started_at, "nonce": "other" });
+        fs::write(dir.join(LOCK_FILE_NAME), owner.to_string()).unwrap();
+    }
+
+    const STALE_AFTER: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn held_lock_blocks_a_second_run() {
+        let dir = scratch_dir("held");
+        let _lock = OutputLock::acquire(&dir, STALE_AFTER).unwrap();
+        let err = OutputLock::acquire(&dir, STALE_AFTER).unwrap_err();
+        assert!(matches!(err, ConvertError::Locked { .. }));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn live_owner_on_this_host_keeps_an_old_lock() {
+        let dir = scratch_dir("live");
+        write_owner(&dir, std::process::id(), &host_name(), 0);
+        let err = OutputLock::acquire(&dir, STALE_AFTER).unwrap_err();
+        assert!(matches!(err, ConvertError::Locked { .. }));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn dead_owner_on_this_host_is_taken_over() {
+        let dir = scratch_dir("dead");
+        // Above any pid_max Linux allows.
+        write_owner(&dir, 4_194_305, &host_name(), unix_now());
+        let lock = OutputLock::acquire(&dir, STALE_AFTER).unwrap();
+        assert!(
+            fs::read_to_string(lock.path())
+                .unwrap()
+                .contains(&lock.nonce)
+        );
+    }
+
+    #[test]
+    fn other_host_lock_is_stale_by_age() {
+        let dir = scratch_dir("remote");
+        write_owner(&dir, 1, "elsewhere.invalid", unix_now());
+        assert!(OutputLock::acquire(&dir, STALE_AFTER).is_err());
+        write_owner(&dir, 1, "elsewhere.invalid", 0);
+        assert!(OutputLock::acquire(&dir, STALE_AFTER).is_ok());
+    }
+
+    #[test]
+    fn dropping_a_taken_over_lock_keeps_the_new_owners() {
+        let dir = scratch_dir("drop");
+        let lock = OutputLock::acquire(&dir, STALE_AFTER).unwrap();
+        write_owner(&dir, 1, "elsewhere.invalid", unix_now());
+        drop(lock);
+        let kept = fs::read_to_string(dir.join(LOCK_FILE_NAME)).unwrap();
+        assert!(kept.contains("elsewhere.invalid"));
+    }
+}
@@ -0,0 +1,1251 @@
+use kuchiki::NodeRef;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use crate::{
+    AnchorMode, ConvertOptions, MarkdownMode, RubyMode, decode_path, element_name, heading_level,
+    normalize_path, normalize_space, resolve_internal_target, serialize_node,
+};
+
+const INLINE_TAGS: &[&str] = &[
+    "a", "abbr", "b", "bdi", "bdo", "big", "br", "cite", "code", "data", "del", "dfn", "em",
+    "font", "i", "img", "ins", "kbd", "label", "mark", "math", "q", "rp", "rt", "ruby", "s",
+    "samp", "small", "span", "strike", "strong", "sub", "sup", "time", "tt", "u", "var", "wbr",
+];
+
+const SKIPPED_TAGS: &[&str] = &["head", "title", "script", "style", "svg", "template"];
+
+#[derive(Clone, Debug)]
+pub(crate) struct RenderOptions {
+    pub(crate) mode: MarkdownMode,
+    /// Backslash-escape markdown syntax characters found in book text.
+    pub(crate) escape: bool,
+    pub(crate) book_notes: Rc<BookNotes>,
+    pub(crate) anchor_mode: AnchorMode,
+    pub(crate) ruby_mode: RubyMode,
+    /// Base direction of the book; explicit `dir` attributes override it per element.
+    pub(crate) rtl: bool,
+    /// `href#id` keys of elements that something links to; only these keep an anchor.
+    pub(crate) link_targets: Rc<HashSet<String>>,
+    /// Line written under a captioned image; `{caption}` is the caption text.
+    pub(crate) figure_caption_template: String,
+}
+
+impl RenderOptions {
+    pub(crate) fn from_convert_options(options: &ConvertOptions) -> Self {
+        Self {
+            mode: options.markdown_mode,
+            escape: options.escape_markdown,
+            book_notes: Rc::default(),
+            anchor_mode: options.anchor_mode,
+            ruby_mode: options.ruby_mode,
+            rtl: false,
+            link_targets: Rc::default(),
+            figure_caption_template: options.figure_caption_template.clone(),
+        }
+    }
+}
+
+/// Serializes DOM nodes to markdown.
+///
+/// Block structure comes from the element tree; text inside a block is assembled
+/// as a single inline run so whitespace is only emitted where the source had it,
+/// regardless of how many inline element boundaries it crosses. `notes` is the
+/// index of the document the nodes belong to, built once per document.
+pub(crate) fn render_nodes(
+    nodes: &[NodeRef],
+    notes: &NoteIndex,
+    options: &RenderOptions,
+) -> String {
+    let ctx = RenderContext {
+        options,
+        base_href: &notes.base_href,
+        notes,
+        note_defs: RefCell::new(Vec::new()),
+        direction: RefCell::new(vec![options.rtl]),
+    };
+    let mut blocks = Vec::new();
+    render_blocks(nodes.iter().cloned(), &ctx, &mut blocks);
+    let note_defs = ctx.note_defs.into_inner();
+    if !note_defs.is_empty() {
+        blocks.push(
+            note_defs
+                .iter()
+                .map(|(label, body)| format!("[^{label}]: {body}"))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        );
+    }
+    blocks.join("\n\n").trim().to_string()
+}
+
+struct RenderContext<'a> {
+    options: &'a RenderOptions,
+    base_href: &'a str,
+    notes: &'a NoteIndex,
+    /// Footnote definitions (label, markdown body) in first-reference order.
+    note_defs: RefCell<Vec<(String, String)>>,
+    /// Text direction stack (`true` = right-to-left), one entry per `dir` override.
+    direction: RefCell<Vec<bool>>,
+}
+
+impl RenderContext<'_> {
+    fn is_rtl(&self) -> bool {
+        self.direction.borrow().last().copied().unwrap_or(false)
+    }
+
+    /// Plain text has no `dir` attribute, so a block whose first strong
+    /// character disagrees with its direction gets a leading RLM/LRM; otherwise
+    /// viewers pick the wrong base direction and move trailing punctuation.
+    fn direction_mark(&self, text: &str) -> Option<char> {
+        if self.options.mode != MarkdownMode::Plain {
+            return None;
+        }
+        // Left-to-right books only need marks inside explicit overrides.
+        if !self.options.rtl && self.direction.borrow().len() == 1 {
+            return None;
+        }
+        let rtl = self.is_rtl();
+        let first_strong = text
+            .chars()
+            .find(|ch| is_rtl_char(*ch) || ch.is_alphabetic())
+            .map(is_rtl_char);
+        match (rtl, first_strong) {
+            (true, Some(true)) | (false, Some(false)) => None,
+            (true, _) => Some('\u{200f}'),
+            (false, _) => Some('\u{200e}'),
+        }
+    }
+
+    fn with_direction(&self, text: String) -> String {
+        match self.direction_mark(&text) {
+            Some(mark) => format!("{mark}{text}"),
+            None => text,
+        }
+    }
+
+    /// The id of `node` when it is a link target that should survive as an anchor.
+    fn anchor_id(&self, node: &NodeRef) -> Option<String> {
+        if self.options.anchor_mode == AnchorMode::Off {
+            return None;
+        }
+        let el = node.as_element()?;
+        let attrs = el.attributes.borrow();
+        let mut ids = vec![attrs.get("id")];
+        if &*el.name.local == "a" {
+            ids.push(attrs.get("name"));
+        }
+        ids.into_iter()
+            .flatten()
+            .map(str::trim)
+            .find(|id| {
+                !id.is_empty()
+                    && self
+                        .options
+                        .link_targets
+                        .contains(&format!("{}#{}", self.base_href, id))
+            })
+            .map(str::to_string)
+    }
+}
+
+fn html_anchor(id: &str) -> String {
+    format!(
+        "<a id=\"{}\"></a>",
+        id.replace('&', "&amp;").replace('"', "&quot;")
+    )
+}
+
+fn render_blocks(
+    nodes: impl Iterator<Item = NodeRef>,
+    ctx: &RenderContext,
+    blocks: &mut Vec<String>,
+) {
+    let mut run = InlineRun::new(ctx);
+    for node in nodes {
+        if ctx.notes.is_body(&node) {
+            continue;
+        }
+        if is_block(&node) {
+            flush_paragraph(&mut run, ctx, blocks);
+            render_block(&node, ctx, blocks);
+        } else {
+            run.push_node(&node);
+        }
+    }
+    flush_paragraph(&mut run, ctx, blocks);
+}
+
+fn flush_paragraph<'a>(
+    run: &mut InlineRun<'a>,
+    ctx: &'a RenderContext<'a>,
+    blocks: &mut Vec<String>,
+) {
+    let text = std::mem::replace(run, InlineRun::new(ctx)).finish();
+    if !text.is_empty() {
+        blocks.push(ctx.with_direction(text));
+    }
+}
+
+fn is_block(node: &NodeRef) -> bool {
+    match element_name(node) {
+        Some(tag) => !INLINE_TAGS.contains(&tag),
+        None => false,
+    }
+}
+
+fn render_block(node: &NodeRef, ctx: &RenderContext, blocks: &mut Vec<String>) {
+    let Some(tag) = element_name(node) else {
+        return;
+    };
+    if SKIPPED_TAGS.contains(&tag) {
+        return;
+    }
+    if let Some(rtl) = element_rtl(node).filter(|rtl| *rtl != ctx.is_rtl()) {
+        ctx.direction.borrow_mut().push(rtl);
+        let mut inner = Vec::new();
+        render_block_content(node, tag, ctx, &mut inner);
+        ctx.direction.borrow_mut().pop();
+        if inner.is_empty() {
+            return;
+        }
+        if ctx.options.mode == MarkdownMode::Rich {
+            let dir = if rtl { "rtl" } else { "ltr" };
+            blocks.push(format!(
+                "<div dir=\"{dir}\">\n\n{}\n\n</div>",
+                inner.join("\n\n")
+            ));
+        } else {
+            blocks.extend(inner);
+        }
+        return;
+    }
+    render_block_content(node, tag, ctx, blocks);
+}
+
+fn render_block_content(node: &NodeRef, tag: &str, ctx: &RenderContext, blocks: &mut Vec<String>) {
+    let anchor = ctx.anchor_id(node);
+    if let Some(level) = heading_level(tag) {
+        let mut run = InlineRun::new(ctx);
+        run.push_children(node);
+        let text = ctx.with_direction(run.finish().replace("  \n", " "));
+        if !text.is_empty() {
+            let marker = "#".repeat(level as usize);
+            blocks.push(match (anchor, ctx.options.anchor_mode) {
+                (Some(id), AnchorMode::Attributes) => format!("{marker} {text} {{#{id}}}"),
+                (Some(id), _) => format!("{}\n{marker} {text}", html_anchor(&id)),
+                (None, _) => format!("{marker} {text}"),
+            });
+        } else if let Some(id) = anchor {
+            blocks.push(html_anchor(&id));
+        }
+        return;
+    }
+    let first_block = blocks.len();
+    render_block_body(node, tag, ctx, blocks);
+    if let Some(id) = anchor {
+        // Paragraph text can carry the anchor inline; anything with its own
+        // line syntax (lists, quotes, tables) gets it as a separate block.
+        match blocks.get_mut(first_block) {
+            Some(first) if tag == "p" => first.insert_str(0, &html_anchor(&id)),
+            _ => blocks.insert(first_block, html_anchor(&id)),
+        }
+    }
+}
+
+fn render_block_body(node: &NodeRef, tag: &str, ctx: &RenderContext, blocks: &mut Vec<String>) {
+    match tag {
+        "ul" => render_list(node, false, ctx, blocks),
+        "ol" => render_list(node, true, ctx, blocks),
+        "blockquote" => render_blockquote(node, None, ctx, blocks),
+        "pre" => render_code_block(node, blocks),
+        "hr" => blocks.push("---".to_string()),
+        "table" => render_table(node, ctx, blocks),
+        "figure" => render_figure(node, ctx, blocks),
+        "div" | "section" | "aside" if is_quote_container(node) => {
+            render_blockquote(node, None, ctx, blocks)
+        }
+        _ => render_blocks(node.children(), ctx, blocks),
+    }
+}
+
+fn render_list(node: &NodeRef, ordered: bool, ctx: &RenderContext, blocks: &mut Vec<String>) {
+    let mut number = node
+        .as_element()
+        .and_then(|el| el.attributes.borrow().get("start").map(str::to_string))
+        .and_then(|start| start.trim().parse::<usize>().ok())
+        .unwrap_or(1);
+    let mut items: Vec<String> = Vec::new();
+    for child in node.children() {
+        match element_name(&child) {
+            Some("li") => {
+                let mut item_blocks = Vec::new();
+                render_blocks(child.children(), ctx, &mut item_blocks);
+                let marker = if ordered {
+                    format!("{number}. ")
+                } else {
+                    "- ".to_string()
+                };
+                number += 1;
+                items.push(indent_item(&marker, &item_blocks.join("\n\n")));
+            }
+            Some("ul" | "ol") => {
+                // Nested lists placed directly inside a list belong to the previous item.
+                let mut nested = Vec::new();
+                render_block(&child, ctx, &mut nested);
+                let nested = nested.join("\n\n");
+                match items.last_mut() {
+                    Some(last) => {
+                        let pad = if ordered { "   " } else { "  " };
+                        last.push('\n');
+                        last.push_str(&indent_lines(pad, &nested));
+                    }
+                    None => items.push(nested),
+                }
+            }
+            _ => {}
+        }
+    }
+    if !items.is_empty() {
+        blocks.push(items.join("\n"));
+    }
+}
+
+fn indent_item(marker: &str, body: &str) -> String {
+    let pad = " ".repeat(marker.len());
+    let mut lines = body.lines();
+    let first = lines.next().unwrap_or("");
+    let mut out = format!("{marker}{first}").trim_end().to_string();
+    for line in lines {
+        out.push('\n');
+        if !line.is_empty() {
+            out.push_str(&pad);
+            out.push_str(line);
+        }
+    }
+    out
+}
+
+fn indent_lines(pad: &str, body: &str) -> String {
+    body.lines()
+        .map(|line| {
+            if line.is_empty() {
+                String::new()
+            } else {
+                format!("{pad}{line}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Renders a quotation with its nesting preserved; attribution elements (`<cite>`,
+/// `<footer>`, `.attribution`) become a trailing `— Author` line inside the quote.
+fn render_blockquote(
+    node: &NodeRef,
+    extra_attribution: Option<&NodeRef>,
+    ctx: &RenderContext,
+    blocks: &mut Vec<String>,
+) {
+    let children: Vec<NodeRef> = node.children().collect();
+    let mut attributions: Vec<&NodeRef> = children
+        .iter()
+        .filter(|child| is_attribution(child))
+        .collect();
+    attributions.extend(extra_attribution);
+    let mut inner = Vec::new();
+    render_blocks(
+        children
+            .iter()
+            .filter(|child| !is_attribution(child))
+            .cloned(),
+        ctx,
+        &mut inner,
+    );
+    for attribution in attributions {
+        let mut run = InlineRun::new(ctx);
+        run.push_children(attribution);
+        let text = run.finish().replace("  \n", " ");
+        let text = text.trim_start_matches(['—', '–', '-', '\\', ' ']).trim();
+        if !text.is_empty() {
+            inner.push(format!("— {text}"));
+        }
+    }
+    let body = inner.join("\n\n");
+    if body.trim().is_empty() {
+        return;
+    }
+    blocks.push(quote_lines(&body));
+}
+
+fn render_figure(node: &NodeRef, ctx: &RenderContext, blocks: &mut Vec<String>) {
+    let quote = node
+        .children()
+        .find(|child| element_name(child) == Some("blockquote"));
+    let caption = node
+        .children()
+        .find(|child| element_name(child) == Some("figcaption"));
+    match (quote, caption) {
+        (Some(quote), caption) => render_blockquote(&quote, caption.as_ref(), ctx, blocks),
+        (None, Some(caption)) if is_image_figure(node) => {
+            render_blocks(
+                node.children().filter(|child| *child != caption),
+                ctx,
+                blocks,
+            );
+            let mut run = InlineRun::new(ctx);
+            run.push_children(&caption);
+            let mut text = run.finish().replace("  \n", " ");
+            let template = &ctx.options.figure_caption_template;
+            if template.contains("*{caption}") {
+                // `*Map of *Rome**` would close the outer emphasis early.
+                text = underscore_emphasis(&text);
+            }
+            if !text.is_empty() {
+                let line = template.replace("{caption}", &text);
+                blocks.push(ctx.with_direction(line));
+            }
+        }
+        (None, _) => render_blocks(node.children(), ctx, blocks),
+    }
+}
+
+/// Rewrites unescaped `*` emphasis markers as `_`.
+fn underscore_emphasis(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut escaped = false;
+    for ch in text.chars() {
+        out.push(if ch == '*' && !escaped { '_' } else { ch });
+        escaped = ch == '\\' && !escaped;
+    }
+    out
+}
+
+/// Elements a figure may contain and still be written as an image plus caption line.
+const IMAGE_FIGURE_TAGS: &[&str] = &[
+    "figure",
+    "figcaption",
+    "img",
+    "picture",
+    "source",
+    "p",
+    "div",
+    "span",
+    "a",
+    "br",
+    "em",
+    "i",
+    "strong",
+    "b",
+    "cite",
+    "small",
+    "sub",
+    "sup",
+];
+
+/// A `<figure>` of images and an optional `<figcaption>`; anything richer
+/// (tables, SVG, code) is left for Rich mode to keep as HTML.
+pub(crate) fn is_image_figure(node: &NodeRef) -> bool {
+    let mut has_image = false;
+    for descendant in node.descendants() {
+        match element_name(&descendant) {
+            Some("img") => has_image = true,
+            Some(tag) if !IMAGE_FIGURE_TAGS.contains(&tag) => return false,
+            _ => {}
+        }
+    }
+    has_image
+}
+
+fn is_quote_container(node: &NodeRef) -> bool {
+    has_semantic(node, &["epigraph", "blockquote", "quotation"])
+}
+
+fn is_attribution(node: &NodeRef) -> bool {
+    match element_name(node) {
+        Some("cite" | "footer") => true,
+        Some("p" | "div") => {
+            if has_semantic(
+                node,
+                &["attribution", "credit", "epigraph-author", "signature"],
+            ) {
+                return true;
+            }
+            // `<p>— <cite>Author</cite></p>`: a cite with nothing but dashes around it.
+            let mut elements = node.children().filter(|child| child.as_element().is_some());
+            let only_cite = matches!(
+                (elements.next(), elements.next()),
+                (Some(ref first), None) if element_name(first) == Some("cite")
+            );
+            only_cite
+                && node
+                    .children()
+                    .filter_map(|child| child.as_text().map(|text| text.borrow().clone()))
+                    .all(|text| {
+                        text.chars()
+                            .all(|c| c.is_whitespace() || matches!(c, '—' | '–' | '-'))
+                    })
+        }
+        Some(_) => has_semantic(node, &["attribution", "credit"]),
+        None => false,
+    }
+}
+
+fn quote_lines(body: &str) -> String {
+    body.lines()
+        .map(|line| {
+            if line.is_empty() {
+                ">".to_string()
+            } else {
+                format!("> {line}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn render_code_block(node: &NodeRef, blocks: &mut Vec<String>) {
+    let text = node.text_contents();
+    let code = text.trim_matches('\n').trim_end();
+    if code.trim().is_empty() {
+        return;
+    }
+    let mut fence = "```".to_string();
+    while code.contains(fence.as_str()) {
+        fence.push('`');
+    }
+    let language = code_language(node).unwrap_or_default();
+    blocks.push(format!("{fence}{language}\n{code}\n{fence}"));
+}
+
+fn code_language(node: &NodeRef) -> Option<String> {
+    let mut candidates = vec![node.clone()];
+    if let Ok(code) = node.select_first("code") {
+        candidates.push(code.as_node().clone());
+    }
+    for candidate in candidates {
+        let Some(el) = candidate.as_element() else {
+            continue;
+        };
+        let attrs = el.attributes.borrow();
+        if let Some(class) = attrs.get("class") {
+            for name in class.split_whitespace() {
+                if let Some(lang) = name
+                    .strip_prefix("language-")
+                    .or_else(|| name.strip_prefix("lang-"))
+                {
+                    return Some(lang.to_string());
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Whether a table can be written as a GFM pipe table without losing structure:
+/// no merged cells, no nested tables and no block content inside cells.
+pub(crate) fn is_simple_table(node: &NodeRef) -> bool {
+    let rows = table_rows(node);
+    if rows.is_empty() {
+        return false;
+    }
+    for cell in rows.iter().flatten() {
+        let spans = ["rowspan", "colspan"].iter().any(|name| {
+            attr(cell, name)
+                .and_then(|value| value.trim().parse::<usize>().ok())
+                .is_some_and(|span| span > 1)
+        });
+        if spans {
+            return false;
+        }
+        let paragraphs = cell
+            .descendants()
+            .filter(|child| element_name(child) == Some("p"))
+            .count();
+        let has_blocks = cell.descendants().any(|child| {
+            matches!(
+                element_name(&child),
+                Some("table" | "ul" | "ol" | "dl" | "pre" | "blockquote" | "hr")
+            ) || element_name(&child).and_then(heading_level).is_some()
+        });
+        if paragraphs > 1 || has_blocks {
+            return false;
+        }
+    }
+    true
+}
+
+/// Rows of `node` in document order (`thead`, `tbody`, `tfoot` and bare `tr`),
+/// without descending into nested tables.
+fn table_rows(node: &NodeRef) -> Vec<Vec<NodeRef>> {
+    let mut rows = Vec::new();
+    let mut sections = vec![node.clone()];
+    sections.extend(
+        node.children()
+            .filter(|child| matches!(element_name(child), Some("thead" | "tbody" | "tfoot"))),
+    );
+    // `thead` first, `tfoot` last, whatever order the markup used.
+    sections.sort_by_key(|section| match element_name(section) {
+        Some("thead") => 0,
+        Some("tfoot") => 2,
+        _ => 1,
+    });
+    for section in sections {
+        for tr in section
+            .children()
+            .filter(|child| element_name(child) == Some("tr"))
+        {
+            let cells: Vec<NodeRef> = tr
+                .children()
+                .filter(|cell| matches!(element_name(cell), Some("td" | "th")))
+                .collect();
+            if !cells.is_empty() {
+                rows.push(cells);
+            }
+        }
+    }
+    rows
+}
+
+fn cell_alignment(cell: &NodeRef) -> &'static str {
+    let style = attr(cell, "style").unwrap_or_default().to_lowercase();
+    let align = attr(cell, "align")
+        .map(|value| value.trim().to_lowercase())
+        .or_else(|| {
+            style.split(';').find_map(|decl| {
+                let (name, value) = decl.split_once(':')?;
+                (name.trim() == "text-align").then(|| value.trim().to_string())
+            })
+        });
+    match align.as_deref() {
+        Some("left") => " :--- |",
+        Some("center") => " :---: |",
+        Some("right") => " ---: |",
+        _ => " --- |",
+    }
+}
+
+fn render_table(node: &NodeRef, ctx: &RenderContext, blocks: &mut Vec<String>) {
+    let rows = table_rows(node);
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+    if columns == 0 {
+        return;
+    }
+    if let Ok(caption) = node.select_first("caption") {
+        let mut run = InlineRun::new(ctx);
+        run.push_children(caption.as_node());
+        let text = run.finish().replace("  \n", " ");
+        if !text.is_empty() {
+            blocks.push(format!("*{text}*"));
+        }
+    }
+    let mut lines = Vec::new();
+    for (idx, row) in rows.iter().enumerate() {
+        let mut cells: Vec<String> = row
+            .iter()
+            .map(|cell| {
+                let mut run = InlineRun::new(ctx);
+                run.push_children(cell);
+                let text = run.finish().replace("  \n", " ");
+                if ctx.options.escape {
+                    text
+                } else {
+                    // Pipes must stay escaped even for trusted content or the row splits.
+                    text.replace('|', "\\|")
+                }
+            })
+            .collect();
+        cells.resize(columns, String::new());
+        lines.push(format!("| {} |", cells.join(" | ")));
+        if idx == 0 {
+            let mut separator = "|".to_string();
+            for col in 0..columns {
+                separator.push_str(row.get(col).map(cell_alignment).unwrap_or(" --- |"));
+            }
+            lines.push(separator);
+        }
+    }
+    blocks.push(lines.join("\n"));
+}
+
+fn define_note(ctx: &RenderContext, label: &str, body: &NodeRef) {
+    if ctx
+        .note_defs
+        .borrow()
+        .iter()
+        .any(|(existing, _)| existing == label)
+    {
+        return;
+    }
+    // Reserve the slot first so a note referencing itself cannot recurse.
+    let slot = {
+        let mut defs = ctx.note_defs.borrow_mut();
+        defs.push((label.to_string(), String::new()));
+        defs.len() - 1
+    };
+    let mut blocks = Vec::new();
+    render_blocks(body.children(), ctx, &mut blocks);
+    let text = strip_note_marker(&blocks.join("\n\n"));
+    let rendered = text
+        .lines()
+        .map(str::trim_end)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n    ");
+    ctx.note_defs.borrow_mut()[slot].1 = rendered;
+}
+
+/// Drops the leading "1." / "[2]" / "*" marker many books print before note text.
+fn strip_note_marker(text: &str) -> String {
+    let trimmed = text.trim_start();
+    let rest = trimmed
+        .strip_prefix("\\[")
+        .or_else(|| trimmed.strip_prefix('['))
+        .unwrap_or(trimmed);
+    let marker_len = rest
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || matches!(c, '*' | '†' | '‡' | '§' | '\\'))
+        .map(char::len_utf8)
+        .sum::<usize>();
+    if marker_len == 0 || marker_len > 8 {
+        return trimmed.to_string();
+    }
+    let rest = &rest[marker_len..];
+    let rest = rest
+        .strip_prefix("\\]")
+        .or_else(|| rest.strip_prefix(']'))
+        .unwrap_or(rest);
+    let rest = rest
+        .strip_prefix("\\.")
+        .or_else(|| rest.strip_prefix('.'))
+        .or_else(|| rest.strip_prefix(')'))
+        .unwrap_or(rest);
+    if rest.starts_with(char::is_whitespace) {
+        rest.trim_start().to_string()
+    } else {
+        trimmed.to_string()
+    }
+}
+
+/// Footnote references found in a document, with the element holding each note
+/// body. Bodies are suppressed in the normal flow and emitted as `[^label]:`
+/// definitions where they are first referenced.
+#[derive(Default)]
+pub(crate) struct NoteIndex {
+    base_href: String,
+    refs: Vec<(NodeRef, String, NodeRef)>,
+    bodies: Vec<NodeRef>,
+    noteref_keys: HashSet<String>,
+    book_refs: HashSet<String>,
+}
+
+/// Notes whose reference and body live in different spine documents
+/// (a trailing "Notes" chapter), keyed by `href#id`.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct BookNotes {
+    pub(crate) bodies: HashMap<String, NodeRef>,
+    /// `href#id` of every noteref anchor, so back-links in note bodies can be dropped.
+    pub(crate) refs: HashSet<String>,
+}
+
+impl NoteIndex {
+    pub(crate) fn build(document: &NodeRef, base_href: &str, book_notes: &BookNotes) -> Self {
+        let mut index = NoteIndex {
+            base_href: base_href.to_string(),
+            book_refs: book_notes.refs.clone(),
+            ..NoteIndex::default()
+        };
+        let base_key = normalize_path(base_href);
+        for (key, body) in &book_notes.bodies {
+            if key.split_once('#').map(|(href, _)| href) == Some(base_key.as_str())
+                && !index.bodies.contains(body)
+            {
+                index.bodies.push(body.clone());
+            }
+        }
+        let mut ids: HashMap<String, NodeRef> = HashMap::new();
+        if let Ok(matches) = document.select("[id]") {
+            for node in matches {
+                if let Some(id) = node.attributes.borrow().get("id") {
+                    ids.entry(id.to_string())
+                        .or_insert_with(|| node.as_node().clone());
+                }
+            }
+        }
+        let Ok(anchors) = document.select("a[href]") else {
+            return index;
+        };
+        for anchor in anchors {
+            let anchor = anchor.as_node().clone();
+            let Some(href) = attr(&anchor, "href") else {
+                continue;
+            };
+            let Some((target_href, Some(target_id))) = resolve_internal_target(&href, base_href)
+            else {
+                continue;
+            };
+            let body = if target_href == base_key {
+                let Some(target) = ids.get(&target_id) else {
+                    continue;
+                };
+                if !is_noteref(&anchor, target) {
+                    continue;
+                }
+                note_body(target)
+            } else {
+                // Endnotes living in another spine document, resolved book-wide up front.
+                match book_notes.bodies.get(&format!("{target_href}#{target_id}")) {
+                    Some(body) => body.clone(),
+                    None => continue,
+                }
+            };
+            if body.inclusive_descendants().any(|node| node == anchor) {
+                continue;
+            }
+            if let Some(id) = attr(&anchor, "id") {
+                index.noteref_keys.insert(format!("{base_key}#{id}"));
+            }
+            if !index.bodies.contains(&body) {
+                index.bodies.push(body.clone());
+            }
+            let label = format!(
+                "{}-{}",
+                note_label_part(href_stem(&target_href)),
+                note_label_part(&target_id)
+            );
+            index.refs.push((anchor, label, body));
+        }
+        index
+    }
+
+    fn lookup(&self, node: &NodeRef) -> Option<(&str, &NodeRef)> {
+        self.refs
+            .iter()
+            .find(|(anchor, _, _)| anchor == node)
+            .map(|(_, label, body)| (label.as_str(), body))
+    }
+
+    fn is_body(&self, node: &NodeRef) -> bool {
+        !self.bodies.is_empty() && self.bodies.contains(node)
+    }
+
+    fn is_backlink(&self, node: &NodeRef) -> bool {
+        if has_semantic(node, &["backlink"]) {
+            return true;
+        }
+        let Some(href) = attr(node, "href") else {
+            return false;
+        };
+        let Some((target_href, Some(target_id))) = resolve_internal_target(&href, &self.base_href)
+        else {
+            return false;
+        };
+        let key = format!("{target_href}#{target_id}");
+        self.noteref_keys.contains(&key) || self.book_refs.contains(&key)
+    }
+}
+
+fn href_stem(href: &str) -> &str {
+    href.rsplit('/')
+        .next()
+        .and_then(|name| name.split('.').next())
+        .unwrap_or("note")
+}
+
+/// True when `node` carries one of `kinds` via `epub:type`, `role="doc-*"`, or class.
+pub(crate) fn has_semantic(node: &NodeRef, kinds: &[&str]) -> bool {
+    let Some(el) = node.as_element() else {
+        return false;
+    };
+    let attrs = el.attributes.borrow();
+    let epub_type = attrs.get("epub:type").unwrap_or("");
+    let role = attrs.get("role").unwrap_or("");
+    let class = attrs.get("class").unwrap_or("").to_lowercase();
+    kinds.iter().any(|kind| {
+        epub_type.split_whitespace().any(|value| value == *kind)
+            || role == format!("doc-{kind}")
+            || class.split_whitespace().any(|value| value.contains(kind))
+    })
+}
+
+pub(crate) fn is_noteref(anchor: &NodeRef, target: &NodeRef) -> bool {
+    if has_semantic(anchor, &["noteref", "footnote-ref", "fnref"]) {
+        return true;
+    }
+    let text = anchor.text_contents();
+    let short = !text.trim().is_empty() && text.trim().chars().count() <= 6;
+    if !short {
+        return false;
+    }
+    let note_like = [target.clone(), note_body(target)]
+        .iter()
+        .any(|node| has_semantic(node, &["footnote", "endnote", "rearnote", "note"]));
+    let superscript = anchor
+        .parent()
+        .map(|parent| element_name(&parent) == Some("sup"))
+        .unwrap_or(false)
+        || anchor
+            .children()
+            .any(|child| element_name(&child) == Some("sup"));
+    note_like || superscript
+}
+
+pub(crate) fn note_body(target: &NodeRef) -> NodeRef {
+    if is_block(target) {
+        return target.clone();
+    }
+    for ancestor in target.ancestors() {
+        match element_name(&ancestor) {
+            Some("body" | "html") | None => break,
+            Some(_) if is_block(&ancestor) => return ancestor,
+            Some(_) => {}
+        }
+    }
+    target.clone()
+}
+
+fn note_label_part(value: &str) -> String {
+    let label: String = value
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let label = label.trim_matches('-').to_string();
+    if label.is_empty() {
+        "note".to_string()
+    } else {
+        label
+    }
+}
+
+/// Inline text assembly that defers whitespace until the next visible
+/// character, so element boundaries never introduce or drop spaces.
+struct InlineRun<'a> {
+    ctx: &'a RenderContext<'a>,
+    out: String,
+    pending_space: bool,
+    leading_space: bool,
+}
+
+impl<'a> InlineRun<'a> {
+    fn new(ctx: &'a RenderContext<'a>) -> Self {
+        Self {
+            ctx,
+            out: String::new(),
+            pending_space: false,
+            leading_space: false,
+        }
+    }
+
+    fn push_node(&mut self, node: &NodeRef) {
+        if let Some(text) = node.as_text() {
+            self.push_text(&text.borrow());
+            return;
+        }
+        let Some(tag) = element_name(node) else {
+            return;
+        };
+        if SKIPPED_TAGS.contains(&tag) || self.ctx.notes.is_body(node) {
+            return;
+        }
+        let is_note_link = tag == "a"
+            && (self.ctx.notes.is_backlink(node) || self.ctx.notes.lookup(node).is_some());
+        if let Some(id) = self.ctx.anchor_id(node).filter(|_| !is_note_link) {
+            self.push_raw(&html_anchor(&id));
+        }
+        match tag {
+            "br" => self.hard_break(),
+            "img" => {
+                if let Some(image) = image_markdown(node, self.ctx.options) {
+                    self.push_raw(&image);
+                }
+            }
+            "em" | "i" | "cite" | "dfn" | "var" => self.push_wrapped(node, "*", "*"),
+            "q" => self.push_wrapped(node, "\u{201c}", "\u{201d}"),
+            "strong" | "b" => self.push_wrapped(node, "**", "**"),
+            "s" | "strike" | "del" => self.push_wrapped(node, "~~", "~~"),
+            "code" | "kbd" | "samp" | "tt" => self.push_code(node),
+            "span" | "bdi" | "bdo" if tag == "bdi" || element_rtl(node).is_some() => {
+                self.push_directional(node, tag);
+            }
+            "ruby" => self.push_ruby(node),
+            // Annotations outside a <ruby> are stray markup; drop them with it.
+            "rt" | "rp" | "rtc" => {}
+            "a" if self.ctx.notes.is_backlink(node) => {}
+            "a" if self.ctx.notes.lookup(node).is_some() => {
+                if let Some((label, body)) = self.ctx.notes.lookup(node) {
+                    self.push_raw(&format!("[^{label}]"));
+                    define_note(self.ctx, label, body);
+                }
+            }
+            "a" => match attr(node, "href") {
+                Some(href) if !href.trim().is_empty() => {
+                    let close = format!("]({})", encode_link_target(href.trim()));
+                    self.push_wrapped(node, "[", &close);
+                }
+                _ => self.push_children(node),
+            },
+            _ => {
+                if is_block(node) {
+                    self.pending_space = true;
+                    self.push_children(node);
+                    self.pending_space = true;
+                } else {
+                    self.push_children(node);
+                }
+            }
+        }
+    }
+
+    fn push_children(&mut self, node: &NodeRef) {
+        for child in node.children() {
+            self.push_node(&child);
+        }
+    }
+
+    fn push_text(&mut self, text: &str) {
+        let chars: Vec<char> = text.chars().collect();
+        for (idx, &ch) in chars.iter().enumerate() {
+            if ch.is_whitespace() && ch != '\u{a0}' {
+                if self.out.is_empty() {
+                    self.leading_space = true;
+                }
+                self.pending_space = true;
+            } else {
+                self.flush_space();
+                if self.ctx.options.escape
+                    && needs_escape(&self.out, ch, chars.get(idx + 1).copied())
+                {
+                    self.out.push('\\');
+                }
+                self.out.push(ch);
+            }
+        }
+    }
+
+    fn push_raw(&mut self, value: &str) {
+        self.flush_space();
+        self.out.push_str(value);
+    }
+
+    fn flush_space(&mut self) {
+        if self.pending_space && !self.out.is_empty() && !self.out.ends_with([' ', '\n']) {
+            self.out.push(' ');
+        }
+        self.pending_space = false;
+    }
+
+    fn hard_break(&mut self) {
+        if !self.out.is_empty() && !self.out.ends_with('\n') {
+            self.out.push_str("  \n");
+        }
+        self.pending_space = false;
+    }
+
+    /// Renders `node` into its own run and wraps the visible part in delimiters,
+    /// hoisting boundary whitespace outside them (`<em> a </em>` -> ` *a* `).
+    fn push_wrapped(&mut self, node: &NodeRef, open: &str, close: &str) {
+        let mut inner = InlineRun::new(self.ctx);
+        inner.push_children(node);
+        if inner.leading_space {
+            if self.out.is_empty() {
+                self.leading_space = true;
+            }
+            self.pending_space = true;
+        }
+        let trailing_space = inner.pending_space;
+        let text = inner.out.trim_end_matches([' ', '\n']).to_string();
+        if !text.is_empty() {
+            self.push_raw(open);
+            self.out.push_str(&text);
+            self.out.push_str(close);
+        }
+        if trailing_space {
+            self.pending_space = true;
+        }
+    }
+
+    /// Inline direction changes become unicode isolates in plain text (or an
+    /// override for `<bdo>`), and a `dir`-carrying span in rich output.
+    fn push_directional(&mut self, node: &NodeRef, tag: &str) {
+        let rtl = element_rtl(node);
+        if self.ctx.options.mode == MarkdownMode::Rich {
+            let open = match rtl {
+                Some(true) => format!("<{tag} dir=\"rtl\">"),
+                Some(false) => format!("<{tag} dir=\"ltr\">"),
+                None => format!("<{tag}>"),
+            };
+            self.push_wrapped(node, &open, &format!("</{tag}>"));
+            return;
+        }
+        let (open, close) = match (tag, rtl) {
+            ("bdo", Some(true)) => ("\u{202e}", "\u{202c}"),
+            ("bdo", Some(false)) => ("\u{202d}", "\u{202c}"),
+            (_, Some(true)) => ("\u{2067}", "\u{2069}"),
+            (_, Some(false)) => ("\u{2066}", "\u{2069}"),
+            (_, None) => ("\u{2068}", "\u{2069}"),
+        };
+        self.push_wrapped(node, open, close);
+    }
+
+    /// Ruby keeps each annotation next to the base text it belongs to:
+    /// `<ruby>漢<rt>かん</rt>字<rt>じ</rt></ruby>` -> `漢（かん）字（じ）`.
+    fn push_ruby(&mut self, node: &NodeRef) {
+        if self.ctx.options.ruby_mode == RubyMode::Html {
+            self.push_raw(&serialize_node(node));
+            return;
+        }
+        for child in node.children() {
+            match element_name(&child) {
+                Some("rp") => {}
+                Some("rt" | "rtc") => {
+                    if self.ctx.options.ruby_mode == RubyMode::Bracket {
+                        let annotation = normalize_space(&child.text_contents());
+                        if !annotation.is_empty() {
+                            self.push_raw("\u{ff08}");
+                            self.push_text(&annotation);
+                            self.out.push('\u{ff09}');
+                        }
+                    }
+                }
+                _ => self.push_node(&child),
+            }
+        }
+    }
+
+    fn push_code(&mut self, node: &NodeRef) {
+        let raw = node.text_contents();
+        let code = raw.split_whitespace().collect::<Vec<_>>().join(" ");
+        if code.is_empty() {
+            if !raw.is_empty() {
+                self.pending_space = true;
+            }
+            return;
+        }
+        if raw.starts_with(char::is_whitespace) {
+            self.pending_space = true;
+        }
+        let mut ticks = "`".to_string();
+        while code.contains(ticks.as_str()) {
+            ticks.push('`');
+        }
+        let pad = if code.starts_with('`') || code.ends_with('`') {
+            " "
+        } else {
+            ""
+        };
+        self.push_raw(&format!("{ticks}{pad}{code}{pad}{ticks}"));
+        if raw.ends_with(char::is_whitespace) {
+            self.pending_space = true;
+        }
+    }
+
+    fn finish(self) -> String {
+        self.out.trim_end().to_string()
+    }
+}
+
+/// `Some(true)` for `dir="rtl"`, `Some(false)` for `dir="ltr"`, `None` otherwise.
+pub(crate) fn element_rtl(node: &NodeRef) -> Option<bool> {
+    match attr(node, "dir")?.trim().to_ascii_lowercase().as_str() {
+        "rtl" => Some(true),
+        "ltr" => Some(false),
+        _ => None,
+    }
+}
+
+/// Strong right-to-left characters: Hebrew, Arabic, Syriac, Thaana, NKo and
+/// their presentation forms.
+fn is_rtl_char(ch: char) -> bool {
+    matches!(
+        ch as u32,
+        0x0590..=0x08FF | 0xFB1D..=0xFDFF | 0xFE70..=0xFEFF | 0x10800..=0x10FFF | 0x1E800..=0x1EFFF
+    )
+}
+
+/// Decides whether `ch` would be read as markdown syntax when appended to `out`.
+/// Characters that only matter at the start of a line are escaped only there, and
+/// `_` is left alone inside words where it cannot open emphasis.
+fn needs_escape(out: &str, ch: char, next: Option<char>) -> bool {
+    let line = out.rsplit('\n').next().unwrap_or("");
+    let line_start = line.is_empty();
+    let prev = out.chars().last();
+    match ch {
+        '\\' | '*' | '`' | '[' | ']' | '|' => true,
+        '_' => {
+            !(prev.is_some_and(char::is_alphanumeric) && next.is_some_and(char::is_alphanumeric))
+        }
+        '#' | '>' | '=' => line_start,
+        '-' | '+' => line_start && next.is_none_or(|next| next.is_whitespace() || next == ch),
+        '.' | ')' => {
+            !line.is_empty()
+                && line.len() <= 9
+                && line.chars().all(|c| c.is_ascii_digit())
+                && next.is_none_or(char::is_whitespace)
+        }
+        '<' => next.is_some_and(|next| next.is_ascii_alphabetic() || next == '/' || next == '!'),
+        '~' => next == Some('~') || prev == Some('~'),
+        _ => false,
+    }
+}
+
+fn image_markdown(node: &NodeRef, options: &RenderOptions) -> Option<String> {
+    let src = attr(node, "src")?;
+    if src.trim().is_empty() {
+        return None;
+    }
+    // An empty alt marks a decorative image; only a missing one is synthesized.
+    let mut alt = attr(node, "alt")
+        .or_else(|| alt_from_filename(&src))
+        .map(|alt| alt.split_whitespace().collect::<Vec<_>>().join(" "))
+        .unwrap_or_default();
+    if options.escape {
+        alt = alt.replace('[', "\\[").replace(']', "\\]");
+    }
+    let title = attr(node, "title")
+        .map(|title| title.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|title| !title.is_empty())
+        .map(|title| format!(" \"{}\"", title.replace('\\', "\\\\").replace('"', "\\\"")))
+        .unwrap_or_default();
+    Some(format!(
+        "![{}]({}{})",
+        alt,
+        encode_link_target(src.trim()),
+        title
+    ))
+}
+
+/// `images/fig_03-map.png` -> `fig 03 map`, for images without an alt attribute.
+pub(crate) fn alt_from_filename(src: &str) -> Option<String> {
+    if src.trim_start().to_lowercase().starts_with("data:") {
+        return None;
+    }
+    let path = src.split(['#', '?']).next().unwrap_or(src);
+    let name = decode_path(path.rsplit('/').next().unwrap_or(path));
+    let stem = name.rsplit_once('.').map(|(stem, _)| stem).unwrap_or(&name);
+    let alt = stem
+        .split(['_', '-', '.', ' '])
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    (!alt.is_empty()).then_some(alt)
+}
+
+fn attr(node: &NodeRef, name: &str) -> Option<String> {
+    let el = node.as_element()?;
+    let attrs = el.attributes.borrow();
+    attrs.get(name).map(str::to_string)
+}
+
+fn encode_link_target(target: &str) -> String {
+    target
+        .replace(' ', "%20")
+        .replace('(', "%28")
+        .replace(')', "%29")
+}
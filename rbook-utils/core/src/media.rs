@@ -0,0 +1,124 @@
+use kuchiki::NodeRef;
+use kuchiki::traits::*;
+use rbook::Epub;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::content_cache::ContentCache;
+use crate::output::OutputSink;
+use crate::{element_name, extract_media_file, is_external, load_content, resolve_href};
+
+/// Replaces `<audio>`/`<video>` elements in the spine with a link to the media
+/// file (extracted next to the images) and the video poster when there is one.
+/// Returns how many elements were replaced.
+///
+/// Links are left as book-absolute hrefs (`/OEBPS/audio/a.mp3`); section link
+/// rewriting turns them into the extracted file's link like any other target.
+pub(crate) fn replace_media_elements(
+    epub: &Epub,
+    spine_hrefs: &[String],
+    cache: &mut ContentCache,
+    media_root: &Path,
+    media_link_prefix: &str,
+    extracted_media: &mut HashMap<String, String>,
+    extracted_media_count: &mut usize,
+    files: &dyn OutputSink,
+) -> usize {
+    let mut replaced = 0usize;
+    for href in spine_hrefs {
+        let Ok(content) = load_content(epub, href, cache) else {
+            continue;
+        };
+        let Ok(matches) = content.document.select("audio, video") else {
+            continue;
+        };
+        let elements: Vec<NodeRef> = matches.map(|m| m.as_node().clone()).collect();
+        let replaced_before = replaced;
+        for element in elements {
+            let kind = if element_name(&element) == Some("video") {
+                "Video"
+            } else {
+                "Audio"
+            };
+            let src = attr(&element, "src").or_else(|| {
+                element
+                    .children()
+                    .filter(|child| element_name(child) == Some("source"))
+                    .find_map(|source| attr(&source, "src"))
+            });
+            let poster = attr(&element, "poster");
+            if src.is_none() && poster.is_none() {
+                continue;
+            }
+
+            let mut markup = String::new();
+            if let Some(poster) = &poster {
+                markup.push_str(&format!(
+                    "<img src=\"{}\" alt=\"{kind} poster\">",
+                    escape_attr(poster)
+                ));
+            }
+            if let Some(src) = src.filter(|src| !src.trim().is_empty()) {
+                let target = if is_external(&src) {
+                    src.clone()
+                } else {
+                    let resolved = resolve_href(href, &src);
+                    extract_media_file(
+                        epub,
+                        &resolved,
+                        media_root,
+                        media_link_prefix,
+                        extracted_media,
+                        extracted_media_count,
+                        files,
+                    );
+                    format!("/{}", resolved.trim_start_matches('/'))
+                };
+                let label = attr(&element, "title")
+                    .or_else(|| attr(&element, "aria-label"))
+                    .filter(|label| !label.trim().is_empty())
+                    .unwrap_or_else(|| src.rsplit('/').next().unwrap_or(src.as_str()).to_string());
+                if !markup.is_empty() {
+                    markup.push(' ');
+                }
+                markup.push_str(&format!(
+                    "<a href=\"{}\">{kind}: {}</a>",
+                    escape_attr(&target),
+                    escape_text(label.trim())
+                ));
+            }
+
+            let fragment = kuchiki::parse_html().one(format!("<body>{markup}</body>"));
+            let Ok(body) = fragment.select_first("body") else {
+                continue;
+            };
+            let nodes: Vec<NodeRef> = body.as_node().children().collect();
+            for node in nodes {
+                node.detach();
+                element.insert_before(node);
+            }
+            element.detach();
+            replaced += 1;
+        }
+        if replaced > replaced_before {
+            cache.pin(href);
+        }
+    }
+    replaced
+}
+
+fn attr(node: &NodeRef, name: &str) -> Option<String> {
+    node.as_element()
+        .and_then(|el| el.attributes.borrow().get(name).map(str::to_string))
+}
+
+fn escape_attr(value: &str) -> String {
+    value.replace('&', "&amp;").replace('"', "&quot;")
+}
+
+fn escape_text(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
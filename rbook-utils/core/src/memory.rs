@@ -0,0 +1,74 @@
+use rbook::Epub;
+use std::io::{Read, Seek};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::output::MemorySink;
+use crate::{Book, ConvertError, ConvertOptions, Diagnostic, Result, convert_opened_epub};
+
+/// A book converted by [`convert_epub_to_sections`].
+#[derive(Clone, Debug, Default)]
+pub struct BookOutput {
+    pub book: Book,
+    /// Extracted images as (path, bytes). Paths are relative to the output
+    /// root, matching the links in the sections, e.g. `my-book/images/cover.jpg`.
+    pub images: Vec<(String, Vec<u8>)>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Converts the EPUB read from `reader` without touching the filesystem.
+/// `options` are used as for [`convert_epub`](crate::convert_epub), except
+/// that `input_dir` and `output_dir` are ignored, sections are never split
+/// into files, and `preview`, `plan` and `lock_output` are off. Exports such
+/// as the manifest are produced but not returned.
+pub fn convert_epub_to_sections<R>(reader: R, options: &ConvertOptions) -> Result<BookOutput>
+where
+    R: Read + Seek + Send + Sync + 'static,
+{
+    let pseudo_path = Path::new("book.epub");
+    let epub = Epub::read(reader).map_err(|err| ConvertError::OpenFailed {
+        path: pseudo_path.to_path_buf(),
+        source: err.into(),
+    })?;
+
+    let mut options = options.clone();
+    options.output_dir = PathBuf::new();
+    options.split_chapters = false;
+    options.preview = false;
+    options.plan = None;
+    options.plan_export = None;
+    options.lock_output = false;
+    // Prefetching reopens the book by path, which an in-memory book lacks.
+    options.section_jobs = 1;
+    let sink = Arc::new(MemorySink::default());
+    options.output_sink = sink.clone();
+
+    let (result, book) = convert_opened_epub(&epub, pseudo_path, &options)?;
+    if let Some(reason) = result.skipped {
+        return Err(ConvertError::Skipped {
+            path: pseudo_path.to_path_buf(),
+            reason,
+        });
+    }
+    let book_slug = options.slug_strategy.slug(&result.title);
+    let image_root = Path::new(&book_slug).join("images");
+    let images = sink
+        .take()
+        .into_iter()
+        .filter(|(path, _)| path.starts_with(&image_root))
+        .map(|(path, bytes)| (path_key(&path), bytes))
+        .collect();
+
+    Ok(BookOutput {
+        book,
+        images,
+        diagnostics: result.diagnostics,
+    })
+}
+
+fn path_key(path: &Path) -> String {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
@@ -0,0 +1,231 @@
+use rbook::ebook::manifest::Manifest;
+use rbook::ebook::toc::{Toc, TocChildren, TocEntry};
+use rbook::prelude::{ManifestEntry, MetaEntry, Metadata};
+use rbook::{Ebook, Epub};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{SectionRecord, count_words};
+
+/// A converted book: its metadata, chapters, navigation and the resources
+/// written for it. This is the stable schema for tools downstream of the
+/// converter; fields are only ever added, with serde defaults.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Book {
+    pub title: String,
+    pub authors: Vec<String>,
+    pub language: Option<String>,
+    pub identifier: Option<String>,
+    pub publisher: Option<String>,
+    pub published: Option<String>,
+    /// Metadata without a field above, by property (`subject`,
+    /// `dcterms:modified`, `calibre:rating`), with refinements as
+    /// `property.refinement` (`creator.role`).
+    pub extra: BTreeMap<String, Vec<String>>,
+    /// Sections in output order.
+    pub chapters: Vec<Chapter>,
+    /// The book's own table of contents, as written in the EPUB.
+    pub toc: Vec<TocNode>,
+    /// Images and media extracted for the chapters, by source href.
+    pub resources: Vec<Resource>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Chapter {
+    /// The section's id, as used for its anchor and file name.
+    pub id: String,
+    pub title: String,
+    pub markdown: String,
+    pub words: usize,
+    /// File the chapter was written to, relative to the book directory when
+    /// chapters are split and to the output directory otherwise.
+    pub output_path: String,
+    /// Where the chapter starts in the book, as `href#fragment` and as an
+    /// index into the reading order.
+    pub source_start: String,
+    pub spine_start: usize,
+    /// Where the next chapter starts, if anywhere.
+    pub source_end: Option<String>,
+    pub spine_end: usize,
+    /// Ids of the book's anchors the chapter contains.
+    pub anchors: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Resource {
+    /// The resolved href inside the EPUB.
+    pub href: String,
+    pub media_type: Option<String>,
+    /// How the chapters link to it: a path relative to the markdown, or a
+    /// `data:` URI for inlined images.
+    pub link: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TocNode {
+    pub label: String,
+    /// The target as `path#fragment`; headings without a target have none.
+    pub href: Option<String>,
+    pub children: Vec<TocNode>,
+}
+
+impl Book {
+    /// The book's metadata and table of contents, without chapters.
+    pub(crate) fn describe(epub: &Epub, title: String) -> Self {
+        let metadata = epub.metadata();
+        let value = |meta: Option<&str>| {
+            meta.map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+        };
+        let mut book = Self {
+            title,
+            authors: metadata
+                .creators()
+                .map(|creator| creator.value().trim().to_string())
+                .collect(),
+            language: value(metadata.language().map(|m| m.value())),
+            identifier: value(metadata.identifier().map(|m| m.value())),
+            publisher: value(metadata.publishers().next().map(|m| m.value())),
+            published: value(metadata.published().map(|m| m.value())),
+            toc: toc_nodes(epub),
+            ..Self::default()
+        };
+        book.extra = extra_metadata(epub, |property, value| match property {
+            "title" => book.title == value,
+            "creator" => book.authors.iter().any(|author| author == value),
+            "language" => book.language.as_deref() == Some(value),
+            "identifier" => book.identifier.as_deref() == Some(value),
+            "publisher" => book.publisher.as_deref() == Some(value),
+            "date" => book.published.as_deref() == Some(value),
+            _ => false,
+        });
+        book
+    }
+
+    /// Fills in the chapters and the resources written for them, from the
+    /// extracted links by resolved href.
+    pub(crate) fn populate(
+        &mut self,
+        epub: &Epub,
+        sections: Vec<SectionRecord>,
+        links: impl IntoIterator<Item = (String, String)>,
+    ) {
+        self.chapters = sections.into_iter().map(Chapter::of).collect();
+        let media_types: HashMap<String, String> = epub
+            .manifest()
+            .entries()
+            .map(|entry| {
+                (
+                    entry.href().as_str().to_string(),
+                    entry.media_type().to_string(),
+                )
+            })
+            .collect();
+        let mut resources: Vec<Resource> = links
+            .into_iter()
+            .map(|(href, link)| Resource {
+                media_type: media_types.get(&href).cloned(),
+                href,
+                link,
+            })
+            .collect();
+        resources.sort_by(|a, b| a.href.cmp(&b.href));
+        self.resources = resources;
+    }
+}
+
+impl Chapter {
+    fn of(section: SectionRecord) -> Self {
+        let position = |href: &str, fragment: &Option<String>| match fragment {
+            Some(fragment) => format!("{href}#{fragment}"),
+            None => href.to_string(),
+        };
+        Self {
+            words: count_words(&section.text),
+            source_start: position(&section.start_href, &section.start_fragment),
+            source_end: section
+                .end_href
+                .as_deref()
+                .map(|href| position(href, &section.end_fragment)),
+            id: section.section_id,
+            title: section.title,
+            markdown: section.text,
+            output_path: section.output_path,
+            spine_start: section.spine_start,
+            spine_end: section.spine_end,
+            anchors: section.anchors,
+        }
+    }
+}
+
+/// Every metadata value but those `named` says are written elsewhere, by
+/// property, and every refinement under `property.refinement`, so outputs
+/// lose none of the book's cataloguing information.
+pub(crate) fn extra_metadata(
+    epub: &Epub,
+    named: impl Fn(&str, &str) -> bool,
+) -> BTreeMap<String, Vec<String>> {
+    let mut extra: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for entry in epub.metadata().entries() {
+        let property = entry.property().as_str().to_string();
+        let value = entry.value().trim();
+        if !value.is_empty() && !named(&property, value) {
+            extra
+                .entry(property.clone())
+                .or_default()
+                .push(value.to_string());
+        }
+        for refinement in entry.refinements() {
+            let value = refinement.value().trim();
+            if !value.is_empty() {
+                extra
+                    .entry(format!("{property}.{}", refinement.property().as_str()))
+                    .or_default()
+                    .push(value.to_string());
+            }
+        }
+    }
+    extra
+}
+
+fn toc_nodes(epub: &Epub) -> Vec<TocNode> {
+    let Some(root) = epub.toc().contents() else {
+        return Vec::new();
+    };
+    let flat: Vec<(usize, TocNode)> = root
+        .children()
+        .flatten()
+        .map(|entry| {
+            (
+                entry.depth(),
+                TocNode {
+                    label: entry.label().trim().to_string(),
+                    href: entry.href().map(|href| href.as_str().to_string()),
+                    children: Vec::new(),
+                },
+            )
+        })
+        .collect();
+    let base_depth = flat.first().map(|(depth, _)| *depth).unwrap_or(0);
+    nest(&flat, &mut 0, base_depth)
+}
+
+/// Rebuilds the hierarchy from a pre-order list of (depth, node) pairs.
+fn nest(flat: &[(usize, TocNode)], idx: &mut usize, depth: usize) -> Vec<TocNode> {
+    let mut siblings = Vec::new();
+    while let Some((node_depth, node)) = flat.get(*idx) {
+        if *node_depth < depth {
+            break;
+        }
+        *idx += 1;
+        let mut node = node.clone();
+        node.children = nest(flat, idx, node_depth + 1);
+        siblings.push(node);
+    }
+    siblings
+}
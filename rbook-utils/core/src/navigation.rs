@@ -0,0 +1,139 @@
+use kuchiki::parse_html;
+use kuchiki::traits::*;
+use rbook::Ebook;
+use rbook::ebook::spine::Spine;
+use rbook::ebook::toc::{Toc, TocChildren, TocEntry};
+use rbook::prelude::{ManifestEntry, SpineEntry};
+use serde_json::json;
+use std::path::Path;
+
+use crate::{
+    Result, book_title, count_words, document_html, is_readable, open_epub, reading_order,
+};
+
+/// The full navigation of one EPUB as JSON: the hierarchical table of contents
+/// plus landmarks and page-list, each entry carrying its label, href (split into
+/// path and fragment), depth and kind.
+pub fn book_navigation(epub_path: &Path) -> Result<serde_json::Value> {
+    let epub = open_epub(epub_path)?;
+    let toc = epub.toc();
+    let mut navigation = json!({
+        "path": epub_path.display().to_string(),
+        "title": book_title(&epub, epub_path),
+    });
+    for (key, root) in [
+        ("toc", toc.contents()),
+        ("landmarks", toc.landmarks()),
+        ("page_list", toc.page_list()),
+    ] {
+        let flat: Vec<(usize, serde_json::Value)> = root
+            .map(|root| {
+                root.children()
+                    .flatten()
+                    .map(|entry| {
+                        let href = entry.href();
+                        (
+                            entry.depth(),
+                            json!({
+                                "label": entry.label().trim(),
+                                "href": href.as_ref().map(|href| href.as_str().to_string()),
+                                "path": href.as_ref().map(|href| href.path().as_str().to_string()),
+                                "fragment": href
+                                    .as_ref()
+                                    .and_then(|href| href.fragment().map(|frag| frag.to_string())),
+                                "depth": entry.depth(),
+                                "kind": entry.kind().as_str(),
+                                "media_type": entry
+                                    .manifest_entry()
+                                    .map(|manifest| manifest.media_type().to_string()),
+                            }),
+                        )
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let base_depth = flat.first().map(|(depth, _)| *depth).unwrap_or(0);
+        navigation[key] = serde_json::Value::Array(nest(&flat, &mut 0, base_depth));
+    }
+    Ok(navigation)
+}
+
+/// The spine of one EPUB as JSON, in the order a conversion walks it. Every
+/// itemref is listed with its `idref`, href, media type and `linear` flag;
+/// those the converter reads carry their `index` into the reading order (the
+/// numbering of section spine ranges and coverage warnings) and a word
+/// estimate, the rest a `skipped` reason. Non-linear documents are read like
+/// any other.
+pub fn book_spine(epub_path: &Path, extra_readable_types: &[String]) -> Result<serde_json::Value> {
+    let epub = open_epub(epub_path)?;
+    let order = reading_order(&epub, extra_readable_types);
+    let mut documents = Vec::new();
+    let mut index = 0usize;
+    let mut total_words = 0usize;
+    for (position, entry) in epub.spine().entries().enumerate() {
+        let manifest = entry.manifest_entry();
+        let mut document = json!({
+            "position": position,
+            "idref": entry.idref(),
+            "href": manifest.map(|manifest| manifest.href().as_str().to_string()),
+            "media_type": manifest.map(|manifest| manifest.media_type().to_string()),
+            "linear": entry.is_linear(),
+        });
+        let Some(manifest) = manifest else {
+            document["skipped"] = json!("no manifest item with this id");
+            documents.push(document);
+            continue;
+        };
+        if !is_readable(manifest.media_type(), extra_readable_types) {
+            document["skipped"] = json!("media type is not readable");
+            documents.push(document);
+            continue;
+        }
+        let words = document_words(&epub, manifest.href().as_str());
+        total_words += words.unwrap_or(0);
+        document["index"] = json!(index);
+        document["words"] = json!(words);
+        documents.push(document);
+        index += 1;
+    }
+    Ok(json!({
+        "path": epub_path.display().to_string(),
+        "title": book_title(&epub, epub_path),
+        "documents": documents,
+        "totals": {
+            "itemrefs": documents.len(),
+            "readable": order.len(),
+            "words": total_words,
+        },
+    }))
+}
+
+/// Words in a spine document's body, or `None` when it cannot be read.
+fn document_words(epub: &rbook::Epub, href: &str) -> Option<usize> {
+    let html = document_html(epub, href).ok()?;
+    let document = parse_html().one(html);
+    let text = match document.select_first("body") {
+        Ok(body) => body.as_node().text_contents(),
+        Err(()) => document.text_contents(),
+    };
+    Some(count_words(&text))
+}
+
+/// Rebuilds the hierarchy from a pre-order list of (depth, entry) pairs.
+fn nest(
+    flat: &[(usize, serde_json::Value)],
+    idx: &mut usize,
+    depth: usize,
+) -> Vec<serde_json::Value> {
+    let mut siblings = Vec::new();
+    while let Some((entry_depth, entry)) = flat.get(*idx) {
+        if *entry_depth < depth {
+            break;
+        }
+        *idx += 1;
+        let mut entry = entry.clone();
+        entry["children"] = serde_json::Value::Array(nest(flat, idx, entry_depth + 1));
+        siblings.push(entry);
+    }
+    siblings
+}
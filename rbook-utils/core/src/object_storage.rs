@@ -0,0 +1,108 @@
+use object_store::aws::AmazonS3Builder;
+use object_store::gcp::GoogleCloudStorageBuilder;
+use object_store::path::Path as ObjectPath;
+use object_store::{Attribute, Attributes, ObjectStore, PutOptions, PutPayload};
+use std::collections::HashMap;
+
+use crate::{ConvertError, Result, Storage, storage};
+
+/// An S3 or Google Cloud Storage bucket. Credentials and region come from
+/// the environment, as for the cloud providers' own tools (`AWS_*` variables,
+/// or `GOOGLE_SERVICE_ACCOUNT` / application default credentials).
+pub struct ObjectStorage {
+    store: Box<dyn ObjectStore>,
+    prefix: String,
+    content_types: HashMap<String, String>,
+    runtime: tokio::runtime::Runtime,
+}
+
+impl ObjectStorage {
+    /// `s3://bucket/prefix` or `gs://bucket/prefix`; the prefix is optional.
+    pub fn from_url(url: &str) -> Result<Self> {
+        let invalid = |reason: String| ConvertError::InvalidStorageUrl {
+            url: url.to_string(),
+            reason,
+        };
+        let (scheme, rest) = url
+            .split_once("://")
+            .ok_or_else(|| invalid("expected s3://bucket/prefix or gs://bucket/prefix".into()))?;
+        let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        if bucket.is_empty() {
+            return Err(invalid("missing bucket name".into()));
+        }
+        let store: Box<dyn ObjectStore> = match scheme {
+            "s3" => Box::new(
+                AmazonS3Builder::from_env()
+                    .with_bucket_name(bucket)
+                    .build()
+                    .map_err(|err| invalid(err.to_string()))?,
+            ),
+            "gs" => Box::new(
+                GoogleCloudStorageBuilder::from_env()
+                    .with_bucket_name(bucket)
+                    .build()
+                    .map_err(|err| invalid(err.to_string()))?,
+            ),
+            other => return Err(invalid(format!("unsupported scheme {other}"))),
+        };
+        Ok(Self::new(store, prefix))
+    }
+
+    /// Stores under `prefix` (a `/`-separated key prefix, possibly empty) in
+    /// an already configured store.
+    pub fn new(store: Box<dyn ObjectStore>, prefix: &str) -> Self {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .expect("tokio runtime for object storage");
+        Self {
+            store,
+            prefix: prefix.trim_matches('/').to_string(),
+            content_types: HashMap::new(),
+            runtime,
+        }
+    }
+
+    /// Content type for files with this extension, instead of the one from
+    /// [`storage::content_type`].
+    pub fn with_content_type(mut self, extension: &str, content_type: &str) -> Self {
+        self.content_types.insert(
+            extension.trim_start_matches('.').to_ascii_lowercase(),
+            content_type.to_string(),
+        );
+        self
+    }
+
+    fn content_type(&self, key: &str) -> String {
+        key.rsplit_once('.')
+            .and_then(|(_, extension)| self.content_types.get(&extension.to_ascii_lowercase()))
+            .cloned()
+            .unwrap_or_else(|| storage::content_type(key).to_string())
+    }
+}
+
+impl Storage for ObjectStorage {
+    fn put(&self, key: &str, bytes: Vec<u8>) -> Result<()> {
+        let full_key = if self.prefix.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}/{key}", self.prefix)
+        };
+        let options = PutOptions {
+            attributes: Attributes::from_iter([(Attribute::ContentType, self.content_type(key))]),
+            ..PutOptions::default()
+        };
+        self.runtime
+            .block_on(self.store.put_opts(
+                &ObjectPath::from(full_key.as_str()),
+                PutPayload::from(bytes),
+                options,
+            ))
+            .map_err(|err| ConvertError::StoreFailed {
+                key: full_key,
+                source: err.into(),
+            })?;
+        Ok(())
+    }
+}
@@ -0,0 +1,361 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Receives every file a conversion writes, by its path under
+/// `output_dir`. [`FsSink`], the default, writes them to disk; other sinks
+/// can send them to object storage, an archive or a map in memory.
+pub trait OutputSink: fmt::Debug + Send + Sync {
+    /// A markdown file: a book, a chapter or a chapter index.
+    fn write_markdown(&self, path: &Path, bytes: &[u8]) -> io::Result<()>;
+
+    /// Any other output: images, media, styles, fonts, thumbnails and
+    /// exports such as the manifest.
+    fn write_asset(&self, path: &Path, bytes: &[u8]) -> io::Result<()>;
+
+    /// Creates a directory that may stay empty. Sinks without directories
+    /// need not do anything.
+    fn create_dir_all(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Reads back a file written earlier in the conversion, for exports such
+    /// as the positions file that are computed from the markdown, or the
+    /// [chapter list](CHAPTER_LIST_FILE_NAME) of an earlier run. Sinks that
+    /// cannot read back leave those exports out.
+    fn read(&self, _path: &Path) -> io::Result<Vec<u8>> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Deletes the chapter files and `index.md` directly inside `dir`, left
+    /// there by an earlier split of the same book (see [`is_chapter_file`]).
+    /// Other markdown in `dir` must be kept. Only used for directories split
+    /// before the [chapter list](CHAPTER_LIST_FILE_NAME) was recorded.
+    fn remove_markdown_files(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Deletes one file written by an earlier run; a missing file is not an
+    /// error.
+    fn remove_file(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Whether a file or directory is already at `path`, for
+    /// [`OnConflict`](crate::OnConflict). Sinks that cannot tell answer
+    /// false, so only books of the same run conflict in them.
+    fn exists(&self, _path: &Path) -> bool {
+        false
+    }
+
+    /// The files directly inside `dir`; empty when the sink cannot list them.
+    fn list_dir(&self, _dir: &Path) -> Vec<PathBuf> {
+        Vec::new()
+    }
+}
+
+impl dyn OutputSink + '_ {
+    /// Writes `bytes` to `path` as markdown or as an asset, by extension.
+    pub(crate) fn write(&self, path: &Path, bytes: &[u8]) -> io::Result<()> {
+        if is_markdown(path) {
+            self.write_markdown(path, bytes)
+        } else {
+            self.write_asset(path, bytes)
+        }
+    }
+}
+
+pub(crate) fn is_markdown(path: &Path) -> bool {
+    path.extension().and_then(|ext| ext.to_str()) == Some("md")
+}
+
+/// Names of the files a split wrote directly into the book's directory, as a
+/// JSON array. The next split of the same book deletes exactly these, so
+/// chapters named by an output template are cleaned up too.
+pub const CHAPTER_LIST_FILE_NAME: &str = ".rbook-chapters.json";
+
+/// Deletes the chapter files an earlier split left in `dir`, by its chapter
+/// list; directories without one fall back to [`is_chapter_file`].
+pub(crate) fn remove_chapter_files(files: &dyn OutputSink, dir: &Path) -> io::Result<()> {
+    let Ok(bytes) = files.read(&dir.join(CHAPTER_LIST_FILE_NAME)) else {
+        return files.remove_markdown_files(dir);
+    };
+    let names: Vec<String> = serde_json::from_slice(&bytes).unwrap_or_default();
+    // Only bare file names, so an edited list cannot reach outside `dir`.
+    for name in names
+        .iter()
+        .filter(|name| Path::new(name).file_name() == Some(name.as_ref()))
+    {
+        files.remove_file(&dir.join(name))?;
+    }
+    Ok(())
+}
+
+/// Records which of `written` are directly inside `dir`, for the next split's
+/// [`remove_chapter_files`].
+pub(crate) fn record_chapter_files(
+    files: &dyn OutputSink,
+    dir: &Path,
+    written: &[PathBuf],
+) -> crate::Result<()> {
+    let names: Vec<&str> = written
+        .iter()
+        .filter(|path| path.parent() == Some(dir))
+        .filter_map(|path| path.file_name()?.to_str())
+        .collect();
+    let path = dir.join(CHAPTER_LIST_FILE_NAME);
+    files
+        .write_asset(&path, &serde_json::to_vec(&names)?)
+        .map_err(|err| crate::ConvertError::write_failed(&path, err))?;
+    Ok(())
+}
+
+/// Whether `path` is named like a file a split writes: `index.md`, or
+/// `{number}_{slug}.md` and `{section id}_{slug}.md` from the two filename
+/// schemes. Section ids are 12 hex digits. The large-print profile writes
+/// the same names ending in `.html`.
+pub fn is_chapter_file(path: &Path) -> bool {
+    let extension = path.extension().and_then(|ext| ext.to_str());
+    if !matches!(extension, Some("md" | "html")) {
+        return false;
+    }
+    let Some(name) = path.file_stem().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    if name == "index" {
+        return true;
+    }
+    let Some((prefix, _)) = name.split_once('_') else {
+        return false;
+    };
+    (!prefix.is_empty() && prefix.bytes().all(|byte| byte.is_ascii_digit()))
+        || (prefix.len() == 12 && prefix.bytes().all(|byte| byte.is_ascii_hexdigit()))
+}
+
+/// Writes outputs to the filesystem, creating missing directories.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FsSink;
+
+impl FsSink {
+    fn write_file(path: &Path, bytes: &[u8]) -> io::Result<()> {
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, bytes)
+    }
+}
+
+impl OutputSink for FsSink {
+    fn write_markdown(&self, path: &Path, bytes: &[u8]) -> io::Result<()> {
+        Self::write_file(path, bytes)
+    }
+
+    fn write_asset(&self, path: &Path, bytes: &[u8]) -> io::Result<()> {
+        Self::write_file(path, bytes)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn remove_markdown_files(&self, dir: &Path) -> io::Result<()> {
+        if !dir.exists() {
+            return Ok(());
+        }
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if is_chapter_file(&path) {
+                let _ = fs::remove_file(path);
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        match fs::remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn list_dir(&self, dir: &Path) -> Vec<PathBuf> {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return Vec::new();
+        };
+        entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_file()))
+            .map(|entry| entry.path())
+            .collect()
+    }
+}
+
+/// Keeps outputs in memory, for [`convert_epub_to_sections`],
+/// [`convert_all_to_storage`] and the rendering stage of a batch, whose
+/// writing stage replays them on the real sink.
+///
+/// [`convert_epub_to_sections`]: crate::convert_epub_to_sections
+/// [`convert_all_to_storage`]: crate::convert_all_to_storage
+#[derive(Debug, Default)]
+pub(crate) struct MemorySink {
+    files: Mutex<BTreeMap<PathBuf, Vec<u8>>>,
+    /// Directories passed to `create_dir_all` and `remove_markdown_files`, and
+    /// files passed to `remove_file`, for replaying them on disk.
+    dirs: Mutex<Vec<DirChange>>,
+    /// Where the files will be replayed, consulted by `exists` and
+    /// `list_dir` too.
+    target: Option<Arc<dyn OutputSink>>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum DirChange {
+    Created(PathBuf),
+    MarkdownRemoved(PathBuf),
+    FileRemoved(PathBuf),
+}
+
+impl MemorySink {
+    /// A sink whose files will be replayed on `target`, so what is already
+    /// there counts as existing.
+    pub(crate) fn over(target: Arc<dyn OutputSink>) -> Self {
+        Self {
+            target: Some(target),
+            ..Self::default()
+        }
+    }
+
+    /// The files written so far, by path.
+    pub(crate) fn take(&self) -> BTreeMap<PathBuf, Vec<u8>> {
+        std::mem::take(&mut *self.files.lock().expect("memory sink lock"))
+    }
+
+    /// The directory changes made so far, in order.
+    pub(crate) fn take_dir_changes(&self) -> Vec<DirChange> {
+        std::mem::take(&mut *self.dirs.lock().expect("memory sink lock"))
+    }
+
+    fn record(&self, change: DirChange) {
+        self.dirs.lock().expect("memory sink lock").push(change);
+    }
+
+    /// Replays what a conversion did to this sink on `target`: the directory
+    /// changes in order, then every file.
+    pub(crate) fn replay(&self, target: &dyn OutputSink) -> crate::Result<()> {
+        for change in self.take_dir_changes() {
+            match change {
+                DirChange::Created(dir) => target
+                    .create_dir_all(&dir)
+                    .map_err(|err| crate::ConvertError::write_failed(&dir, err))?,
+                DirChange::MarkdownRemoved(dir) => target
+                    .remove_markdown_files(&dir)
+                    .map_err(|err| crate::ConvertError::write_failed(&dir, err))?,
+                DirChange::FileRemoved(path) => target
+                    .remove_file(&path)
+                    .map_err(|err| crate::ConvertError::write_failed(&path, err))?,
+            }
+        }
+        for (path, bytes) in self.take() {
+            if let Err(err) = target.write(&path, &bytes) {
+                return Err(crate::ConvertError::WriteFailed {
+                    path,
+                    source: err.into(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn insert(&self, path: &Path, bytes: &[u8]) {
+        self.files
+            .lock()
+            .expect("memory sink lock")
+            .insert(path.to_path_buf(), bytes.to_vec());
+    }
+}
+
+impl OutputSink for MemorySink {
+    fn write_markdown(&self, path: &Path, bytes: &[u8]) -> io::Result<()> {
+        self.insert(path, bytes);
+        Ok(())
+    }
+
+    fn write_asset(&self, path: &Path, bytes: &[u8]) -> io::Result<()> {
+        self.insert(path, bytes);
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.record(DirChange::Created(path.to_path_buf()));
+        Ok(())
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let file = self
+            .files
+            .lock()
+            .expect("memory sink lock")
+            .get(path)
+            .cloned();
+        match (file, &self.target) {
+            (Some(bytes), _) => Ok(bytes),
+            (None, Some(target)) => target.read(path),
+            (None, None) => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn remove_markdown_files(&self, dir: &Path) -> io::Result<()> {
+        self.record(DirChange::MarkdownRemoved(dir.to_path_buf()));
+        self.files
+            .lock()
+            .expect("memory sink lock")
+            .retain(|path, _| !(path.parent() == Some(dir) && is_chapter_file(path)));
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.record(DirChange::FileRemoved(path.to_path_buf()));
+        self.files.lock().expect("memory sink lock").remove(path);
+        Ok(())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.files
+            .lock()
+            .expect("memory sink lock")
+            .keys()
+            .any(|file| file.starts_with(path))
+            || self
+                .target
+                .as_ref()
+                .is_some_and(|target| target.exists(path))
+    }
+
+    fn list_dir(&self, dir: &Path) -> Vec<PathBuf> {
+        let mut files: BTreeSet<PathBuf> = self
+            .files
+            .lock()
+            .expect("memory sink lock")
+            .keys()
+            .filter(|file| file.parent() == Some(dir))
+            .cloned()
+            .collect();
+        if let Some(target) = &self.target {
+            files.extend(target.list_dir(dir));
+        }
+        files.into_iter().collect()
+    }
+}
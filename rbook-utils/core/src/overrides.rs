@@ -0,0 +1,107 @@
+use rbook::Epub;
+use rbook::prelude::{MetaEntry, Metadata};
+use std::borrow::Cow;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::ConvertOptions;
+
+/// Settings for one book of a batch, which [`convert_all`] applies to a copy
+/// of the batch options when it reaches the book. `key` is the book's file
+/// name, its path under `input_dir`, or one of its `dc:identifier` values.
+///
+/// [`convert_all`]: crate::convert_all
+#[derive(Clone)]
+pub struct BookOverride {
+    pub key: String,
+    /// What the override changes. It is recorded with the build settings, so
+    /// incremental runs notice when an override is edited.
+    pub summary: String,
+    apply: Arc<OverrideFn>,
+}
+
+type OverrideFn = dyn Fn(&mut ConvertOptions) + Send + Sync;
+
+impl BookOverride {
+    pub fn new(
+        key: impl Into<String>,
+        summary: impl Into<String>,
+        apply: impl Fn(&mut ConvertOptions) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            key: key.into(),
+            summary: summary.into(),
+            apply: Arc::new(apply),
+        }
+    }
+
+    fn matches_path(&self, epub_path: &Path, input_dir: &Path) -> bool {
+        let key = Path::new(self.key.trim());
+        epub_path.file_name() == Some(key.as_os_str())
+            || epub_path == key
+            || epub_path
+                .strip_prefix(input_dir)
+                .is_ok_and(|relative| relative == key)
+    }
+
+    fn matches_identifier(&self, identifiers: &[String]) -> bool {
+        let key = self.key.trim();
+        identifiers
+            .iter()
+            .any(|identifier| identifier.eq_ignore_ascii_case(key))
+    }
+}
+
+impl fmt::Debug for BookOverride {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BookOverride")
+            .field("key", &self.key)
+            .field("summary", &self.summary)
+            .finish_non_exhaustive()
+    }
+}
+
+/// `options` with the overrides for the book at `epub_path` applied, in
+/// order, and none left to apply again. Identifiers are only read from `epub`
+/// when no override matches the path.
+pub(crate) fn book_options<'a>(
+    epub_path: &Path,
+    epub: &Epub,
+    options: &'a ConvertOptions,
+) -> Cow<'a, ConvertOptions> {
+    if options.book_overrides.is_empty() {
+        return Cow::Borrowed(options);
+    }
+    let mut matching: Vec<&BookOverride> = options
+        .book_overrides
+        .iter()
+        .filter(|book| book.matches_path(epub_path, &options.input_dir))
+        .collect();
+    if matching.is_empty() {
+        let identifiers: Vec<String> = epub
+            .metadata()
+            .entries()
+            .filter(|meta| meta.property().as_str() == "identifier")
+            .map(|meta| meta.value().trim().to_string())
+            .collect();
+        matching = options
+            .book_overrides
+            .iter()
+            .filter(|book| book.matches_identifier(&identifiers))
+            .collect();
+    }
+    if matching.is_empty() {
+        return Cow::Borrowed(options);
+    }
+    let mut book_options = options.clone();
+    book_options.book_overrides.clear();
+    for book in matching {
+        tracing::info!(key = %book.key, "applying book override");
+        (book.apply)(&mut book_options);
+    }
+    // The batch's state, whatever the override put in its place.
+    book_options.output_slugs = options.output_slugs.clone();
+    book_options.book_lock = options.book_lock.clone();
+    Cow::Owned(book_options)
+}
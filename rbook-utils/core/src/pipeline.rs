@@ -0,0 +1,127 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, sync_channel};
+use std::sync::{Arc, Mutex};
+
+use crate::output::MemorySink;
+use crate::{
+    BookConversionResult, ConvertOptions, Result, convert_in_memory, convert_one_with,
+    not_started_result,
+};
+
+/// A book read into memory, waiting for a renderer.
+type ReadBook = (usize, PathBuf, std::io::Result<Vec<u8>>);
+
+/// A converted book, waiting for its outputs to be written, with the options
+/// that hold its output lock until then.
+type RenderedBook = (usize, BookConversionResult, Arc<MemorySink>, ConvertOptions);
+
+/// Converts `epub_paths` in three stages joined by bounded channels: one
+/// thread reads books into memory, `jobs` threads parse and render them into
+/// memory, and this thread writes their outputs to `options.output_sink`.
+/// Writing overlaps rendering, and each channel holds at most `jobs` books,
+/// so memory stays bounded however far one stage runs ahead.
+///
+/// Results come back in input order; books never started because the batch
+/// was cancelled are left out, as in `convert_batch`. Under `fail_fast`,
+/// books not started after a failure come back as skipped.
+pub(crate) fn convert_staged(
+    epub_paths: &[PathBuf],
+    options: &ConvertOptions,
+    jobs: usize,
+) -> Result<Vec<BookConversionResult>> {
+    let total = epub_paths.len();
+    let (read_tx, read_rx) = sync_channel::<ReadBook>(jobs);
+    let (rendered_tx, rendered_rx) = sync_channel::<RenderedBook>(jobs);
+    let read_rx = Mutex::new(read_rx);
+    let stopped = AtomicBool::new(false);
+
+    let mut results = std::thread::scope(|scope| {
+        let reader_stopped = &stopped;
+        scope.spawn(move || {
+            for (idx, epub_path) in epub_paths.iter().enumerate() {
+                // Books left unread under `fail_fast` are filled in below.
+                if options.is_cancelled() || reader_stopped.load(Ordering::Relaxed) {
+                    break;
+                }
+                let bytes = std::fs::read(epub_path);
+                if read_tx.send((idx, epub_path.clone(), bytes)).is_err() {
+                    break;
+                }
+            }
+        });
+        for _ in 0..jobs {
+            let rendered_tx = rendered_tx.clone();
+            let read_rx = &read_rx;
+            let stopped = &stopped;
+            scope.spawn(move || {
+                while let Some((idx, epub_path, bytes)) = next_book(read_rx) {
+                    // Keep draining, so the reader is never left blocked.
+                    if options.is_cancelled() {
+                        continue;
+                    }
+                    let rendered = if stopped.load(Ordering::Relaxed) {
+                        let sink = Arc::new(MemorySink::default());
+                        (idx, not_started_result(&epub_path), sink, options.clone())
+                    } else {
+                        render(&epub_path, bytes, idx, total, options)
+                    };
+                    if options.fail_fast && rendered.1.failed() {
+                        stopped.store(true, Ordering::Relaxed);
+                    }
+                    if rendered_tx.send(rendered).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        // The writer stops once every renderer has hung up.
+        drop(rendered_tx);
+
+        let mut results = Vec::with_capacity(total);
+        for (idx, mut result, sink, _book_options) in rendered_rx {
+            if let Err(err) = sink.replay(&*options.output_sink) {
+                result.fail_output(&err);
+                if options.fail_fast {
+                    stopped.store(true, Ordering::Relaxed);
+                }
+            }
+            results.push((idx, result));
+        }
+        results
+    });
+    // Books cut short are failed results; the batch as a whole is not done.
+    options.check_cancelled()?;
+    let mut done = vec![false; total];
+    for (idx, _) in &results {
+        done[*idx] = true;
+    }
+    results.extend(
+        done.iter()
+            .enumerate()
+            .filter(|(_, done)| !**done)
+            .map(|(idx, _)| (idx, not_started_result(&epub_paths[idx]))),
+    );
+    results.sort_by_key(|(idx, _)| *idx);
+    Ok(results.into_iter().map(|(_, result)| result).collect())
+}
+
+fn next_book(read_rx: &Mutex<Receiver<ReadBook>>) -> Option<ReadBook> {
+    read_rx.lock().expect("read stage lock").recv().ok()
+}
+
+fn render(
+    epub_path: &Path,
+    bytes: std::io::Result<Vec<u8>>,
+    idx: usize,
+    total: usize,
+    options: &ConvertOptions,
+) -> RenderedBook {
+    let sink = Arc::new(MemorySink::over(options.output_sink.clone()));
+    let mut book_options = options.locking_book();
+    book_options.output_sink = sink.clone();
+    let result = convert_one_with(epub_path, idx, total, &book_options, || {
+        convert_in_memory(epub_path, bytes, &book_options)
+    });
+    (idx, result, sink, book_options)
+}
@@ -0,0 +1,137 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    BookConversionResult, ConvertError, ConvertOptions, Result, SectionRecord, text_output,
+};
+
+const PLAN_HEADER: &str = "\
+# rbook-utils conversion plan. Replay it with --plan to convert exactly these
+# sections: delete a [[books.sections]] entry to leave the section out, edit
+# its title to rename it, or move entries to reorder the output. Books that
+# are not listed are skipped. `source` is informational.
+";
+
+/// Sections to convert per book, as written by `--plan-export` and read back
+/// by `--plan`. Sections are identified by their stable section id, so a plan
+/// only replays against the same section detection settings.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConversionPlan {
+    #[serde(default)]
+    pub books: Vec<PlannedBook>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PlannedBook {
+    pub path: PathBuf,
+    pub title: String,
+    #[serde(default)]
+    pub sections: Vec<SectionInfo>,
+}
+
+/// A converted section: its id, (possibly renamed) title, and where it starts
+/// in the book.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SectionInfo {
+    pub id: String,
+    pub title: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+impl SectionInfo {
+    pub(crate) fn of(section: &SectionRecord) -> Self {
+        let source = match &section.start_fragment {
+            Some(fragment) => format!("{}#{fragment}", section.start_href),
+            None => section.start_href.clone(),
+        };
+        Self {
+            id: section.section_id.clone(),
+            title: section.title.clone(),
+            source: Some(source),
+        }
+    }
+}
+
+impl ConversionPlan {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let invalid = |reason: String| ConvertError::InvalidPlan {
+            path: path.to_path_buf(),
+            reason,
+        };
+        let text = fs::read_to_string(path).map_err(|err| invalid(err.to_string()))?;
+        toml::from_str(&text).map_err(|err| invalid(err.to_string()))
+    }
+
+    /// The converted books, with the sections each one produced.
+    pub fn from_results(books: &[BookConversionResult]) -> Self {
+        Self {
+            books: books
+                .iter()
+                .filter(|book| book.output_path.is_some())
+                .map(|book| PlannedBook {
+                    path: book.input_path.clone(),
+                    title: book.title.clone(),
+                    sections: book.sections.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    /// The entry for `epub_path`: the same path, or failing that the only
+    /// entry with the same file name (the plan was exported from elsewhere).
+    pub fn book(&self, epub_path: &Path) -> Option<&PlannedBook> {
+        self.books
+            .iter()
+            .find(|book| book.path == epub_path)
+            .or_else(|| {
+                let name = epub_path.file_name()?;
+                let mut same_name = self
+                    .books
+                    .iter()
+                    .filter(|book| book.path.file_name() == Some(name));
+                let only = same_name.next()?;
+                same_name.next().is_none().then_some(only)
+            })
+    }
+
+    pub(crate) fn write(&self, path: &Path, options: &ConvertOptions) -> Result<()> {
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            fs::create_dir_all(parent).map_err(|err| ConvertError::write_failed(parent, err))?;
+        }
+        text_output::TextWriter::new(options).write_utf8(
+            path,
+            &format!("{PLAN_HEADER}\n{}", toml::to_string_pretty(self)?),
+        )
+    }
+}
+
+/// Keeps the planned sections, in plan order and with plan titles. Returns
+/// the plan ids that matched no detected section.
+pub(crate) fn apply_plan(sections: &mut Vec<SectionRecord>, planned: &PlannedBook) -> Vec<String> {
+    let mut detected: HashMap<String, SectionRecord> = sections
+        .drain(..)
+        .map(|section| (section.section_id.clone(), section))
+        .collect();
+    let mut missing = Vec::new();
+    for entry in &planned.sections {
+        match detected.remove(&entry.id) {
+            Some(mut section) => {
+                if !entry.title.trim().is_empty() {
+                    section.title = entry.title.trim().to_string();
+                }
+                sections.push(section);
+            }
+            None => missing.push(entry.id.clone()),
+        }
+    }
+    missing
+}
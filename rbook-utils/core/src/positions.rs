@@ -0,0 +1,293 @@
+use kuchiki::NodeRef;
+use rbook::Epub;
+use rbook::prelude::{ManifestEntry, SpineEntry};
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::content_cache::ContentCache;
+use crate::{
+    ConvertError, ConvertOptions, ExportMode, Result, SectionRecord, element_name, find_anchor,
+    load_content, text_output,
+};
+
+/// A located marker in one written markdown file, in characters from the file start.
+struct Located {
+    path: PathBuf,
+    offset: usize,
+    end: usize,
+}
+
+/// Writes `positions.v1.json`: where every section and link-target anchor ended up
+/// in the markdown output (file + character offset), the matching EPUB CFI, and a
+/// whole-book percentage index so a reader can map "43% through the EPUB" to a
+/// spot in the converted files and back.
+pub(crate) fn write_positions_export(
+    enabled: ExportMode,
+    epub: &Epub,
+    book_dir: &Path,
+    single_output: &Path,
+    book_slug: &str,
+    sections: &[SectionRecord],
+    cache: &mut ContentCache,
+    options: &ConvertOptions,
+) -> Result<()> {
+    if enabled != ExportMode::V1 {
+        return Ok(());
+    }
+    // CFI spine steps count every itemref, including non-readable ones.
+    let spine_steps: HashMap<String, (usize, String)> = epub
+        .spine()
+        .entries()
+        .enumerate()
+        .filter_map(|(idx, entry)| {
+            let href = entry.manifest_entry()?.href().as_str().to_string();
+            Some((href, ((idx + 1) * 2, entry.idref().to_string())))
+        })
+        .collect();
+
+    let mut files: HashMap<PathBuf, Vec<char>> = HashMap::new();
+    let mut located: Vec<Option<Located>> = Vec::new();
+    for section in sections {
+        let path = if options.split_chapters {
+            book_dir.join(&section.output_path)
+        } else {
+            single_output.to_path_buf()
+        };
+        if !files.contains_key(&path) {
+            let text = text_output::read_text(
+                &*options.output_sink,
+                &path,
+                options.output_encoding.as_deref(),
+            )
+            .unwrap_or_default();
+            files.insert(path.clone(), text.chars().collect());
+        }
+        let chars = &files[&path];
+        let marker = format!("<a id=\"{}\"></a>", section.section_id);
+        located.push(find_chars(chars, &marker).map(|offset| Located {
+            path,
+            offset,
+            end: chars.len(),
+        }));
+    }
+    // In single-file output a section runs until the next section's marker.
+    for idx in 0..located.len() {
+        let next = located[idx + 1..]
+            .iter()
+            .flatten()
+            .find(|next| {
+                located[idx]
+                    .as_ref()
+                    .is_some_and(|cur| cur.path == next.path)
+            })
+            .map(|next| next.offset);
+        if let (Some(current), Some(next)) = (located[idx].as_mut(), next) {
+            current.end = next;
+        }
+    }
+
+    let total_chars: usize = located
+        .iter()
+        .flatten()
+        .map(|loc| loc.end - loc.offset)
+        .sum();
+    let mut book_offset = 0usize;
+    let mut percent_spans = Vec::new();
+    let mut sections_json = Vec::new();
+    for (section, loc) in sections.iter().zip(&located) {
+        let Some(loc) = loc else {
+            continue;
+        };
+        let chars = &files[&loc.path];
+        let output_path = output_path_for(section, book_slug, options);
+        let length = loc.end - loc.offset;
+        let start_cfi = section_cfi(
+            epub,
+            cache,
+            &spine_steps,
+            &section.start_href,
+            section.start_fragment.as_deref(),
+        );
+
+        let mut anchors_json = Vec::new();
+        for anchor in &section.anchors {
+            let Some(offset) = find_anchor_marker(&chars[loc.offset..loc.end], anchor) else {
+                continue;
+            };
+            let source_href = section_source_for_anchor(epub, cache, sections, section, anchor);
+            let cfi = source_href
+                .as_deref()
+                .and_then(|href| section_cfi(epub, cache, &spine_steps, href, Some(anchor)));
+            anchors_json.push(json!({
+                "id": anchor,
+                "char_offset": loc.offset + offset,
+                "cfi": cfi,
+            }));
+        }
+
+        sections_json.push(json!({
+            "section_id": section.section_id,
+            "title": section.title,
+            "output_path": output_path,
+            "char_offset": loc.offset,
+            "char_length": length,
+            "book_percent_start": percent(book_offset, total_chars),
+            "book_percent_end": percent(book_offset + length, total_chars),
+            "source": {
+                "href": section.start_href,
+                "fragment": section.start_fragment,
+                "spine_index": section.spine_start,
+                "cfi": start_cfi,
+            },
+            "anchors": anchors_json,
+        }));
+        percent_spans.push((book_offset, length, loc.offset, section, output_path));
+        book_offset += length;
+    }
+
+    let percent_index: Vec<serde_json::Value> = (0..=100usize)
+        .filter_map(|pct| {
+            let target = total_chars * pct / 100;
+            let (start, length, file_offset, section, output_path) = percent_spans
+                .iter()
+                .find(|(start, length, ..)| target < start + length)
+                .or(percent_spans.last())?;
+            let within = target.saturating_sub(*start).min(length.saturating_sub(1));
+            Some(json!({
+                "percent": pct,
+                "section_id": section.section_id,
+                "output_path": output_path,
+                "char_offset": file_offset + within,
+            }))
+        })
+        .collect();
+
+    let payload = json!({
+        "schema_version": "v1",
+        "offset_unit": "unicode_scalar",
+        "total_chars": total_chars,
+        "sections": sections_json,
+        "percent_index": percent_index,
+    });
+    options
+        .output_sink
+        .create_dir_all(book_dir)
+        .map_err(|err| ConvertError::write_failed(book_dir, err))?;
+    text_output::TextWriter::new(options).write_utf8(
+        &book_dir.join("positions.v1.json"),
+        &(serde_json::to_string_pretty(&payload)? + "\n"),
+    )?;
+    Ok(())
+}
+
+pub(crate) fn output_path_for(
+    section: &SectionRecord,
+    book_slug: &str,
+    options: &ConvertOptions,
+) -> String {
+    if options.split_chapters {
+        format!("{}/{}", book_slug, section.output_path)
+    } else {
+        format!("{book_slug}.md")
+    }
+}
+
+fn percent(offset: usize, total: usize) -> f64 {
+    if total == 0 {
+        return 0.0;
+    }
+    (offset as f64 * 10000.0 / total as f64).round() / 100.0
+}
+
+fn find_chars(haystack: &[char], needle: &str) -> Option<usize> {
+    let needle: Vec<char> = needle.chars().collect();
+    if needle.is_empty() || haystack.len() < needle.len() {
+        return None;
+    }
+    (0..=haystack.len() - needle.len()).find(|&idx| haystack[idx..].starts_with(&needle))
+}
+
+/// Offset of the line that carries `id`, either as `<a id>` or as a heading `{#id}`.
+fn find_anchor_marker(chars: &[char], id: &str) -> Option<usize> {
+    if let Some(offset) = find_chars(chars, &format!("<a id=\"{id}\"></a>")) {
+        return Some(offset);
+    }
+    let offset = find_chars(chars, &format!("{{#{id}}}"))?;
+    let line_start = chars[..offset]
+        .iter()
+        .rposition(|ch| *ch == '\n')
+        .map(|idx| idx + 1)
+        .unwrap_or(0);
+    Some(line_start)
+}
+
+/// Sections can span several spine documents; find the one that defines `anchor`.
+fn section_source_for_anchor(
+    epub: &Epub,
+    cache: &mut ContentCache,
+    sections: &[SectionRecord],
+    section: &SectionRecord,
+    anchor: &str,
+) -> Option<String> {
+    let mut candidates = vec![section.start_href.clone()];
+    candidates.extend(section.end_href.clone());
+    candidates.extend(
+        sections
+            .iter()
+            .filter(|other| {
+                other.spine_start >= section.spine_start && other.spine_start <= section.spine_end
+            })
+            .map(|other| other.start_href.clone()),
+    );
+    candidates.into_iter().find(|href| {
+        load_content(epub, href, cache)
+            .ok()
+            .and_then(|content| find_anchor(&content.document, anchor))
+            .is_some()
+    })
+}
+
+fn section_cfi(
+    epub: &Epub,
+    cache: &mut ContentCache,
+    spine_steps: &HashMap<String, (usize, String)>,
+    href: &str,
+    fragment: Option<&str>,
+) -> Option<String> {
+    let (step, idref) = spine_steps.get(href)?;
+    let mut cfi = format!("/6/{step}[{idref}]!");
+    if let Some(fragment) = fragment {
+        let content = load_content(epub, href, cache).ok()?;
+        let target = find_anchor(&content.document, fragment)?;
+        cfi.push_str(&element_path(&target)?);
+    }
+    Some(format!("epubcfi({cfi})"))
+}
+
+/// CFI steps from the root `html` element down to `node`: even indexes over
+/// element children, with the id assertion where the element has one.
+fn element_path(node: &NodeRef) -> Option<String> {
+    let mut steps = Vec::new();
+    let mut current = node.clone();
+    loop {
+        if element_name(&current) == Some("html") {
+            break;
+        }
+        let parent = current.parent()?;
+        let index = parent
+            .children()
+            .filter(|child| child.as_element().is_some())
+            .position(|child| child == current)?;
+        let id = current
+            .as_element()
+            .and_then(|el| el.attributes.borrow().get("id").map(str::to_string));
+        steps.push(match id {
+            Some(id) => format!("/{}[{}]", (index + 1) * 2, id),
+            None => format!("/{}", (index + 1) * 2),
+        });
+        current = parent;
+    }
+    steps.reverse();
+    Some(steps.concat())
+}
@@ -0,0 +1,333 @@
+use once_cell::sync::Lazy;
+use rbook::ebook::spine::Spine;
+use rbook::ebook::toc::{Toc, TocChildren, TocEntry};
+use rbook::prelude::{ManifestEntry, MetaEntry, Metadata, SpineEntry};
+use rbook::{Ebook, Epub};
+use regex::Regex;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::content_cache::ContentCache;
+use crate::images::{ImageExtractor, ImageOptions};
+use crate::markdown::{RenderOptions, has_semantic};
+use crate::{
+    BookConversionResult, ContentDoc, ConvertError, ConvertOptions, ConvertReport, Diagnostic,
+    DiagnosticLevel, MissingResources, Result, WarningCode, asset_link_prefix, book_is_rtl,
+    book_title, build_toc_entries, count_words, covers, decorative, is_readable, load_content,
+    model, prettify_section_name, render_partial_with_anchors, resolve_and_extract_image,
+    text_output, watermarks,
+};
+
+/// Documents marked as any of these are never the preview chapter.
+const FRONT_MATTER_TYPES: &[&str] = &[
+    "cover",
+    "titlepage",
+    "halftitlepage",
+    "copyright-page",
+    "toc",
+    "dedication",
+    "epigraph",
+    "acknowledgments",
+    "frontmatter",
+];
+
+/// Same idea for TOC labels and file names of books without semantics.
+static FRONT_MATTER_LABEL_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"(?i)\b(?:cover|title\s*page|half\s*title|copyright|contents|dedication|epigraph|acknowledge?ments?|also\s+by|praise|about\s+the\s+(?:author|publisher))\b|^(?:toc|nav|title|titlepage|copy)$",
+    )
+    .expect("valid front matter regex")
+});
+
+/// A spine document with fewer words is a separator or ornament page, not a chapter.
+const MIN_CHAPTER_WORDS: usize = 150;
+
+/// Writes `{slug}.preview.md` with the book's metadata as front matter followed
+/// by its first body-matter chapter, cut at a block boundary once
+/// `preview_max_words` is reached. Only that chapter is rendered.
+pub(crate) fn convert_preview(
+    epub: &Epub,
+    epub_path: &Path,
+    options: &ConvertOptions,
+) -> Result<BookConversionResult> {
+    let watermarks = options
+        .strip_watermarks
+        .then(|| watermarks::Watermarks::detect(epub));
+    let scrub = |field: &str, value: &str| match &watermarks {
+        Some(watermarks) => watermarks.scrub_metadata(field, value),
+        None => value.to_string(),
+    };
+    let title = scrub("title", &book_title(epub, epub_path));
+    let book_slug = options.slug_strategy.slug(&title);
+    // A preview is always a single file next to the book directory.
+    let mut layout = options.clone();
+    layout.split_chapters = false;
+    let book_dir = options.output_dir.join(&book_slug);
+    let image_root = book_dir.join("images");
+    let image_link_prefix = asset_link_prefix(&layout, &book_slug, "images");
+    let image_extractor = ImageExtractor::new(
+        image_root,
+        image_link_prefix,
+        ImageOptions::from_convert_options(options, epub_path),
+    );
+    let mut diagnostics = Vec::new();
+
+    let cover_link = covers::cover_href(epub).and_then(|href| image_extractor.extract(epub, &href));
+
+    let spine_hrefs: Vec<String> = epub
+        .spine()
+        .entries()
+        .filter_map(|entry| entry.manifest_entry())
+        .filter(|entry| is_readable(entry.media_type(), &options.extra_readable_types))
+        .map(|entry| entry.href().as_str().to_string())
+        .collect();
+    let toc_labels: HashMap<String, String> =
+        build_toc_entries(epub, &options.extra_readable_types)?
+            .into_iter()
+            .rev()
+            .map(|entry| (entry.href_path, entry.label))
+            .collect();
+    let mut cache = ContentCache::default();
+    let mut render_options = RenderOptions::from_convert_options(options);
+    render_options.rtl = book_is_rtl(epub, &spine_hrefs, &mut cache, options);
+    if options.skip_images {
+        decorative::remove_all_images(epub, &spine_hrefs, &mut cache);
+    }
+    if options.skip_decorative_images {
+        decorative::remove_decorative_images(epub, &spine_hrefs, &mut cache);
+    }
+    let mut image_resolver = |src: &str, base_href: &str| -> Option<String> {
+        resolve_and_extract_image(epub, src, base_href, &image_extractor)
+    };
+
+    let (start_idx, start_fragment) = bodymatter_landmark(epub, &spine_hrefs)
+        .map(|(idx, fragment)| (Some(idx), fragment))
+        .unwrap_or((None, None));
+    let candidates: Vec<usize> = match start_idx {
+        Some(idx) => (idx..spine_hrefs.len()).collect(),
+        None => (0..spine_hrefs.len()).collect(),
+    };
+    let mut chapter = None;
+    for spine_idx in candidates {
+        let href = &spine_hrefs[spine_idx];
+        let label = toc_labels.get(href).cloned();
+        let from_landmark = Some(spine_idx) == start_idx;
+        if !from_landmark
+            && label
+                .iter()
+                .chain(std::iter::once(&prettify_section_name(href)))
+                .any(|name| FRONT_MATTER_LABEL_RE.is_match(name.trim()))
+        {
+            continue;
+        }
+        let Ok(content) = load_content(epub, href, &mut cache) else {
+            continue;
+        };
+        if !from_landmark && is_front_matter(content) {
+            continue;
+        }
+        let fragment = if from_landmark {
+            start_fragment.as_deref()
+        } else {
+            None
+        };
+        let (text, _) = render_partial_with_anchors(
+            content,
+            &render_options,
+            fragment,
+            None,
+            &mut image_resolver,
+        );
+        let Some(text) = text.filter(|text| count_words(text) >= MIN_CHAPTER_WORDS) else {
+            continue;
+        };
+        let label = label.unwrap_or_else(|| prettify_section_name(href));
+        chapter = Some((label, text));
+        break;
+    }
+    let Some((mut chapter_title, mut chapter_text)) = chapter else {
+        return Err(ConvertError::NoPreviewChapter {
+            path: epub_path.to_path_buf(),
+        });
+    };
+    if let Some(watermarks) = &watermarks {
+        chapter_title = watermarks.scrub_metadata("chapter_title", &chapter_title);
+        chapter_text = watermarks.scrub_text(&chapter_text);
+    }
+
+    let (excerpt, truncated) = truncate_words(&chapter_text, options.preview_max_words);
+    let word_count = count_words(&excerpt);
+
+    let metadata = epub.metadata();
+    let mut front_matter = vec![format!("title: {}", serde_json::to_string(&title)?)];
+    let authors: Vec<String> = metadata
+        .creators()
+        .map(|creator| scrub("author", creator.value().trim()))
+        .collect();
+    if !authors.is_empty() {
+        front_matter.push(format!("authors: {}", serde_json::to_string(&authors)?));
+    }
+    let mut written: Vec<(&str, String)> = Vec::new();
+    for (key, property, value) in [
+        (
+            "language",
+            "language",
+            metadata.language().map(|m| m.value()),
+        ),
+        (
+            "publisher",
+            "publisher",
+            metadata.publishers().next().map(|m| m.value()),
+        ),
+        ("published", "date", metadata.published().map(|m| m.value())),
+        (
+            "identifier",
+            "identifier",
+            metadata.identifier().map(|m| m.value()),
+        ),
+        (
+            "description",
+            "description",
+            metadata
+                .entries()
+                .find(|meta| meta.property().as_str() == "description")
+                .map(|m| m.value()),
+        ),
+    ] {
+        if let Some(value) = value
+            .map(|value| scrub(key, value.trim()))
+            .filter(|value| !value.is_empty())
+        {
+            front_matter.push(format!("{key}: {}", serde_json::to_string(&value)?));
+            written.push((property, value));
+        }
+    }
+    let mut extra = model::extra_metadata(epub, |property, value| match property {
+        "title" => title == value,
+        "creator" => authors.iter().any(|author| author == value),
+        _ => written
+            .iter()
+            .any(|(written, written_value)| *written == property && written_value == value),
+    });
+    if let Some(watermarks) = &watermarks {
+        watermarks.scrub_extra(&mut extra);
+    }
+    if !extra.is_empty() {
+        front_matter.push(format!("extra: {}", serde_json::to_string(&extra)?));
+    }
+    if let Some(cover_link) = &cover_link {
+        front_matter.push(format!("cover: {}", serde_json::to_string(cover_link)?));
+    }
+    front_matter.push(format!(
+        "preview_chapter: {}",
+        serde_json::to_string(&chapter_title)?
+    ));
+    front_matter.push(format!("preview_words: {word_count}"));
+    front_matter.push(format!("preview_truncated: {truncated}"));
+
+    let mut lines = vec!["---".to_string()];
+    lines.extend(front_matter);
+    lines.push("---".to_string());
+    lines.push(format!("# {title}"));
+    lines.push(String::new());
+    lines.push(format!("## {chapter_title}"));
+    lines.push(String::new());
+    lines.push(excerpt);
+    options
+        .output_sink
+        .create_dir_all(&options.output_dir)
+        .map_err(|err| ConvertError::write_failed(&options.output_dir, err))?;
+    let output_path = options.output_dir.join(format!("{book_slug}.preview.md"));
+    let writer = text_output::TextWriter::new(options);
+    writer.write(&output_path, &(lines.join("\n").trim().to_string() + "\n"))?;
+    if writer.unmappable.get() > 0 {
+        diagnostics.push(Diagnostic {
+            level: DiagnosticLevel::Warning,
+            code: Some(WarningCode::UnmappableCharacters),
+            message: format!(
+                "{title}: {} characters cannot be written in {} and were replaced with ?",
+                writer.unmappable.get(),
+                writer.encoding_name()
+            ),
+        });
+    }
+
+    diagnostics.push(Diagnostic {
+        level: DiagnosticLevel::Info,
+        code: None,
+        message: format!("Preview of {title}: {chapter_title} ({word_count} words)"),
+    });
+    let report = ConvertReport {
+        output_paths: writer.written.take(),
+        section_count: 1,
+        word_count,
+        section_words: vec![word_count],
+        warnings: diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.code.is_some())
+            .cloned()
+            .collect(),
+        ..ConvertReport::default()
+    };
+    Ok(BookConversionResult {
+        input_path: epub_path.to_path_buf(),
+        title,
+        output_path: Some(output_path),
+        diagnostics,
+        missing_resources: MissingResources::default(),
+        skipped: None,
+        error_kind: None,
+        sections: Vec::new(),
+        report,
+    })
+}
+
+/// Spine index and fragment of the `bodymatter` landmark (EPUB 2 guide `text`).
+fn bodymatter_landmark(epub: &Epub, spine_hrefs: &[String]) -> Option<(usize, Option<String>)> {
+    let landmarks = epub.toc().landmarks()?;
+    for entry in landmarks.children().flatten() {
+        if !matches!(entry.kind().as_str(), "bodymatter" | "text") {
+            continue;
+        }
+        let Some(href) = entry.href() else {
+            continue;
+        };
+        let path = href.path().as_str();
+        if let Some(idx) = spine_hrefs.iter().position(|spine| spine == path) {
+            return Some((idx, href.fragment().map(str::to_string)));
+        }
+    }
+    None
+}
+
+fn is_front_matter(content: &ContentDoc) -> bool {
+    let Ok(body) = content.document.select_first("body") else {
+        return false;
+    };
+    let body = body.as_node();
+    if has_semantic(body, FRONT_MATTER_TYPES) {
+        return true;
+    }
+    // Semantics usually sit on the first section rather than on <body>.
+    body.children()
+        .filter(|child| child.as_element().is_some())
+        .take(1)
+        .any(|child| has_semantic(&child, FRONT_MATTER_TYPES))
+}
+
+/// Keeps whole blocks until `max_words` is reached; the block that crosses the
+/// limit is kept so the preview never ends mid-paragraph.
+fn truncate_words(text: &str, max_words: usize) -> (String, bool) {
+    let mut kept = Vec::new();
+    let mut words = 0usize;
+    let blocks: Vec<&str> = text.split("\n\n").collect();
+    for block in &blocks {
+        if words >= max_words {
+            break;
+        }
+        words += count_words(block);
+        kept.push(*block);
+    }
+    let truncated = kept.len() < blocks.len();
+    (kept.join("\n\n").trim().to_string(), truncated)
+}
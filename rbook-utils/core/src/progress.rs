@@ -0,0 +1,109 @@
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// A step of a conversion, reported through [`ProgressHook`].
+#[derive(Clone, Debug)]
+pub enum Progress<'a> {
+    /// Book `index` (0-based) of `total` in the batch is being converted.
+    BookStarted {
+        path: &'a Path,
+        index: usize,
+        total: usize,
+    },
+    /// Section `index` (0-based) of at most `total` in the book is being
+    /// rendered; sections that turn out empty are counted too.
+    SectionStarted {
+        path: &'a Path,
+        index: usize,
+        total: usize,
+    },
+    /// Section `index` (0-based) of at most `total` is done: rendered, or
+    /// found empty.
+    SectionRendered {
+        path: &'a Path,
+        index: usize,
+        total: usize,
+    },
+    /// An image of the book was written out; `count` images have been
+    /// extracted from the book so far. Duplicates and images embedded as data
+    /// URIs are not reported.
+    ImageExtracted {
+        path: &'a Path,
+        href: &'a str,
+        count: usize,
+    },
+    BookFinished {
+        path: &'a Path,
+        index: usize,
+        total: usize,
+    },
+}
+
+/// Callback for [`Progress`] events. Called from worker threads when books
+/// are converted in parallel.
+#[derive(Clone)]
+pub struct ProgressHook(Arc<dyn Fn(&Progress<'_>) + Send + Sync>);
+
+impl ProgressHook {
+    pub fn new(hook: impl Fn(&Progress<'_>) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(hook))
+    }
+
+    pub(crate) fn emit(&self, progress: Progress<'_>) {
+        (self.0)(&progress);
+    }
+}
+
+/// Reports [`Progress::SectionRendered`] when dropped, at the end of the loop
+/// iteration that rendered the section.
+pub(crate) struct SectionProgress<'a> {
+    pub(crate) hook: Option<&'a ProgressHook>,
+    pub(crate) path: &'a Path,
+    pub(crate) index: usize,
+    pub(crate) total: usize,
+}
+
+impl Drop for SectionProgress<'_> {
+    fn drop(&mut self) {
+        if let Some(hook) = self.hook {
+            hook.emit(Progress::SectionRendered {
+                path: self.path,
+                index: self.index,
+                total: self.total,
+            });
+        }
+    }
+}
+
+impl fmt::Debug for ProgressHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressHook")
+    }
+}
+
+/// Shared flag that stops a conversion: the book being converted stops before
+/// its next section, no further books are started, and [`convert_all`] or
+/// [`convert_epub`] returns [`ConvertError::Cancelled`]. Clone it to keep a
+/// handle (for example in a GUI's cancel button) while the options are in use.
+///
+/// [`convert_all`]: crate::convert_all
+/// [`convert_epub`]: crate::convert_epub
+/// [`ConvertError::Cancelled`]: crate::ConvertError::Cancelled
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
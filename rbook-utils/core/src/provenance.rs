@@ -0,0 +1,239 @@
+use kuchiki::{Node, NodeRef};
+use once_cell::sync::Lazy;
+use rbook::Epub;
+use regex::Regex;
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::content_cache::ContentCache;
+use crate::positions::output_path_for;
+use crate::{
+    ConvertError, ConvertOptions, ExportMode, Result, SectionRecord, element_name, load_content,
+    normalize_space, partial_body_nodes, text_output,
+};
+
+/// Elements whose content becomes one markdown paragraph (or heading, list
+/// item, ...). Only the innermost are recorded: a `<li>` holding `<p>`s is
+/// covered by its paragraphs.
+pub(crate) const PARAGRAPH_TAGS: &[&str] = &[
+    "p",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "li",
+    "dt",
+    "dd",
+    "pre",
+    "blockquote",
+    "figcaption",
+    "td",
+    "th",
+];
+
+/// Comments, CDATA, declarations and processing instructions are skipped;
+/// quoted attribute values may contain `>`.
+static TAG_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r#"(?s)<!--.*?-->|<!\[CDATA\[.*?\]\]>|<[?!][^>]*>|<(/?)([A-Za-z][\w:.-]*)((?:[^>"']|"[^"]*"|'[^']*')*)>"#,
+    )
+    .expect("valid tag regex")
+});
+
+/// Where an element's content sits in the raw resource.
+#[derive(Clone, Copy, Default)]
+struct SourceSpan {
+    byte_start: usize,
+    byte_end: usize,
+    char_start: usize,
+    char_end: usize,
+}
+
+/// Writes `provenance.v1.json`: for every emitted paragraph, the section and
+/// output file it went to and the byte and character range of its content in
+/// the source XHTML, so a quotation checker can compare a quote against the
+/// exact source text without converting the book again.
+pub(crate) fn write_provenance_export(
+    enabled: ExportMode,
+    epub: &Epub,
+    book_dir: &Path,
+    book_slug: &str,
+    sections: &[SectionRecord],
+    spine_hrefs: &[String],
+    cache: &mut ContentCache,
+    options: &ConvertOptions,
+) -> Result<()> {
+    if enabled != ExportMode::V1 {
+        return Ok(());
+    }
+    let mut spans_by_href: HashMap<String, HashMap<*const Node, SourceSpan>> = HashMap::new();
+    let mut paragraphs = Vec::new();
+    for section in sections {
+        let output_path = output_path_for(section, book_slug, options);
+        let mut ordinal = 0usize;
+        for spine_idx in section.spine_start..=section.spine_end {
+            let Some(href) = spine_hrefs.get(spine_idx) else {
+                continue;
+            };
+            let start = (spine_idx == section.spine_start)
+                .then_some(section.start_fragment.as_deref())
+                .flatten();
+            let end = (section.end_href.as_deref() == Some(href.as_str()))
+                .then_some(section.end_fragment.as_deref())
+                .flatten();
+            let Ok(content) = load_content(epub, href, cache) else {
+                continue;
+            };
+            let spans = spans_by_href
+                .entry(href.clone())
+                .or_insert_with(|| source_spans(epub, href, &content.document));
+            let Some(nodes) = partial_body_nodes(content, start, end) else {
+                continue;
+            };
+            for node in nodes.iter().flat_map(|node| node.inclusive_descendants()) {
+                let Some(span) = spans.get(&(&*node as *const Node)) else {
+                    continue;
+                };
+                let text = normalize_space(&node.text_contents());
+                if text.is_empty() {
+                    continue;
+                }
+                ordinal += 1;
+                paragraphs.push(json!({
+                    "section_id": section.section_id,
+                    "output_path": output_path,
+                    "ordinal": ordinal,
+                    "tag": element_name(&node),
+                    "source_href": href,
+                    "byte_start": span.byte_start,
+                    "byte_end": span.byte_end,
+                    "char_start": span.char_start,
+                    "char_end": span.char_end,
+                    "text": text,
+                }));
+            }
+        }
+    }
+
+    let payload = json!({
+        "schema_version": "v1",
+        "offset_units": {
+            "byte": "utf8_bytes_of_resource",
+            "char": "unicode_scalars_of_resource",
+        },
+        "paragraphs": paragraphs,
+    });
+    options
+        .output_sink
+        .create_dir_all(book_dir)
+        .map_err(|err| ConvertError::write_failed(book_dir, err))?;
+    text_output::TextWriter::new(options).write_utf8(
+        &book_dir.join("provenance.v1.json"),
+        &(serde_json::to_string_pretty(&payload)? + "\n"),
+    )?;
+    Ok(())
+}
+
+/// Content spans of the innermost paragraph elements of `document`, keyed by
+/// node. Elements are paired with their start tags in the raw resource by
+/// order; a tag whose count differs between the two (markup the parser had
+/// to repair) is left out rather than mismatched.
+fn source_spans(epub: &Epub, href: &str, document: &NodeRef) -> HashMap<*const Node, SourceSpan> {
+    let mut spans = HashMap::new();
+    // Offsets must count the resource as stored, so no decoding fallback.
+    let Some(raw) = epub
+        .read_resource_bytes(href)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+    else {
+        return spans;
+    };
+    let raw_spans = scan_tags(&raw);
+    let mut elements: HashMap<&str, Vec<NodeRef>> = HashMap::new();
+    for node in document.descendants() {
+        if let Some(name) = element_name(&node).and_then(paragraph_tag) {
+            elements.entry(name).or_default().push(node);
+        }
+    }
+    for (name, nodes) in elements {
+        let Some(found) = raw_spans.get(name) else {
+            continue;
+        };
+        if found.len() != nodes.len() {
+            continue;
+        }
+        for (node, span) in nodes.iter().zip(found) {
+            let innermost = !node
+                .descendants()
+                .any(|child| element_name(&child).and_then(paragraph_tag).is_some());
+            if let (true, Some(span)) = (innermost, span) {
+                spans.insert(&**node as *const Node, *span);
+            }
+        }
+    }
+    spans
+}
+
+fn paragraph_tag(name: &str) -> Option<&'static str> {
+    PARAGRAPH_TAGS.iter().copied().find(|tag| *tag == name)
+}
+
+/// Start tags of paragraph elements in source order, with the span between the
+/// end of the start tag and the start of the matching end tag (`None` when
+/// never closed).
+fn scan_tags(raw: &str) -> HashMap<&'static str, Vec<Option<SourceSpan>>> {
+    let mut found: HashMap<&'static str, Vec<Option<SourceSpan>>> = HashMap::new();
+    let mut open: Vec<(String, Option<(&'static str, usize)>)> = Vec::new();
+    let mut chars = 0usize;
+    let mut last = 0usize;
+    for captures in TAG_RE.captures_iter(raw) {
+        let whole = captures.get(0).expect("whole match");
+        chars += raw[last..whole.start()].chars().count();
+        let tag_start_chars = chars;
+        chars += whole.as_str().chars().count();
+        last = whole.end();
+        let Some(name) = captures.get(2) else {
+            continue;
+        };
+        let name = name.as_str().to_ascii_lowercase();
+        let closing = &captures[1] == "/";
+        let self_closing = captures[3].trim_end().ends_with('/');
+        if closing {
+            let Some(depth) = open.iter().rposition(|(open_name, _)| *open_name == name) else {
+                continue;
+            };
+            for (_, slot) in open.drain(depth..).rev() {
+                if let Some((tag, index)) = slot {
+                    if let Some(span) = found.get_mut(tag).and_then(|spans| spans[index].as_mut()) {
+                        span.byte_end = whole.start();
+                        span.char_end = tag_start_chars;
+                    }
+                }
+            }
+            continue;
+        }
+        let slot = paragraph_tag(&name).map(|tag| {
+            let spans = found.entry(tag).or_default();
+            spans.push(Some(SourceSpan {
+                byte_start: whole.end(),
+                byte_end: whole.end(),
+                char_start: chars,
+                char_end: chars,
+            }));
+            (tag, spans.len() - 1)
+        });
+        if !self_closing {
+            open.push((name, slot));
+        }
+    }
+    // Never closed: the extent is unknown.
+    for (_, slot) in open {
+        if let Some((tag, index)) = slot {
+            found.get_mut(tag).expect("recorded tag")[index] = None;
+        }
+    }
+    found
+}
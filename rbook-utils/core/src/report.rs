@@ -0,0 +1,88 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use crate::{ConversionSummary, Diagnostic, DiagnosticLevel, Fingerprint};
+
+/// What converting one book produced. Empty for skipped and failed books.
+#[derive(Clone, Debug, Default)]
+pub struct ConvertReport {
+    /// Every markdown file written: the book file, or chapter files plus
+    /// `index.md`, notes and list of figures.
+    pub output_paths: Vec<PathBuf>,
+    pub section_count: usize,
+    pub word_count: usize,
+    /// Words per section, in output order.
+    pub section_words: Vec<usize>,
+    pub images_extracted: usize,
+    pub media_extracted: usize,
+    /// Sections were split at detected headings instead of the TOC.
+    pub heading_fallback_used: bool,
+    pub toc: TocStats,
+    /// Coded diagnostics, including warnings promoted to errors.
+    pub warnings: Vec<Diagnostic>,
+    /// Whole-book fingerprint, with `ConvertOptions::fingerprints`.
+    pub fingerprint: Option<Fingerprint>,
+    /// Per section, in output order; empty without `fingerprints`.
+    pub section_fingerprints: Vec<Fingerprint>,
+}
+
+/// How well the navigation document covers the spine.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TocStats {
+    pub entries: usize,
+    /// Distinct spine documents the entries point at.
+    pub unique_hrefs: usize,
+    /// Share of the spine the entries point at.
+    pub coverage_ratio: f32,
+    /// Too sparse to split chapters on.
+    pub degenerate: bool,
+}
+
+/// Totals over a [`ConversionSummary`].
+#[derive(Clone, Debug, Default)]
+pub struct BatchReport {
+    pub books: usize,
+    pub converted: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    pub sections: usize,
+    pub words: usize,
+    pub images_extracted: usize,
+    pub media_extracted: usize,
+    /// Books whose sections came from heading fallback.
+    pub heading_fallback_books: usize,
+    pub degenerate_toc_books: usize,
+    /// Warning and error diagnostics by `W001`-style code.
+    pub warnings_by_code: BTreeMap<&'static str, usize>,
+    pub errors: usize,
+}
+
+impl ConversionSummary {
+    pub fn report(&self) -> BatchReport {
+        let mut batch = BatchReport {
+            books: self.books.len(),
+            converted: self.success_count(),
+            failed: self.failure_count(),
+            skipped: self.skipped_count(),
+            ..BatchReport::default()
+        };
+        for book in &self.books {
+            let report = &book.report;
+            batch.sections += report.section_count;
+            batch.words += report.word_count;
+            batch.images_extracted += report.images_extracted;
+            batch.media_extracted += report.media_extracted;
+            batch.heading_fallback_books += usize::from(report.heading_fallback_used);
+            batch.degenerate_toc_books += usize::from(report.toc.degenerate);
+            for diagnostic in &book.diagnostics {
+                if let Some(code) = diagnostic.code {
+                    *batch.warnings_by_code.entry(code.code()).or_default() += 1;
+                }
+                if diagnostic.level == DiagnosticLevel::Error {
+                    batch.errors += 1;
+                }
+            }
+        }
+        batch
+    }
+}
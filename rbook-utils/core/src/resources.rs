@@ -0,0 +1,255 @@
+use kuchiki::traits::*;
+use once_cell::sync::Lazy;
+use rbook::ebook::manifest::Manifest;
+use rbook::ebook::spine::Spine;
+use rbook::prelude::{ManifestEntry, SpineEntry};
+use rbook::{Ebook, Epub};
+use regex::Regex;
+use serde_json::json;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::path::Path;
+
+use crate::{
+    ConvertError, Result, book_title, decode_path, is_external, normalize_path, open_epub,
+    resolve_href,
+};
+
+static CSS_URL_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"(?i)url\(\s*['"]?([^'")]+)['"]?\s*\)|@import\s+['"]([^'"]+)['"]"#)
+        .expect("valid css url regex")
+});
+
+/// Every manifest item of one EPUB with its media type, stored (compressed) and
+/// uncompressed size, and whether the spine reaches it: spine documents plus
+/// anything they reference, followed through stylesheets and SVG.
+pub fn book_resources(epub_path: &Path) -> Result<serde_json::Value> {
+    let epub = open_epub(epub_path)?;
+    // Stored sizes only exist for zipped books; unpacked directories report none.
+    let mut archive = File::open(epub_path)
+        .ok()
+        .and_then(|file| zip::ZipArchive::new(file).ok());
+
+    let mut media_types: HashMap<String, String> = HashMap::new();
+    for entry in epub.manifest().entries() {
+        media_types.insert(
+            entry.href().as_str().to_string(),
+            entry.media_type().to_string(),
+        );
+    }
+    let spine: HashSet<String> = epub
+        .spine()
+        .entries()
+        .filter_map(|entry| entry.manifest_entry())
+        .map(|entry| entry.href().as_str().to_string())
+        .collect();
+    let reachable = reachable_from(&epub, &spine, &media_types);
+
+    let mut resources = Vec::new();
+    let (mut total_compressed, mut total_size, mut unreachable_size) = (0u64, 0u64, 0u64);
+    let mut unreachable_items = 0usize;
+    for entry in epub.manifest().entries() {
+        let href = entry.href().as_str().to_string();
+        let stored = archive.as_mut().and_then(|archive| {
+            archive
+                .by_name(&decode_path(&href))
+                .ok()
+                .map(|file| (file.compressed_size(), file.size()))
+        });
+        let (compressed_size, size) = match stored {
+            Some((compressed, size)) => (Some(compressed), Some(size)),
+            None => (
+                None,
+                epub.read_resource_bytes(href.as_str())
+                    .ok()
+                    .map(|bytes| bytes.len() as u64),
+            ),
+        };
+        total_compressed += compressed_size.unwrap_or(0);
+        total_size += size.unwrap_or(0);
+        let is_reachable = reachable.contains(&href);
+        if !is_reachable {
+            unreachable_items += 1;
+            unreachable_size += size.unwrap_or(0);
+        }
+        let properties: Vec<&str> = ["nav", "cover-image", "scripted", "svg", "mathml"]
+            .into_iter()
+            .filter(|property| entry.properties().has_property(property))
+            .collect();
+        resources.push(json!({
+            "id": entry.id(),
+            "href": href,
+            "media_type": entry.media_type(),
+            "properties": properties,
+            "compressed_size": compressed_size,
+            "size": size,
+            "in_spine": spine.contains(&href),
+            "reachable": is_reachable,
+        }));
+    }
+
+    let file_size = std::fs::metadata(epub_path)
+        .ok()
+        .filter(|meta| meta.is_file())
+        .map(|meta| meta.len());
+    Ok(json!({
+        "path": epub_path.display().to_string(),
+        "title": book_title(&epub, epub_path),
+        "file_size": file_size,
+        "totals": {
+            "items": resources.len(),
+            "compressed_size": total_compressed,
+            "size": total_size,
+            "unreachable_items": unreachable_items,
+            "unreachable_size": unreachable_size,
+        },
+        "resources": resources,
+    }))
+}
+
+/// A manifest resource read by [`extract_resource`].
+#[derive(Clone, Debug)]
+pub struct ExtractedResource {
+    /// The href after resolving it against the referring document and
+    /// dropping any `#fragment`, as the converter reads it.
+    pub resolved: String,
+    /// The manifest item with this href, if any.
+    pub manifest_href: Option<String>,
+    pub media_type: Option<String>,
+    /// Where the converter would put the file, relative to the book's asset
+    /// directory: the resolved href, percent-decoded.
+    pub output_path: String,
+    pub bytes: Vec<u8>,
+}
+
+/// Reads one resource of an EPUB the way the converter does: `href` is
+/// resolved against `base_href` (the href of the document referring to it;
+/// the package root when `None`), `.` and `..` segments are collapsed and the
+/// fragment is dropped. When the book has no such resource, the error names
+/// manifest items with the same file name.
+pub fn extract_resource(
+    epub_path: &Path,
+    href: &str,
+    base_href: Option<&str>,
+) -> Result<ExtractedResource> {
+    let epub = open_epub(epub_path)?;
+    let path = href.split('#').next().unwrap_or("");
+    let resolved = match base_href {
+        Some(base) => resolve_href(base, path),
+        None => normalize_path(path),
+    };
+    let manifest_entry = epub.manifest().entries().find(|entry| {
+        entry.href().as_str().trim_start_matches('/') == resolved.trim_start_matches('/')
+    });
+    let bytes = epub.read_resource_bytes(resolved.as_str()).map_err(|err| {
+        let wanted = decode_path(&resolved);
+        let name = wanted.rsplit('/').next().unwrap_or("");
+        let similar: Vec<String> = epub
+            .manifest()
+            .entries()
+            .map(|entry| entry.href().as_str().to_string())
+            .filter(|candidate| {
+                decode_path(candidate)
+                    .rsplit('/')
+                    .next()
+                    .is_some_and(|candidate| candidate.eq_ignore_ascii_case(name))
+            })
+            .collect();
+        let source: crate::BoxError = if similar.is_empty() {
+            err.into()
+        } else {
+            format!("{err}; the manifest has {}", similar.join(", ")).into()
+        };
+        ConvertError::ResourceRead {
+            href: resolved.clone(),
+            source,
+        }
+    })?;
+    Ok(ExtractedResource {
+        manifest_href: manifest_entry.map(|entry| entry.href().as_str().to_string()),
+        media_type: manifest_entry.map(|entry| entry.media_type().to_string()),
+        output_path: decode_path(&resolved),
+        resolved,
+        bytes,
+    })
+}
+
+/// Breadth-first walk from the spine over manifest items referenced by
+/// (X)HTML attributes, SVG links and CSS `url()`/`@import`.
+fn reachable_from(
+    epub: &Epub,
+    spine: &HashSet<String>,
+    media_types: &HashMap<String, String>,
+) -> HashSet<String> {
+    let mut seen: HashSet<String> = spine.clone();
+    let mut queue: VecDeque<String> = spine.iter().cloned().collect();
+    while let Some(href) = queue.pop_front() {
+        let Some(media_type) = media_types.get(&href) else {
+            continue;
+        };
+        let references = if media_type.eq_ignore_ascii_case("text/css") {
+            epub.read_resource_str(href.as_str())
+                .map(|css| css_references(&css))
+                .unwrap_or_default()
+        } else if media_type.contains("html") || media_type.contains("svg") {
+            epub.read_resource_str(href.as_str())
+                .map(|markup| markup_references(&markup))
+                .unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+        for reference in references {
+            if is_external(&reference) || reference.starts_with('#') {
+                continue;
+            }
+            let path = reference.split('#').next().unwrap_or("");
+            let resolved = resolve_href(&href, path);
+            // Manifest hrefs may or may not carry the leading slash.
+            let trimmed = resolved.trim_start_matches('/');
+            let target = [trimmed.to_string(), format!("/{trimmed}")]
+                .into_iter()
+                .find(|candidate| media_types.contains_key(candidate));
+            if let Some(target) = target {
+                if seen.insert(target.clone()) {
+                    queue.push_back(target);
+                }
+            }
+        }
+    }
+    seen
+}
+
+fn markup_references(markup: &str) -> Vec<String> {
+    let document = kuchiki::parse_html().one(markup);
+    let mut references = Vec::new();
+    for node in document.descendants() {
+        let Some(el) = node.as_element() else {
+            continue;
+        };
+        for (name, attr) in el.attributes.borrow().map.iter() {
+            match &*name.local {
+                "src" | "href" | "poster" | "data" => references.push(attr.value.clone()),
+                "srcset" => references.extend(
+                    attr.value
+                        .split(',')
+                        .filter_map(|candidate| candidate.split_whitespace().next())
+                        .map(str::to_string),
+                ),
+                "style" => references.extend(css_references(&attr.value)),
+                _ => {}
+            }
+        }
+        if &*el.name.local == "style" {
+            references.extend(css_references(&node.text_contents()));
+        }
+    }
+    references
+}
+
+fn css_references(css: &str) -> Vec<String> {
+    CSS_URL_RE
+        .captures_iter(css)
+        .filter_map(|caps| caps.get(1).or_else(|| caps.get(2)))
+        .map(|m| m.as_str().trim().to_string())
+        .collect()
+}
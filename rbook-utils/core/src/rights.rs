@@ -0,0 +1,151 @@
+use once_cell::sync::Lazy;
+use rbook::Epub;
+use rbook::prelude::{MetaEntry, Metadata};
+use regex::Regex;
+use serde_json::json;
+
+static YEAR_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\b(1[5-9]\d\d|20\d\d)\b").expect("valid year regex"));
+static PUBLIC_DOMAIN_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)public[\s-]domain|\bcc0\b|publicdomain/(zero|mark)")
+        .expect("valid public domain regex")
+});
+static COPYRIGHT_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)©|\(c\)\s*\d|copyright|all rights reserved|\bcc[\s-]by")
+        .expect("valid copyright regex")
+});
+
+/// How freely a book can be shared, judged from its metadata alone. This is a
+/// heuristic for building corpora, not legal advice.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RightsStatus {
+    /// The rights statement says so, or it was published before the cutoff.
+    PublicDomain,
+    /// Distributed by Project Gutenberg without a copyright notice.
+    LikelyPublicDomain,
+    /// A copyright or licence notice, or published after the cutoff.
+    InCopyright,
+    Unknown,
+}
+
+impl RightsStatus {
+    pub fn name(self) -> &'static str {
+        match self {
+            RightsStatus::PublicDomain => "public_domain",
+            RightsStatus::LikelyPublicDomain => "likely_public_domain",
+            RightsStatus::InCopyright => "in_copyright",
+            RightsStatus::Unknown => "unknown",
+        }
+    }
+
+    pub fn is_shareable(self) -> bool {
+        matches!(
+            self,
+            RightsStatus::PublicDomain | RightsStatus::LikelyPublicDomain
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RightsInfo {
+    pub status: RightsStatus,
+    /// Which evidence decided the status.
+    pub reason: String,
+    pub rights: Option<String>,
+    pub year: Option<i32>,
+    pub gutenberg: bool,
+}
+
+impl RightsInfo {
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        json!({
+            "status": self.status.name(),
+            "reason": self.reason,
+            "rights": self.rights,
+            "year": self.year,
+            "gutenberg": self.gutenberg,
+        })
+    }
+}
+
+/// Classifies by, in order: an explicit public-domain statement, a copyright
+/// notice, Project Gutenberg provenance, then the publication year against
+/// `public_domain_before`.
+pub(crate) fn classify_rights(epub: &Epub, public_domain_before: i32) -> RightsInfo {
+    let metadata = epub.metadata();
+    let values = |property: &str| -> Vec<String> {
+        metadata
+            .entries()
+            .filter(|meta| meta.property().as_str() == property)
+            .map(|meta| meta.value().trim().to_string())
+            .filter(|value| !value.is_empty())
+            .collect()
+    };
+    let rights = Some(values("rights").join(" ")).filter(|rights| !rights.is_empty());
+    let year = metadata
+        .published()
+        .and_then(|date| YEAR_RE.find(date.value()))
+        .and_then(|year| year.as_str().parse::<i32>().ok());
+    let gutenberg = values("identifier")
+        .iter()
+        .chain(&values("source"))
+        .chain(&values("publisher"))
+        .any(|value| value.to_ascii_lowercase().contains("gutenberg"));
+
+    let (status, reason) = match (&rights, year) {
+        (Some(rights), _) if PUBLIC_DOMAIN_RE.is_match(rights) => (
+            RightsStatus::PublicDomain,
+            "rights statement declares public domain".to_string(),
+        ),
+        (Some(rights), _) if COPYRIGHT_RE.is_match(rights) => (
+            RightsStatus::InCopyright,
+            "rights statement carries a copyright or licence notice".to_string(),
+        ),
+        _ if gutenberg => (
+            RightsStatus::LikelyPublicDomain,
+            "distributed by Project Gutenberg".to_string(),
+        ),
+        (_, Some(year)) if year < public_domain_before => (
+            RightsStatus::PublicDomain,
+            format!("published {year}, before {public_domain_before}"),
+        ),
+        (_, Some(year)) => (
+            RightsStatus::InCopyright,
+            format!("published {year}, not before {public_domain_before}"),
+        ),
+        (_, None) => (
+            RightsStatus::Unknown,
+            "no rights statement, provenance or publication year".to_string(),
+        ),
+    };
+    RightsInfo {
+        status,
+        reason,
+        rights,
+        year,
+        gutenberg,
+    }
+}
+
+/// First year whose publications are still protected in the US (95 years
+/// after publication, expiring at the end of the calendar year).
+pub(crate) fn default_public_domain_before() -> i32 {
+    let secs = unix_time_secs();
+    // Mean Gregorian year; exact enough away from New Year's Eve.
+    let year = 1970 + (secs / 31_556_952) as i32;
+    year - 95
+}
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+fn unix_time_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+/// `SystemTime::now` panics in the browser; ask JavaScript instead.
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+fn unix_time_secs() -> u64 {
+    (js_sys::Date::now() / 1000.0) as u64
+}
@@ -0,0 +1,198 @@
+use rbook::Ebook;
+use rbook::ebook::spine::Spine;
+use rbook::prelude::{ManifestEntry, SpineEntry};
+use regex::Regex;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::content_cache::ContentCache;
+use crate::markdown::RenderOptions;
+use crate::{
+    ConvertError, ConvertOptions, Diagnostic, DiagnosticLevel, MarkdownMode, Result, book_title,
+    build_toc_entries, collect_epub_paths, is_readable, isolate_panics, load_content,
+    normalize_space, open_epub, prettify_section_name, render_partial_with_anchors,
+};
+
+#[derive(Clone, Debug)]
+pub struct SearchOptions {
+    pub input_dir: PathBuf,
+    pub pattern: String,
+    /// Treat `pattern` as a regular expression instead of a literal phrase.
+    pub regex: bool,
+    pub ignore_case: bool,
+    /// Characters of context kept on each side of a match.
+    pub context_chars: usize,
+    /// See [`ConvertOptions::extra_readable_types`].
+    pub extra_readable_types: Vec<String>,
+}
+
+impl SearchOptions {
+    pub fn new(input_dir: PathBuf, pattern: String) -> Self {
+        Self {
+            input_dir,
+            pattern,
+            regex: false,
+            ignore_case: false,
+            context_chars: 60,
+            extra_readable_types: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SearchHit {
+    pub input_path: PathBuf,
+    pub title: String,
+    /// TOC label of the section the document belongs to.
+    pub section: String,
+    pub href: String,
+    pub context: String,
+    /// Byte range of the match within `context`.
+    pub match_range: (usize, usize),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SearchSummary {
+    pub books: usize,
+    pub hits: usize,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Searches the rendered text of every spine document under `input_dir`,
+/// calling `on_hit` as matches are found. Nothing is written to disk: images
+/// keep their source links and documents are dropped once searched.
+pub fn search_library(
+    options: &SearchOptions,
+    on_hit: &mut dyn FnMut(&SearchHit),
+) -> Result<SearchSummary> {
+    let epub_paths = collect_epub_paths(&options.input_dir);
+    if epub_paths.is_empty() {
+        return Err(ConvertError::NoInput {
+            dir: options.input_dir.clone(),
+        });
+    }
+    let pattern = if options.regex {
+        options.pattern.clone()
+    } else {
+        regex::escape(&options.pattern)
+    };
+    let pattern = if options.ignore_case {
+        format!("(?i){pattern}")
+    } else {
+        pattern
+    };
+    let matcher = Regex::new(&pattern)?;
+
+    let mut summary = SearchSummary::default();
+    for epub_path in epub_paths {
+        summary.books += 1;
+        match isolate_panics(|| search_book(&epub_path, &matcher, options, on_hit)) {
+            Ok(hits) => summary.hits += hits,
+            Err(err) => summary.diagnostics.push(Diagnostic {
+                level: DiagnosticLevel::Error,
+                code: None,
+                message: format!(
+                    "Failed to search {}: {}",
+                    epub_path.display(),
+                    err.full_message()
+                ),
+            }),
+        }
+    }
+    Ok(summary)
+}
+
+fn search_book(
+    epub_path: &Path,
+    matcher: &Regex,
+    options: &SearchOptions,
+    on_hit: &mut dyn FnMut(&SearchHit),
+) -> Result<usize> {
+    let epub = open_epub(epub_path)?;
+    let title = book_title(&epub, epub_path);
+    let mut toc_labels: HashMap<String, String> = HashMap::new();
+    for entry in build_toc_entries(&epub, &options.extra_readable_types)? {
+        toc_labels.entry(entry.href_path).or_insert(entry.label);
+    }
+    let mut convert_options = ConvertOptions::new(PathBuf::new(), PathBuf::new());
+    convert_options.markdown_mode = MarkdownMode::Plain;
+    convert_options.escape_markdown = false;
+    convert_options.extra_readable_types = options.extra_readable_types.clone();
+    let render_options = RenderOptions::from_convert_options(&convert_options);
+    let mut keep_src = |src: &str, _base_href: &str| Some(src.to_string());
+
+    let spine_hrefs: Vec<String> = epub
+        .spine()
+        .entries()
+        .filter_map(|entry| entry.manifest_entry())
+        .filter(|entry| is_readable(entry.media_type(), &options.extra_readable_types))
+        .map(|entry| entry.href().as_str().to_string())
+        .collect();
+    let mut hits = 0usize;
+    let mut section = String::new();
+    for href in &spine_hrefs {
+        // Documents without a TOC entry continue the previous section.
+        if let Some(label) = toc_labels.get(href) {
+            section = normalize_space(label);
+        } else if section.is_empty() {
+            section = prettify_section_name(href);
+        }
+        // One document at a time keeps memory flat on large libraries.
+        let mut cache = ContentCache::default();
+        let Ok(content) = load_content(&epub, href, &mut cache) else {
+            continue;
+        };
+        let (text, _) =
+            render_partial_with_anchors(content, &render_options, None, None, &mut keep_src);
+        let Some(text) = text else {
+            continue;
+        };
+        for block in text.split("\n\n") {
+            let block = normalize_space(block);
+            for found in matcher.find_iter(&block) {
+                let (context, match_range) =
+                    context_window(&block, found.start(), found.end(), options.context_chars);
+                hits += 1;
+                on_hit(&SearchHit {
+                    input_path: epub_path.to_path_buf(),
+                    title: title.clone(),
+                    section: section.clone(),
+                    href: href.clone(),
+                    context,
+                    match_range,
+                });
+            }
+        }
+    }
+    Ok(hits)
+}
+
+/// Up to `context_chars` characters either side of `start..end`, with `…`
+/// where the block was cut.
+fn context_window(
+    block: &str,
+    start: usize,
+    end: usize,
+    context_chars: usize,
+) -> (String, (usize, usize)) {
+    let from = if context_chars == 0 {
+        start
+    } else {
+        block[..start]
+            .char_indices()
+            .rev()
+            .nth(context_chars - 1)
+            .map(|(idx, _)| idx)
+            .unwrap_or(0)
+    };
+    let to = block[end..]
+        .char_indices()
+        .nth(context_chars)
+        .map(|(idx, _)| end + idx)
+        .unwrap_or(block.len());
+    let prefix = if from > 0 { "…" } else { "" };
+    let suffix = if to < block.len() { "…" } else { "" };
+    let context = format!("{prefix}{}{suffix}", &block[from..to]);
+    let offset = prefix.len() + start - from;
+    (context, (offset, offset + end - start))
+}
@@ -0,0 +1,207 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{BookConversionResult, ConvertError, ConvertOptions, Result};
+
+const SKIP_LIST_HEADER: &str = "\
+# rbook-utils skip list: books listed here are skipped by batch runs. One
+# book per line, by path, file name or dc:identifier; text after `#` is the
+# reason. Books that keep failing are added automatically; delete a line to
+# try the book again.
+";
+
+/// Books a batch leaves out, kept in a text file such as `skip-list.txt`.
+/// Books that fail `max_failures` runs in a row are appended with the error,
+/// so repeated runs stop spending time on the same broken files. The count of
+/// failed runs so far is kept next to the list, in `<list>.failures.json`.
+#[derive(Clone, Debug)]
+pub struct SkipList {
+    path: PathBuf,
+    entries: Vec<SkipEntry>,
+    /// Failed runs in a row, by book path, for books not listed yet.
+    failures: BTreeMap<String, u32>,
+    /// Failed runs in a row after which a book is added; `0` never adds.
+    pub max_failures: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SkipEntry {
+    /// A path, a file name, or a `dc:identifier`.
+    pub key: String,
+    pub reason: Option<String>,
+}
+
+impl SkipList {
+    /// Reads the list at `path`; a missing file is an empty list.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let entries = match fs::read_to_string(&path) {
+            Ok(text) => text.lines().filter_map(SkipEntry::parse).collect(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+        let failures = fs::read_to_string(failures_path(&path))
+            .ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .unwrap_or_default();
+        Ok(Self {
+            path,
+            entries,
+            failures,
+            max_failures: 3,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn entries(&self) -> &[SkipEntry] {
+        &self.entries
+    }
+
+    /// The entry naming the book at `epub_path`, by path or file name.
+    pub fn by_path(&self, epub_path: &Path) -> Option<&SkipEntry> {
+        let name = epub_path.file_name().and_then(|name| name.to_str());
+        self.entries.iter().find(|entry| {
+            Path::new(&entry.key) == epub_path
+                || (!entry.key.contains(['/', '\\']) && Some(entry.key.as_str()) == name)
+        })
+    }
+
+    /// The entry naming a book with this `dc:identifier`.
+    pub fn by_identifier(&self, identifier: &str) -> Option<&SkipEntry> {
+        let identifier = identifier.trim();
+        self.entries
+            .iter()
+            .find(|entry| !identifier.is_empty() && entry.key == identifier)
+    }
+
+    /// Counts this run's failures, resets the count of books that converted,
+    /// and appends books that reached `max_failures`. Returns the paths of
+    /// the books added.
+    pub fn record_run(&mut self, books: &[BookConversionResult]) -> Result<Vec<PathBuf>> {
+        let mut added = Vec::new();
+        let mut appended = String::new();
+        for book in books {
+            let key = book.input_path.to_string_lossy().into_owned();
+            if book.output_path.is_some() {
+                self.failures.remove(&key);
+                continue;
+            }
+            if book.skipped.is_some() || book.error_kind == Some("cancelled") {
+                continue;
+            }
+            let count = self.failures.entry(key.clone()).or_default();
+            *count += 1;
+            if self.max_failures == 0 || *count < self.max_failures {
+                continue;
+            }
+            let error = book
+                .diagnostics
+                .iter()
+                .rev()
+                .find(|diagnostic| diagnostic.level == crate::DiagnosticLevel::Error)
+                .map(|diagnostic| crate::normalize_space(&diagnostic.message))
+                .unwrap_or_else(|| "no output".to_string());
+            let entry = SkipEntry {
+                key: key.clone(),
+                reason: Some(format!("failed {count} runs in a row: {error}")),
+            };
+            self.failures.remove(&key);
+            appended.push_str(&entry.line());
+            appended.push('\n');
+            self.entries.push(entry);
+            added.push(book.input_path.clone());
+        }
+
+        if !appended.is_empty() {
+            let mut text = match fs::read_to_string(&self.path) {
+                Ok(text) => text,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                    SKIP_LIST_HEADER.to_string()
+                }
+                Err(err) => return Err(err.into()),
+            };
+            if !text.is_empty() && !text.ends_with('\n') {
+                text.push('\n');
+            }
+            text.push_str(&appended);
+            write_file(&self.path, text.as_bytes())?;
+        }
+        let failures_path = failures_path(&self.path);
+        if self.failures.is_empty() {
+            let _ = fs::remove_file(failures_path);
+        } else {
+            write_file(
+                &failures_path,
+                (serde_json::to_string_pretty(&self.failures)? + "\n").as_bytes(),
+            )?;
+        }
+        Ok(added)
+    }
+}
+
+impl SkipEntry {
+    fn parse(line: &str) -> Option<Self> {
+        let (key, reason) = match line.split_once('#') {
+            Some((key, reason)) => (key, Some(reason.trim()).filter(|r| !r.is_empty())),
+            None => (line, None),
+        };
+        let key = key.trim();
+        (!key.is_empty()).then(|| Self {
+            key: key.to_string(),
+            reason: reason.map(str::to_string),
+        })
+    }
+
+    fn line(&self) -> String {
+        match &self.reason {
+            Some(reason) => format!("{}  # {}", self.key, reason.replace('\n', " ")),
+            None => self.key.clone(),
+        }
+    }
+}
+
+/// Updates `options.skip_list` with a finished batch. Failing to update it
+/// is logged rather than failing the batch.
+pub(crate) fn record_run(options: &ConvertOptions, books: &[BookConversionResult]) {
+    let Some(list) = &options.skip_list else {
+        return;
+    };
+    // Reread the list, in case it was edited while the batch ran.
+    let updated = SkipList::open(list.path()).and_then(|mut current| {
+        current.max_failures = list.max_failures;
+        current.record_run(books)
+    });
+    match updated {
+        Ok(added) => {
+            for path in added {
+                tracing::warn!(path = %path.display(), "added to the skip list after repeated failures");
+            }
+        }
+        Err(err) => tracing::warn!(
+            path = %list.path().display(),
+            "could not update the skip list: {}",
+            err.full_message()
+        ),
+    }
+}
+
+fn failures_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".failures.json");
+    PathBuf::from(name)
+}
+
+fn write_file(path: &Path, bytes: &[u8]) -> Result<()> {
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent).map_err(|err| ConvertError::write_failed(parent, err))?;
+    }
+    fs::write(path, bytes).map_err(|err| ConvertError::write_failed(path, err))?;
+    Ok(())
+}
@@ -0,0 +1,185 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::collections::HashMap;
+use std::fmt;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::slugify;
+
+static MARKDOWN_LINK_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"!?\[([^\]]*)\]\([^)]*\)").expect("valid markdown link regex"));
+static HTML_TAG_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"<[^>]+>").expect("valid tag regex"));
+static HEADING_ATTRS_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\s*\{#[^}]*\}\s*$").expect("valid heading attribute regex"));
+
+/// Naming rules for everything a reader or renderer addresses by name: book
+/// directories, section file names and heading anchors. Pick the one matching
+/// the tool the markdown is published with so generated links resolve there.
+pub trait SlugStrategy: fmt::Debug + Send + Sync {
+    /// File or directory name for a title; never empty.
+    fn slug(&self, value: &str) -> String;
+
+    /// Fragment the downstream renderer derives from a heading's text. `None`
+    /// (the default) keeps section links on the explicit `<a id>` anchors.
+    fn heading_anchor(&self, _heading: &str) -> Option<String> {
+        None
+    }
+
+    /// Anchor of the `index`-th repeat (1-based) of a heading in one document.
+    fn duplicate_anchor(&self, anchor: &str, index: usize) -> String {
+        format!("{anchor}-{index}")
+    }
+}
+
+/// ASCII letters, digits, `.` and `-`, with everything else collapsed to `_`.
+/// Other scripts and accented letters are transliterated first (`Война и мир`
+/// as `Voina_i_mir`, `Café` as `Cafe`).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AsciiSlugs;
+
+impl SlugStrategy for AsciiSlugs {
+    fn slug(&self, value: &str) -> String {
+        slugify(value)
+    }
+}
+
+/// Like [`AsciiSlugs`] but keeps letters and digits of any script as they
+/// are, for libraries that want names in the title's own spelling.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UnicodeSlugs;
+
+impl SlugStrategy for UnicodeSlugs {
+    fn slug(&self, value: &str) -> String {
+        let mut out = String::new();
+        let mut prev_underscore = false;
+        for ch in value.chars() {
+            if ch.is_alphanumeric() || ch == '.' || ch == '-' {
+                out.push(ch);
+                prev_underscore = false;
+            } else if !prev_underscore {
+                out.push('_');
+                prev_underscore = true;
+            }
+        }
+        let trimmed = out.trim_matches(&['_', '.', '-'][..]).to_string();
+        if trimmed.is_empty() {
+            "book".to_string()
+        } else {
+            trimmed
+        }
+    }
+}
+
+/// GitHub's rules: lowercase, punctuation dropped, spaces to `-`. Headings
+/// get anchors, so section links work without raw HTML (which GitHub and
+/// Obsidian strip).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GithubSlugs;
+
+impl SlugStrategy for GithubSlugs {
+    fn slug(&self, value: &str) -> String {
+        let slug = github_slug(value);
+        if slug.trim_matches('-').is_empty() {
+            "book".to_string()
+        } else {
+            slug
+        }
+    }
+
+    fn heading_anchor(&self, heading: &str) -> Option<String> {
+        Some(github_slug(&heading_text(heading)))
+    }
+}
+
+/// Python-Markdown's `toc` rules used by mkdocs: accents folded to ASCII,
+/// punctuation dropped, runs of spaces and hyphens to `-`, and repeats
+/// suffixed `_1`, `_2`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MkdocsSlugs;
+
+impl SlugStrategy for MkdocsSlugs {
+    fn slug(&self, value: &str) -> String {
+        let slug = mkdocs_slug(value);
+        if slug.is_empty() {
+            "book".to_string()
+        } else {
+            slug
+        }
+    }
+
+    fn heading_anchor(&self, heading: &str) -> Option<String> {
+        Some(mkdocs_slug(&heading_text(heading)))
+    }
+
+    fn duplicate_anchor(&self, anchor: &str, index: usize) -> String {
+        format!("{anchor}_{index}")
+    }
+}
+
+fn mkdocs_slug(value: &str) -> String {
+    let folded: String = value
+        .nfkd()
+        .filter(|ch| ch.is_ascii())
+        .filter(|ch| ch.is_ascii_alphanumeric() || matches!(ch, '_' | '-') || ch.is_whitespace())
+        .collect();
+    folded
+        .trim()
+        .to_lowercase()
+        .split(|ch: char| ch == '-' || ch.is_whitespace())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+fn github_slug(value: &str) -> String {
+    value
+        .trim()
+        .to_lowercase()
+        .chars()
+        .filter_map(|ch| match ch {
+            ' ' => Some('-'),
+            '-' | '_' => Some(ch),
+            ch if ch.is_alphanumeric() => Some(ch),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The text a renderer sees in a markdown heading: link targets, inline HTML
+/// and escapes removed.
+fn heading_text(markdown: &str) -> String {
+    let text = HEADING_ATTRS_RE.replace(markdown, "");
+    let text = MARKDOWN_LINK_RE.replace_all(&text, "$1");
+    let text = HTML_TAG_RE.replace_all(&text, "");
+    text.replace('\\', "")
+}
+
+/// Anchors renderers give repeated headings in one document: on GitHub the
+/// second `Notes` becomes `notes-1`, the third `notes-2`.
+pub(crate) struct AnchorCounter<'a> {
+    slugs: &'a dyn SlugStrategy,
+    seen: HashMap<String, usize>,
+}
+
+impl<'a> AnchorCounter<'a> {
+    pub(crate) fn new(slugs: &'a dyn SlugStrategy) -> Self {
+        Self {
+            slugs,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// The anchor of the next heading with this text, or `None` when the
+    /// strategy leaves headings to HTML anchors.
+    pub(crate) fn next(&mut self, heading: &str) -> Option<String> {
+        let anchor = self.slugs.heading_anchor(heading)?;
+        let count = self.seen.entry(anchor.clone()).or_insert(0);
+        let unique = if *count == 0 {
+            anchor
+        } else {
+            self.slugs.duplicate_anchor(&anchor, *count)
+        };
+        *count += 1;
+        Some(unique)
+    }
+}
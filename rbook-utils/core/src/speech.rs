@@ -0,0 +1,354 @@
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+use std::collections::HashMap;
+
+static NOTE_DEF_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^\[\^([^\]]+)\]:\s?(.*)$").expect("valid note definition regex"));
+static NOTE_REF_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\[\^([^\]]+)\]").expect("valid note reference regex"));
+static IMAGE_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"!\[[^\]]*\]\([^)]*\)").expect("valid image regex"));
+static LINK_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\[([^\]]*)\]\([^)]*\)").expect("valid link regex"));
+static HTML_TAG_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"<[^>]+>").expect("valid tag regex"));
+static LIST_MARKER_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^(?:[-*+]|\d+[.)])\s+").expect("valid list marker regex"));
+static RULE_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^(?:[-*_]\s*){3,}$").expect("valid rule regex"));
+static PERCENT_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(\d)\s?%").expect("valid percent regex"));
+static NUMBER_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"\b(\d{1,3}(?:,\d{3})+|\d+)(?:\.(\d+))?(st|nd|rd|th)?\b")
+        .expect("valid number regex")
+});
+
+/// Abbreviations read out in full, matched case-sensitively at a word
+/// start. `etc.` keeps its period before a capital or at the end of a line,
+/// where it usually ends the sentence.
+const ABBREVIATIONS: &[(&str, &str)] = &[
+    ("Mr.", "Mister"),
+    ("Mrs.", "Missus"),
+    ("Dr.", "Doctor"),
+    ("Prof.", "Professor"),
+    ("St.", "Saint"),
+    ("Mt.", "Mount"),
+    ("Jr.", "Junior"),
+    ("Sr.", "Senior"),
+    ("Capt.", "Captain"),
+    ("Col.", "Colonel"),
+    ("Gen.", "General"),
+    ("Lt.", "Lieutenant"),
+    ("Sgt.", "Sergeant"),
+    ("Rev.", "Reverend"),
+    ("e.g.", "for example"),
+    ("i.e.", "that is"),
+    ("etc.", "et cetera"),
+    ("vs.", "versus"),
+    ("approx.", "approximately"),
+    ("ch.", "chapter"),
+    ("vol.", "volume"),
+    ("fig.", "figure"),
+    ("pp.", "pages"),
+];
+
+/// Abbreviations read out only before a number, where they cannot be words.
+const NUMBER_ABBREVIATIONS: &[(&str, &str)] = &[("No.", "number"), ("p.", "page")];
+
+static ABBREVIATION_RES: Lazy<Vec<(Regex, &'static str)>> = Lazy::new(|| {
+    let word = |abbr: &str| format!(r"(^|[^\w.]){}", regex::escape(abbr));
+    ABBREVIATIONS
+        .iter()
+        .map(|(abbr, full)| {
+            let re = format!(r"{}(\s+[A-Z]|\s*$)?", word(abbr));
+            (Regex::new(&re).expect("valid abbreviation regex"), *full)
+        })
+        .chain(NUMBER_ABBREVIATIONS.iter().map(|(abbr, full)| {
+            let re = format!(r"{}(\s*\d)", word(abbr));
+            (Regex::new(&re).expect("valid abbreviation regex"), *full)
+        }))
+        .collect()
+});
+
+/// Turns rendered markdown into text for a speech engine: markup, anchors,
+/// images, tables, rules and code blocks are dropped, headings become
+/// sentences, footnote references become parenthetical asides holding the
+/// note, and common abbreviations and symbols are written out. With
+/// `spell_out_numbers`, numbers, ordinals and years are written as words.
+pub(crate) fn speech_text(markdown: &str, spell_out_numbers: bool) -> String {
+    let markdown = strip_front_matter(markdown);
+    let (body, notes) = take_note_definitions(markdown);
+
+    let mut lines: Vec<String> = Vec::new();
+    let mut in_code = false;
+    for line in body.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_code = !in_code;
+            continue;
+        }
+        if in_code || trimmed.starts_with('|') || RULE_RE.is_match(trimmed) {
+            push_break(&mut lines);
+            continue;
+        }
+        let mut text = trimmed;
+        while let Some(rest) = text.strip_prefix('>') {
+            text = rest.trim_start();
+        }
+        let heading = text.starts_with('#');
+        let text = LIST_MARKER_RE.replace(text.trim_start_matches('#').trim_start(), "");
+        let text = NOTE_REF_RE.replace_all(&text, |caps: &Captures| match notes.get(&caps[1]) {
+            Some(note) => format!(" (Note: {note})"),
+            None => String::new(),
+        });
+        let text = IMAGE_RE.replace_all(&text, "");
+        let text = LINK_RE.replace_all(&text, "$1");
+        let text = HTML_TAG_RE.replace_all(&text, "");
+        let mut text = expand(&strip_inline_markup(&text), spell_out_numbers);
+        if text.trim().is_empty() {
+            push_break(&mut lines);
+            continue;
+        }
+        if heading {
+            if !text.ends_with(['.', '!', '?', ':']) {
+                text.push('.');
+            }
+            push_break(&mut lines);
+            lines.push(text);
+            push_break(&mut lines);
+        } else {
+            lines.push(text);
+        }
+    }
+    while lines.last().is_some_and(String::is_empty) {
+        lines.pop();
+    }
+    lines.join("\n") + "\n"
+}
+
+fn push_break(lines: &mut Vec<String>) {
+    if lines.last().is_some_and(|line| !line.is_empty()) {
+        lines.push(String::new());
+    }
+}
+
+fn strip_front_matter(markdown: &str) -> &str {
+    markdown
+        .strip_prefix("---\n")
+        .and_then(|rest| rest.split_once("\n---\n"))
+        .map_or(markdown, |(_, body)| body)
+}
+
+/// Removes `[^label]: text` definitions, with their indented continuation
+/// lines, returning the rest and each note's text on one line.
+fn take_note_definitions(markdown: &str) -> (String, HashMap<String, String>) {
+    let mut body = Vec::new();
+    let mut notes: HashMap<String, String> = HashMap::new();
+    let mut current: Option<String> = None;
+    for line in markdown.lines() {
+        if let Some(label) = &current
+            && line.starts_with("    ")
+        {
+            let note = notes.entry(label.clone()).or_default();
+            note.push(' ');
+            note.push_str(line.trim());
+            continue;
+        }
+        current = None;
+        if let Some(caps) = NOTE_DEF_RE.captures(line) {
+            notes.insert(caps[1].to_string(), caps[2].trim().to_string());
+            current = Some(caps[1].to_string());
+            continue;
+        }
+        body.push(line);
+    }
+    let notes = notes
+        .into_iter()
+        .map(|(label, note)| {
+            let note = strip_inline_markup(&LINK_RE.replace_all(&note, "$1"));
+            (label, note.trim().to_string())
+        })
+        .collect();
+    (body.join("\n"), notes)
+}
+
+/// Drops emphasis and code markers and unescapes backslash escapes.
+/// Underscores count as emphasis only at a word's edge.
+fn strip_inline_markup(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::with_capacity(text.len());
+    let mut idx = 0;
+    while idx < chars.len() {
+        let ch = chars[idx];
+        match ch {
+            '\\' if chars
+                .get(idx + 1)
+                .is_some_and(|next| next.is_ascii_punctuation()) =>
+            {
+                out.push(chars[idx + 1]);
+                idx += 2;
+                continue;
+            }
+            '*' | '`' => {}
+            '_' => {
+                let inner = |other: Option<&char>| other.is_some_and(|c| c.is_alphanumeric());
+                let prev = idx.checked_sub(1).and_then(|prev| chars.get(prev));
+                if inner(prev) && inner(chars.get(idx + 1)) {
+                    out.push(ch);
+                }
+            }
+            _ => out.push(ch),
+        }
+        idx += 1;
+    }
+    out.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn expand(text: &str, spell_out_numbers: bool) -> String {
+    let mut text = text.replace(" & ", " and ");
+    for (re, full) in ABBREVIATION_RES.iter() {
+        text = re
+            .replace_all(&text, |caps: &Captures| {
+                let after = caps.get(2).map_or("", |m| m.as_str());
+                let period = if *full == "et cetera" && !after.is_empty() {
+                    "."
+                } else {
+                    ""
+                };
+                format!("{}{full}{period}{after}", &caps[1])
+            })
+            .into_owned();
+    }
+    text = PERCENT_RE.replace_all(&text, "$1 percent").into_owned();
+    if spell_out_numbers {
+        text = NUMBER_RE
+            .replace_all(&text, |caps: &Captures| {
+                let digits = caps[1].replace(',', "");
+                let Ok(value) = digits.parse::<u64>() else {
+                    return caps[0].to_string();
+                };
+                let mut words = match (caps.get(2), caps.get(3)) {
+                    (None, Some(_)) => ordinal_words(value),
+                    (None, None) if !caps[1].contains(',') && is_year(value) => year_words(value),
+                    _ => number_words(value),
+                };
+                if let Some(fraction) = caps.get(2) {
+                    words.push_str(" point");
+                    for digit in fraction.as_str().chars() {
+                        words.push(' ');
+                        words.push_str(ONES[digit.to_digit(10).unwrap_or(0) as usize]);
+                    }
+                }
+                words
+            })
+            .into_owned();
+    }
+    text
+}
+
+const ONES: [&str; 20] = [
+    "zero",
+    "one",
+    "two",
+    "three",
+    "four",
+    "five",
+    "six",
+    "seven",
+    "eight",
+    "nine",
+    "ten",
+    "eleven",
+    "twelve",
+    "thirteen",
+    "fourteen",
+    "fifteen",
+    "sixteen",
+    "seventeen",
+    "eighteen",
+    "nineteen",
+];
+
+const TENS: [&str; 10] = [
+    "", "", "twenty", "thirty", "forty", "fifty", "sixty", "seventy", "eighty", "ninety",
+];
+
+const SCALES: [(u64, &str); 4] = [
+    (1_000_000_000_000, "trillion"),
+    (1_000_000_000, "billion"),
+    (1_000_000, "million"),
+    (1_000, "thousand"),
+];
+
+fn number_words(value: u64) -> String {
+    if value >= 1_000_000_000_000_000 {
+        return value.to_string();
+    }
+    let mut parts = Vec::new();
+    let mut rest = value;
+    for (scale, name) in SCALES {
+        if rest >= scale {
+            parts.push(format!("{} {name}", below_thousand(rest / scale)));
+            rest %= scale;
+        }
+    }
+    if rest > 0 || parts.is_empty() {
+        parts.push(below_thousand(rest));
+    }
+    parts.join(" ")
+}
+
+fn below_thousand(value: u64) -> String {
+    let (hundreds, rest) = (value / 100, value % 100);
+    let rest_words = if rest < 20 {
+        ONES[rest as usize].to_string()
+    } else if rest % 10 == 0 {
+        TENS[(rest / 10) as usize].to_string()
+    } else {
+        format!(
+            "{}-{}",
+            TENS[(rest / 10) as usize],
+            ONES[(rest % 10) as usize]
+        )
+    };
+    match (hundreds, rest) {
+        (0, _) => rest_words,
+        (_, 0) => format!("{} hundred", ONES[hundreds as usize]),
+        _ => format!("{} hundred {rest_words}", ONES[hundreds as usize]),
+    }
+}
+
+fn ordinal_words(value: u64) -> String {
+    let words = number_words(value);
+    let (stem, last) = match words.rfind([' ', '-']) {
+        Some(idx) => words.split_at(idx + 1),
+        None => ("", words.as_str()),
+    };
+    let last = match last {
+        "one" => "first".to_string(),
+        "two" => "second".to_string(),
+        "three" => "third".to_string(),
+        "five" => "fifth".to_string(),
+        "eight" => "eighth".to_string(),
+        "nine" => "ninth".to_string(),
+        "twelve" => "twelfth".to_string(),
+        word if word.ends_with('y') => format!("{}ieth", &word[..word.len() - 1]),
+        word => format!("{word}th"),
+    };
+    format!("{stem}{last}")
+}
+
+fn is_year(value: u64) -> bool {
+    (1100..2100).contains(&value)
+}
+
+/// Years are read in pairs: "nineteen eighty-four", "eighteen hundred",
+/// "two thousand five", "twenty twenty-four".
+fn year_words(value: u64) -> String {
+    let (high, low) = (value / 100, value % 100);
+    match (high, low) {
+        (20, 0..=9) => number_words(value),
+        (_, 0) => format!("{} hundred", below_thousand(high)),
+        (_, 1..=9) => format!("{} oh {}", below_thousand(high), ONES[low as usize]),
+        _ => format!("{} {}", below_thousand(high), below_thousand(low)),
+    }
+}
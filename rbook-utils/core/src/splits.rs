@@ -0,0 +1,190 @@
+use kuchiki::NodeRef;
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+use crate::content_cache::ContentCache;
+use crate::{
+    ConvertOptions, Result, book_title, build_toc_entries, cleanup_toc_entries, count_words,
+    heading_fallback_starts, load_content, open_epub, reading_order, toc_degeneracy_stats,
+    toc_section_span,
+};
+
+/// Both ways of splitting one book into sections, for deciding whether it
+/// needs `--chapter-fallback force` (or `off`). Nothing is rendered: sections
+/// are spans of the reading order, as [`book_spine`](crate::book_spine)
+/// numbers it.
+#[derive(Clone, Debug)]
+pub struct SplitComparison {
+    pub path: PathBuf,
+    pub title: String,
+    /// Sections from the table of contents, after nav cleanup.
+    pub toc: Vec<SplitSection>,
+    /// Sections heading fallback would produce; empty when it finds no
+    /// confident headings.
+    pub headings: Vec<SplitSection>,
+    /// Whether the TOC counts as degenerate, so `--chapter-fallback auto`
+    /// tries headings.
+    pub toc_is_degenerate: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SplitSection {
+    pub label: String,
+    pub spine_start: usize,
+    pub spine_end: usize,
+    /// Where in the first document the section starts; heading sections
+    /// always start at the top.
+    pub start_fragment: Option<String>,
+    /// Words in the spanned documents, counting whole documents even where
+    /// the section starts or ends at a fragment.
+    pub words: usize,
+}
+
+impl SplitSection {
+    fn boundary(&self) -> (usize, Option<&str>) {
+        (self.spine_start, self.start_fragment.as_deref())
+    }
+}
+
+impl SplitComparison {
+    /// Whether `--chapter-fallback auto` would split this book on headings.
+    pub fn auto_uses_headings(&self) -> bool {
+        self.toc_is_degenerate && !self.headings.is_empty()
+    }
+
+    /// Section starts, in reading order, with the TOC and heading section
+    /// starting there (either may be missing).
+    pub fn boundaries(&self) -> Vec<(Option<&SplitSection>, Option<&SplitSection>)> {
+        let starts: BTreeSet<(usize, Option<&str>)> = self
+            .toc
+            .iter()
+            .chain(&self.headings)
+            .map(SplitSection::boundary)
+            .collect();
+        starts
+            .into_iter()
+            .map(|start| {
+                (
+                    starting_at(&self.toc, start),
+                    starting_at(&self.headings, start),
+                )
+            })
+            .collect()
+    }
+
+    /// Starts only one of the two splits has.
+    pub fn differing_boundaries(&self) -> usize {
+        self.boundaries()
+            .iter()
+            .filter(|(toc, headings)| toc.is_none() || headings.is_none())
+            .count()
+    }
+}
+
+/// Computes the TOC and heading-fallback sections of one book the way
+/// [`convert_epub`](crate::convert_epub) would, honouring
+/// `options.nav_cleanup`, `options.extra_readable_types` and
+/// `options.heading_patterns`.
+pub fn compare_splits(epub_path: &Path, options: &ConvertOptions) -> Result<SplitComparison> {
+    let epub = open_epub(epub_path)?;
+    let spine_hrefs = reading_order(&epub, &options.extra_readable_types);
+    let spine_index_by_href: HashMap<String, usize> = spine_hrefs
+        .iter()
+        .enumerate()
+        .map(|(idx, href)| (href.clone(), idx))
+        .collect();
+    let (toc_entries, _) = cleanup_toc_entries(
+        build_toc_entries(&epub, &options.extra_readable_types)?,
+        options.nav_cleanup,
+    );
+    let (toc_is_degenerate, ..) = toc_degeneracy_stats(&toc_entries, spine_hrefs.len());
+
+    let mut cache = ContentCache::default();
+    let mut words: Vec<Option<usize>> = vec![None; spine_hrefs.len()];
+    let mut span_words = |start: usize, end: usize, cache: &mut ContentCache| {
+        (start..=end)
+            .map(|idx| {
+                *words[idx].get_or_insert_with(|| {
+                    load_content(&epub, &spine_hrefs[idx], cache)
+                        .map(|content| body_words(&content.document))
+                        .unwrap_or(0)
+                })
+            })
+            .sum::<usize>()
+    };
+
+    let mut toc = Vec::new();
+    for (idx, entry) in toc_entries.iter().enumerate() {
+        let Some((start, mut end)) =
+            toc_section_span(&toc_entries, idx, &spine_index_by_href, spine_hrefs.len())
+        else {
+            continue;
+        };
+        // A next entry at the top of a document ends this one before it.
+        if toc_entries
+            .get(idx + 1)
+            .is_some_and(|next| next.fragment.is_none())
+        {
+            if end == start {
+                continue;
+            }
+            end -= 1;
+        }
+        toc.push(SplitSection {
+            label: entry.label.clone(),
+            spine_start: start,
+            spine_end: end,
+            start_fragment: entry.fragment.clone(),
+            words: span_words(start, end, &mut cache),
+        });
+    }
+
+    let starts = heading_fallback_starts(
+        &epub,
+        &toc_entries,
+        &spine_hrefs,
+        &mut cache,
+        &options.heading_patterns,
+    )
+    .unwrap_or_default();
+    let mut headings = Vec::new();
+    for (pos, (start, label)) in starts.iter().enumerate() {
+        let next = starts
+            .get(pos + 1)
+            .map(|(idx, _)| *idx)
+            .unwrap_or(spine_hrefs.len());
+        if next <= *start {
+            continue;
+        }
+        headings.push(SplitSection {
+            label: label.clone(),
+            spine_start: *start,
+            spine_end: next - 1,
+            start_fragment: None,
+            words: span_words(*start, next - 1, &mut cache),
+        });
+    }
+
+    Ok(SplitComparison {
+        path: epub_path.to_path_buf(),
+        title: book_title(&epub, epub_path),
+        toc,
+        headings,
+        toc_is_degenerate,
+    })
+}
+
+fn starting_at<'a>(
+    sections: &'a [SplitSection],
+    start: (usize, Option<&str>),
+) -> Option<&'a SplitSection> {
+    sections.iter().find(|section| section.boundary() == start)
+}
+
+fn body_words(document: &NodeRef) -> usize {
+    let text = match document.select_first("body") {
+        Ok(body) => body.as_node().text_contents(),
+        Err(()) => document.text_contents(),
+    };
+    count_words(&text)
+}
@@ -0,0 +1,229 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::output::MemorySink;
+use crate::{
+    BookConversionResult, ConversionPlan, ConversionSummary, ConvertError, ConvertOptions, Result,
+    collect_epub_paths, convert_batch, convert_one, skip_list, skipped_result, usage,
+};
+
+/// Where [`convert_all_to_storage`] puts converted files. Keys are
+/// `/`-separated paths relative to the output root, such as
+/// `my-book/01-chapter.md` or `images/my-book/cover.jpg`.
+pub trait Storage: Send + Sync {
+    fn put(&self, key: &str, bytes: Vec<u8>) -> Result<()>;
+}
+
+/// A directory on the local filesystem.
+#[derive(Clone, Debug)]
+pub struct LocalStorage {
+    pub root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+impl Storage for LocalStorage {
+    fn put(&self, key: &str, bytes: Vec<u8>) -> Result<()> {
+        let path = self.root.join(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|err| ConvertError::write_failed(parent, err))?;
+        }
+        fs::write(&path, bytes).map_err(|err| ConvertError::WriteFailed {
+            path,
+            source: err.into(),
+        })
+    }
+}
+
+/// Files kept in memory, keyed by storage key, for converting without
+/// touching disk and inspecting the results afterwards.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    files: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Contents of the file stored under `key`.
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        self.lock().get(key).cloned()
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.lock().contains_key(key)
+    }
+
+    /// Every stored key, in order.
+    pub fn keys(&self) -> Vec<String> {
+        self.lock().keys().cloned().collect()
+    }
+
+    /// Names of the files and directories directly inside `dir` (a
+    /// `/`-separated key prefix; empty for the root), in order. Directory
+    /// names end with `/`.
+    pub fn list(&self, dir: &str) -> Vec<String> {
+        let dir = dir.trim_matches('/');
+        let prefix = if dir.is_empty() {
+            String::new()
+        } else {
+            format!("{dir}/")
+        };
+        let mut names: Vec<String> = self
+            .lock()
+            .keys()
+            .filter_map(|key| key.strip_prefix(&prefix))
+            .map(|rest| match rest.split_once('/') {
+                Some((name, _)) => format!("{name}/"),
+                None => rest.to_string(),
+            })
+            .collect();
+        names.dedup();
+        names
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    /// The stored files by key.
+    pub fn into_files(self) -> BTreeMap<String, Vec<u8>> {
+        self.files.into_inner().expect("memory storage lock")
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, Vec<u8>>> {
+        self.files.lock().expect("memory storage lock")
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn put(&self, key: &str, bytes: Vec<u8>) -> Result<()> {
+        self.lock().insert(key.to_string(), bytes);
+        Ok(())
+    }
+}
+
+/// MIME type of a converted file, by extension.
+pub fn content_type(key: &str) -> &'static str {
+    let extension = key
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_ascii_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "md" => "text/markdown; charset=utf-8",
+        "json" => "application/json",
+        "css" => "text/css; charset=utf-8",
+        "html" | "xhtml" => "text/html; charset=utf-8",
+        "toml" => "application/toml",
+        "txt" => "text/plain; charset=utf-8",
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "mp3" => "audio/mpeg",
+        "mp4" | "m4a" => "audio/mp4",
+        "ogg" => "audio/ogg",
+        "otf" => "font/otf",
+        "ttf" => "font/ttf",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Converts every book like [`convert_all`](crate::convert_all), but hands
+/// the outputs to `storage` instead of leaving them in `options.output_dir`.
+/// Each book is converted in memory and stored as soon as it is done, so
+/// nothing is written to local disk.
+///
+/// The returned summary's output paths are storage keys. Once storing fails,
+/// the remaining books are skipped and the error is returned.
+/// `options.incremental` is rejected: whether a book's output is still in
+/// the storage cannot be checked.
+pub fn convert_all_to_storage(
+    options: &ConvertOptions,
+    storage: &dyn Storage,
+) -> Result<ConversionSummary> {
+    if options.incremental {
+        return Err(ConvertError::UnsupportedOption {
+            option: "incremental",
+            operation: "convert_all_to_storage",
+        });
+    }
+    let epub_paths = collect_epub_paths(&options.input_dir);
+    if epub_paths.is_empty() {
+        return Err(ConvertError::NoInput {
+            dir: options.input_dir.clone(),
+        });
+    }
+
+    let options = &options.for_batch();
+    let started = std::time::Instant::now();
+    let failed = AtomicBool::new(false);
+    let failure = Mutex::new(None::<ConvertError>);
+    let total = epub_paths.len();
+    let books = convert_batch(&epub_paths, options, |idx, epub_path| {
+        if failed.load(Ordering::Relaxed) {
+            return skipped_result(epub_path, String::new(), "storage write failed".to_string());
+        }
+        let mut book_options = options.clone();
+        book_options.output_dir = PathBuf::new();
+        let sink = Arc::new(MemorySink::default());
+        book_options.output_sink = sink.clone();
+        let mut result = convert_one(epub_path, idx, total, &book_options);
+        if let Err(err) = store_files(storage, &sink) {
+            if !failed.swap(true, Ordering::Relaxed) {
+                *failure.lock().expect("failure lock") = Some(err);
+            }
+        }
+        relocate_paths(&mut result);
+        result
+    })?;
+    if let Some(err) = failure.into_inner().expect("failure lock") {
+        return Err(err);
+    }
+    if let Some(path) = &options.plan_export {
+        ConversionPlan::from_results(&books).write(path, options)?;
+    }
+    skip_list::record_run(options, &books);
+    let summary = ConversionSummary { books };
+    usage::record_usage(options, &summary, started);
+    Ok(summary)
+}
+
+/// Stores every file written to `sink`, in path order.
+fn store_files(storage: &dyn Storage, sink: &MemorySink) -> Result<()> {
+    for (path, bytes) in sink.take() {
+        storage.put(&storage_key(&path), bytes)?;
+    }
+    Ok(())
+}
+
+fn storage_key(path: &Path) -> String {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn relocate_paths(result: &mut BookConversionResult) {
+    let relocate = |path: &mut PathBuf| *path = PathBuf::from(storage_key(path));
+    if let Some(path) = &mut result.output_path {
+        relocate(path);
+    }
+    result.report.output_paths.iter_mut().for_each(relocate);
+}
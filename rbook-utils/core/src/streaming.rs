@@ -0,0 +1,438 @@
+use rbook::prelude::{MetaEntry, Metadata};
+use rbook::{Ebook, Epub};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use crate::cleanup_profiles;
+use crate::content_cache::ContentCache;
+use crate::images::{ImageExtractor, ImageOptions};
+use crate::markdown::RenderOptions;
+use crate::output;
+use crate::templates::BookFields;
+use crate::watermarks;
+use crate::{
+    BookConversionResult, ChapterFallbackMode, ChapterNav, ConvertError, ConvertOptions,
+    ConvertReport, Diagnostic, DiagnosticLevel, ExportMode, FlashcardExport, MarkdownMode,
+    MissingResources, NotesMode, OcrCleanupMode, Result, SectionNaming, SectionRecord, SvgMode,
+    TranslationExport, WarningCode, asset_link_prefix, book_is_rtl, book_title, build_toc_entries,
+    cleanup_toc_entries, count_words, escape_link_text, load_content, lock_book,
+    rebase_asset_links, render_partial_with_anchors, resolve_and_extract_image,
+    resolve_output_conflict, section_file_name, skipped_result, text_output, toc_section_parts,
+    toc_section_span, unique_section_id,
+};
+
+/// One chapter to render: its spine documents with the fragments it starts
+/// and ends at in each.
+struct Span {
+    label: String,
+    start_href: String,
+    start_fragment: Option<String>,
+    end_href: Option<String>,
+    end_fragment: Option<String>,
+    parts: Vec<(usize, Option<String>, Option<String>)>,
+}
+
+/// Converts a book with bounded memory: each chapter file is written as soon
+/// as it is rendered, and parsed documents are dropped once the reading order
+/// has moved past them, so a multi-hundred-MB book never has more than a
+/// chapter's worth of DOM and markdown in memory.
+///
+/// Chapters are always split and follow the TOC, or the spine when there is
+/// none. Whole-book passes are left out: heading fallback, tiny-section
+/// merging, plans, notes consolidation, rewriting links between chapters,
+/// previous/next navigation and the book-level exports. `index.md` is still
+/// written with `chapter_nav`. Each option set that only those passes apply
+/// is reported with [`WarningCode::StreamingIgnoresOption`].
+pub(crate) fn convert_streaming(
+    epub: &Epub,
+    epub_path: &Path,
+    options: &ConvertOptions,
+) -> Result<BookConversionResult> {
+    // Streaming scrubs each chapter as it goes, so a buyer only named in a
+    // later chapter's stamp is not redacted from earlier ones.
+    let watermarks = options
+        .strip_watermarks
+        .then(|| watermarks::Watermarks::detect(epub));
+    let mut title = book_title(epub, epub_path);
+    if let Some(watermarks) = &watermarks {
+        title = watermarks.scrub_metadata("title", &title);
+    }
+    let book_fields = BookFields::from_epub(epub, &title, options.slug_strategy.as_ref());
+    let Some(book_slug) = resolve_output_conflict(
+        book_fields.book_path(options.output_template.as_ref()),
+        true,
+        options,
+    ) else {
+        let reason = format!("{title} already exists in the output directory");
+        return Ok(skipped_result(epub_path, title, reason));
+    };
+    lock_book(&book_slug, options)?;
+    let mut layout = options.clone();
+    layout.split_chapters = true;
+    let book_dir = options.output_dir.join(&book_slug);
+    let image_extractor = ImageExtractor::new(
+        book_dir.join("images"),
+        asset_link_prefix(&layout, &book_slug, "images"),
+        ImageOptions::from_convert_options(options, epub_path),
+    );
+    let asset_prefixes: Vec<String> = ["images", "media", "styles", "fonts", "thumbs"]
+        .iter()
+        .map(|kind| asset_link_prefix(&layout, &book_slug, kind))
+        .collect();
+
+    let spine_hrefs = crate::reading_order(epub, &options.extra_readable_types);
+    let spans = chapter_spans(epub, &spine_hrefs, options)?;
+    let spine_index: HashMap<&str, usize> = spine_hrefs
+        .iter()
+        .enumerate()
+        .map(|(idx, href)| (href.as_str(), idx))
+        .collect();
+    let mut diagnostics = Vec::new();
+    let mut warn = |code: WarningCode, message: String| {
+        if !options.suppress_warnings.contains(&code) {
+            diagnostics.push(Diagnostic {
+                level: if options.error_on_warnings.contains(&code) {
+                    DiagnosticLevel::Error
+                } else {
+                    DiagnosticLevel::Warning
+                },
+                code: Some(code),
+                message,
+            });
+        }
+    };
+
+    for option in ignored_options(options) {
+        warn(
+            WarningCode::StreamingIgnoresOption,
+            format!("{title}: {option} is not applied when streaming"),
+        );
+    }
+
+    let files = &*options.output_sink;
+    files
+        .create_dir_all(&book_dir)
+        .map_err(|err| ConvertError::write_failed(&book_dir, err))?;
+    output::remove_chapter_files(files, &book_dir)
+        .map_err(|err| ConvertError::write_failed(&book_dir, err))?;
+    let writer = text_output::TextWriter::new(options);
+    let mut header = vec![format!("# {title}")];
+    if let Some(author) = epub.metadata().creators().next() {
+        let author = match &watermarks {
+            Some(watermarks) => watermarks.scrub_metadata("author", author.value()),
+            None => author.value().to_string(),
+        };
+        header.push(format!("**Author:** {author}"));
+    }
+    header.push(String::new());
+
+    let mut cache = ContentCache::from_options(options);
+    let cleanup_profile = options
+        .cleanup_profile
+        .as_deref()
+        .and_then(|choice| options.cleanup_profiles.select(choice, epub))
+        .map(|(_, profile)| profile);
+    let mut render_options = RenderOptions::from_convert_options(options);
+    render_options.rtl = book_is_rtl(epub, &spine_hrefs, &mut cache, options);
+    let mut image_resolver = |src: &str, base_href: &str| {
+        resolve_and_extract_image(epub, src, base_href, &image_extractor)
+    };
+
+    let width = std::cmp::max(2, spans.len().to_string().len());
+    let mut seen_ids: HashSet<String> = HashSet::new();
+    let mut chapters: Vec<(String, String)> = Vec::new();
+    let mut section_words = Vec::new();
+    for (idx, span) in spans.iter().enumerate() {
+        let _progress = options.section_progress(epub_path, idx, spans.len());
+        options.check_cancelled()?;
+        if cleanup_profile
+            .is_some_and(|profile| cleanup_profiles::skips_section(profile, &span.label))
+        {
+            continue;
+        }
+        let mut chunks = Vec::new();
+        for (spine_idx, start_fragment, end_fragment) in &span.parts {
+            let Some(href) = spine_hrefs.get(*spine_idx) else {
+                continue;
+            };
+            if let Some(profile) = cleanup_profile {
+                let hrefs = std::slice::from_ref(href);
+                cleanup_profiles::strip_classes(profile, epub, hrefs, &mut cache);
+            }
+            let content = match load_content(epub, href, &mut cache) {
+                Ok(content) => content,
+                Err(err) => {
+                    warn(
+                        WarningCode::UnreadableSpineItem,
+                        format!("{title}: left out a spine document: {}", err.full_message()),
+                    );
+                    continue;
+                }
+            };
+            let (part, _) = render_partial_with_anchors(
+                content,
+                &render_options,
+                start_fragment.as_deref(),
+                end_fragment.as_deref(),
+                &mut image_resolver,
+            );
+            if let Some(part) = part.filter(|part| !part.trim().is_empty()) {
+                chunks.push(part);
+            }
+        }
+        // Later chapters start at or after the next span's first document, so
+        // earlier ones go even if pinned.
+        let keep_from = spans
+            .get(idx + 1)
+            .and_then(|next| next.parts.first())
+            .map_or(spine_hrefs.len(), |(spine_idx, _, _)| *spine_idx);
+        cache.retain_all(|href| {
+            spine_index
+                .get(href)
+                .is_none_or(|spine_idx| *spine_idx >= keep_from)
+        });
+
+        let mut text = chunks.join("\n\n").trim().to_string();
+        if let Some(profile) = cleanup_profile {
+            text = cleanup_profiles::remove_patterns(profile, &text).0;
+        }
+        if text.is_empty() {
+            continue;
+        }
+        let mut section = SectionRecord {
+            title: span.label.clone(),
+            text,
+            start_href: span.start_href.clone(),
+            start_fragment: span.start_fragment.clone(),
+            end_href: span.end_href.clone(),
+            end_fragment: span.end_fragment.clone(),
+            spine_start: span.parts.first().map_or(0, |(spine_idx, _, _)| *spine_idx),
+            spine_end: span.parts.last().map_or(0, |(spine_idx, _, _)| *spine_idx),
+            anchors: Vec::new(),
+            section_id: String::new(),
+            output_path: String::new(),
+            heading_anchor: None,
+        };
+        if let Some(watermarks) = &watermarks {
+            watermarks.scrub_sections(std::slice::from_mut(&mut section));
+        }
+        section.section_id = unique_section_id(&section, &mut seen_ids);
+        let output_path = section_file_name(
+            &section,
+            chapters.len(),
+            width,
+            SectionNaming::new(options, &book_fields),
+            options.slug_strategy.as_ref(),
+        );
+
+        let mut lines = header.clone();
+        lines.push(format!("<a id=\"{}\"></a>", section.section_id));
+        lines.push(format!("## {}", section.title));
+        lines.push(String::new());
+        section_words.push(count_words(&section.text));
+        lines.push(section.text);
+        let path = book_dir.join(&output_path);
+        let text = rebase_asset_links(&lines.join("\n"), &asset_prefixes, &book_dir, &path);
+        writer.write(&path, &(text.trim().to_string() + "\n"))?;
+        chapters.push((section.title, output_path));
+    }
+
+    if chapters.is_empty() {
+        return Err(crate::ConvertError::NoReadableSections {
+            path: epub_path.to_path_buf(),
+        });
+    }
+    if options.chapter_nav != ChapterNav::Off {
+        let mut lines = vec![format!("# {title}"), String::new()];
+        for (idx, (chapter_title, output_path)) in chapters.iter().enumerate() {
+            lines.push(format!(
+                "{}. [{}](./{output_path})",
+                idx + 1,
+                escape_link_text(chapter_title)
+            ));
+        }
+        writer.write(&book_dir.join("index.md"), &(lines.join("\n") + "\n"))?;
+    }
+    output::record_chapter_files(files, &book_dir, &writer.written.borrow())?;
+    if writer.unmappable.get() > 0 {
+        warn(
+            WarningCode::UnmappableCharacters,
+            format!(
+                "{title}: {} characters cannot be written in {} and were replaced with ?",
+                writer.unmappable.get(),
+                writer.encoding_name()
+            ),
+        );
+    }
+
+    let images_extracted = image_extractor.written();
+    if images_extracted > 0 {
+        diagnostics.push(Diagnostic {
+            level: DiagnosticLevel::Info,
+            code: None,
+            message: format!("Extracted {images_extracted} images for {title}"),
+        });
+    }
+    let removed = watermarks.map(|watermarks| watermarks.report.into_inner());
+    if let Some(report) = removed.filter(|report| report.removed() > 0) {
+        diagnostics.push(Diagnostic {
+            level: DiagnosticLevel::Info,
+            code: None,
+            message: format!(
+                "Removed {} watermark stamps and {} mentions of the buyer for {title}",
+                report.stamp_lines + report.metadata_fields.len(),
+                report.redactions
+            ),
+        });
+    }
+    let report = ConvertReport {
+        output_paths: writer.written.take(),
+        section_count: chapters.len(),
+        word_count: section_words.iter().sum(),
+        section_words,
+        images_extracted,
+        warnings: diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.code.is_some())
+            .cloned()
+            .collect(),
+        ..ConvertReport::default()
+    };
+    Ok(BookConversionResult {
+        input_path: epub_path.to_path_buf(),
+        title,
+        output_path: Some(book_dir),
+        diagnostics,
+        missing_resources: MissingResources {
+            images: image_extractor.options.take_missing(),
+            ..MissingResources::default()
+        },
+        skipped: None,
+        error_kind: None,
+        sections: Vec::new(),
+        report,
+    })
+}
+
+/// The options set in `options` that need a whole-book pass, by field name.
+fn ignored_options(options: &ConvertOptions) -> Vec<&'static str> {
+    [
+        (
+            "chapter_fallback",
+            options.chapter_fallback == ChapterFallbackMode::Force,
+        ),
+        ("heading_patterns", !options.heading_patterns.is_empty()),
+        (
+            "split_on_heading_level",
+            options.split_on_heading_level.is_some(),
+        ),
+        ("min_section_words", options.min_section_words > 0),
+        ("plan", options.plan.is_some()),
+        ("consolidate_endnotes", options.consolidate_endnotes),
+        ("notes_mode", options.notes_mode != NotesMode::Inline),
+        ("ocr_cleanup", options.ocr_cleanup != OcrCleanupMode::Off),
+        ("media_all", options.media_all),
+        ("extract_fonts", options.extract_fonts),
+        ("style", options.markdown_mode == MarkdownMode::Rich),
+        ("svg_mode", options.svg_mode != SvgMode::Inline),
+        ("skip_decorative_images", options.skip_decorative_images),
+        ("skip_images", options.skip_images),
+        ("only_public_domain", options.only_public_domain),
+        (
+            "chapter_nav previous/next links",
+            options.chapter_nav != ChapterNav::Off,
+        ),
+        ("chapter_thumbnails", options.chapter_thumbnails),
+        ("list_of_figures", options.list_of_figures),
+        ("compare_view", options.compare_view),
+        ("fingerprints", options.fingerprints),
+        (
+            "export_manifest",
+            options.export_manifest != ExportMode::Off,
+        ),
+        (
+            "export_positions",
+            options.export_positions != ExportMode::Off,
+        ),
+        (
+            "export_provenance",
+            options.export_provenance != ExportMode::Off,
+        ),
+        (
+            "export_audio_split",
+            options.export_audio_split != ExportMode::Off,
+        ),
+        ("quality_report", options.quality_report != ExportMode::Off),
+        (
+            "translation_export",
+            options.translation_export != TranslationExport::Off,
+        ),
+        (
+            "flashcard_export",
+            options.flashcard_export != FlashcardExport::Off,
+        ),
+    ]
+    .into_iter()
+    .filter(|(_, set)| *set)
+    .map(|(name, _)| name)
+    .collect()
+}
+
+/// The chapters a streaming conversion renders: one per TOC entry, as the
+/// regular conversion splits them, or one per spine document without a TOC.
+fn chapter_spans(
+    epub: &Epub,
+    spine_hrefs: &[String],
+    options: &ConvertOptions,
+) -> Result<Vec<Span>> {
+    let (toc_entries, _) = cleanup_toc_entries(
+        build_toc_entries(epub, &options.extra_readable_types)?,
+        options.nav_cleanup,
+    );
+    if toc_entries.is_empty() {
+        return Ok(spine_hrefs
+            .iter()
+            .enumerate()
+            .map(|(spine_idx, href)| Span {
+                label: crate::prettify_section_name(href),
+                start_href: href.clone(),
+                start_fragment: None,
+                end_href: None,
+                end_fragment: None,
+                parts: vec![(spine_idx, None, None)],
+            })
+            .collect());
+    }
+    let spine_index_by_href: HashMap<String, usize> = spine_hrefs
+        .iter()
+        .enumerate()
+        .map(|(idx, href)| (href.clone(), idx))
+        .collect();
+    let mut spans = Vec::new();
+    for (idx, entry) in toc_entries.iter().enumerate() {
+        let Some((start_idx, end_idx)) =
+            toc_section_span(&toc_entries, idx, &spine_index_by_href, spine_hrefs.len())
+        else {
+            continue;
+        };
+        let next_entry = toc_entries.get(idx + 1);
+        spans.push(Span {
+            label: entry.label.clone(),
+            start_href: entry.href_path.clone(),
+            start_fragment: entry.fragment.clone(),
+            end_href: next_entry.map(|next| next.href_path.clone()),
+            end_fragment: next_entry.and_then(|next| next.fragment.clone()),
+            parts: toc_section_parts(entry, next_entry, start_idx, end_idx)
+                .into_iter()
+                .map(|(spine_idx, start, end)| {
+                    (
+                        spine_idx,
+                        start.map(str::to_string),
+                        end.map(str::to_string),
+                    )
+                })
+                .collect(),
+        });
+    }
+    Ok(spans)
+}
@@ -0,0 +1,350 @@
+use kuchiki::NodeRef;
+use kuchiki::traits::*;
+use rbook::Epub;
+use sha1::{Digest, Sha1};
+use std::path::Path;
+
+use crate::content_cache::ContentCache;
+use crate::images::ImageExtractor;
+use crate::{
+    SvgMode, element_name, is_external, load_content, normalize_space, resolve_href, serialize_node,
+};
+
+const SVG_NS: &str = "http://www.w3.org/2000/svg";
+const XLINK_NS: &str = "http://www.w3.org/1999/xlink";
+
+/// Output locations shared with the regular image extraction.
+pub(crate) struct SvgTargets<'a> {
+    pub(crate) image_root: &'a Path,
+    pub(crate) image_link_prefix: &'a str,
+    pub(crate) images: &'a ImageExtractor,
+}
+
+/// Replaces every inline `<svg>` in the spine with an `<img>` pointing at a
+/// standalone copy under `images/svg/` (or a PNG rendering of it), so both
+/// markdown modes treat it like any other image. Returns how many were written.
+///
+/// An `<svg>` that only wraps a single `<image>` (the usual cover page) becomes
+/// an `<img>` of that image directly.
+pub(crate) fn replace_inline_svgs(
+    epub: &Epub,
+    spine_hrefs: &[String],
+    cache: &mut ContentCache,
+    mode: SvgMode,
+    targets: &SvgTargets,
+    warn: &mut dyn FnMut(String),
+) -> usize {
+    if mode == SvgMode::Inline {
+        return 0;
+    }
+    let svg_root = targets.image_root.join("svg");
+    let mut written = 0usize;
+    for href in spine_hrefs {
+        let Ok(content) = load_content(epub, href, cache) else {
+            continue;
+        };
+        let Ok(matches) = content.document.select("svg") else {
+            continue;
+        };
+        // Collect first: replacing nodes while iterating the selection would skip some.
+        let svgs: Vec<NodeRef> = matches
+            .map(|svg| svg.as_node().clone())
+            .filter(|svg| !svg.ancestors().any(|a| element_name(&a) == Some("svg")))
+            .collect();
+        if !svgs.is_empty() {
+            cache.pin(href);
+        }
+        for svg in svgs {
+            let alt = svg_title(&svg);
+            let images = svg_image_hrefs(&svg);
+            let has_shapes = svg.descendants().any(|node| {
+                matches!(
+                    element_name(&node),
+                    Some(
+                        "path"
+                            | "text"
+                            | "rect"
+                            | "circle"
+                            | "ellipse"
+                            | "line"
+                            | "polyline"
+                            | "polygon"
+                            | "use"
+                    )
+                )
+            });
+            if images.len() == 1 && !has_shapes {
+                replace_with_img(&svg, &images[0].1, &alt);
+                continue;
+            }
+
+            // Referenced bitmaps are extracted next to the SVG so relative links keep working.
+            for (node, image_href) in &images {
+                if is_external(image_href) {
+                    continue;
+                }
+                let resolved = resolve_href(href, image_href);
+                if let Some(link) = targets.images.extract(epub, &resolved) {
+                    set_image_href(node, &svg_relative_link(&link, targets.image_link_prefix));
+                }
+            }
+            let markup = standalone_svg(&svg);
+            let mut hasher = Sha1::new();
+            hasher.update(markup.as_bytes());
+            let stem = href
+                .rsplit('/')
+                .next()
+                .and_then(|name| name.split('.').next())
+                .unwrap_or("doc");
+            let name = format!("{stem}_{}", &format!("{:x}", hasher.finalize())[..12]);
+
+            if let Err(err) = targets.images.options.files.create_dir_all(&svg_root) {
+                warn(format!("Failed to create {}: {err}", svg_root.display()));
+                return written;
+            }
+            let file_name = match mode {
+                SvgMode::Rasterize => match rasterize(&markup, &svg_root) {
+                    Ok(png) => {
+                        let file_name = format!("{name}.png");
+                        if let Err(err) = targets
+                            .images
+                            .options
+                            .files
+                            .write(&svg_root.join(&file_name), &png)
+                        {
+                            warn(format!("Failed to write {file_name}: {err}"));
+                            continue;
+                        }
+                        file_name
+                    }
+                    Err(err) => {
+                        warn(format!("Failed to rasterize inline SVG in {href}: {err}"));
+                        continue;
+                    }
+                },
+                _ => {
+                    let file_name = format!("{name}.svg");
+                    if let Err(err) = targets
+                        .images
+                        .options
+                        .files
+                        .write(&svg_root.join(&file_name), markup.as_bytes())
+                    {
+                        warn(format!("Failed to write {file_name}: {err}"));
+                        continue;
+                    }
+                    file_name
+                }
+            };
+            let link = format!("{}/svg/{file_name}", targets.image_link_prefix);
+            replace_with_img(&svg, &link, &alt);
+            written += 1;
+        }
+    }
+    written
+}
+
+fn svg_title(svg: &NodeRef) -> String {
+    if let Some(label) = svg
+        .as_element()
+        .and_then(|el| el.attributes.borrow().get("aria-label").map(str::to_string))
+    {
+        return label.trim().to_string();
+    }
+    svg.children()
+        .find(|child| element_name(child) == Some("title"))
+        .map(|title| title.text_contents().trim().to_string())
+        .unwrap_or_default()
+}
+
+/// `<image>` elements with their `href`/`xlink:href`, whichever namespace the
+/// parser put it in.
+fn svg_image_hrefs(svg: &NodeRef) -> Vec<(NodeRef, String)> {
+    svg.descendants()
+        .filter(|node| element_name(node) == Some("image"))
+        .filter_map(|node| {
+            let href = node.as_element().and_then(|el| {
+                el.attributes
+                    .borrow()
+                    .map
+                    .iter()
+                    .find(|(name, _)| &*name.local == "href")
+                    .map(|(_, attr)| attr.value.clone())
+            })?;
+            Some((node, href))
+        })
+        .collect()
+}
+
+/// `link`, as the image extractor returned it, for use from `images/svg/`:
+/// `data:` URIs as they are, files one directory up.
+fn svg_relative_link(link: &str, image_link_prefix: &str) -> String {
+    match link
+        .strip_prefix(image_link_prefix)
+        .and_then(|relative| relative.strip_prefix('/'))
+    {
+        Some(relative) => format!("../{relative}"),
+        None => link.to_string(),
+    }
+}
+
+fn set_image_href(node: &NodeRef, value: &str) {
+    if let Some(el) = node.as_element() {
+        let mut attrs = el.attributes.borrow_mut();
+        for (name, attr) in attrs.map.iter_mut() {
+            if &*name.local == "href" {
+                attr.value = value.to_string();
+            }
+        }
+    }
+}
+
+fn replace_with_img(svg: &NodeRef, src: &str, alt: &str) {
+    let escape = |value: &str| value.replace('&', "&amp;").replace('"', "&quot;");
+    let fragment = kuchiki::parse_html().one(format!(
+        "<img src=\"{}\" alt=\"{}\">",
+        escape(src),
+        escape(alt)
+    ));
+    if let Ok(img) = fragment.select_first("img") {
+        let img = img.as_node().clone();
+        img.detach();
+        svg.insert_before(img);
+        svg.detach();
+    }
+}
+
+/// Inline SVG in XHTML inherits its namespaces from the document; a standalone
+/// file has to declare them itself.
+fn standalone_svg(svg: &NodeRef) -> String {
+    let mut markup = serialize_node(svg);
+    if let Some(rest) = markup.strip_prefix("<svg") {
+        let mut declarations = String::new();
+        if !rest.split('>').next().unwrap_or("").contains("xmlns=") {
+            declarations.push_str(&format!(" xmlns=\"{SVG_NS}\""));
+        }
+        if markup.contains("xlink:") && !markup.contains("xmlns:xlink") {
+            declarations.push_str(&format!(" xmlns:xlink=\"{XLINK_NS}\""));
+        }
+        markup = format!("<svg{declarations}{rest}");
+    }
+    format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{markup}\n")
+}
+
+/// The system fonts, scanned on the first rasterized SVG and shared by every
+/// later one in the process.
+#[cfg(feature = "svg-raster")]
+fn system_fonts() -> std::sync::Arc<resvg::usvg::fontdb::Database> {
+    static FONTS: std::sync::OnceLock<std::sync::Arc<resvg::usvg::fontdb::Database>> =
+        std::sync::OnceLock::new();
+    FONTS
+        .get_or_init(|| {
+            let mut fonts = resvg::usvg::fontdb::Database::new();
+            fonts.load_system_fonts();
+            std::sync::Arc::new(fonts)
+        })
+        .clone()
+}
+
+#[cfg(feature = "svg-raster")]
+fn rasterize(markup: &str, resources_dir: &Path) -> Result<Vec<u8>, String> {
+    let options = resvg::usvg::Options {
+        resources_dir: Some(resources_dir.to_path_buf()),
+        fontdb: system_fonts(),
+        ..Default::default()
+    };
+    let tree = resvg::usvg::Tree::from_str(markup, &options).map_err(|err| err.to_string())?;
+    // Twice the intrinsic size so the bitmap stays sharp on high-DPI screens.
+    let scale = 2.0;
+    let size = tree
+        .size()
+        .to_int_size()
+        .scale_by(scale)
+        .ok_or("SVG has an empty canvas")?;
+    let mut pixmap = resvg::tiny_skia::Pixmap::new(size.width(), size.height())
+        .ok_or("SVG canvas is too large")?;
+    resvg::render(
+        &tree,
+        resvg::tiny_skia::Transform::from_scale(scale, scale),
+        &mut pixmap.as_mut(),
+    );
+    pixmap.encode_png().map_err(|err| err.to_string())
+}
+
+#[cfg(not(feature = "svg-raster"))]
+fn rasterize(_markup: &str, _resources_dir: &Path) -> Result<Vec<u8>, String> {
+    Err("built without the svg-raster feature".to_string())
+}
+
+/// Turns an SVG content document (a spine item, common in comics and picture
+/// books) into HTML the renderers understand: each `<text>` layer becomes a
+/// paragraph, with `<tspan>`s on different baselines as separate lines, and
+/// each `<image>` an `<img>` so it goes through image extraction. Layers keep
+/// document order, which is the order reading systems present them in.
+pub(crate) fn svg_document_to_html(markup: &str) -> String {
+    let document = kuchiki::parse_html().one(markup);
+    let title = document
+        .select_first("svg > title")
+        .map(|title| title.text_contents().trim().to_string())
+        .unwrap_or_default();
+    let images = svg_image_hrefs(&document);
+    let escape = |value: &str| {
+        value
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+    };
+    let mut body = String::new();
+    for node in document.descendants() {
+        match element_name(&node) {
+            Some("image") => {
+                if let Some((_, href)) = images.iter().find(|(image, _)| *image == node) {
+                    body.push_str(&format!(
+                        "<p><img src=\"{}\" alt=\"{}\"></p>\n",
+                        escape(href),
+                        escape(&title)
+                    ));
+                }
+            }
+            Some("text") if !node.ancestors().any(|a| element_name(&a) == Some("text")) => {
+                let lines = text_lines(&node);
+                if !lines.is_empty() {
+                    let lines: Vec<String> = lines.iter().map(|line| escape(line)).collect();
+                    body.push_str(&format!("<p>{}</p>\n", lines.join("<br>")));
+                }
+            }
+            _ => {}
+        }
+    }
+    format!("<html><body>\n{body}</body></html>")
+}
+
+/// Lines of one `<text>` element: a `<tspan>` with its own `y` starts a new line.
+fn text_lines(text: &NodeRef) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    let mut current = String::new();
+    let mut current_y: Option<String> = None;
+    for child in text.children() {
+        let y = (element_name(&child) == Some("tspan"))
+            .then(|| {
+                child
+                    .as_element()
+                    .and_then(|el| el.attributes.borrow().get("y").map(str::to_string))
+            })
+            .flatten();
+        if y.is_some() && current_y.is_some() && y != current_y && !current.trim().is_empty() {
+            lines.push(normalize_space(&current));
+            current.clear();
+        }
+        if y.is_some() {
+            current_y = y;
+        }
+        current.push_str(&child.text_contents());
+        current.push(' ');
+    }
+    if !current.trim().is_empty() {
+        lines.push(normalize_space(&current));
+    }
+    lines
+}
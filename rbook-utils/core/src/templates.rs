@@ -0,0 +1,316 @@
+use rbook::Epub;
+use rbook::prelude::{MetaEntry, Metadata};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::slugs::SlugStrategy;
+
+/// Where a book's outputs go under `output_dir`, as a path template such as
+/// `{author_sort}/{title}/{index:03}_{section}.md`.
+///
+/// Placeholders: `{title}`, `{author}`, `{author_sort}`, `{series}` and
+/// `{series_index}` from the book's metadata, `{slug}` (the title through
+/// the slug strategy), and, in the last component only, `{index}`,
+/// `{section}` (the section title's slug) and `{section_id}`. Numbers take a
+/// zero-padded width, as in `{index:03}`. Metadata values keep their spelling
+/// with path separators and characters Windows rejects replaced by `_`;
+/// missing ones are empty, and components left empty are dropped.
+///
+/// A last component with section placeholders names split chapter files and
+/// the rest names the book directory; otherwise the whole template names the
+/// book directory (split) or file (single). A trailing `.md` is optional.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutputTemplate {
+    source: String,
+    book: Vec<Vec<Piece>>,
+    section: Option<Vec<Piece>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Piece {
+    Text(String),
+    Field(Field, usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Field {
+    Title,
+    Author,
+    AuthorSort,
+    Series,
+    SeriesIndex,
+    Slug,
+    Index,
+    Section,
+    SectionId,
+}
+
+impl Field {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "title" => Field::Title,
+            "author" => Field::Author,
+            "author_sort" => Field::AuthorSort,
+            "series" => Field::Series,
+            "series_index" => Field::SeriesIndex,
+            "slug" => Field::Slug,
+            "index" => Field::Index,
+            "section" => Field::Section,
+            "section_id" => Field::SectionId,
+            _ => return None,
+        })
+    }
+
+    fn is_section(self) -> bool {
+        matches!(self, Field::Index | Field::Section | Field::SectionId)
+    }
+
+    fn is_number(self) -> bool {
+        matches!(self, Field::Index | Field::SeriesIndex)
+    }
+}
+
+/// The book-level values a template is filled with.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct BookFields {
+    title: String,
+    author: String,
+    author_sort: String,
+    series: String,
+    series_index: String,
+    slug: String,
+}
+
+impl BookFields {
+    /// Where the book goes under `output_dir`: the template's book path, or
+    /// the title's slug without a template.
+    pub(crate) fn book_path(&self, template: Option<&OutputTemplate>) -> String {
+        match template {
+            Some(template) => template.book_path(self),
+            None => self.slug.clone(),
+        }
+    }
+
+    pub(crate) fn from_epub(epub: &Epub, title: &str, slugs: &dyn SlugStrategy) -> Self {
+        let metadata = epub.metadata();
+        let creator = metadata.creators().next();
+        let author = creator
+            .map(|creator| creator.value().trim().to_string())
+            .unwrap_or_default();
+        // EPUB 3 refines the creator with `file-as`; EPUB 2 metadata as read
+        // here does not carry `opf:file-as`, so derive "Last, First".
+        let author_sort = creator
+            .and_then(|creator| {
+                creator
+                    .refinements()
+                    .find(|meta| meta.property().as_str() == "file-as")
+                    .map(|meta| meta.value().trim().to_string())
+            })
+            .filter(|sort| !sort.is_empty())
+            .unwrap_or_else(|| sort_name(&author));
+        let entry = |property: &str| {
+            metadata
+                .entries()
+                .find(|meta| meta.property().as_str() == property)
+        };
+        let collection = entry("belongs-to-collection");
+        let series = collection
+            .map(|meta| meta.value().trim().to_string())
+            .or_else(|| entry("calibre:series").map(|meta| meta.value().trim().to_string()))
+            .unwrap_or_default();
+        let series_index = collection
+            .and_then(|meta| {
+                meta.refinements()
+                    .find(|meta| meta.property().as_str() == "group-position")
+            })
+            .or_else(|| entry("calibre:series_index"))
+            .map(|meta| meta.value().trim().to_string())
+            .unwrap_or_default();
+        Self {
+            title: title.to_string(),
+            author,
+            author_sort,
+            series,
+            series_index,
+            slug: slugs.slug(title),
+        }
+    }
+}
+
+/// "Jane Q. Public" as "Public, Jane Q."; single names stay as they are.
+fn sort_name(name: &str) -> String {
+    let name = name.trim();
+    if name.contains(',') {
+        return name.to_string();
+    }
+    match name.rsplit_once(' ') {
+        Some((given, family)) => format!("{family}, {}", given.trim()),
+        None => name.to_string(),
+    }
+}
+
+impl OutputTemplate {
+    /// The book's directory (split) or file path without `.md` (single),
+    /// relative to `output_dir` and `/`-separated. Falls back to the slug
+    /// when the template leaves nothing.
+    fn book_path(&self, fields: &BookFields) -> String {
+        let path = self
+            .book
+            .iter()
+            .map(|component| render(component, fields, None))
+            .filter(|component| !component.is_empty())
+            .collect::<Vec<_>>()
+            .join("/");
+        if path.is_empty() {
+            fields.slug.clone()
+        } else {
+            path
+        }
+    }
+
+    /// Whether the template names chapter files itself.
+    pub(crate) fn names_sections(&self) -> bool {
+        self.section.is_some()
+    }
+
+    /// File name of the `index`th (1-based) chapter; empty unless
+    /// [`names_sections`](Self::names_sections).
+    pub(crate) fn section_file_name(
+        &self,
+        fields: &BookFields,
+        index: usize,
+        section_slug: &str,
+        section_id: &str,
+    ) -> String {
+        let Some(section) = &self.section else {
+            return String::new();
+        };
+        let name = render(section, fields, Some((index, section_slug, section_id)));
+        format!("{name}.md")
+    }
+}
+
+fn render(pieces: &[Piece], fields: &BookFields, section: Option<(usize, &str, &str)>) -> String {
+    let mut out = String::new();
+    for piece in pieces {
+        match piece {
+            Piece::Text(text) => out.push_str(text),
+            Piece::Field(field, width) => {
+                let value = match field {
+                    Field::Title => path_safe(&fields.title),
+                    Field::Author => path_safe(&fields.author),
+                    Field::AuthorSort => path_safe(&fields.author_sort),
+                    Field::Series => path_safe(&fields.series),
+                    Field::SeriesIndex => path_safe(&fields.series_index),
+                    Field::Slug => fields.slug.clone(),
+                    Field::Index => section.map_or(String::new(), |(index, ..)| index.to_string()),
+                    Field::Section => section.map_or(String::new(), |(_, slug, _)| slug.into()),
+                    Field::SectionId => section.map_or(String::new(), |(.., id)| id.into()),
+                };
+                if !value.is_empty() && value.len() < *width {
+                    out.push_str(&"0".repeat(width - value.len()));
+                }
+                out.push_str(&value);
+            }
+        }
+    }
+    out.trim().to_string()
+}
+
+/// Replaces path separators and the characters Windows rejects in names,
+/// and trims the dots and spaces it drops from their ends.
+fn path_safe(value: &str) -> String {
+    let value: String = value
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .map(|ch| match ch {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            ch if ch.is_control() => '_',
+            ch => ch,
+        })
+        .collect();
+    value.trim_matches(['.', ' ']).to_string()
+}
+
+fn parse_component(component: &str) -> Result<Vec<Piece>, String> {
+    let mut pieces = Vec::new();
+    let mut rest = component;
+    while let Some(open) = rest.find('{') {
+        if open > 0 {
+            pieces.push(Piece::Text(rest[..open].to_string()));
+        }
+        let Some(close) = rest[open..].find('}') else {
+            return Err(format!("unclosed {{ in {component:?}"));
+        };
+        let placeholder = &rest[open + 1..open + close];
+        let (name, width) = match placeholder.split_once(':') {
+            Some((name, width)) => {
+                let width = width
+                    .parse::<usize>()
+                    .map_err(|_| format!("invalid width in {{{placeholder}}}"))?;
+                (name, width)
+            }
+            None => (placeholder, 0),
+        };
+        let field = Field::parse(name).ok_or_else(|| format!("unknown placeholder {{{name}}}"))?;
+        if width > 0 && !field.is_number() {
+            return Err(format!("{{{name}}} is not a number and takes no width"));
+        }
+        pieces.push(Piece::Field(field, width));
+        rest = &rest[open + close + 1..];
+    }
+    if rest.contains('}') {
+        return Err(format!("unmatched }} in {component:?}"));
+    }
+    if !rest.is_empty() {
+        pieces.push(Piece::Text(rest.to_string()));
+    }
+    Ok(pieces)
+}
+
+fn uses_section(pieces: &[Piece]) -> bool {
+    pieces
+        .iter()
+        .any(|piece| matches!(piece, Piece::Field(field, _) if field.is_section()))
+}
+
+impl FromStr for OutputTemplate {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let trimmed = source.trim();
+        if trimmed.starts_with('/') || trimmed.starts_with('\\') {
+            return Err("the template must be relative to the output directory".to_string());
+        }
+        let path = trimmed.strip_suffix(".md").unwrap_or(trimmed);
+        let mut components = Vec::new();
+        for component in path.split(['/', '\\']) {
+            if component.is_empty() || component == "." || component == ".." {
+                return Err(format!("invalid path component {component:?} in template"));
+            }
+            components.push(parse_component(component)?);
+        }
+        let section = components.last().filter(|last| uses_section(last)).cloned();
+        if section.is_some() {
+            components.pop();
+        }
+        if components.iter().any(|component| uses_section(component)) {
+            return Err(
+                "{index}, {section} and {section_id} may only appear in the file name".to_string(),
+            );
+        }
+        Ok(Self {
+            source: source.to_string(),
+            book: components,
+            section,
+        })
+    }
+}
+
+impl fmt::Display for OutputTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
@@ -0,0 +1,286 @@
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use unicode_normalization::UnicodeNormalization;
+
+use crate::output::OutputSink;
+use crate::{
+    ConvertError, ConvertOptions, Newline, OutputProfile, Result, TextNormalization, large_print,
+    speech,
+};
+
+const UTF8_BOM: &str = "\u{feff}";
+
+/// Writes text outputs with the configured line endings and byte order mark.
+/// Markdown is also written in the configured encoding, counting characters
+/// the encoding cannot represent. Those are written as `?`, one per
+/// character, so character offsets into the output stay valid. Markdown is
+/// normalized to the configured Unicode form before anything else. Under the TTS
+/// profile, markdown is turned into speech text first; under the large-print
+/// profile, into an HTML page written next to where the markdown would go.
+pub(crate) struct TextWriter<'a> {
+    /// WHATWG encoding label; `None` writes UTF-8.
+    encoding: Option<&'a str>,
+    newline: Newline,
+    bom: bool,
+    normalization: TextNormalization,
+    collapse_spaces: bool,
+    profile: OutputProfile,
+    spell_out_numbers: bool,
+    large_print_font_size: u16,
+    pub(crate) unmappable: Cell<usize>,
+    /// Markdown files written, in order.
+    pub(crate) written: RefCell<Vec<PathBuf>>,
+    pub(crate) files: Arc<dyn OutputSink>,
+}
+
+impl<'a> TextWriter<'a> {
+    pub(crate) fn new(options: &'a ConvertOptions) -> Self {
+        Self {
+            encoding: options.output_encoding.as_deref(),
+            newline: options.newline,
+            bom: options.bom,
+            normalization: options.text_normalization,
+            collapse_spaces: options.collapse_spaces,
+            profile: options.profile,
+            spell_out_numbers: options.spell_out_numbers,
+            large_print_font_size: options.large_print_font_size,
+            unmappable: Cell::new(0),
+            written: RefCell::new(Vec::new()),
+            files: options.output_sink.clone(),
+        }
+    }
+
+    /// Markdown, in the output encoding. A BOM is only written for UTF-8.
+    pub(crate) fn write(&self, path: &Path, text: &str) -> Result<()> {
+        let text = normalize_text(text, self.normalization, self.collapse_spaces);
+        let text = match self.profile {
+            OutputProfile::Markdown => text,
+            OutputProfile::Tts => Cow::Owned(speech::speech_text(&text, self.spell_out_numbers)),
+            OutputProfile::LargePrint => Cow::Owned(large_print::large_print_page(
+                &text,
+                self.large_print_font_size,
+            )),
+        };
+        let path = self.output_path(path);
+        let text = self.line_endings(&text);
+        let bytes = match self.encoding {
+            None => self.with_bom(&text).into_owned().into_bytes(),
+            Some(label) => {
+                let (bytes, unmappable) = encode(&text, label)?;
+                self.unmappable.set(self.unmappable.get() + unmappable);
+                bytes
+            }
+        };
+        self.files
+            .write(&path, &bytes)
+            .map_err(|err| ConvertError::write_failed(&path, err))?;
+        self.written.borrow_mut().push(path);
+        Ok(())
+    }
+
+    /// Where markdown meant for `path` is written under the profile.
+    pub(crate) fn output_path(&self, path: &Path) -> PathBuf {
+        match self.profile {
+            OutputProfile::LargePrint => path.with_extension(output_extension(self.profile)),
+            _ => path.to_path_buf(),
+        }
+    }
+
+    /// Stylesheets and JSON sidecars, which stay UTF-8.
+    pub(crate) fn write_utf8(&self, path: &Path, text: &str) -> Result<()> {
+        let text = self.line_endings(text);
+        self.files
+            .write(path, self.with_bom(&text).as_bytes())
+            .map_err(|err| ConvertError::write_failed(path, err))?;
+        Ok(())
+    }
+
+    fn line_endings<'t>(&self, text: &'t str) -> Cow<'t, str> {
+        match self.newline {
+            Newline::Lf => Cow::Borrowed(text),
+            Newline::Crlf => Cow::Owned(text.replace("\r\n", "\n").replace('\n', "\r\n")),
+        }
+    }
+
+    fn with_bom<'t>(&self, text: &'t str) -> Cow<'t, str> {
+        let text = text.strip_prefix(UTF8_BOM).unwrap_or(text);
+        if self.bom {
+            Cow::Owned(format!("{UTF8_BOM}{text}"))
+        } else {
+            Cow::Borrowed(text)
+        }
+    }
+
+    /// Name of the encoding for messages.
+    pub(crate) fn encoding_name(&self) -> &str {
+        self.encoding.unwrap_or("UTF-8")
+    }
+}
+
+/// `text` in `form`, with fixed-width spaces inside lines made ordinary when
+/// `collapse_spaces` is set. Those starting a line are indentation and stay
+/// in either case, since ordinary spaces there could turn the line into a
+/// code block.
+fn normalize_text(text: &str, form: TextNormalization, collapse_spaces: bool) -> Cow<'_, str> {
+    let text = match form {
+        TextNormalization::None => Cow::Borrowed(text),
+        TextNormalization::Nfc if unicode_normalization::is_nfc(text) => Cow::Borrowed(text),
+        TextNormalization::Nfc => Cow::Owned(text.nfc().collect()),
+        TextNormalization::Nfkc if unicode_normalization::is_nfkc(text) => Cow::Borrowed(text),
+        // NFKC makes no-break spaces ordinary; indentation made of them is kept.
+        TextNormalization::Nfkc => Cow::Owned(
+            text.split_inclusive('\n')
+                .map(|line| {
+                    let body = line.trim_start_matches(is_fixed_width_space);
+                    let indent = &line[..line.len() - body.len()];
+                    indent.chars().chain(body.nfkc()).collect::<String>()
+                })
+                .collect(),
+        ),
+    };
+    if !collapse_spaces || !text.chars().any(is_fixed_width_space) {
+        return text;
+    }
+    let mut out = String::with_capacity(text.len());
+    for line in text.split_inclusive('\n') {
+        let body = line.trim_start_matches(is_fixed_width_space);
+        out.push_str(&line[..line.len() - body.len()]);
+        // A run with a fixed-width space becomes one space; runs of ordinary
+        // spaces stay, as two before a newline are a hard line break.
+        let mut run = String::new();
+        for ch in body.chars() {
+            if ch == ' ' || is_fixed_width_space(ch) {
+                run.push(ch);
+                continue;
+            }
+            push_space_run(&mut out, &mut run);
+            out.push(ch);
+        }
+        push_space_run(&mut out, &mut run);
+    }
+    Cow::Owned(out)
+}
+
+fn push_space_run(out: &mut String, run: &mut String) {
+    if run.chars().any(is_fixed_width_space) {
+        out.push(' ');
+    } else {
+        out.push_str(run);
+    }
+    run.clear();
+}
+
+/// No-break, typographic fixed-width and narrow spaces; not the ideographic
+/// space, which belongs to CJK text.
+fn is_fixed_width_space(ch: char) -> bool {
+    matches!(
+        ch,
+        '\u{a0}' | '\u{2000}'..='\u{200a}' | '\u{202f}' | '\u{205f}'
+    )
+}
+
+/// Extension of the files a [`TextWriter`] writes markdown as.
+pub(crate) fn output_extension(profile: OutputProfile) -> &'static str {
+    match profile {
+        OutputProfile::LargePrint => "html",
+        OutputProfile::Markdown | OutputProfile::Tts => "md",
+    }
+}
+
+/// Reads back a file written by a [`TextWriter`] with this encoding.
+pub(crate) fn read_text(
+    files: &dyn OutputSink,
+    path: &Path,
+    encoding: Option<&str>,
+) -> Option<String> {
+    let bytes = files.read(path).ok()?;
+    match encoding {
+        None => String::from_utf8(bytes).ok(),
+        Some(label) => decode(&bytes, label),
+    }
+}
+
+/// Checks that `label` names an encoding this build can write.
+pub fn validate_encoding(label: &str) -> Result<(), String> {
+    if is_utf8(label) {
+        return Ok(());
+    }
+    #[cfg(feature = "output-encoding")]
+    {
+        match encoding_rs::Encoding::for_label(label.trim().as_bytes()) {
+            Some(_) => Ok(()),
+            None => Err(format!("unknown encoding {label}")),
+        }
+    }
+    #[cfg(not(feature = "output-encoding"))]
+    {
+        Err(format!(
+            "encoding {label} needs a build with the output-encoding feature"
+        ))
+    }
+}
+
+fn is_utf8(label: &str) -> bool {
+    matches!(
+        label.trim().to_ascii_lowercase().as_str(),
+        "utf-8" | "utf8" | "unicode-1-1-utf-8"
+    )
+}
+
+#[cfg(feature = "output-encoding")]
+fn encode(text: &str, label: &str) -> Result<(Vec<u8>, usize)> {
+    use encoding_rs::EncoderResult;
+
+    let encoding = encoding_rs::Encoding::for_label(label.trim().as_bytes())
+        .ok_or_else(|| ConvertError::UnsupportedEncoding(format!("unknown encoding {label}")))?
+        .output_encoding();
+    if encoding == encoding_rs::UTF_8 {
+        return Ok((text.as_bytes().to_vec(), 0));
+    }
+    let mut encoder = encoding.new_encoder();
+    let mut out = Vec::with_capacity(text.len());
+    let mut unmappable = 0usize;
+    let mut remaining = text;
+    loop {
+        if let Some(needed) =
+            encoder.max_buffer_length_from_utf8_without_replacement(remaining.len())
+        {
+            out.reserve(needed);
+        }
+        let (result, read) =
+            encoder.encode_from_utf8_to_vec_without_replacement(remaining, &mut out, true);
+        remaining = &remaining[read..];
+        match result {
+            EncoderResult::InputEmpty => break,
+            EncoderResult::OutputFull => {}
+            EncoderResult::Unmappable(_) => {
+                unmappable += 1;
+                // Through the encoder, so stateful encodings stay consistent.
+                out.reserve(8);
+                let _ = encoder.encode_from_utf8_to_vec_without_replacement("?", &mut out, false);
+            }
+        }
+    }
+    Ok((out, unmappable))
+}
+
+#[cfg(not(feature = "output-encoding"))]
+fn encode(text: &str, label: &str) -> Result<(Vec<u8>, usize)> {
+    validate_encoding(label).map_err(ConvertError::UnsupportedEncoding)?;
+    Ok((text.as_bytes().to_vec(), 0))
+}
+
+#[cfg(feature = "output-encoding")]
+fn decode(bytes: &[u8], label: &str) -> Option<String> {
+    let encoding = encoding_rs::Encoding::for_label(label.trim().as_bytes())?.output_encoding();
+    let (text, _) = encoding.decode_without_bom_handling(bytes);
+    Some(text.into_owned())
+}
+
+#[cfg(not(feature = "output-encoding"))]
+fn decode(bytes: &[u8], _label: &str) -> Option<String> {
+    String::from_utf8(bytes.to_vec()).ok()
+}
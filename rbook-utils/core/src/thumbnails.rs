@@ -0,0 +1,80 @@
+use image::{GenericImageView, ImageFormat};
+use rbook::Epub;
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::path::Path;
+
+use crate::output::OutputSink;
+use crate::{MARKDOWN_LINK_RE, SectionRecord};
+
+/// Images smaller than this on either side are treated as ornaments, dingbats
+/// or drop caps rather than chapter art.
+const MIN_THUMBNAIL_SOURCE_EDGE: u32 = 64;
+/// Rules and banners are skipped even when they are large.
+const MAX_THUMBNAIL_ASPECT: f32 = 4.0;
+
+/// Writes a resized copy of the first meaningful image of every section into
+/// `thumbs_root` and returns the link to it keyed by section id.
+pub(crate) fn generate_chapter_thumbnails(
+    epub: &Epub,
+    sections: &[SectionRecord],
+    extracted_images: &HashMap<String, String>,
+    thumbs_root: &Path,
+    thumb_link_prefix: &str,
+    max_edge: u32,
+    files: &dyn OutputSink,
+    warn: &mut dyn FnMut(String),
+) -> HashMap<String, String> {
+    let href_by_link: HashMap<&str, &str> = extracted_images
+        .iter()
+        .map(|(href, link)| (link.as_str(), href.as_str()))
+        .collect();
+    let mut thumbnails = HashMap::new();
+    for section in sections {
+        let candidates = MARKDOWN_LINK_RE
+            .captures_iter(&section.text)
+            .filter(|caps| &caps[1] == "!")
+            .filter_map(|caps| href_by_link.get(caps[3].trim()).copied());
+        for href in candidates {
+            let Ok(bytes) = epub.read_resource_bytes(href) else {
+                continue;
+            };
+            let Ok(image) = image::load_from_memory(&bytes) else {
+                continue;
+            };
+            let (width, height) = image.dimensions();
+            if width.min(height) < MIN_THUMBNAIL_SOURCE_EDGE {
+                continue;
+            }
+            let aspect = width.max(height) as f32 / width.min(height) as f32;
+            if aspect > MAX_THUMBNAIL_ASPECT {
+                continue;
+            }
+            let file_name = format!("{}.png", section.section_id);
+            let thumb = if width.max(height) > max_edge {
+                image.thumbnail(max_edge, max_edge)
+            } else {
+                image
+            };
+            let mut png = Vec::new();
+            let written = thumb
+                .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+                .map_err(std::io::Error::other)
+                .and_then(|_| files.write(&thumbs_root.join(&file_name), &png));
+            match written {
+                Ok(()) => {
+                    thumbnails.insert(
+                        section.section_id.clone(),
+                        format!("{thumb_link_prefix}/{file_name}"),
+                    );
+                }
+                Err(err) => warn(format!(
+                    "Failed to write thumbnail for section {}: {err}",
+                    section.section_id
+                )),
+            }
+            break;
+        }
+    }
+    thumbnails
+}
@@ -0,0 +1,259 @@
+use kuchiki::NodeRef;
+use rbook::prelude::{MetaEntry, Metadata};
+use rbook::{Ebook, Epub};
+use serde::Deserialize;
+use std::path::Path;
+
+use crate::content_cache::ContentCache;
+use crate::provenance::PARAGRAPH_TAGS;
+use crate::{
+    ConvertError, ConvertOptions, Result, SectionRecord, element_name, load_content,
+    normalize_space, partial_body_nodes, text_output,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum TranslationExport {
+    Off,
+    /// `translation.tmx`, TMX 1.4 with one translation unit per sentence.
+    Tmx,
+    /// `translation.xlf`, XLIFF 1.2 with a group per section.
+    Xliff,
+}
+
+/// Abbreviations that end in a period without ending the sentence.
+const ABBREVIATIONS: &[&str] = &[
+    "mr", "mrs", "ms", "dr", "st", "prof", "rev", "gen", "col", "capt", "lt", "sgt", "jr", "sr",
+    "vs", "etc", "e.g", "i.e", "cf", "no", "vol", "ch", "fig", "p", "pp",
+];
+
+/// Writes a translation skeleton of the book: the source text of every
+/// section, split into paragraphs and sentences, with empty targets. The
+/// source language comes from the book's metadata (`und` without one); the
+/// target is `options.translation_target_language`, also `und` when unset.
+pub(crate) fn write_translation_export(
+    epub: &Epub,
+    book_dir: &Path,
+    book_slug: &str,
+    sections: &[SectionRecord],
+    spine_hrefs: &[String],
+    cache: &mut ContentCache,
+    options: &ConvertOptions,
+) -> Result<()> {
+    let format = options.translation_export;
+    if format == TranslationExport::Off {
+        return Ok(());
+    }
+    let mut segmented = Vec::new();
+    for section in sections {
+        let mut segments = Vec::new();
+        for spine_idx in section.spine_start..=section.spine_end {
+            let Some(href) = spine_hrefs.get(spine_idx) else {
+                continue;
+            };
+            let start = (spine_idx == section.spine_start)
+                .then_some(section.start_fragment.as_deref())
+                .flatten();
+            let end = (section.end_href.as_deref() == Some(href.as_str()))
+                .then_some(section.end_fragment.as_deref())
+                .flatten();
+            let Ok(content) = load_content(epub, href, cache) else {
+                continue;
+            };
+            let Some(nodes) = partial_body_nodes(content, start, end) else {
+                continue;
+            };
+            for node in nodes.iter().flat_map(|node| node.inclusive_descendants()) {
+                if !is_innermost_paragraph(&node) {
+                    continue;
+                }
+                segments.extend(split_sentences(&normalize_space(&node.text_contents())));
+            }
+        }
+        segmented.push((section, segments));
+    }
+
+    let language = epub.metadata().language();
+    let source = language
+        .map(|language| language.value().trim())
+        .filter(|language| !language.is_empty())
+        .unwrap_or("und");
+    let target = options
+        .translation_target_language
+        .as_deref()
+        .unwrap_or("und");
+    let (file_name, document) = match format {
+        TranslationExport::Tmx => ("translation.tmx", tmx(source, target, &segmented)),
+        _ => (
+            "translation.xlf",
+            xliff(
+                book_slug,
+                source,
+                options.translation_target_language.as_deref(),
+                &segmented,
+            ),
+        ),
+    };
+    options
+        .output_sink
+        .create_dir_all(book_dir)
+        .map_err(|err| ConvertError::write_failed(book_dir, err))?;
+    text_output::TextWriter::new(options).write_utf8(&book_dir.join(file_name), &document)?;
+    Ok(())
+}
+
+fn tmx(source: &str, target: &str, sections: &[(&SectionRecord, Vec<String>)]) -> String {
+    let mut out =
+        String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<tmx version=\"1.4\">\n");
+    out.push_str(&format!(
+        "  <header creationtool=\"rbook-utils\" creationtoolversion=\"{}\" segtype=\"sentence\" \
+o-tmf=\"rbook-utils\" adminlang=\"en\" srclang=\"{}\" datatype=\"plaintext\"/>\n  <body>\n",
+        env!("CARGO_PKG_VERSION"),
+        escape_xml(source)
+    ));
+    for (section, segments) in sections {
+        for (idx, segment) in segments.iter().enumerate() {
+            out.push_str(&format!(
+                "    <tu tuid=\"{id}-{n}\">\n      <prop type=\"x-section\">{id}</prop>\n      \
+<prop type=\"x-section-title\">{title}</prop>\n      \
+<tuv xml:lang=\"{source}\"><seg>{text}</seg></tuv>\n      \
+<tuv xml:lang=\"{target}\"><seg></seg></tuv>\n    </tu>\n",
+                id = escape_xml(&section.section_id),
+                n = idx + 1,
+                title = escape_xml(&section.title),
+                source = escape_xml(source),
+                target = escape_xml(target),
+                text = escape_xml(segment),
+            ));
+        }
+    }
+    out.push_str("  </body>\n</tmx>\n");
+    out
+}
+
+fn xliff(
+    book_slug: &str,
+    source: &str,
+    target: Option<&str>,
+    sections: &[(&SectionRecord, Vec<String>)],
+) -> String {
+    let mut out = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+<xliff version=\"1.2\" xmlns=\"urn:oasis:names:tc:xliff:document:1.2\">\n",
+    );
+    let target = target
+        .map(|target| format!(" target-language=\"{}\"", escape_xml(target)))
+        .unwrap_or_default();
+    out.push_str(&format!(
+        "  <file original=\"{}\" source-language=\"{}\"{target} datatype=\"plaintext\">\n    <body>\n",
+        escape_xml(book_slug),
+        escape_xml(source)
+    ));
+    for (section, segments) in sections {
+        out.push_str(&format!(
+            "      <group id=\"{}\" resname=\"{}\">\n",
+            escape_xml(&section.section_id),
+            escape_xml(&section.title)
+        ));
+        for (idx, segment) in segments.iter().enumerate() {
+            out.push_str(&format!(
+                "        <trans-unit id=\"{}-{}\">\n          <source>{}</source>\n          \
+<target></target>\n        </trans-unit>\n",
+                escape_xml(&section.section_id),
+                idx + 1,
+                escape_xml(segment)
+            ));
+        }
+        out.push_str("      </group>\n");
+    }
+    out.push_str("    </body>\n  </file>\n</xliff>\n");
+    out
+}
+
+/// A paragraph-level element with no paragraph-level element inside, so each
+/// piece of text is segmented once. Leaf `<div>`s count, since some books
+/// use them for paragraphs.
+fn is_innermost_paragraph(node: &NodeRef) -> bool {
+    let is_paragraph = |node: &NodeRef| {
+        element_name(node).is_some_and(|name| name == "div" || PARAGRAPH_TAGS.contains(&name))
+    };
+    is_paragraph(node) && !node.descendants().any(|child| is_paragraph(&child))
+}
+
+/// Splits a paragraph into sentences: after `.`, `!`, `?` or `…` (and any
+/// closing quotes or brackets) followed by a space and a capital, digit or
+/// opening quote, unless the period ends a known abbreviation or an initial;
+/// and after CJK full stops, which take no space.
+fn split_sentences(paragraph: &str) -> Vec<String> {
+    let chars: Vec<char> = paragraph.chars().collect();
+    let mut sentences = Vec::new();
+    let mut start = 0usize;
+    let mut idx = 0usize;
+    while idx < chars.len() {
+        let ch = chars[idx];
+        if matches!(ch, '。' | '！' | '？') {
+            let mut end = idx + 1;
+            while end < chars.len() && matches!(chars[end], '」' | '』' | '）' | '”') {
+                end += 1;
+            }
+            push_sentence(&mut sentences, &chars[start..end]);
+            start = end;
+            idx = end;
+            continue;
+        }
+        if matches!(ch, '.' | '!' | '?' | '…') {
+            let mut end = idx + 1;
+            while end < chars.len() && matches!(chars[end], '"' | '\'' | '”' | '’' | ')' | ']')
+            {
+                end += 1;
+            }
+            let next = chars.get(end + 1);
+            let starts_sentence = chars.get(end) == Some(&' ')
+                && next.is_some_and(|next| {
+                    next.is_uppercase()
+                        || next.is_ascii_digit()
+                        || matches!(next, '"' | '“' | '‘' | '(' | '[')
+                });
+            if starts_sentence && !(ch == '.' && ends_abbreviation(&chars[start..idx])) {
+                push_sentence(&mut sentences, &chars[start..end]);
+                start = end + 1;
+                idx = start;
+                continue;
+            }
+        }
+        idx += 1;
+    }
+    push_sentence(&mut sentences, &chars[start..]);
+    sentences
+}
+
+fn ends_abbreviation(before: &[char]) -> bool {
+    let word: String = before
+        .iter()
+        .rev()
+        .take_while(|ch| !ch.is_whitespace())
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+        .collect();
+    let word = word.trim_start_matches(['(', '"', '“', '‘']).to_lowercase();
+    // A single letter is an initial, as in "J. R. R. Tolkien".
+    word.chars().count() == 1 || ABBREVIATIONS.contains(&word.as_str())
+}
+
+fn push_sentence(sentences: &mut Vec<String>, chars: &[char]) {
+    let sentence: String = chars.iter().collect();
+    let sentence = sentence.trim();
+    if !sentence.is_empty() {
+        sentences.push(sentence.to_string());
+    }
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
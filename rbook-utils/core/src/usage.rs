@@ -0,0 +1,158 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{ConversionSummary, ConvertError, ConvertOptions, Result};
+
+/// Runs kept in [`UsageStats::recent_runs`]; older ones only count towards
+/// the totals.
+const RECENT_RUNS: usize = 500;
+
+/// Conversion statistics accumulated across runs in a local file (see
+/// `ConvertOptions::usage_stats`), for watching how the heuristics behave as
+/// books and crate versions change. Nothing is sent anywhere.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UsageStats {
+    pub runs: u64,
+    pub books_converted: u64,
+    pub books_failed: u64,
+    pub books_skipped: u64,
+    pub sections: u64,
+    pub words: u64,
+    /// Books whose sections came from heading fallback.
+    pub heading_fallback_books: u64,
+    pub degenerate_toc_books: u64,
+    /// Failed books by [`ConvertError::kind`](crate::ConvertError::kind);
+    /// `other` for failures without an error, such as a missing cover.
+    pub failures: BTreeMap<String, u64>,
+    /// Warning and error diagnostics by `W001`-style code.
+    pub warnings: BTreeMap<String, u64>,
+    /// The latest runs, oldest first.
+    pub recent_runs: Vec<RunStats>,
+}
+
+/// One run recorded in [`UsageStats`].
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RunStats {
+    /// Seconds since the Unix epoch when the run finished.
+    pub finished_at: u64,
+    /// Version of this crate that did the conversion.
+    pub version: String,
+    pub duration_ms: u64,
+    pub books: u64,
+    pub converted: u64,
+    pub failed: u64,
+    pub skipped: u64,
+    pub heading_fallback_books: u64,
+    pub failures: BTreeMap<String, u64>,
+}
+
+impl UsageStats {
+    /// Reads the stats file at `path`, or starts empty when there is none.
+    /// Files ending in `.toml` are TOML, anything else JSON.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err.into()),
+        };
+        if is_toml(path) {
+            toml::from_str(&text).map_err(|err| std::io::Error::other(err).into())
+        } else {
+            Ok(serde_json::from_str(&text)?)
+        }
+    }
+
+    /// Writes the stats to `path`, replacing it in one step so an
+    /// interrupted run never leaves a truncated file.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let text = if is_toml(path) {
+            toml::to_string_pretty(self)?
+        } else {
+            serde_json::to_string_pretty(self)? + "\n"
+        };
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            fs::create_dir_all(parent).map_err(|err| ConvertError::write_failed(parent, err))?;
+        }
+        let mut staging = path.as_os_str().to_owned();
+        staging.push(format!(".{}.tmp", std::process::id()));
+        fs::write(&staging, text)
+            .map_err(|err| ConvertError::write_failed(Path::new(&staging), err))?;
+        fs::rename(&staging, path).map_err(|err| ConvertError::write_failed(path, err))?;
+        Ok(())
+    }
+
+    /// Adds a finished run that took `duration`.
+    pub fn record(&mut self, summary: &ConversionSummary, duration: Duration) {
+        let batch = summary.report();
+        let mut failures: BTreeMap<String, u64> = BTreeMap::new();
+        for book in &summary.books {
+            if book.output_path.is_none() && book.skipped.is_none() {
+                *failures
+                    .entry(book.error_kind.unwrap_or("other").to_string())
+                    .or_default() += 1;
+            }
+        }
+
+        self.runs += 1;
+        self.books_converted += batch.converted as u64;
+        self.books_failed += batch.failed as u64;
+        self.books_skipped += batch.skipped as u64;
+        self.sections += batch.sections as u64;
+        self.words += batch.words as u64;
+        self.heading_fallback_books += batch.heading_fallback_books as u64;
+        self.degenerate_toc_books += batch.degenerate_toc_books as u64;
+        for (kind, count) in &failures {
+            *self.failures.entry(kind.clone()).or_default() += count;
+        }
+        for (code, count) in &batch.warnings_by_code {
+            *self.warnings.entry(code.to_string()).or_default() += *count as u64;
+        }
+
+        self.recent_runs.push(RunStats {
+            finished_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            duration_ms: duration.as_millis() as u64,
+            books: batch.books as u64,
+            converted: batch.converted as u64,
+            failed: batch.failed as u64,
+            skipped: batch.skipped as u64,
+            heading_fallback_books: batch.heading_fallback_books as u64,
+            failures,
+        });
+        let excess = self.recent_runs.len().saturating_sub(RECENT_RUNS);
+        self.recent_runs.drain(..excess);
+    }
+}
+
+/// Adds a run to `options.usage_stats`, if set. Failing to update the file
+/// is logged rather than failing the conversion.
+pub(crate) fn record_usage(
+    options: &ConvertOptions,
+    summary: &ConversionSummary,
+    started: Instant,
+) {
+    let Some(path) = &options.usage_stats else {
+        return;
+    };
+    let updated = UsageStats::load(path).and_then(|mut stats| {
+        stats.record(summary, started.elapsed());
+        stats.save(path)
+    });
+    if let Err(err) = updated {
+        tracing::warn!(path = %path.display(), "could not update usage stats: {}", err.full_message());
+    }
+}
+
+fn is_toml(path: &Path) -> bool {
+    path.extension().and_then(|ext| ext.to_str()) == Some("toml")
+}
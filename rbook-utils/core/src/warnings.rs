@@ -0,0 +1,149 @@
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::Diagnostic;
+
+/// Stable identifier of a kind of warning, so pipelines can filter or gate on
+/// warnings without matching their text. Codes are never reused or renumbered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum WarningCode {
+    /// The TOC was unusable for splitting and chapters come from headings.
+    HeadingFallbackUsed,
+    /// Heading fallback was requested but the TOC is good enough.
+    HeadingFallbackNotNeeded,
+    /// Heading fallback was wanted but no confident chapter starts were found.
+    HeadingFallbackLowConfidence,
+    UnresolvedLinks,
+    CoverMissing,
+    CoverUnreadable,
+    CoverNotReencoded,
+    FontCopyFailed,
+    SvgExportFailed,
+    ThumbnailFailed,
+    /// Images referenced by the content that the book does not contain.
+    MissingImages,
+    /// Stylesheets linked by the content that the book does not contain.
+    MissingStylesheets,
+    /// Characters the output encoding cannot represent were written as `?`.
+    UnmappableCharacters,
+    /// Sections listed in the conversion plan that the book no longer yields.
+    PlanSectionsMissing,
+    /// A spine document could not be read and was left out of the output.
+    UnreadableSpineItem,
+    /// An option was set that a streaming conversion does not apply.
+    StreamingIgnoresOption,
+}
+
+impl WarningCode {
+    pub const ALL: &'static [WarningCode] = &[
+        WarningCode::HeadingFallbackUsed,
+        WarningCode::HeadingFallbackNotNeeded,
+        WarningCode::HeadingFallbackLowConfidence,
+        WarningCode::UnresolvedLinks,
+        WarningCode::CoverMissing,
+        WarningCode::CoverUnreadable,
+        WarningCode::CoverNotReencoded,
+        WarningCode::FontCopyFailed,
+        WarningCode::SvgExportFailed,
+        WarningCode::ThumbnailFailed,
+        WarningCode::MissingImages,
+        WarningCode::MissingStylesheets,
+        WarningCode::UnmappableCharacters,
+        WarningCode::PlanSectionsMissing,
+        WarningCode::UnreadableSpineItem,
+        WarningCode::StreamingIgnoresOption,
+    ];
+
+    /// `W001`-style code.
+    pub fn code(self) -> &'static str {
+        match self {
+            WarningCode::HeadingFallbackUsed => "W001",
+            WarningCode::HeadingFallbackNotNeeded => "W002",
+            WarningCode::HeadingFallbackLowConfidence => "W003",
+            WarningCode::UnresolvedLinks => "W004",
+            WarningCode::CoverMissing => "W005",
+            WarningCode::CoverUnreadable => "W006",
+            WarningCode::CoverNotReencoded => "W007",
+            WarningCode::FontCopyFailed => "W008",
+            WarningCode::SvgExportFailed => "W009",
+            WarningCode::ThumbnailFailed => "W010",
+            WarningCode::MissingImages => "W011",
+            WarningCode::MissingStylesheets => "W012",
+            WarningCode::UnmappableCharacters => "W013",
+            WarningCode::PlanSectionsMissing => "W014",
+            WarningCode::UnreadableSpineItem => "W015",
+            WarningCode::StreamingIgnoresOption => "W016",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            WarningCode::HeadingFallbackUsed => "HeadingFallbackUsed",
+            WarningCode::HeadingFallbackNotNeeded => "HeadingFallbackNotNeeded",
+            WarningCode::HeadingFallbackLowConfidence => "HeadingFallbackLowConfidence",
+            WarningCode::UnresolvedLinks => "UnresolvedLinks",
+            WarningCode::CoverMissing => "CoverMissing",
+            WarningCode::CoverUnreadable => "CoverUnreadable",
+            WarningCode::CoverNotReencoded => "CoverNotReencoded",
+            WarningCode::FontCopyFailed => "FontCopyFailed",
+            WarningCode::SvgExportFailed => "SvgExportFailed",
+            WarningCode::ThumbnailFailed => "ThumbnailFailed",
+            WarningCode::MissingImages => "MissingImages",
+            WarningCode::MissingStylesheets => "MissingStylesheets",
+            WarningCode::UnmappableCharacters => "UnmappableCharacters",
+            WarningCode::PlanSectionsMissing => "PlanSectionsMissing",
+            WarningCode::UnreadableSpineItem => "UnreadableSpineItem",
+            WarningCode::StreamingIgnoresOption => "StreamingIgnoresOption",
+        }
+    }
+}
+
+impl fmt::Display for WarningCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+/// Accepts the code (`W004`) or the name (`UnresolvedLinks`), ignoring case.
+impl FromStr for WarningCode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        WarningCode::ALL
+            .iter()
+            .copied()
+            .find(|code| {
+                code.code().eq_ignore_ascii_case(value) || code.name().eq_ignore_ascii_case(value)
+            })
+            .ok_or_else(|| format!("unknown warning code {value}"))
+    }
+}
+
+/// Callback receiving the warning and error diagnostics of each book (with
+/// the EPUB path) as soon as that book is done, before [`convert_all`]
+/// returns. Called from worker threads when books are converted in parallel.
+///
+/// [`convert_all`]: crate::convert_all
+#[derive(Clone)]
+pub struct WarningHook(Arc<WarningFn>);
+
+type WarningFn = dyn Fn(&Path, &Diagnostic) + Send + Sync;
+
+impl WarningHook {
+    pub fn new(hook: impl Fn(&Path, &Diagnostic) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(hook))
+    }
+
+    pub(crate) fn emit(&self, path: &Path, diagnostic: &Diagnostic) {
+        (self.0)(path, diagnostic);
+    }
+}
+
+impl fmt::Debug for WarningHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("WarningHook")
+    }
+}
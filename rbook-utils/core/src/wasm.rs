@@ -0,0 +1,46 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde_json::{Value, json};
+use std::io::Cursor;
+use std::path::PathBuf;
+use wasm_bindgen::prelude::*;
+
+use crate::json_api::{apply_json_options, diagnostic_json};
+use crate::{ConvertOptions, convert_epub_to_sections};
+
+/// Converts the EPUB in `bytes` (e.g. a dropped file's `arrayBuffer()`).
+/// `options` is a JSON object of conversion options by their Rust field
+/// names, such as `{"markdown_mode": "rich", "min_section_words": 50}`.
+///
+/// Returns JSON: the [`Book`](crate::Book) with its metadata, `chapters`,
+/// `toc` and `resources`, plus `images` (`path` as linked from the markdown
+/// and base64 `data`) and `diagnostics`.
+///
+/// Build with `cargo rustc -p rbook-utils-core --lib --crate-type cdylib
+/// --release --target wasm32-unknown-unknown --features wasm` and run
+/// `wasm-bindgen` on the result; the conversion never touches a
+/// filesystem, clock or thread.
+#[wasm_bindgen(js_name = convertEpub)]
+pub fn convert_epub_bytes(bytes: Vec<u8>, options: &str) -> Result<String, JsError> {
+    let mut convert_options = ConvertOptions::new(PathBuf::new(), PathBuf::new());
+    if !options.trim().is_empty() {
+        let options: Value = serde_json::from_str(options)?;
+        apply_json_options(&mut convert_options, &options).map_err(|err| JsError::new(&err))?;
+    }
+    let book = convert_epub_to_sections(Cursor::new(bytes), &convert_options)
+        .map_err(|err| JsError::new(&err.full_message()))?;
+    let mut output = serde_json::to_value(&book.book)?;
+    output["images"] = json!(
+        book.images
+            .iter()
+            .map(|(path, bytes)| json!({"path": path, "data": STANDARD.encode(bytes)}))
+            .collect::<Vec<_>>()
+    );
+    output["diagnostics"] = json!(
+        book.diagnostics
+            .iter()
+            .map(diagnostic_json)
+            .collect::<Vec<_>>()
+    );
+    Ok(output.to_string())
+}
@@ -0,0 +1,102 @@
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::mpsc::{RecvTimeoutError, channel};
+use std::time::{Duration, Instant};
+
+use crate::incremental::IncrementalState;
+use crate::{
+    BookConversionResult, ConvertError, ConvertOptions, INCREMENTAL_STATE_FILE_NAME, Result,
+    convert_one, is_epub,
+};
+
+/// A book is converted once no event has touched it for this long, so a file
+/// still being copied in is not read half-written.
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// How often the loop wakes to check for settled books and cancellation.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Watches `options.input_dir` (recursively) and converts every EPUB that is
+/// created or modified there, passing each result to `on_book`. Books already
+/// present are left to a regular [`convert_all`](crate::convert_all) run.
+///
+/// Blocks until the options' [`CancelToken`](crate::CancelToken) is triggered,
+/// which ends the watch without error, or the watcher fails. With
+/// `options.lock_output`, each book is locked only while it is converted, so a
+/// scheduled batch can share the output directory; a book another run holds
+/// is retried once it settles again. With `options.incremental`, modified
+/// books whose contents did not change are skipped and each conversion is
+/// recorded as it finishes.
+pub fn watch_input_dir(
+    options: &ConvertOptions,
+    mut on_book: impl FnMut(&BookConversionResult),
+) -> Result<()> {
+    let watch_failed = |err: notify::Error| ConvertError::WatchFailed {
+        dir: options.input_dir.clone(),
+        source: err.into(),
+    };
+    let (events_tx, events_rx) = channel();
+    let mut watcher = notify::recommended_watcher(events_tx).map_err(watch_failed)?;
+    watcher
+        .watch(&options.input_dir, RecursiveMode::Recursive)
+        .map_err(watch_failed)?;
+    tracing::info!(dir = %options.input_dir.display(), "watching for new books");
+
+    let mut incremental = options.incremental.then(|| IncrementalState::load(options));
+    // Books with recent events, by when the last one arrived.
+    let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+    while !options.is_cancelled() {
+        match events_rx.recv_timeout(POLL_INTERVAL) {
+            Ok(Ok(event)) => {
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    for path in event.paths.into_iter().filter(|path| is_epub(path)) {
+                        pending.insert(path, Instant::now());
+                    }
+                }
+            }
+            Ok(Err(err)) => return Err(watch_failed(err)),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        let mut settled: Vec<PathBuf> = pending
+            .iter()
+            .filter(|(_, last_event)| last_event.elapsed() >= SETTLE_TIME)
+            .map(|(path, _)| path.clone())
+            .collect();
+        settled.sort();
+        for epub_path in settled {
+            pending.remove(&epub_path);
+            // Renamed away or deleted again before it settled.
+            if !epub_path.is_file() {
+                continue;
+            }
+            if incremental
+                .as_ref()
+                .is_some_and(|state| state.is_current(&epub_path))
+            {
+                tracing::info!(path = %epub_path.display(), "unchanged, not converted");
+                continue;
+            }
+            let result = convert_one(&epub_path, 0, 1, &options.locking_book());
+            if result.error_kind == Some("locked") {
+                tracing::info!(path = %epub_path.display(), "locked by another run, retrying");
+                pending.insert(epub_path, Instant::now());
+                continue;
+            }
+            if let Some(Err(err)) = incremental
+                .as_mut()
+                .map(|state| state.record_run(std::slice::from_ref(&result)))
+            {
+                tracing::warn!(
+                    "could not update {}: {}",
+                    INCREMENTAL_STATE_FILE_NAME,
+                    err.full_message()
+                );
+            }
+            on_book(&result);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,295 @@
+use once_cell::sync::Lazy;
+use rbook::Epub;
+use rbook::prelude::{MetaEntry, Metadata};
+use regex::Regex;
+use serde_json::json;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::SectionRecord;
+
+static EMAIL_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)\b[a-z0-9._%+-]+@[a-z0-9-]+(?:\.[a-z0-9-]+)*\.[a-z]{2,}\b")
+        .expect("valid email regex")
+});
+/// "Licensed to", "purchased by", "for the exclusive use of" and the like,
+/// with the name that follows up to punctuation, an email or a date.
+static STAMP_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"(?i)(?:\b(?:purchased|bought|licensed|sold|issued|prepared|registered|downloaded)\s+(?:by|to|for)|\bfor\s+the\s+(?:exclusive|personal|sole)\s+use\s+of)\s*:?\s*([^.;,()<>\[\]\n]*)",
+    )
+    .expect("valid stamp regex")
+});
+/// A stamp in a metadata value, through the end of its sentence.
+static STAMP_CLAUSE_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"(?i)(?:\b(?:purchased|bought|licensed|sold|issued|prepared|registered|downloaded)\s+(?:by|to|for)|\bfor\s+the\s+(?:exclusive|personal|sole)\s+use\s+of)\b[^;\n]*?(?:[.;](?:\s+|$)|$)",
+    )
+    .expect("valid stamp clause regex")
+});
+/// A stamp line in the text: the stamp opens it, optionally after "This
+/// ebook was", and the line is short.
+static STAMP_LINE_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"(?i)^[\s*_>#-]*(?:this\s+(?:e-?book|book|copy|file)\s+(?:was|is|has\s+been)\s+)?(?:purchased|bought|licensed|sold|issued|prepared|registered|downloaded|for\s+the\s+(?:exclusive|personal|sole)\s+use)\b",
+    )
+    .expect("valid stamp line regex")
+});
+static EMPTY_BRACKETS_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\s*(?:\(\s*\)|<\s*>|\[\s*\])").expect("valid brackets regex"));
+static BLANK_RUN_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\n[ \t]*(?:\n[ \t]*){2,}").expect("valid blank run regex"));
+static DATE_TAIL_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)\s+(?:on|at)\s+\d.*$|\s+\d{1,4}[-/]\d{1,2}[-/]\d{1,4}.*$")
+        .expect("valid date tail regex")
+});
+
+/// Metadata properties some retailers record the buyer or order under.
+const PERSONAL_PROPERTIES: &[&str] = &[
+    "buyer",
+    "purchaser",
+    "customer",
+    "licensee",
+    "watermark",
+    "transaction",
+];
+/// Longest line taken for a text stamp.
+const MAX_STAMP_LINE_CHARS: usize = 200;
+const REDACTED: &str = "[redacted]";
+
+/// What [`Watermarks`] removed from one book. Only where and how many are
+/// recorded, never the removed strings, so the report can be shared too.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct WatermarkReport {
+    /// Metadata fields that carried personalization.
+    pub(crate) metadata_fields: BTreeSet<String>,
+    pub(crate) stamp_lines: usize,
+    pub(crate) redactions: usize,
+    /// Titles of the sections with removals.
+    pub(crate) sections: BTreeSet<String>,
+}
+
+impl WatermarkReport {
+    pub(crate) fn removed(&self) -> usize {
+        self.metadata_fields.len() + self.stamp_lines + self.redactions
+    }
+
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        json!({
+            "metadata_fields": self.metadata_fields,
+            "stamp_lines_removed": self.stamp_lines,
+            "redactions": self.redactions,
+            "sections": self.sections,
+        })
+    }
+}
+
+/// Buyer names and emails a retailer stamped into a book, found in its
+/// metadata and in stamp lines such as "Licensed to Jane Doe
+/// (jane@example.com)", and scrubbed from everything written.
+///
+/// Stamp lines are dropped whole. Elsewhere emails and names of more than one
+/// word are replaced by `[redacted]`; a one-word name is too likely to be an
+/// ordinary word of the book and only goes with its stamp.
+pub(crate) struct Watermarks {
+    identities: RefCell<BTreeSet<String>>,
+    pub(crate) report: RefCell<WatermarkReport>,
+}
+
+impl Watermarks {
+    pub(crate) fn detect(epub: &Epub) -> Self {
+        let watermarks = Self {
+            identities: RefCell::new(BTreeSet::new()),
+            report: RefCell::new(WatermarkReport::default()),
+        };
+        for entry in epub.metadata().entries() {
+            let personal = is_personal(entry.property().as_str());
+            let values: Vec<(bool, String)> =
+                std::iter::once((personal, entry.value().to_string()))
+                    .chain(entry.refinements().map(|meta| {
+                        let personal = personal || is_personal(meta.property().as_str());
+                        (personal, meta.value().to_string())
+                    }))
+                    .collect();
+            for (personal, value) in values {
+                let value = value.trim();
+                if personal && !value.is_empty() && value.chars().count() <= 120 {
+                    watermarks.learn(value);
+                }
+                watermarks.learn_stamps(value);
+            }
+        }
+        watermarks
+    }
+
+    /// A metadata value for output, with stamps and known identities taken
+    /// out; `field` names it in the report when anything was.
+    pub(crate) fn scrub_metadata(&self, field: &str, value: &str) -> String {
+        self.learn_stamps(value);
+        // Emails first: their dots would end the stamp's sentence early.
+        let (redacted, count) = self.redact(value, "");
+        let scrubbed = STAMP_CLAUSE_RE.replace_all(&redacted, "");
+        if count == 0 && scrubbed == value {
+            return value.to_string();
+        }
+        self.report
+            .borrow_mut()
+            .metadata_fields
+            .insert(field.to_string());
+        EMPTY_BRACKETS_RE
+            .replace_all(&scrubbed, "")
+            .trim()
+            .trim_end_matches([',', ';', ':', '-'])
+            .trim()
+            .to_string()
+    }
+
+    /// Drops the properties retailers record the buyer under from `extra`
+    /// metadata and scrubs the rest.
+    pub(crate) fn scrub_extra(&self, extra: &mut BTreeMap<String, Vec<String>>) {
+        extra.retain(|property, _| {
+            let personal = is_personal(property);
+            if personal {
+                self.report
+                    .borrow_mut()
+                    .metadata_fields
+                    .insert(property.clone());
+            }
+            !personal
+        });
+        for (property, values) in extra.iter_mut() {
+            for value in values.iter_mut() {
+                *value = self.scrub_metadata(property, value);
+            }
+            values.retain(|value| !value.is_empty());
+        }
+        extra.retain(|_, values| !values.is_empty());
+    }
+
+    /// Learns the identities of every section's stamp lines first, so a name
+    /// stamped in the last chapter is also redacted from the first.
+    pub(crate) fn scrub_sections(&self, sections: &mut [SectionRecord]) {
+        for section in sections.iter() {
+            for line in section.text.lines().filter(|line| is_stamp_line(line)) {
+                self.learn_stamps(line);
+            }
+        }
+        for section in sections.iter_mut() {
+            let before = self.report.borrow().removed();
+            section.text = self.scrub_text(&section.text);
+            let (title, count) = self.redact(&section.title, REDACTED);
+            if count > 0 {
+                section.title = title;
+                self.report.borrow_mut().redactions += count;
+            }
+            if self.report.borrow().removed() > before {
+                let title = section.title.clone();
+                self.report.borrow_mut().sections.insert(title);
+            }
+        }
+    }
+
+    /// Markdown without stamp lines or known identities.
+    pub(crate) fn scrub_text(&self, text: &str) -> String {
+        let mut lines = Vec::new();
+        let mut stamp_lines = 0usize;
+        for line in text.lines() {
+            if is_stamp_line(line) || self.names_identity_only(line) {
+                self.learn_stamps(line);
+                stamp_lines += 1;
+            } else {
+                lines.push(line);
+            }
+        }
+        let mut text = lines.join("\n");
+        if stamp_lines > 0 {
+            text = BLANK_RUN_RE.replace_all(&text, "\n\n").trim().to_string();
+        }
+        let (text, redactions) = self.redact(&text, REDACTED);
+        let mut report = self.report.borrow_mut();
+        report.stamp_lines += stamp_lines;
+        report.redactions += redactions;
+        text
+    }
+
+    /// A short line that mentions an identity (as stamps without a stamp
+    /// phrase do: "Jane Doe <jane@example.com>") and little else.
+    fn names_identity_only(&self, line: &str) -> bool {
+        let line = line.trim();
+        if line.is_empty() || line.chars().count() > 120 {
+            return false;
+        }
+        let (redacted, count) = self.redact(line, REDACTED);
+        count > 0
+            && redacted
+                .replace(REDACTED, "")
+                .chars()
+                .all(|ch| !ch.is_alphanumeric())
+    }
+
+    fn learn_stamps(&self, value: &str) {
+        if !STAMP_RE.is_match(value) {
+            return;
+        }
+        for email in EMAIL_RE.find_iter(value) {
+            self.learn(email.as_str());
+        }
+        // Emails end the name, and their dots would cut it short.
+        let without_emails = EMAIL_RE.replace_all(value, ";");
+        for captures in STAMP_RE.captures_iter(&without_emails) {
+            let name = DATE_TAIL_RE.replace(captures[1].trim(), "");
+            self.learn(name.trim());
+        }
+    }
+
+    fn learn(&self, value: &str) {
+        let value = value.trim().trim_matches(|ch: char| !ch.is_alphanumeric());
+        let chars = value.chars().count();
+        if !(2..=80).contains(&chars) || !value.chars().any(char::is_alphabetic) {
+            return;
+        }
+        let mut identities = self.identities.borrow_mut();
+        for email in EMAIL_RE.find_iter(value) {
+            identities.insert(email.as_str().to_lowercase());
+        }
+        let name = EMAIL_RE.replace_all(value, "");
+        let name = name.trim();
+        if !name.is_empty() {
+            identities.insert(name.to_string());
+        }
+    }
+
+    /// `text` with emails and multi-word names replaced by `replacement`, and
+    /// how many were.
+    fn redact(&self, text: &str, replacement: &str) -> (String, usize) {
+        let identities = self.identities.borrow();
+        let mut out = text.to_string();
+        let mut count = 0usize;
+        for identity in identities.iter() {
+            if !identity.contains('@') && !identity.contains(char::is_whitespace) {
+                continue;
+            }
+            let pattern = format!(r"(?i)\b{}\b", regex::escape(identity));
+            let Ok(re) = Regex::new(&pattern) else {
+                continue;
+            };
+            let found = re.find_iter(&out).count();
+            if found > 0 {
+                count += found;
+                out = re.replace_all(&out, replacement).into_owned();
+            }
+        }
+        (out, count)
+    }
+}
+
+fn is_personal(property: &str) -> bool {
+    let property = property.to_ascii_lowercase();
+    PERSONAL_PROPERTIES
+        .iter()
+        .any(|name| property.contains(name))
+}
+
+fn is_stamp_line(line: &str) -> bool {
+    line.chars().count() <= MAX_STAMP_LINE_CHARS && STAMP_LINE_RE.is_match(line)
+}